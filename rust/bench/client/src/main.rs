@@ -4,7 +4,7 @@ use failure::Error;
 extern crate log;
 use futures::future::join_all;
 use loqui_bench_common::{configure_logging, make_socket_address};
-use loqui_client::{Client, Config};
+use loqui_client::{Client, Config, TransportOptions};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -101,6 +101,8 @@ async fn main() -> Result<(), Error> {
         request_timeout: Duration::from_secs(5),
         handshake_timeout: Duration::from_secs(5),
         supported_encodings: &["msgpack", "identity"],
+        transport_options: TransportOptions::default(),
+        retry_policy: None,
     };
     let client = Arc::new(
         Client::start_connect(make_socket_address(), config)
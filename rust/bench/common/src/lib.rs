@@ -1,7 +1,4 @@
-use chrono;
 use failure::Error;
-use fern;
-use log;
 use std::net::SocketAddr;
 
 const ADDRESS: &str = "127.0.0.1:8080";
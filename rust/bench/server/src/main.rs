@@ -1,7 +1,7 @@
 use bytesize::ByteSize;
 use failure::Error;
 use loqui_bench_common::{configure_logging, make_socket_address};
-use loqui_server::{Config, RequestHandler, Server};
+use loqui_server::{Config, RequestHandler, Server, TransportOptions};
 use std::future::Future;
 use std::pin::Pin;
 use std::time::Duration;
@@ -35,6 +35,7 @@ async fn main() -> Result<(), Error> {
         ping_interval: Duration::from_secs(5),
         handshake_timeout: Duration::from_secs(5),
         supported_encodings: &["msgpack", "identity"],
+        transport_options: TransportOptions::default(),
     };
     let server = Server::new(config);
     let result = server.listen_and_serve(make_socket_address()).await;
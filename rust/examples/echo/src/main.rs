@@ -2,10 +2,8 @@
 extern crate log;
 
 use bytesize::ByteSize;
-use chrono;
 use failure::Error;
-use fern;
-use loqui_client::{Client, Config as ClientConfig};
+use loqui_client::{Client, Config as ClientConfig, TransportOptions};
 use loqui_server::{Config as ServerConfig, RequestHandler, Server};
 use std::future::Future;
 use std::net::SocketAddr;
@@ -59,6 +57,8 @@ async fn client_send_loop() {
         request_timeout: Duration::from_secs(5),
         handshake_timeout: Duration::from_secs(5),
         supported_encodings: SUPPORTED_ENCODINGS,
+        transport_options: TransportOptions::default(),
+        retry_policy: None,
     };
 
     let address: SocketAddr = ADDRESS.parse().expect("Failed to parse address.");
@@ -103,6 +103,7 @@ fn spawn_server() {
             ping_interval: Duration::from_secs(5),
             handshake_timeout: Duration::from_secs(5),
             supported_encodings: SUPPORTED_ENCODINGS,
+            transport_options: TransportOptions::default(),
         };
         let server = Server::new(config);
         let address: SocketAddr = ADDRESS.parse().expect("Failed to parse address.");
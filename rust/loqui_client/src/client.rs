@@ -1,18 +1,28 @@
 use crate::connection_handler::{ConnectionHandler, InternalEvent};
-use crate::waiter::ResponseWaiter;
-use crate::Config;
+use crate::retry::new_idempotency_key;
+use crate::waiter::{ResponseWaiter, TracedResponse};
+use crate::{
+    ClientError, ClockSkew, Config, ConnectionHealth, ConnectionStats, RetryPolicy,
+    ServerRequestHandler,
+};
 use failure::Error;
-use futures::channel::mpsc::{channel, Sender};
+use futures::channel::mpsc::{channel, unbounded, Sender, UnboundedReceiver};
 use futures::channel::oneshot;
-use futures::{SinkExt, StreamExt, TryFutureExt};
-use loqui_connection::{timeout_at, Connection, LoquiError};
+use futures::future::join_all;
+use futures::task::{Context, Poll};
+use futures::{ready, SinkExt, Stream, StreamExt, TryFutureExt};
+use loqui_connection::{
+    find_encoding, timeout_at, Connection, Encoder, Factory, LoquiError, CONNECTION_REQUEST_FLAGS,
+};
+use loqui_protocol::frames::{IdempotencyKey, Priority, TraceId};
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst};
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::time::Duration;
 use tokio::task::spawn;
-use tokio::time::Instant;
+use tokio::time::{delay_for, Instant};
 
 pub struct Client {
     connection: Connection<ConnectionHandler>,
@@ -21,16 +31,126 @@ pub struct Client {
     ready: Arc<AtomicBool>,
     ready_waiter_tx: Sender<oneshot::Sender<()>>,
     encoding: Arc<RwLock<Option<&'static str>>>,
+    rtt: Arc<RwLock<Option<Duration>>>,
+    clock_skew: Arc<RwLock<Option<ClockSkew>>>,
+    /// Set once the server accepted `RequestBatch`es in the handshake.
+    batches: Arc<AtomicBool>,
+    supported_encodings: &'static [&'static str],
+    renegotiation: bool,
+    /// Set while the encoding is being renegotiated.
+    renegotiating: AtomicBool,
+    half_closed: AtomicBool,
+    flow_controlled: bool,
+    /// Set when requests made before the handshake completed wait for it, see
+    /// `TransportOptions::queue_until_ready`.
+    queue_until_ready: bool,
+    retry_policy: Option<RetryPolicy>,
+}
+
+/// How a single request is sent. The defaults are those of `Client::request`.
+#[derive(Default)]
+struct RequestOptions {
+    trace_id: Option<TraceId>,
+    /// Set when the request may be retried.
+    idempotency_key: Option<IdempotencyKey>,
+    priority: Priority,
+    /// Overrides `Config::request_timeout`.
+    timeout: Option<Duration>,
+    /// Set when the payload is encoded with another of the negotiated encodings.
+    encoding: Option<&'static str>,
+    /// OR'd into `TransportOptions::default_request_flags`.
+    flags: u8,
+}
+
+/// The responses of a streamed response. Grants the server a credit for each one taken off the
+/// stream when flow controlled.
+struct ResponseStream {
+    responses: UnboundedReceiver<Result<Vec<u8>, Error>>,
+    sequence_id: Arc<AtomicU32>,
+    /// Set when the stream is flow controlled.
+    connection: Option<Connection<ConnectionHandler>>,
+}
+
+impl Stream for ResponseStream {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.responses.poll_next_unpin(cx));
+        if let (Some(Ok(_payload)), Some(connection)) = (&item, &self.connection) {
+            let window_update = InternalEvent::WindowUpdate {
+                sequence_id: self.sequence_id.load(SeqCst),
+                increment: 1,
+            };
+            // It's okay to ignore this result. The connection closed.
+            let _result = connection.send(window_update);
+        }
+        Poll::Ready(item)
+    }
+}
+
+/// The pushes published to a subscription. Unsubscribes when dropped.
+struct SubscriptionStream {
+    pushes: UnboundedReceiver<Result<Vec<u8>, Error>>,
+    subscription_id: Arc<AtomicU32>,
+    connection: Connection<ConnectionHandler>,
+}
+
+impl Stream for SubscriptionStream {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.pushes.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for SubscriptionStream {
+    fn drop(&mut self) {
+        let unsubscribe = InternalEvent::Unsubscribe {
+            subscription_id: self.subscription_id.clone(),
+        };
+        // It's okay to ignore this result. The connection closed.
+        let _result = self.connection.send(unsubscribe);
+    }
 }
 
 const READY_CHAN_BUFFER_SIZE: usize = 100_000;
 
 impl Client {
     pub async fn start_connect(address: SocketAddr, config: Config) -> Result<Client, Error> {
+        Self::connect(address, config, None)
+    }
+
+    /// Like `start_connect`, also answering the requests the server sends with the handler. Both
+    /// sides must turn on `TransportOptions::peer_requests`.
+    pub async fn start_connect_with_handler<S: ServerRequestHandler>(
+        address: SocketAddr,
+        config: Config,
+        request_handler: S,
+    ) -> Result<Client, Error> {
+        Self::connect(address, config, Some(Arc::new(request_handler)))
+    }
+
+    fn connect(
+        address: SocketAddr,
+        config: Config,
+        request_handler: Option<Arc<dyn ServerRequestHandler>>,
+    ) -> Result<Client, Error> {
         let handshake_deadline = Instant::now() + config.handshake_timeout;
         let request_timeout = config.request_timeout;
+        let flow_controlled = config.transport_options.stream_window.is_some();
+        let retry_policy = config.retry_policy.clone();
+        let supported_encodings = config.supported_encodings;
+        let renegotiation = config.transport_options.renegotiation;
+        let queue_until_ready = config.transport_options.queue_until_ready;
 
-        let handler = ConnectionHandler::new(config);
+        let rtt = Arc::new(RwLock::new(None));
+        let clock_skew = Arc::new(RwLock::new(None));
+        let batches = Arc::new(AtomicBool::new(false));
+        let mut handler =
+            ConnectionHandler::new(config, rtt.clone(), clock_skew.clone(), batches.clone());
+        if let Some(request_handler) = request_handler {
+            handler.set_request_handler(request_handler);
+        }
 
         let ready = Arc::new(AtomicBool::new(false));
         let (ready_tx, ready_rx) = oneshot::channel();
@@ -64,6 +184,16 @@ impl Client {
             ready,
             ready_waiter_tx,
             encoding,
+            rtt,
+            clock_skew,
+            batches,
+            supported_encodings,
+            renegotiation,
+            renegotiating: AtomicBool::new(false),
+            half_closed: AtomicBool::new(false),
+            flow_controlled,
+            queue_until_ready,
+            retry_policy,
         })
     }
 
@@ -75,44 +205,448 @@ impl Client {
         self.encoding
             .read()
             .expect("Failed to read encoding.")
-            .clone()
             .ok_or_else(|| Error::from(LoquiError::NoClientEncoding))
     }
 
+    /// Decodes the payload of an error response with the negotiated encoding, see
+    /// `ClientError::decode`. Returns the error as is if it isn't an error response.
+    pub fn decode_error<F: Factory>(
+        &self,
+        error: Error,
+    ) -> Result<ClientError<<F::Encoder as Encoder>::Decoded>, Error> {
+        let encoder =
+            F::make(self.encoding()?).ok_or_else(|| Error::from(LoquiError::InvalidEncoding))?;
+        ClientError::decode(error, &encoder)
+    }
+
+    /// The moving average of the ping round-trip time. `None` until the first `Pong` arrives.
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.read().expect("Failed to read rtt.")
+    }
+
+    /// The clock skew estimated from the latest timestamped `Pong`. `None` until one arrives, or
+    /// unless both sides turned on `TransportOptions::ping_timestamps`.
+    pub fn clock_skew(&self) -> Option<ClockSkew> {
+        *self.clock_skew.read().expect("Failed to read clock skew.")
+    }
+
+    /// A snapshot of the counters of the connection, e.g. the frames sent and received, see
+    /// `Connection::stats`.
+    pub fn stats(&self) -> ConnectionStats {
+        self.connection.stats()
+    }
+
     pub fn is_ready(&self) -> bool {
         self.ready.load(SeqCst)
     }
 
-    /// Send a request to the server.
+    /// Send a request to the server. It is never retried.
     pub async fn request(&self, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
-        if self.is_closed() {
-            return Err(LoquiError::ConnectionClosed.into());
+        self.send_request(payload, RequestOptions::default())
+            .await
+            .map(|(payload, _trace_id)| payload)
+    }
+
+    /// Send a request with flags of its own, OR'd into `TransportOptions::default_request_flags`.
+    /// The flags a frame derives from its fields, e.g. `Flags::Traced`, are still set by it. Fails
+    /// with `LoquiError::InvalidRequestFlags` if they include `CONNECTION_REQUEST_FLAGS`.
+    pub async fn request_with_flags(&self, payload: Vec<u8>, flags: u8) -> Result<Vec<u8>, Error> {
+        if flags & CONNECTION_REQUEST_FLAGS != 0 {
+            return Err(LoquiError::InvalidRequestFlags { flags }.into());
         }
-        if !self.is_ready() {
-            return Err(LoquiError::NotReady.into());
+        let options = RequestOptions {
+            flags,
+            ..RequestOptions::default()
+        };
+        self.send_request(payload, options)
+            .await
+            .map(|(payload, _trace_id)| payload)
+    }
+
+    /// Send a request that is safe to handle more than once. It is retried as the
+    /// `Config::retry_policy` says when it fails with a transient error, e.g.
+    /// `LoquiErrorCode::ServiceUnavailable`, and every attempt carries the same idempotency key so
+    /// the server can dedupe them. There is no reconnect, so once the connection closed the error
+    /// is returned without retrying.
+    pub async fn request_idempotent(&self, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let idempotency_key = new_idempotency_key();
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .send_request(
+                    payload.clone(),
+                    RequestOptions {
+                        idempotency_key: Some(idempotency_key),
+                        ..RequestOptions::default()
+                    },
+                )
+                .await;
+            let retry_policy = match (&result, &self.retry_policy) {
+                (Err(error), Some(retry_policy))
+                    if attempt < retry_policy.max_attempts
+                        && RetryPolicy::is_retryable(error)
+                        && !self.is_closed() =>
+                {
+                    retry_policy
+                }
+                _ => return result.map(|(payload, _trace_id)| payload),
+            };
+            debug!(
+                "Retrying request. attempt={} error={:?}",
+                attempt,
+                result.unwrap_err()
+            );
+            delay_for(retry_policy.backoff(attempt)).await;
+            attempt += 1;
         }
-        let (waiter, awaitable) = ResponseWaiter::new(self.request_timeout);
-        let request = InternalEvent::Request { payload, waiter };
+    }
+
+    /// Send a request to the server carrying a trace id. Resolves to the response along with the
+    /// trace id the server echoed back.
+    pub async fn request_traced(
+        &self,
+        payload: Vec<u8>,
+        trace_id: TraceId,
+    ) -> Result<TracedResponse, Error> {
+        let options = RequestOptions {
+            trace_id: Some(trace_id),
+            ..RequestOptions::default()
+        };
+        self.send_request(payload, options).await
+    }
+
+    /// Send a request the server serves ahead of lower priority ones while it is at its
+    /// `TransportOptions::max_concurrent_requests`, see `TransportOptions::request_queue_depth`.
+    pub async fn request_with_priority(
+        &self,
+        payload: Vec<u8>,
+        priority: Priority,
+    ) -> Result<Vec<u8>, Error> {
+        let options = RequestOptions {
+            priority,
+            ..RequestOptions::default()
+        };
+        self.send_request(payload, options)
+            .await
+            .map(|(payload, _trace_id)| payload)
+    }
+
+    /// Send a request with its own timeout instead of `Config::request_timeout`. The server gives
+    /// up on it once the timeout elapsed too, though it may clamp it, see
+    /// `TransportOptions::max_request_timeout`.
+    pub async fn request_with_timeout(
+        &self,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let options = RequestOptions {
+            timeout: Some(timeout),
+            ..RequestOptions::default()
+        };
+        self.send_request(payload, options)
+            .await
+            .map(|(payload, _trace_id)| payload)
+    }
+
+    /// Send a request encoded with another of the supported encodings than the one in use. The
+    /// server handles it, and responds, with that encoding. Both sides must turn on
+    /// `TransportOptions::encoding_ids`, and fails with `LoquiError::InvalidEncoding` unless the
+    /// server supports the encoding too.
+    pub async fn request_with_encoding(
+        &self,
+        payload: Vec<u8>,
+        encoding: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let encoding = find_encoding(encoding, self.supported_encodings)
+            .ok_or_else(|| Error::from(LoquiError::InvalidEncoding))?;
+        let options = RequestOptions {
+            encoding: Some(encoding),
+            ..RequestOptions::default()
+        };
+        self.send_request(payload, options)
+            .await
+            .map(|(payload, _trace_id)| payload)
+    }
+
+    async fn send_request(
+        &self,
+        payload: Vec<u8>,
+        options: RequestOptions,
+    ) -> Result<TracedResponse, Error> {
+        let RequestOptions {
+            trace_id,
+            idempotency_key,
+            priority,
+            timeout,
+            encoding,
+            flags,
+        } = options;
+        self.check_can_send().await?;
+        let (waiter, awaitable) = ResponseWaiter::new(timeout.unwrap_or(self.request_timeout));
+        // Rounded up, so a sub-millisecond timeout doesn't mean the server's default.
+        let timeout_ms = timeout.map_or(0, |timeout| {
+            timeout.as_micros().div_ceil(1000).min(u128::from(u32::MAX)) as u32
+        });
+        let request = InternalEvent::Request {
+            trace_id,
+            idempotency_key,
+            priority,
+            timeout_ms,
+            encoding,
+            flags,
+            payload,
+            waiter,
+        };
+        self.connection.send(request)?;
+        let result = awaitable.await;
+        if let Err(error) = &result {
+            if let Some(LoquiError::RequestTimeout) = error.downcast_ref::<LoquiError>() {
+                // It's okay to ignore this result. The connection closed.
+                let _result = self.connection.send(InternalEvent::CancelExpired);
+            }
+        }
+        result
+    }
+
+    /// Send several requests in a single `RequestBatch`, saving the overhead of a frame per
+    /// request. Resolves once every request completed, to their results in order. When the server
+    /// didn't accept batches in the handshake the requests are sent one by one instead.
+    pub async fn request_batch(
+        &self,
+        payloads: Vec<Vec<u8>>,
+    ) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
+        self.check_can_send().await?;
+        if !self.batches.load(SeqCst) {
+            return Ok(join_all(payloads.into_iter().map(|payload| self.request(payload))).await);
+        }
+        let (requests, awaitables): (Vec<_>, Vec<_>) = payloads
+            .into_iter()
+            .map(|payload| {
+                let (waiter, awaitable) = ResponseWaiter::new(self.request_timeout);
+                ((payload, waiter), awaitable)
+            })
+            .unzip();
+        self.connection
+            .send(InternalEvent::RequestBatch { requests })?;
+        let results = join_all(awaitables).await;
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                if let Err(error) = &result {
+                    if let Some(LoquiError::RequestTimeout) = error.downcast_ref::<LoquiError>() {
+                        // It's okay to ignore this result. The connection closed.
+                        let _result = self.connection.send(InternalEvent::CancelExpired);
+                    }
+                }
+                result.map(|(payload, _trace_id)| payload)
+            })
+            .collect())
+    }
+
+    /// Send a request to the server asking for a streamed response. The stream yields each
+    /// response as it arrives and ends once the server ended it or sent an error. Unlike
+    /// `request` it isn't bound by the request timeout.
+    ///
+    /// When `TransportOptions::stream_window` is set the stream is flow controlled, and the
+    /// server is granted a credit for each response once it was taken off the stream.
+    pub async fn request_stream(
+        &self,
+        payload: Vec<u8>,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>, Error>>, Error> {
+        self.check_can_send().await?;
+        let (stream, responses) = unbounded();
+        let sequence_id = Arc::new(AtomicU32::new(0));
+        let request = InternalEvent::StreamRequest {
+            payload,
+            stream,
+            sequence_id: sequence_id.clone(),
+        };
         self.connection.send(request)?;
-        awaitable.await
+        let connection = if self.flow_controlled {
+            Some(self.connection.clone())
+        } else {
+            None
+        };
+        Ok(ResponseStream {
+            responses,
+            sequence_id,
+            connection,
+        })
+    }
+
+    /// Subscribe to pushes published by the server. The stream yields each push as it arrives
+    /// and ends once the server unsubscribed or rejected the subscription with an error.
+    /// Dropping the stream unsubscribes.
+    pub async fn subscribe(
+        &self,
+        payload: Vec<u8>,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>, Error>>, Error> {
+        self.check_can_send().await?;
+        let (stream, pushes) = unbounded();
+        let subscription_id = Arc::new(AtomicU32::new(0));
+        let subscribe = InternalEvent::Subscribe {
+            payload,
+            stream,
+            subscription_id: subscription_id.clone(),
+        };
+        self.connection.send(subscribe)?;
+        Ok(SubscriptionStream {
+            pushes,
+            subscription_id,
+            connection: self.connection.clone(),
+        })
     }
 
     /// Send a push to the server.
     pub async fn push(&self, payload: Vec<u8>) -> Result<(), Error> {
+        self.check_can_send().await?;
+        let push = InternalEvent::Push {
+            payload,
+            waiter: None,
+        };
+        Ok(self.connection.send(push)?)
+    }
+
+    /// Send a push and wait until it was flushed to the socket. Lighter than `push_acked`, but it
+    /// doesn't tell whether the server received it.
+    pub async fn push_flushed(&self, payload: Vec<u8>) -> Result<(), Error> {
+        self.check_can_send().await?;
+        let push = InternalEvent::Push {
+            payload,
+            waiter: None,
+        };
+        let flushed = self.connection.send_flushed(push)?;
+        timeout_at(
+            Instant::now() + self.request_timeout,
+            flushed.map_err(|_canceled| Error::from(LoquiError::NotFlushed)),
+        )
+        .await
+    }
+
+    /// Send a push the server acknowledges. Resolves once it was acked, so on error it may or may
+    /// not have been delivered and can be retried.
+    pub async fn push_acked(&self, payload: Vec<u8>) -> Result<(), Error> {
+        self.check_can_send().await?;
+        let (waiter, awaitable) = ResponseWaiter::new(self.request_timeout);
+        let push = InternalEvent::Push {
+            payload,
+            waiter: Some(waiter),
+        };
+        self.connection.send(push)?;
+        awaitable.await.map(|_ack| ())
+    }
+
+    /// Switch the connection to another of the supported encodings without reconnecting. Both
+    /// sides must turn on `TransportOptions::renegotiation`. The server switches once it drained
+    /// the requests in flight with the current encoding, and no requests or pushes can be sent
+    /// until then. Resolves to the new encoding, or fails with `LoquiError::RenegotiationRefused`
+    /// if the server kept the current one.
+    pub async fn renegotiate_encoding(&self, encoding: &str) -> Result<&'static str, Error> {
+        self.check_can_send().await?;
+        if !self.renegotiation {
+            return Err(LoquiError::RenegotiationDisabled.into());
+        }
+        let encoding = find_encoding(encoding, self.supported_encodings)
+            .ok_or_else(|| Error::from(LoquiError::InvalidEncoding))?;
+        if self.renegotiating.swap(true, SeqCst) {
+            return Err(LoquiError::Renegotiating.into());
+        }
+        let result = self.send_renegotiate(encoding).await;
+        self.renegotiating.store(false, SeqCst);
+        let renegotiated = result?;
+        *self.encoding.write().expect("Failed to write encoding") = Some(renegotiated);
+        if renegotiated != encoding {
+            return Err(LoquiError::RenegotiationRefused {
+                requested: encoding,
+            }
+            .into());
+        }
+        Ok(renegotiated)
+    }
+
+    async fn send_renegotiate(&self, encoding: &'static str) -> Result<&'static str, Error> {
+        let (waiter, awaitable) = oneshot::channel();
+        self.connection
+            .send(InternalEvent::AwaitRenegotiation { waiter })?;
+        self.connection.renegotiate_encoding(encoding)?;
+        timeout_at(
+            Instant::now() + self.request_timeout,
+            awaitable.map_err(|_canceled| Error::from(LoquiError::ConnectionClosed)),
+        )
+        .await
+    }
+
+    /// Asks the server for the stats of the connection, e.g. to validate it before a pool hands
+    /// it out. Unlike a ping, it tells that the server still handles frames. Fails if no answer
+    /// arrived within the request timeout.
+    pub async fn health_check(&self) -> Result<ConnectionHealth, Error> {
+        if !self.is_ready() {
+            return Err(LoquiError::NotReady.into());
+        }
+        timeout_at(
+            Instant::now() + self.request_timeout,
+            self.connection.health_check().map_err(Error::from),
+        )
+        .await
+    }
+
+    /// Stop sending requests and pushes. Responses to the ones already sent still arrive, and the
+    /// server closes the connection once it has sent them all.
+    pub fn half_close(&self) -> Result<(), Error> {
+        self.half_closed.store(true, SeqCst);
+        Ok(self.connection.half_close()?)
+    }
+
+    /// Fail every request waiting for a response, streamed ones included, with
+    /// `LoquiError::ConnectionClosing`, e.g. for test cleanup. The connection stays open. See
+    /// `Connection::cancel_all_inflight`.
+    pub fn cancel_all_inflight(&self) -> Result<(), Error> {
+        Ok(self.connection.cancel_all_inflight()?)
+    }
+
+    /// Stop reading responses and pushes from the socket, e.g. while the application catches up.
+    /// Requests can still be sent, and their timeouts keep running. See
+    /// `Connection::pause_reading`.
+    pub fn pause_reading(&self) -> Result<(), Error> {
+        Ok(self.connection.pause_reading()?)
+    }
+
+    /// Read from the socket again after `pause_reading`.
+    pub fn resume_reading(&self) -> Result<(), Error> {
+        Ok(self.connection.resume_reading()?)
+    }
+
+    async fn check_can_send(&self) -> Result<(), Error> {
+        if self.queue_until_ready && !self.is_ready() && !self.is_closed() {
+            self.await_ready().await?;
+        }
         if self.is_closed() {
             return Err(LoquiError::ConnectionClosed.into());
         }
+        if self.half_closed.load(SeqCst) {
+            return Err(LoquiError::HalfClosed.into());
+        }
+        if self.renegotiating.load(SeqCst) {
+            return Err(LoquiError::Renegotiating.into());
+        }
         if !self.is_ready() {
             return Err(LoquiError::NotReady.into());
         }
-        let push = InternalEvent::Push { payload };
-        self.connection.send(push)
+        Ok(())
     }
 
+    /// Resolves once the handshake completed. Fails with `LoquiError::HandshakeFailed` if it
+    /// didn't, see `Connection::ready`.
     pub async fn await_ready(&self) -> Result<(), Error> {
+        timeout_at(
+            self.handshake_deadline,
+            self.connection.ready().map_err(Error::from),
+        )
+        .await?;
+        // The encoding is stored right after, which requests need.
         let (tx, rx) = oneshot::channel();
 
-        let _result = timeout_at(
+        timeout_at(
             self.handshake_deadline,
             self.ready_waiter_tx.clone().send(tx).map_err(Error::from),
         )
@@ -0,0 +1,129 @@
+use failure::Error;
+use loqui_connection::{Encoder, LoquiError, LoquiErrorCode};
+
+/// The `Error` frame a request was answered with, its payload decoded with the negotiated
+/// encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientError<D> {
+    /// `None` if the code isn't one this version knows, e.g. an application specific code.
+    pub code: Option<LoquiErrorCode>,
+    /// The code as it was sent.
+    pub raw_code: u16,
+    pub details: ErrorDetails<D>,
+    /// The `sequence_id` of the request the error answered.
+    pub sequence_id: u32,
+}
+
+/// The payload of an `Error` frame.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorDetails<D> {
+    /// The payload decoded with the negotiated encoding, see `Handler::error_payload`.
+    Decoded(D),
+    /// The payload didn't decode, e.g. because the server sent the error's message as a string.
+    Message(String),
+}
+
+impl<D> ClientError<D> {
+    /// Decodes the payload of an error response with the encoder. Returns the error as is if it
+    /// isn't an error response, e.g. a timeout.
+    pub fn decode<E: Encoder<Decoded = D>>(error: Error, encoder: &E) -> Result<Self, Error> {
+        match error.downcast::<LoquiError>()? {
+            LoquiError::ErrorResponse {
+                code,
+                reason,
+                sequence_id,
+                payload,
+            } => {
+                let details = match encoder.decode_from(&mut &payload[..], payload.len()) {
+                    Ok(decoded) => ErrorDetails::Decoded(decoded),
+                    Err(_error) => ErrorDetails::Message(unquote(reason)),
+                };
+                Ok(Self {
+                    code: LoquiErrorCode::from_u16(code),
+                    raw_code: code,
+                    details,
+                    sequence_id,
+                })
+            }
+            loqui_error => Err(loqui_error.into()),
+        }
+    }
+}
+
+/// Messages used to be sent in their debug format, e.g. `"Request timeout."` with the quotes.
+fn unquote(reason: String) -> String {
+    if reason.len() >= 2 && reason.starts_with('"') && reason.ends_with('"') {
+        reason[1..reason.len() - 1].replace("\\\"", "\"")
+    } else {
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes single byte payloads.
+    struct ByteEncoder;
+
+    impl Encoder for ByteEncoder {
+        type Decoded = u8;
+        type Encoded = u8;
+
+        fn decode(&self, payload: Vec<u8>) -> Result<u8, Error> {
+            match payload[..] {
+                [byte] => Ok(byte),
+                _ => Err(failure::err_msg("Not a single byte.")),
+            }
+        }
+
+        fn encode(&self, value: u8) -> Result<Vec<u8>, Error> {
+            Ok(vec![value])
+        }
+    }
+
+    fn error_response(code: u16, payload: &[u8]) -> Error {
+        LoquiError::ErrorResponse {
+            code,
+            reason: String::from_utf8_lossy(payload).into_owned(),
+            sequence_id: 3,
+            payload: payload.to_vec(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn it_decodes_the_payload() {
+        let error = ClientError::decode(error_response(7, &[42]), &ByteEncoder).unwrap();
+        assert_eq!(
+            error,
+            ClientError {
+                code: Some(LoquiErrorCode::InternalServerError),
+                raw_code: 7,
+                details: ErrorDetails::Decoded(42),
+                sequence_id: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn it_falls_back_to_the_message() {
+        let error = ClientError::decode(error_response(400, b"\"Request timeout.\""), &ByteEncoder)
+            .unwrap();
+        assert_eq!(error.code, None);
+        assert_eq!(error.raw_code, 400);
+        assert_eq!(
+            error.details,
+            ErrorDetails::Message("Request timeout.".to_string())
+        );
+    }
+
+    #[test]
+    fn it_returns_other_errors_as_is() {
+        let error = ClientError::decode(LoquiError::RequestTimeout.into(), &ByteEncoder);
+        match error.unwrap_err().downcast_ref::<LoquiError>() {
+            Some(LoquiError::RequestTimeout) => {}
+            other => panic!("Unexpected error. {:?}", other),
+        }
+    }
+}
@@ -1,4 +1,6 @@
+use crate::RetryPolicy;
 use bytesize::ByteSize;
+use loqui_connection::TransportOptions;
 use std::time::Duration;
 
 #[derive(Debug, Clone)]
@@ -12,4 +14,8 @@ pub struct Config {
     pub handshake_timeout: Duration,
     /// Supported encodings.
     pub supported_encodings: &'static [&'static str],
+    /// Connection level settings.
+    pub transport_options: TransportOptions,
+    /// How `Client::request_idempotent` retries requests. `None` never retries.
+    pub retry_policy: Option<RetryPolicy>,
 }
@@ -1,57 +1,160 @@
+use crate::pending_requests::PendingRequests;
 use crate::waiter::ResponseWaiter;
-use crate::Config;
+use crate::{Config, ServerRequestHandler};
 use bytesize::ByteSize;
 use failure::{err_msg, Error};
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
+use loqui_connection::compressor::find_compressor;
 use loqui_connection::find_encoding;
-use loqui_connection::handler::{DelegatedFrame, Handler, Ready};
-use loqui_connection::{IdSequence, LoquiError, ReaderWriter};
+use loqui_connection::handler::{
+    ClockSkew, DelegatedFrame, FrameOutcome, Handler, HandshakeFuture, HandshakeTiming,
+    IntoErrorPayload, Negotiated, Ready, Role,
+};
+use loqui_connection::{
+    Compressor, DictionaryRegistry, IdSequence, LoquiError, LoquiErrorCode, ReaderWriter,
+    TransportOptions,
+};
 use loqui_protocol::frames::{
-    Error as ErrorFrame, Frame, Hello, HelloAck, LoquiFrame, Push, Request, Response,
+    BatchEntry, Cancel, Error as ErrorFrame, Frame, Hello, HelloAck, IdempotencyKey, LoquiFrame,
+    Priority, Push, Request, RequestBatch, Response, Subscribe, TraceId, Unsubscribe, WindowUpdate,
 };
 use loqui_protocol::upgrade::{Codec, UpgradeFrame};
-use loqui_protocol::VERSION;
+use loqui_protocol::{
+    has_batches, has_checksums, has_encoding_ids, has_timestamps, is_stream_end, is_streaming,
+    Flags, VERSION,
+};
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio::time::Instant;
 use tokio_util::codec::Framed;
 
 pub enum InternalEvent {
     Request {
+        trace_id: Option<TraceId>,
+        /// Set when the request may be retried.
+        idempotency_key: Option<IdempotencyKey>,
+        /// How urgently the server should serve the request when it is at its concurrency limit.
+        priority: Priority,
+        /// How long the server may take to respond. `0` leaves it to the server.
+        timeout_ms: u32,
+        /// Set when the payload is encoded with another of the negotiated encodings than the one
+        /// in use, see `TransportOptions::encoding_ids`.
+        encoding: Option<&'static str>,
+        /// OR'd into `TransportOptions::default_request_flags`.
+        flags: u8,
         payload: Vec<u8>,
         waiter: ResponseWaiter,
     },
     Push {
         payload: Vec<u8>,
+        /// Set when the push should be acknowledged by the server.
+        waiter: Option<ResponseWaiter>,
+    },
+    /// A request asking for a streamed response. Each response is sent over `stream` as it
+    /// arrives, and `stream` is closed once the server ended it.
+    StreamRequest {
+        payload: Vec<u8>,
+        stream: UnboundedSender<Result<Vec<u8>, Error>>,
+        /// Set to the `sequence_id` of the request once it is sent.
+        sequence_id: Arc<AtomicU32>,
+    },
+    /// A subscription to pushes published by the server. Each push is sent over `stream` as it
+    /// arrives, and `stream` is closed once either side unsubscribed.
+    Subscribe {
+        payload: Vec<u8>,
+        stream: UnboundedSender<Result<Vec<u8>, Error>>,
+        /// Set to the id of the subscription once it is sent.
+        subscription_id: Arc<AtomicU32>,
+    },
+    /// The subscriber stopped listening. Tell the server to stop publishing.
+    Unsubscribe { subscription_id: Arc<AtomicU32> },
+    /// Requests sent together in a single `RequestBatch`, when the server accepts batches.
+    RequestBatch {
+        requests: Vec<(Vec<u8>, ResponseWaiter)>,
+    },
+    /// Grant a flow controlled stream credits for more responses.
+    WindowUpdate { sequence_id: u32, increment: u32 },
+    /// A request timed out. Tell the server to stop working on it.
+    CancelExpired,
+    /// Resolve the waiter with the encoding in use once the renegotiation that is about to be
+    /// requested completed.
+    AwaitRenegotiation {
+        waiter: oneshot::Sender<&'static str>,
     },
 }
 
+/// Weight given to a new round-trip time sample in the moving average.
+const RTT_SAMPLE_WEIGHT: f64 = 0.125;
+
 pub struct ConnectionHandler {
-    waiters: HashMap<u32, ResponseWaiter>,
+    pending: PendingRequests,
+    /// The senders of streamed responses that haven't ended yet, keyed by `sequence_id`.
+    streams: HashMap<u32, UnboundedSender<Result<Vec<u8>, Error>>>,
+    /// The senders of subscriptions that haven't been unsubscribed yet, keyed by subscription id.
+    subscriptions: HashMap<u32, UnboundedSender<Result<Vec<u8>, Error>>>,
     config: Config,
+    rtt: Arc<RwLock<Option<Duration>>>,
+    /// The latest estimate from a timestamped `Pong`.
+    clock_skew: Arc<RwLock<Option<ClockSkew>>>,
+    /// Set once the server accepted `RequestBatch`es in the handshake.
+    batches: Arc<AtomicBool>,
+    /// Waits for the renegotiation in progress to complete.
+    renegotiation: Option<oneshot::Sender<&'static str>>,
+    /// The encodings requests may select, once the server agreed on encoding ids in the
+    /// handshake.
+    encodings: Vec<&'static str>,
+    /// Handles the requests the server sends, see `TransportOptions::peer_requests`.
+    request_handler: Option<Arc<dyn ServerRequestHandler>>,
 }
 
 impl ConnectionHandler {
-    pub fn new(config: Config) -> Self {
+    pub fn new(
+        config: Config,
+        rtt: Arc<RwLock<Option<Duration>>>,
+        clock_skew: Arc<RwLock<Option<ClockSkew>>>,
+        batches: Arc<AtomicBool>,
+    ) -> Self {
         Self {
-            waiters: HashMap::new(),
+            pending: PendingRequests::new(config.transport_options.metrics.clone()),
+            streams: HashMap::new(),
+            subscriptions: HashMap::new(),
             config,
+            rtt,
+            clock_skew,
+            batches,
+            renegotiation: None,
+            encodings: vec![],
+            request_handler: None,
         }
     }
+
+    pub fn set_request_handler(&mut self, request_handler: Arc<dyn ServerRequestHandler>) {
+        self.request_handler = Some(request_handler);
+    }
 }
 
+impl IntoErrorPayload for ConnectionHandler {}
+
 impl Handler for ConnectionHandler {
     type InternalEvent = InternalEvent;
     const SEND_GO_AWAY: bool = false;
+    const ROLE: Option<Role> = Some(Role::Client);
 
     fn max_payload_size(&self) -> ByteSize {
         self.config.max_payload_size
     }
 
+    fn transport_options(&self) -> &TransportOptions {
+        &self.config.transport_options
+    }
+
     fn upgrade(
         &self,
         tcp_stream: TcpStream,
@@ -72,17 +175,11 @@ impl Handler for ConnectionHandler {
         })
     }
 
-    fn handshake(
-        &mut self,
-        mut reader_writer: ReaderWriter,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<(Ready, ReaderWriter), (Error, Option<ReaderWriter>)>>
-                + Send,
-        >,
-    > {
+    fn handshake(&mut self, mut reader_writer: ReaderWriter) -> HandshakeFuture {
         let hello = self.make_hello();
         let supported_encodings = self.config.supported_encodings;
+        let compressors = self.config.transport_options.compressors.clone();
+        let dictionaries = self.config.transport_options.dictionaries.clone();
         Box::pin(async move {
             reader_writer = match reader_writer.write(hello).await {
                 Ok(read_writer) => read_writer,
@@ -90,34 +187,67 @@ impl Handler for ConnectionHandler {
             };
 
             match reader_writer.reader.next().await {
-                Some(Ok(frame)) => match Self::handle_handshake_frame(frame, supported_encodings) {
-                    Ok(ready) => Ok((ready, reader_writer)),
-                    Err(e) => Err((e, Some(reader_writer))),
-                },
+                Some(Ok(frame)) => {
+                    match Self::handle_handshake_frame(
+                        frame,
+                        supported_encodings,
+                        &compressors,
+                        &dictionaries,
+                    ) {
+                        Ok(ready) => Ok((ready, reader_writer)),
+                        Err(e) => Err((e, Some(reader_writer))),
+                    }
+                }
                 Some(Err(e)) => Err((e, Some(reader_writer))),
                 None => Err((LoquiError::TcpStreamClosed.into(), Some(reader_writer))),
             }
         })
     }
 
-    fn handle_frame(
-        &mut self,
-        frame: DelegatedFrame,
-        _encoding: &'static str,
-    ) -> Option<Pin<Box<dyn Send + Future<Output = Result<Response, (Error, u32)>>>>> {
+    fn on_handshake_complete(&mut self, negotiated: &Negotiated, timing: &HandshakeTiming) {
+        debug!(
+            "Handshake complete. negotiated={:?} timing={:?}",
+            negotiated, timing
+        );
+        self.batches.store(negotiated.batches, SeqCst);
+        if let Some(encodings) = &negotiated.encodings {
+            self.encodings = vec![negotiated.encoding];
+            self.encodings.extend(encodings);
+        }
+    }
+
+    fn handle_frame(&mut self, frame: DelegatedFrame, encoding: &'static str) -> FrameOutcome {
         match frame {
             DelegatedFrame::Response(response) => {
                 self.handle_response(response);
-                None
+                FrameOutcome::Ignore
             }
             DelegatedFrame::Error(error) => {
                 self.handle_error(error);
-                None
+                FrameOutcome::Ignore
             }
-            DelegatedFrame::Push(_) | DelegatedFrame::Request(_) => Some(Box::pin(async move {
+            // Without a request handler, requests are caught before they're delegated, see
+            // `TransportOptions::unexpected_frame_policy`. Reject with the request's sequence_id
+            // without spawning a future just in case.
+            DelegatedFrame::Request(request) => match self.request_handler.clone() {
+                Some(request_handler) => FrameOutcome::Respond(Box::pin(handle_request(
+                    request_handler,
+                    request,
+                    encoding,
+                ))),
+                None => FrameOutcome::Reject {
+                    code: LoquiErrorCode::InvalidOpcode,
+                    message: LoquiError::InvalidOpcode {
+                        actual: Request::OPCODE,
+                        expected: None,
+                    }
+                    .to_string(),
+                },
+            },
+            DelegatedFrame::Push(_) => FrameOutcome::Respond(Box::pin(async move {
                 Err((
                     LoquiError::InvalidOpcode {
-                        actual: Request::OPCODE,
+                        actual: Push::OPCODE,
                         expected: None,
                     }
                     .into(),
@@ -134,110 +264,446 @@ impl Handler for ConnectionHandler {
     ) -> Option<LoquiFrame> {
         // Forward Request and Push events to the connection so it can send them to the server.
         match event {
-            InternalEvent::Request { payload, waiter } => {
+            InternalEvent::Request {
+                trace_id,
+                idempotency_key,
+                priority,
+                timeout_ms,
+                encoding,
+                flags,
+                payload,
+                waiter,
+            } => {
+                if let Some(encoding) = encoding {
+                    if !self.encodings.contains(&encoding) {
+                        debug!("Encoding not negotiated. encoding={}", encoding);
+                        waiter.notify(Err(LoquiError::InvalidEncoding.into()));
+                        return None;
+                    }
+                }
+                if self.at_open_sequence_id_limit() {
+                    waiter.notify(Err(too_many_open_sequence_ids()));
+                    return None;
+                }
                 let sequence_id = id_sequence.next();
-                self.send_request(payload, sequence_id, waiter)
+                let request = Request {
+                    trace_id,
+                    idempotency_key,
+                    priority,
+                    timeout_ms,
+                    payload,
+                    sequence_id,
+                    flags: self.config.transport_options.default_request_flags | flags,
+                };
+                self.send_request(request, waiter)
+            }
+            InternalEvent::Push { payload, waiter } => {
+                if waiter.is_some() && self.at_open_sequence_id_limit() {
+                    if let Some(waiter) = waiter {
+                        waiter.notify(Err(too_many_open_sequence_ids()));
+                    }
+                    return None;
+                }
+                let sequence_id = waiter.as_ref().map(|_waiter| id_sequence.next());
+                self.send_push(payload, sequence_id, waiter)
+            }
+            InternalEvent::StreamRequest {
+                payload,
+                stream,
+                sequence_id: stream_sequence_id,
+            } => {
+                if self.at_open_sequence_id_limit() {
+                    // It's okay to ignore this result. The stream is no longer listening.
+                    let _result = stream.unbounded_send(Err(too_many_open_sequence_ids()));
+                    return None;
+                }
+                let sequence_id = id_sequence.next();
+                stream_sequence_id.store(sequence_id, SeqCst);
+                self.send_stream_request(payload, sequence_id, stream)
+            }
+            InternalEvent::Subscribe {
+                payload,
+                stream,
+                subscription_id: stream_subscription_id,
+            } => {
+                if self.at_open_sequence_id_limit() {
+                    // It's okay to ignore this result. The subscriber is no longer listening.
+                    let _result = stream.unbounded_send(Err(too_many_open_sequence_ids()));
+                    return None;
+                }
+                let subscription_id = id_sequence.next();
+                stream_subscription_id.store(subscription_id, SeqCst);
+                self.send_subscribe(payload, subscription_id, stream)
             }
-            InternalEvent::Push { payload } => self.send_push(payload),
+            InternalEvent::Unsubscribe { subscription_id } => {
+                self.send_unsubscribe(subscription_id.load(SeqCst))
+            }
+            InternalEvent::RequestBatch { requests } => {
+                self.send_request_batch(requests, id_sequence)
+            }
+            InternalEvent::WindowUpdate {
+                sequence_id,
+                increment,
+            } => self.send_window_update(sequence_id, increment),
+            InternalEvent::CancelExpired => self.send_cancel(),
+            InternalEvent::AwaitRenegotiation { waiter } => {
+                self.renegotiation = Some(waiter);
+                None
+            }
+        }
+    }
+
+    fn internal_event_encoding(&self, event: &InternalEvent) -> Option<&'static str> {
+        match event {
+            InternalEvent::Request { encoding, .. } => *encoding,
+            _ => None,
+        }
+    }
+
+    fn on_encoding_renegotiated(&mut self, encoding: &'static str) {
+        if let Some(waiter) = self.renegotiation.take() {
+            // It's okay to ignore this result. The client stopped waiting.
+            let _result = waiter.send(encoding);
         }
     }
 
     fn on_ping_received(&mut self) {
         // Use to sweep dead waiters.
-        let now = Instant::now();
-        self.waiters
-            .retain(|_sequence_id, waiter| waiter.deadline > now);
+        self.pending.expire();
+        self.streams
+            .retain(|_sequence_id, stream| !stream.is_closed());
+        self.subscriptions
+            .retain(|_subscription_id, stream| !stream.is_closed());
+    }
+
+    fn on_cancel_all(&mut self) {
+        self.pending.cancel_all();
+        for (_sequence_id, stream) in self.streams.drain() {
+            // It's okay to ignore this result. The stream is no longer listening.
+            let _result = stream.unbounded_send(Err(LoquiError::ConnectionClosing.into()));
+        }
+    }
+
+    fn handle_subscription_push(&mut self, subscription_id: u32, payload: Vec<u8>) -> bool {
+        let stream = match self.subscriptions.get(&subscription_id) {
+            Some(stream) => stream,
+            None => return false,
+        };
+        if stream.unbounded_send(Ok(payload)).is_err() {
+            debug!(
+                "Subscription is no longer listening. subscription_id={:?}",
+                subscription_id
+            );
+            self.subscriptions.remove(&subscription_id);
+            return false;
+        }
+        true
+    }
+
+    fn handle_unsubscribe(&mut self, subscription_id: u32) {
+        // Dropping the sender ends the stream.
+        self.subscriptions.remove(&subscription_id);
+    }
+
+    fn handle_push_ack(&mut self, sequence_id: u32) {
+        self.pending.resolve(sequence_id, Ok((vec![], None)));
+    }
+
+    fn observe_rtt(&mut self, rtt: Duration) {
+        // Exponentially weighted moving average so a single slow pong doesn't dominate.
+        let mut average = self.rtt.write().expect("Failed to write rtt");
+        *average = Some(match *average {
+            Some(average) => {
+                average.mul_f64(1.0 - RTT_SAMPLE_WEIGHT) + rtt.mul_f64(RTT_SAMPLE_WEIGHT)
+            }
+            None => rtt,
+        });
+    }
+
+    fn observe_clock_skew(&mut self, clock_skew: ClockSkew) {
+        *self.clock_skew.write().expect("Failed to write clock skew") = Some(clock_skew);
     }
 }
 
 impl ConnectionHandler {
-    fn send_push(&mut self, payload: Vec<u8>) -> Option<LoquiFrame> {
-        let push = Push { payload, flags: 0 };
-        Some(push.into())
+    /// Whether as many ids wait for the server as `TransportOptions::max_open_sequence_ids`
+    /// allows.
+    fn at_open_sequence_id_limit(&self) -> bool {
+        match self.config.transport_options.max_open_sequence_ids {
+            Some(max_open_sequence_ids) => {
+                self.pending.len() + self.streams.len() + self.subscriptions.len()
+                    >= max_open_sequence_ids
+            }
+            None => false,
+        }
     }
 
-    fn send_request(
+    fn send_push(
         &mut self,
         payload: Vec<u8>,
-        sequence_id: u32,
-        waiter: ResponseWaiter,
+        sequence_id: Option<u32>,
+        waiter: Option<ResponseWaiter>,
     ) -> Option<LoquiFrame> {
-        if waiter.deadline <= Instant::now() {
-            waiter.notify(Err(LoquiError::RequestTimeout.into()));
-            return None;
+        if let (Some(sequence_id), Some(waiter)) = (sequence_id, waiter) {
+            // Acks share the waiters of responses since they share the sequence.
+            if !self.pending.insert(sequence_id, waiter) {
+                return None;
+            }
         }
+        let push = Push {
+            payload,
+            sequence_id,
+            subscription_id: None,
+            flags: 0,
+        };
+        Some(push.into())
+    }
+
+    /// Cancels a request whose waiter has expired. One request is cancelled per call.
+    fn send_cancel(&mut self) -> Option<LoquiFrame> {
+        let sequence_id = self.pending.take_expired()?;
+        let cancel = Cancel {
+            flags: 0,
+            sequence_id,
+        };
+        Some(cancel.into())
+    }
 
+    fn send_request(&mut self, request: Request, waiter: ResponseWaiter) -> Option<LoquiFrame> {
         // Store the waiter so we can notify it when we get a response.
-        self.waiters.insert(sequence_id, waiter);
+        if !self.pending.insert(request.sequence_id, waiter) {
+            return None;
+        }
+        Some(request.into())
+    }
+
+    /// Sends the requests in a `RequestBatch`. The batch and each request get their own
+    /// `sequence_id`, and each request is resolved on its own as the server responds to it.
+    fn send_request_batch(
+        &mut self,
+        requests: Vec<(Vec<u8>, ResponseWaiter)>,
+        id_sequence: &mut IdSequence,
+    ) -> Option<LoquiFrame> {
+        let sequence_id = id_sequence.next();
+        let mut entries = Vec::with_capacity(requests.len());
+        for (payload, waiter) in requests {
+            if self.at_open_sequence_id_limit() {
+                waiter.notify(Err(too_many_open_sequence_ids()));
+                continue;
+            }
+            let sequence_id = id_sequence.next();
+            if self.pending.insert(sequence_id, waiter) {
+                entries.push(BatchEntry {
+                    sequence_id,
+                    code: 0,
+                    payload,
+                });
+            }
+        }
+        if entries.is_empty() {
+            return None;
+        }
+        let request_batch = RequestBatch {
+            flags: self.config.transport_options.default_request_flags,
+            sequence_id,
+            entries,
+        };
+        Some(request_batch.into())
+    }
+
+    fn send_stream_request(
+        &mut self,
+        payload: Vec<u8>,
+        sequence_id: u32,
+        stream: UnboundedSender<Result<Vec<u8>, Error>>,
+    ) -> Option<LoquiFrame> {
+        self.streams.insert(sequence_id, stream);
+        let mut flags =
+            self.config.transport_options.default_request_flags | Flags::Streaming as u8;
+        if self.config.transport_options.stream_window.is_some() {
+            flags |= Flags::FlowControlled as u8;
+        }
         let request = Request {
+            trace_id: None,
+            idempotency_key: None,
+            priority: Priority::Normal,
+            timeout_ms: 0,
             payload,
             sequence_id,
-            flags: 0,
+            flags,
         };
         Some(request.into())
     }
 
-    fn handle_response(&mut self, response: Response) {
+    fn send_subscribe(
+        &mut self,
+        payload: Vec<u8>,
+        subscription_id: u32,
+        stream: UnboundedSender<Result<Vec<u8>, Error>>,
+    ) -> Option<LoquiFrame> {
+        self.subscriptions.insert(subscription_id, stream);
+        let subscribe = Subscribe {
+            flags: 0,
+            sequence_id: subscription_id,
+            payload,
+        };
+        Some(subscribe.into())
+    }
+
+    /// Nothing is sent if the server already ended the subscription.
+    fn send_unsubscribe(&mut self, subscription_id: u32) -> Option<LoquiFrame> {
+        self.subscriptions.remove(&subscription_id)?;
+        let unsubscribe = Unsubscribe {
+            flags: 0,
+            sequence_id: subscription_id,
+        };
+        Some(unsubscribe.into())
+    }
+
+    /// Grants credits to a stream. Nothing is sent once the stream ended.
+    fn send_window_update(&mut self, sequence_id: u32, increment: u32) -> Option<LoquiFrame> {
+        if !self.streams.contains_key(&sequence_id) {
+            return None;
+        }
+        let window_update = WindowUpdate {
+            flags: 0,
+            sequence_id,
+            increment,
+        };
+        Some(window_update.into())
+    }
+
+    /// Forwards a response of a streamed response. Returns it back if it isn't part of one.
+    fn handle_stream_response(&mut self, response: Response) -> Option<Response> {
+        if !is_streaming(response.flags) {
+            return Some(response);
+        }
         let Response {
-            flags: _flags,
+            flags,
             sequence_id,
             payload,
+            ..
         } = response;
-        match self.waiters.remove(&sequence_id) {
-            Some(waiter) => {
-                waiter.notify(Ok(payload));
-            }
-            None => {
-                debug!("No waiter for sequence_id. sequence_id={:?}", sequence_id);
+        if is_stream_end(flags) {
+            // Dropping the sender ends the stream.
+            self.streams.remove(&sequence_id);
+            return None;
+        }
+        match self.streams.get(&sequence_id) {
+            Some(stream) => {
+                if stream.unbounded_send(Ok(payload)).is_err() {
+                    debug!(
+                        "Stream is no longer listening. sequence_id={:?}",
+                        sequence_id
+                    );
+                    self.streams.remove(&sequence_id);
+                }
             }
+            None => debug!("No stream for sequence_id. sequence_id={:?}", sequence_id),
         }
+        None
+    }
+
+    fn handle_response(&mut self, response: Response) {
+        let response = match self.handle_stream_response(response) {
+            Some(response) => response,
+            None => return,
+        };
+        let Response {
+            flags: _flags,
+            sequence_id,
+            trace_id,
+            payload,
+        } = response;
+        self.pending.resolve(sequence_id, Ok((payload, trace_id)));
     }
 
     fn handle_error(&mut self, error: ErrorFrame) {
         let ErrorFrame {
             sequence_id,
+            code,
             payload,
             ..
         } = error;
-        match self.waiters.remove(&sequence_id) {
-            Some(waiter) => {
-                // payload is always a string
-                let result = String::from_utf8(payload)
-                    .map_err(Error::from)
-                    .and_then(|reason| Err(err_msg(reason)));
-                waiter.notify(result);
-            }
-            None => {
-                debug!("No waiter for sequence_id. sequence_id={:?}", sequence_id);
-            }
+        let stream = self
+            .streams
+            .remove(&sequence_id)
+            .or_else(|| self.subscriptions.remove(&sequence_id));
+        if let Some(stream) = stream {
+            let result = String::from_utf8(payload)
+                .map_err(Error::from)
+                .and_then(|reason| Err(err_msg(reason)));
+            // It's okay to ignore this result. The stream is no longer listening.
+            let _result = stream.unbounded_send(result);
+            return;
         }
+        // The code is kept so retries can tell transient errors apart, and the payload so it can
+        // be decoded with the encoding.
+        let error = LoquiError::ErrorResponse {
+            code,
+            reason: String::from_utf8_lossy(&payload).into_owned(),
+            sequence_id,
+            payload,
+        };
+        self.pending.resolve(sequence_id, Err(error.into()));
     }
 
     fn make_hello(&self) -> Hello {
+        let mut flags = Flags::BATCHES;
+        if self.config.transport_options.ping_timestamps {
+            flags |= Flags::TIMESTAMPS;
+        }
+        if self.config.transport_options.checksums {
+            flags |= Flags::CHECKSUMS;
+        }
+        if self.config.transport_options.encoding_ids {
+            flags |= Flags::ENCODING_IDS;
+        }
         Hello {
-            flags: 0,
+            flags,
             version: VERSION,
             encodings: self
                 .config
                 .supported_encodings
-                .to_owned()
-                .into_iter()
+                .iter()
+                .copied()
                 .map(String::from)
                 .collect(),
-            // compression not supported
-            compressions: vec![],
+            compressions: self
+                .config
+                .transport_options
+                .compressors
+                .iter()
+                .map(|compressor| compressor.name().to_string())
+                .collect(),
+            ping_interval_ms: self
+                .config
+                .transport_options
+                .proposed_ping_interval
+                .map(|ping_interval| ping_interval.as_millis().min(u128::from(u32::MAX)) as u32),
+            dictionary_ids: if self.config.transport_options.compressors.is_empty() {
+                vec![]
+            } else {
+                self.config.transport_options.dictionaries.ids()
+            },
+            auth_token: self.config.transport_options.auth_token.clone(),
         }
     }
 
     fn handle_handshake_frame(
         frame: LoquiFrame,
         supported_encodings: &'static [&'static str],
+        compressors: &[Arc<dyn Compressor>],
+        dictionaries: &DictionaryRegistry,
     ) -> Result<Ready, Error> {
         match frame {
-            LoquiFrame::HelloAck(hello_ack) => {
-                Self::handle_handshake_hello_ack(hello_ack, supported_encodings)
-            }
-            LoquiFrame::GoAway(go_away) => Err(LoquiError::ToldToGoAway { go_away }.into()),
+            LoquiFrame::HelloAck(hello_ack) => Self::handle_handshake_hello_ack(
+                hello_ack,
+                supported_encodings,
+                compressors,
+                dictionaries,
+            ),
+            LoquiFrame::GoAway(go_away) => Err(LoquiError::told_to_go_away(go_away).into()),
             frame => Err(LoquiError::InvalidOpcode {
                 actual: frame.opcode(),
                 expected: Some(HelloAck::OPCODE),
@@ -249,6 +715,8 @@ impl ConnectionHandler {
     fn handle_handshake_hello_ack(
         hello_ack: HelloAck,
         supported_encodings: &'static [&'static str],
+        compressors: &[Arc<dyn Compressor>],
+        dictionaries: &DictionaryRegistry,
     ) -> Result<Ready, Error> {
         // Validate the settings and convert them to &'static str.
         let encoding = match find_encoding(hello_ack.encoding, supported_encodings) {
@@ -256,18 +724,79 @@ impl ConnectionHandler {
             None => return Err(LoquiError::InvalidEncoding.into()),
         };
 
-        // compression not supported
-        if hello_ack.compression.is_some() {
-            return Err(LoquiError::InvalidCompression.into());
+        // The server may only pick a compression we offered.
+        let compressor = match hello_ack.compression {
+            Some(compression) => match find_compressor(compression, compressors) {
+                Some(compressor) => Some(compressor),
+                None => return Err(LoquiError::InvalidCompression.into()),
+            },
+            None => None,
+        };
+        // And only a dictionary we offered, for a compression that supports them.
+        if let Some(dictionary_id) = hello_ack.dictionary_id {
+            let primed = compressor
+                .zip(dictionaries.get(dictionary_id))
+                .and_then(|(compressor, dictionary)| compressor.with_dictionary(dictionary));
+            if primed.is_none() {
+                return Err(LoquiError::InvalidCompression.into());
+            }
+        }
+        let compression = compressor.map(|compressor| compressor.name());
+        // And only encodings we offered for the ids.
+        let encodings = if has_encoding_ids(hello_ack.flags) {
+            let encodings = hello_ack
+                .encodings
+                .iter()
+                .map(|encoding| find_encoding(encoding, supported_encodings))
+                .collect::<Option<Vec<&'static str>>>()
+                .ok_or(LoquiError::InvalidEncoding)?;
+            Some(encodings)
+        } else {
+            None
         };
         let ping_interval = Duration::from_millis(u64::from(hello_ack.ping_interval_ms));
+        // The server only acks a `Hello` with a version it supports, i.e. ours.
         Ok(Ready {
             ping_interval,
             encoding,
+            compression,
+            dictionary_id: hello_ack.dictionary_id,
+            peer_version: VERSION,
+            batches: has_batches(hello_ack.flags),
+            // The server only accepts them if we offered.
+            ping_timestamps: has_timestamps(hello_ack.flags),
+            checksums: has_checksums(hello_ack.flags),
+            encodings,
         })
     }
 }
 
+/// Computes the response to a request the server sent.
+async fn handle_request(
+    request_handler: Arc<dyn ServerRequestHandler>,
+    request: Request,
+    encoding: &'static str,
+) -> Result<Response, (Error, u32)> {
+    let Request {
+        payload,
+        sequence_id,
+        trace_id,
+        ..
+    } = request;
+    let payload = request_handler.handle_request(payload, encoding).await;
+    // Echo the trace id like the server does.
+    Ok(Response {
+        trace_id,
+        flags: 0,
+        sequence_id,
+        payload,
+    })
+}
+
+fn too_many_open_sequence_ids() -> Error {
+    LoquiError::service_unavailable("Too many open sequence ids.").into()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -281,9 +810,16 @@ mod test {
             request_timeout: Duration::from_secs(5),
             handshake_timeout: Duration::from_secs(10),
             supported_encodings: &[ENCODING],
+            transport_options: TransportOptions::default(),
+            retry_policy: None,
         };
 
-        ConnectionHandler::new(config)
+        ConnectionHandler::new(
+            config,
+            Arc::new(RwLock::new(None)),
+            Arc::new(RwLock::new(None)),
+            Arc::new(AtomicBool::new(false)),
+        )
     }
 
     #[test]
@@ -295,6 +831,12 @@ mod test {
         let request = handler
             .handle_internal_event(
                 InternalEvent::Request {
+                    trace_id: None,
+                    idempotency_key: None,
+                    priority: Priority::Normal,
+                    timeout_ms: 0,
+                    encoding: None,
+                    flags: 0,
                     payload: payload.clone(),
                     waiter,
                 },
@@ -304,20 +846,41 @@ mod test {
         match request {
             LoquiFrame::Request(request) => {
                 let response = Response {
+                    trace_id: None,
                     sequence_id: request.sequence_id,
                     flags: 0,
                     payload: payload.clone(),
                 };
                 let frame = handler.handle_frame(response.into(), ENCODING);
-                assert!(frame.is_none())
+                assert!(matches!(frame, FrameOutcome::Ignore))
             }
             _other => panic!("request not returned"),
         }
-        let result = Runtime::new()
-            .unwrap()
-            .block_on(async { awaitable.await })
-            .unwrap();
-        assert_eq!(result, payload)
+        let result = Runtime::new().unwrap().block_on(awaitable).unwrap();
+        assert_eq!(result, (payload, None))
+    }
+
+    #[test]
+    fn it_resolves_acked_pushes() {
+        let mut handler = make_handler();
+        let mut id_sequence = IdSequence::default();
+        let (waiter, awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        let push = handler.handle_internal_event(
+            InternalEvent::Push {
+                payload: b"hello".to_vec(),
+                waiter: Some(waiter),
+            },
+            &mut id_sequence,
+        );
+        match push {
+            Some(LoquiFrame::Push(push)) => {
+                let sequence_id = push.sequence_id.expect("no sequence_id");
+                handler.handle_push_ack(sequence_id);
+            }
+            other => panic!("push not returned. {:?}", other),
+        }
+        let result = Runtime::new().unwrap().block_on(awaitable).unwrap();
+        assert_eq!(result, (vec![], None));
     }
 
     #[test]
@@ -328,6 +891,12 @@ mod test {
         let _request = handler
             .handle_internal_event(
                 InternalEvent::Request {
+                    trace_id: None,
+                    idempotency_key: None,
+                    priority: Priority::Normal,
+                    timeout_ms: 0,
+                    encoding: None,
+                    flags: 0,
                     payload: vec![],
                     waiter,
                 },
@@ -335,12 +904,201 @@ mod test {
             )
             .expect("no request");
         let response = Response {
+            trace_id: None,
             sequence_id: id_sequence.next(),
             flags: 0,
             payload: vec![],
         };
         let _frame = handler.handle_frame(response.into(), ENCODING);
-        let result = Runtime::new().unwrap().block_on(async { awaitable.await });
+        let result = Runtime::new().unwrap().block_on(awaitable);
         assert!(result.is_err())
     }
+
+    #[test]
+    fn it_limits_open_sequence_ids() {
+        let mut config = make_handler().config;
+        config.transport_options = TransportOptions::builder()
+            .max_open_sequence_ids(1)
+            .build()
+            .unwrap();
+        let mut handler = ConnectionHandler::new(
+            config,
+            Arc::new(RwLock::new(None)),
+            Arc::new(RwLock::new(None)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        let mut id_sequence = IdSequence::default();
+        let mut request = |handler: &mut ConnectionHandler| {
+            let (waiter, awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+            let event = InternalEvent::Request {
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                encoding: None,
+                flags: 0,
+                payload: vec![],
+                waiter,
+            };
+            let frame = handler.handle_internal_event(event, &mut id_sequence);
+            (frame, awaitable)
+        };
+        let is_unavailable = |error: Error| match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::ErrorResponse { code, .. }) => {
+                *code == LoquiErrorCode::ServiceUnavailable as u16
+            }
+            _ => false,
+        };
+
+        Runtime::new().unwrap().block_on(async move {
+            let (frame, _awaitable) = request(&mut handler);
+            let sequence_id = match frame {
+                Some(LoquiFrame::Request(request)) => request.sequence_id,
+                other => panic!("request not returned. {:?}", other),
+            };
+            // Failed without allocating an id or a waiter.
+            let (frame, awaitable) = request(&mut handler);
+            assert!(frame.is_none());
+            assert!(is_unavailable(awaitable.await.unwrap_err()));
+            let (stream, mut responses) = futures::channel::mpsc::unbounded();
+            let event = InternalEvent::StreamRequest {
+                payload: vec![],
+                stream,
+                sequence_id: Arc::new(AtomicU32::new(0)),
+            };
+            assert!(handler
+                .handle_internal_event(event, &mut IdSequence::default())
+                .is_none());
+            assert!(is_unavailable(responses.next().await.unwrap().unwrap_err()));
+            assert_eq!(handler.pending.len(), 1);
+
+            let response = Response {
+                trace_id: None,
+                sequence_id,
+                flags: 0,
+                payload: vec![],
+            };
+            handler.handle_frame(response.into(), ENCODING);
+            let (frame, _awaitable) = request(&mut handler);
+            assert!(matches!(frame, Some(LoquiFrame::Request(_))));
+        });
+    }
+
+    #[test]
+    fn it_combines_the_default_request_flags_with_those_of_the_request() {
+        let mut config = make_handler().config;
+        config.transport_options = TransportOptions::builder()
+            .default_request_flags(Flags::NO_COMPRESS)
+            .build()
+            .unwrap();
+        let mut handler = ConnectionHandler::new(
+            config,
+            Arc::new(RwLock::new(None)),
+            Arc::new(RwLock::new(None)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        let mut id_sequence = IdSequence::default();
+        let flow_controlled = Flags::FlowControlled as u8;
+        for (flags, expected) in [
+            (0, Flags::NO_COMPRESS),
+            (flow_controlled, Flags::NO_COMPRESS | flow_controlled),
+        ] {
+            let (waiter, _awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+            let request = handler.handle_internal_event(
+                InternalEvent::Request {
+                    trace_id: None,
+                    idempotency_key: None,
+                    priority: Priority::Normal,
+                    timeout_ms: 0,
+                    encoding: None,
+                    flags,
+                    payload: vec![],
+                    waiter,
+                },
+                &mut id_sequence,
+            );
+            match request {
+                Some(LoquiFrame::Request(request)) => assert_eq!(request.flags, expected),
+                other => panic!("request not returned. {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn it_sets_the_default_request_flags_on_batches() {
+        let mut config = make_handler().config;
+        config.transport_options = TransportOptions::builder()
+            .default_request_flags(Flags::NO_COMPRESS)
+            .build()
+            .unwrap();
+        let mut handler = ConnectionHandler::new(
+            config,
+            Arc::new(RwLock::new(None)),
+            Arc::new(RwLock::new(None)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        let (waiter, _awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        let batch = handler.handle_internal_event(
+            InternalEvent::RequestBatch {
+                requests: vec![(vec![], waiter)],
+            },
+            &mut IdSequence::default(),
+        );
+        match batch {
+            Some(LoquiFrame::RequestBatch(batch)) => assert_eq!(batch.flags, Flags::NO_COMPRESS),
+            other => panic!("batch not returned. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_averages_rtt() {
+        let rtt = Arc::new(RwLock::new(None));
+        let mut handler = ConnectionHandler::new(
+            make_handler().config,
+            rtt.clone(),
+            Arc::new(RwLock::new(None)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        handler.observe_rtt(Duration::from_millis(80));
+        assert_eq!(*rtt.read().unwrap(), Some(Duration::from_millis(80)));
+        handler.observe_rtt(Duration::from_millis(160));
+        assert_eq!(*rtt.read().unwrap(), Some(Duration::from_millis(90)));
+    }
+
+    #[test]
+    fn it_cancels_expired_requests() {
+        let mut handler = make_handler();
+        let mut id_sequence = IdSequence::default();
+        let (waiter, _awaitable) = ResponseWaiter::new(Duration::from_millis(10));
+        let request = handler
+            .handle_internal_event(
+                InternalEvent::Request {
+                    trace_id: None,
+                    idempotency_key: None,
+                    priority: Priority::Normal,
+                    timeout_ms: 0,
+                    encoding: None,
+                    flags: 0,
+                    payload: vec![],
+                    waiter,
+                },
+                &mut id_sequence,
+            )
+            .expect("no request");
+        let sequence_id = match request {
+            LoquiFrame::Request(request) => request.sequence_id,
+            other => panic!("request not returned. {:?}", other),
+        };
+
+        // Not expired yet.
+        let cancel = handler.handle_internal_event(InternalEvent::CancelExpired, &mut id_sequence);
+        assert!(cancel.is_none());
+
+        std::thread::sleep(Duration::from_millis(15));
+        match handler.handle_internal_event(InternalEvent::CancelExpired, &mut id_sequence) {
+            Some(LoquiFrame::Cancel(cancel)) => assert_eq!(cancel.sequence_id, sequence_id),
+            other => panic!("cancel not returned. {:?}", other),
+        }
+        assert!(handler.pending.is_empty());
+    }
 }
@@ -2,9 +2,24 @@
 extern crate log;
 
 mod client;
+mod client_error;
 mod config;
 mod connection_handler;
+mod pending_requests;
+mod retry;
+mod server_request_handler;
 mod waiter;
 
 pub use client::Client;
+pub use client_error::{ClientError, ErrorDetails};
 pub use config::Config;
+pub use loqui_connection::handler::{ClockSkew, ConnectionHealth, Negotiated};
+pub use loqui_connection::{
+    ConnectionStats, ConnectionTag, IdStrategy, IdStrategyFactory, ProtocolViolationPolicy,
+    RateLimit, Spawn, Task, TokioSpawn, TransportOptions, TransportOptionsBuilder,
+    UnexpectedFramePolicy,
+};
+pub use loqui_protocol::frames::{IdempotencyKey, Priority, TraceId};
+pub use loqui_protocol::Flags;
+pub use retry::RetryPolicy;
+pub use server_request_handler::ServerRequestHandler;
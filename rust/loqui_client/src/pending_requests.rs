@@ -0,0 +1,243 @@
+use crate::waiter::{ResponseWaiter, TracedResponse};
+use failure::Error;
+use loqui_connection::{LoquiError, Metrics, NoopMetrics};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::time::Instant;
+
+/// How many of the latest resolved `sequence_id`s are remembered to tell duplicate responses
+/// apart from late ones.
+const RESOLVED_HISTORY: usize = 1024;
+
+/// The requests and acked pushes waiting for the server, keyed by `sequence_id`. The server may
+/// answer them in any order.
+#[derive(Debug)]
+pub struct PendingRequests {
+    waiters: HashMap<u32, ResponseWaiter>,
+    /// The latest `sequence_id`s resolved by the server, oldest first.
+    resolved: VecDeque<u32>,
+    resolved_ids: HashSet<u32>,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl Default for PendingRequests {
+    fn default() -> Self {
+        Self::new(Arc::new(NoopMetrics))
+    }
+}
+
+impl PendingRequests {
+    pub fn new(metrics: Arc<dyn Metrics>) -> Self {
+        Self {
+            waiters: HashMap::new(),
+            resolved: VecDeque::new(),
+            resolved_ids: HashSet::new(),
+            metrics,
+        }
+    }
+
+    /// Waits for the response to `sequence_id`. Returns false, after notifying the waiter, when
+    /// its deadline already passed and the request shouldn't be sent.
+    pub fn insert(&mut self, sequence_id: u32, waiter: ResponseWaiter) -> bool {
+        if waiter.deadline <= Instant::now() {
+            waiter.notify(Err(LoquiError::RequestTimeout.into()));
+            return false;
+        }
+        // The id was reused, e.g. after wrapping around.
+        if self.resolved_ids.remove(&sequence_id) {
+            self.resolved.retain(|resolved| *resolved != sequence_id);
+        }
+        self.waiters.insert(sequence_id, waiter);
+        true
+    }
+
+    /// Resolves the request with this `sequence_id`. A response that nothing waits for, e.g.
+    /// because its request already timed out, is logged and dropped. So is a second response to
+    /// a request that was already resolved, which is reported to `Metrics::duplicate_response`.
+    pub fn resolve(&mut self, sequence_id: u32, result: Result<TracedResponse, Error>) {
+        match self.waiters.remove(&sequence_id) {
+            Some(waiter) => {
+                self.remember_resolved(sequence_id);
+                waiter.notify_traced(result);
+            }
+            None if self.resolved_ids.contains(&sequence_id) => {
+                warn!(
+                    "Duplicate response. Dropping it. sequence_id={:?}",
+                    sequence_id
+                );
+                self.metrics.duplicate_response(sequence_id);
+            }
+            None => debug!("No waiter for sequence_id. sequence_id={:?}", sequence_id),
+        }
+    }
+
+    fn remember_resolved(&mut self, sequence_id: u32) {
+        if self.resolved.len() == RESOLVED_HISTORY {
+            if let Some(oldest) = self.resolved.pop_front() {
+                self.resolved_ids.remove(&oldest);
+            }
+        }
+        self.resolved.push_back(sequence_id);
+        self.resolved_ids.insert(sequence_id);
+    }
+
+    /// Removes one request whose deadline passed and returns its `sequence_id`, so the server
+    /// can be told to stop working on it.
+    pub fn take_expired(&mut self) -> Option<u32> {
+        let now = Instant::now();
+        let sequence_id = self
+            .waiters
+            .iter()
+            .find(|(_sequence_id, waiter)| waiter.deadline <= now)
+            .map(|(sequence_id, _waiter)| *sequence_id)?;
+        self.waiters.remove(&sequence_id);
+        Some(sequence_id)
+    }
+
+    /// Times out every request whose deadline passed.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .waiters
+            .iter()
+            .filter(|(_sequence_id, waiter)| waiter.deadline <= now)
+            .map(|(sequence_id, _waiter)| *sequence_id)
+            .collect();
+        // Not remembered as resolved, so a late response isn't mistaken for a duplicate.
+        for sequence_id in expired {
+            if let Some(waiter) = self.waiters.remove(&sequence_id) {
+                waiter.notify(Err(LoquiError::RequestTimeout.into()));
+            }
+        }
+    }
+
+    /// Fails every request with `LoquiError::ConnectionClosing`. A late response to one of them
+    /// is dropped like any response nothing waits for.
+    pub fn cancel_all(&mut self) {
+        for (_sequence_id, waiter) in self.waiters.drain() {
+            waiter.notify(Err(LoquiError::ConnectionClosing.into()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn it_resolves_responses_out_of_order() {
+        let mut pending = PendingRequests::default();
+        let (first, first_awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        let (second, second_awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        assert!(pending.insert(1, first));
+        assert!(pending.insert(2, second));
+
+        pending.resolve(2, Ok((b"two".to_vec(), None)));
+        pending.resolve(1, Ok((b"one".to_vec(), None)));
+        let (first, second) = Runtime::new()
+            .unwrap()
+            .block_on(async { (first_awaitable.await, second_awaitable.await) });
+        assert_eq!(first.unwrap(), (b"one".to_vec(), None));
+        assert_eq!(second.unwrap(), (b"two".to_vec(), None));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn it_drops_orphaned_responses() {
+        let mut pending = PendingRequests::default();
+        let (waiter, _awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        assert!(pending.insert(1, waiter));
+        pending.resolve(7, Ok((vec![], None)));
+        assert!(!pending.is_empty());
+    }
+
+    #[derive(Debug, Default)]
+    struct DuplicateCounter {
+        duplicates: Mutex<Vec<u32>>,
+    }
+
+    impl Metrics for DuplicateCounter {
+        fn duplicate_response(&self, sequence_id: u32) {
+            self.duplicates.lock().unwrap().push(sequence_id);
+        }
+    }
+
+    #[test]
+    fn it_drops_duplicate_responses() {
+        let metrics = Arc::new(DuplicateCounter::default());
+        let mut pending = PendingRequests::new(metrics.clone());
+        let (waiter, awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        assert!(pending.insert(1, waiter));
+
+        pending.resolve(1, Ok((b"first".to_vec(), None)));
+        pending.resolve(1, Ok((b"second".to_vec(), None)));
+        let response = Runtime::new().unwrap().block_on(awaitable);
+        assert_eq!(response.unwrap(), (b"first".to_vec(), None));
+        assert_eq!(*metrics.duplicates.lock().unwrap(), vec![1]);
+
+        // Responses nothing ever waited for aren't duplicates.
+        pending.resolve(7, Ok((vec![], None)));
+        assert_eq!(*metrics.duplicates.lock().unwrap(), vec![1]);
+
+        // Nor is the response to a reused id.
+        let (waiter, _awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        assert!(pending.insert(1, waiter));
+        pending.resolve(1, Ok((vec![], None)));
+        assert_eq!(*metrics.duplicates.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn it_times_out_expired_requests() {
+        let mut pending = PendingRequests::default();
+        let (expired, expired_awaitable) = ResponseWaiter::new(Duration::from_millis(1));
+        let (waiting, _waiting_awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        assert!(pending.insert(1, expired));
+        assert!(pending.insert(2, waiting));
+        std::thread::sleep(Duration::from_millis(5));
+
+        pending.expire();
+        let result = Runtime::new().unwrap().block_on(expired_awaitable);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<LoquiError>(),
+            Some(LoquiError::RequestTimeout)
+        ));
+        assert_eq!(pending.take_expired(), None);
+        assert!(!pending.is_empty());
+    }
+
+    #[test]
+    fn it_cancels_all_requests() {
+        let mut pending = PendingRequests::default();
+        let (waiter, awaitable) = ResponseWaiter::new(Duration::from_secs(5));
+        assert!(pending.insert(1, waiter));
+
+        pending.cancel_all();
+        pending.cancel_all();
+        assert!(pending.is_empty());
+        let result = Runtime::new().unwrap().block_on(awaitable);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<LoquiError>(),
+            Some(LoquiError::ConnectionClosing)
+        ));
+    }
+
+    #[test]
+    fn it_refuses_requests_past_their_deadline() {
+        let mut pending = PendingRequests::default();
+        let (waiter, _awaitable) = ResponseWaiter::new(Duration::from_millis(0));
+        assert!(!pending.insert(1, waiter));
+        assert!(pending.is_empty());
+    }
+}
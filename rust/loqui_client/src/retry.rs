@@ -0,0 +1,111 @@
+use failure::Error;
+use loqui_connection::{LoquiError, LoquiErrorCode};
+use loqui_protocol::frames::IdempotencyKey;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Keys generated by this process are told apart by a counter.
+static NEXT_KEY: AtomicU32 = AtomicU32::new(0);
+
+/// How `Client::request_idempotent` retries requests that failed with a transient error. Plain
+/// requests are never retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The most times a request is sent, including the first attempt.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. Doubles with every retry after it.
+    pub backoff: Duration,
+    /// The longest to wait before a retry.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait before sending the attempt after `attempt`, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    /// Whether the request may succeed when sent again: the server was unavailable, or the
+    /// connection failed before a response arrived.
+    pub fn is_retryable(error: &Error) -> bool {
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::ErrorResponse { code, .. }) => {
+                *code == LoquiErrorCode::ServiceUnavailable as u16
+            }
+            Some(LoquiError::ConnectionClosed)
+            | Some(LoquiError::TcpStreamClosed)
+            | Some(LoquiError::SocketRead { .. })
+            | Some(LoquiError::SocketWrite { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A key no other request of this process is sent with. Made of the time it was generated, the
+/// process id and a counter, so keys of processes on different hosts are unlikely to collide.
+pub fn new_idempotency_key() -> IdempotencyKey {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut key = IdempotencyKey::default();
+    key[..8].copy_from_slice(&now.to_be_bytes());
+    key[8..12].copy_from_slice(&std::process::id().to_be_bytes());
+    key[12..].copy_from_slice(&NEXT_KEY.fetch_add(1, SeqCst).to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        }
+    }
+
+    #[test]
+    fn it_backs_off_exponentially_up_to_the_max() {
+        let retry_policy = retry_policy();
+        assert_eq!(retry_policy.backoff(1), Duration::from_millis(10));
+        assert_eq!(retry_policy.backoff(2), Duration::from_millis(20));
+        assert_eq!(retry_policy.backoff(3), Duration::from_millis(40));
+        assert_eq!(retry_policy.backoff(4), Duration::from_millis(50));
+        assert_eq!(retry_policy.backoff(100), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn it_only_retries_transient_errors() {
+        let unavailable = LoquiError::ErrorResponse {
+            code: LoquiErrorCode::ServiceUnavailable as u16,
+            reason: "busy".to_string(),
+            sequence_id: 1,
+            payload: b"busy".to_vec(),
+        };
+        let bad_request = LoquiError::ErrorResponse {
+            code: LoquiErrorCode::BadRequest as u16,
+            reason: "bad".to_string(),
+            sequence_id: 2,
+            payload: b"bad".to_vec(),
+        };
+        assert!(RetryPolicy::is_retryable(&unavailable.into()));
+        assert!(RetryPolicy::is_retryable(
+            &LoquiError::ConnectionClosed.into()
+        ));
+        assert!(!RetryPolicy::is_retryable(&bad_request.into()));
+        assert!(!RetryPolicy::is_retryable(
+            &LoquiError::RequestTimeout.into()
+        ));
+    }
+
+    #[test]
+    fn it_generates_distinct_keys() {
+        assert_ne!(new_idempotency_key(), new_idempotency_key());
+    }
+}
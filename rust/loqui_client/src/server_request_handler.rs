@@ -0,0 +1,13 @@
+use std::future::Future;
+use std::pin::Pin;
+
+/// Handles the requests a server sends to the client, for RPC both ways, see
+/// `Client::start_connect_with_handler` and `TransportOptions::peer_requests`.
+pub trait ServerRequestHandler: Send + Sync + 'static {
+    /// Handle a single request. Return a future with the response.
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>>;
+}
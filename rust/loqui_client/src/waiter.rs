@@ -2,19 +2,24 @@ use failure::Error;
 use futures::channel::oneshot::{self, Sender};
 use futures::TryFutureExt;
 use loqui_connection::{timeout_at, LoquiError};
+use loqui_protocol::frames::TraceId;
 use std::future::Future;
 use std::time::Duration;
 use tokio::time::Instant;
 
+/// A response payload along with the trace id the server echoed back.
+pub type TracedResponse = (Vec<u8>, Option<TraceId>);
+
 #[derive(Debug)]
 pub struct ResponseWaiter {
-    tx: Sender<Result<Vec<u8>, Error>>,
+    tx: Sender<Result<TracedResponse, Error>>,
     pub deadline: Instant,
 }
 
 impl ResponseWaiter {
     /// Creates a new response waiter that will wait until the specified timeout.
-    /// The returned future will resolve when someone calls waiter.notify().
+    /// The returned future will resolve to the payload and trace id when someone calls
+    /// waiter.notify().
     ///
     /// # Arguments
     ///
@@ -24,7 +29,7 @@ impl ResponseWaiter {
     ///
     /// `LoquiError::RequestTimeout` or some other error from the server.
     ///
-    pub fn new(timeout: Duration) -> (Self, impl Future<Output = Result<Vec<u8>, Error>>) {
+    pub fn new(timeout: Duration) -> (Self, impl Future<Output = Result<TracedResponse, Error>>) {
         let (tx, rx) = oneshot::channel();
 
         let deadline = Instant::now() + timeout;
@@ -41,6 +46,11 @@ impl ResponseWaiter {
 
     /// Notify the waiter that a result was received.
     pub fn notify(self, result: Result<Vec<u8>, Error>) {
+        self.notify_traced(result.map(|payload| (payload, None)))
+    }
+
+    /// Notify the waiter that a result was received along with its trace id.
+    pub fn notify_traced(self, result: Result<TracedResponse, Error>) {
         if let Err(_e) = self.tx.send(result) {
             if self.deadline > Instant::now() {
                 warn!("Waiter is no longer listening.")
@@ -72,7 +82,7 @@ mod tests {
     fn it_receives_error() {
         let (waiter, awaitable) = ResponseWaiter::new(Duration::from_secs(5));
 
-        let result: Result<Vec<u8>, Error> = Runtime::new().unwrap().block_on(async {
+        let result: Result<TracedResponse, Error> = Runtime::new().unwrap().block_on(async {
             spawn(async {
                 waiter.notify(Err(LoquiError::ConnectionClosed.into()));
             });
@@ -0,0 +1,108 @@
+//! Counts the allocations of encoding responses with and without a `BufferPool`.
+//!
+//! Run with `cargo bench -p loqui_connection --bench buffer_pool`.
+
+use bytes::BytesMut;
+use bytesize::ByteSize;
+use failure::Error;
+use loqui_connection::{BufferPool, Encoder};
+use loqui_protocol::codec::Codec;
+use loqui_protocol::frames::{LoquiFrame, Response};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::time::Instant;
+
+const RESPONSES: u32 = 100_000;
+const PAYLOAD_BYTES: usize = 512;
+
+/// Counts every allocation, including the ones that grow a buffer.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Repeats a byte into the payload, standing in for a real encoding.
+struct FillEncoder;
+
+impl Encoder for FillEncoder {
+    type Decoded = Vec<u8>;
+    type Encoded = u8;
+
+    fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+        Ok(payload)
+    }
+
+    fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error> {
+        let mut payload = vec![];
+        self.encode_into(value, &mut payload)?;
+        Ok(payload)
+    }
+
+    fn encode_into(&self, value: Self::Encoded, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        buffer.resize(PAYLOAD_BYTES, value);
+        Ok(())
+    }
+}
+
+/// Encodes the responses into a socket buffer like the writer of a connection does, returning
+/// the allocations it took.
+fn encode_responses(buffer_pool: Option<&BufferPool>) -> usize {
+    let mut codec = Codec::new(ByteSize::kb(64));
+    let mut socket_buffer = BytesMut::with_capacity(64 * 1024);
+    let allocations = ALLOCATIONS.load(Relaxed);
+    for sequence_id in 0..RESPONSES {
+        let value = sequence_id as u8;
+        let payload = match buffer_pool {
+            Some(buffer_pool) => buffer_pool.encode(&FillEncoder, value),
+            None => FillEncoder.encode(value),
+        };
+        let response = Response {
+            flags: 0,
+            sequence_id,
+            trace_id: None,
+            payload: payload.unwrap(),
+        };
+        let payload = codec.encode_reusing(LoquiFrame::Response(response), &mut socket_buffer);
+        if let (Some(buffer_pool), Some(payload)) = (buffer_pool, payload) {
+            buffer_pool.give(payload);
+        }
+        // Written to the socket.
+        socket_buffer.clear();
+    }
+    ALLOCATIONS.load(Relaxed) - allocations
+}
+
+fn main() {
+    for (name, buffer_pool) in &[
+        ("without a pool", None),
+        ("with a pool", Some(BufferPool::new(64 * 1024))),
+    ] {
+        let started_at = Instant::now();
+        let allocations = encode_responses(buffer_pool.as_ref());
+        println!(
+            "{}: {} responses, {} allocations, {:?}",
+            name,
+            RESPONSES,
+            allocations,
+            started_at.elapsed()
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use crate::encoder::Encoder;
+use failure::Error;
+use std::sync::{Arc, Mutex};
+
+/// Payload buffers to reuse instead of allocating a fresh `Vec<u8>` per frame, see
+/// `TransportOptions::buffer_pool`. Clones share the buffers, so every connection with the same
+/// options draws from the same pool.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    inner: Arc<Mutex<Buffers>>,
+    max_bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct Buffers {
+    buffers: Vec<Vec<u8>>,
+    /// The capacity of `buffers`, in bytes.
+    bytes: usize,
+}
+
+impl BufferPool {
+    /// A pool that keeps at most `max_bytes` of buffer capacity around.
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Buffers::default())),
+            max_bytes,
+        }
+    }
+
+    /// The most bytes of buffer capacity the pool keeps around.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// The number of buffers waiting to be reused.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// An empty buffer, reused if the pool has one.
+    pub fn take(&self) -> Vec<u8> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.buffers.pop() {
+            Some(buffer) => {
+                inner.bytes -= buffer.capacity();
+                buffer
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns a buffer to be reused. It's dropped if it would take the pool over `max_bytes`.
+    pub fn give(&self, mut buffer: Vec<u8>) {
+        let capacity = buffer.capacity();
+        if capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.bytes + capacity > self.max_bytes {
+            return;
+        }
+        buffer.clear();
+        inner.bytes += capacity;
+        inner.buffers.push(buffer);
+    }
+
+    /// Encodes a value into a buffer from the pool, e.g. the payload of a response. The buffer
+    /// returns to the pool once the frame it's sent in was written.
+    pub fn encode<E: Encoder>(&self, encoder: &E, value: E::Encoded) -> Result<Vec<u8>, Error> {
+        let mut buffer = self.take();
+        match encoder.encode_into(value, &mut buffer) {
+            Ok(()) => Ok(buffer),
+            Err(error) => {
+                self.give(buffer);
+                Err(error)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct BytesEncoder;
+
+    impl Encoder for BytesEncoder {
+        type Decoded = Vec<u8>;
+        type Encoded = &'static [u8];
+
+        fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+            Ok(payload)
+        }
+
+        fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error> {
+            Ok(value.to_vec())
+        }
+
+        fn encode_into(&self, value: Self::Encoded, buffer: &mut Vec<u8>) -> Result<(), Error> {
+            buffer.extend_from_slice(value);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_reuses_given_buffers() {
+        let pool = BufferPool::new(1024);
+        let payload = pool.encode(&BytesEncoder, b"hello").unwrap();
+        assert_eq!(payload, b"hello");
+        let pointer = payload.as_ptr();
+        pool.give(payload);
+        assert_eq!(pool.len(), 1);
+
+        let payload = pool.encode(&BytesEncoder, b"hey").unwrap();
+        assert_eq!(payload, b"hey");
+        assert_eq!(payload.as_ptr(), pointer);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn it_keeps_at_most_max_bytes() {
+        let pool = BufferPool::new(100);
+        pool.give(Vec::with_capacity(60));
+        pool.give(Vec::with_capacity(60));
+        pool.give(Vec::with_capacity(40));
+        pool.give(Vec::new());
+        assert_eq!(pool.len(), 2);
+
+        assert_eq!(pool.take().capacity(), 40);
+        pool.give(Vec::with_capacity(60));
+        assert_eq!(pool.len(), 1);
+        pool.give(Vec::with_capacity(40));
+        assert_eq!(pool.len(), 2);
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// The source of time for a connection's deadlines, e.g. ping timeouts.
+pub trait Clock: Debug + Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    /// The wall clock time since the Unix epoch, for the timestamps of pings.
+    fn unix_time(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// `Clock` that reads the system's monotonic time.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// `Clock` that only moves when it is advanced, so deadlines can be hit without sleeping.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<(Instant, Duration)>,
+}
+
+impl ManualClock {
+    /// Creates a clock stopped at the current time.
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new((Instant::now(), SystemClock.unix_time())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().expect("Failed to lock clock");
+        now.0 += duration;
+        now.1 += duration;
+    }
+
+    /// Sets the wall clock, e.g. to skew it from another clock. The monotonic time is unaffected.
+    pub fn set_unix_time(&self, unix_time: Duration) {
+        let mut now = self.now.lock().expect("Failed to lock clock");
+        now.1 = unix_time;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.lock().expect("Failed to lock clock").0
+    }
+
+    fn unix_time(&self) -> Duration {
+        self.now.lock().expect("Failed to lock clock").1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_only_moves_when_advanced() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn it_advances_the_wall_clock_with_the_monotonic_one() {
+        let clock = ManualClock::new();
+        clock.set_unix_time(Duration::from_secs(100));
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.unix_time(), Duration::from_secs(103));
+    }
+}
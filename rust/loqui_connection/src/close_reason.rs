@@ -0,0 +1,248 @@
+use crate::{GoAwayCode, LoquiError};
+use failure::Error;
+use loqui_protocol::error::ProtocolError;
+
+/// Why a connection that completed its handshake closed, see `Handler::on_close`. E.g. to decide
+/// whether to reconnect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// This side closed the connection, e.g. with `Connection::close`, by shutting down or by
+    /// dropping it.
+    LocalClose,
+    /// The other side went away with the code, or with `GoAwayCode::Normal` once its half close
+    /// drained.
+    RemoteGoAway(GoAwayCode),
+    /// A pong wasn't received in time.
+    PingTimeout,
+    /// The socket closed, or reading from or writing to it failed or stalled.
+    IoError,
+    /// The other side broke the protocol, e.g. it sent a frame that couldn't be decoded or had
+    /// an unexpected opcode.
+    ProtocolError,
+    /// Nothing but keepalives went either way for `TransportOptions::idle_timeout`.
+    IdleTimeout,
+    /// This side failed, e.g. its handler or encoder, or with an error that isn't one of the
+    /// connection's.
+    LocalError,
+}
+
+impl From<&Error> for CloseReason {
+    fn from(error: &Error) -> Self {
+        if let Some(error) = error.downcast_ref::<LoquiError>() {
+            return error.into();
+        }
+        if error.downcast_ref::<std::io::Error>().is_some() {
+            return CloseReason::IoError;
+        }
+        // Raised by the frame codec while decoding what the other side sent.
+        if error.downcast_ref::<ProtocolError>().is_some() {
+            return CloseReason::ProtocolError;
+        }
+        CloseReason::LocalError
+    }
+}
+
+impl From<&LoquiError> for CloseReason {
+    fn from(error: &LoquiError) -> Self {
+        match error {
+            LoquiError::ConnectionCloseRequested { .. }
+            | LoquiError::ShutDown { .. }
+            | LoquiError::ConnectionClosed => CloseReason::LocalClose,
+            LoquiError::ToldToGoAway { code, .. } => CloseReason::RemoteGoAway(*code),
+            LoquiError::PeerHalfClosed => CloseReason::RemoteGoAway(GoAwayCode::Normal),
+            LoquiError::PingTimeout => CloseReason::PingTimeout,
+            LoquiError::TcpStreamClosed
+            | LoquiError::SocketWrite { .. }
+            | LoquiError::SocketRead { .. }
+            | LoquiError::WriteStalled => CloseReason::IoError,
+            LoquiError::InvalidUpgradeFrame { .. }
+            | LoquiError::InvalidOpcode { .. }
+            | LoquiError::UnsupportedVersion { .. }
+            | LoquiError::InvalidEncoding
+            | LoquiError::InvalidCompression
+            | LoquiError::PingTokenMismatch { .. }
+            | LoquiError::ChecksumMismatch { .. }
+            | LoquiError::UnknownEncodingId { .. }
+            | LoquiError::UnknownMandatoryFrame { .. }
+            | LoquiError::DecodeFailed { .. }
+            | LoquiError::DecompressFailed { .. } => CloseReason::ProtocolError,
+            // These only fail handshakes, which don't reach `Handler::on_close`.
+            LoquiError::Unauthorized { .. }
+            | LoquiError::NoCommonEncoding { .. }
+            | LoquiError::NoCommonEncodingVersion
+            | LoquiError::NoCommonCompression { .. }
+            | LoquiError::HandshakeTimeout
+            | LoquiError::HandshakeFailed { .. } => CloseReason::LocalError,
+            LoquiError::NotReady
+            | LoquiError::InternalServerError { .. }
+            | LoquiError::EventReceiveError
+            | LoquiError::ReadySendFailed
+            | LoquiError::HandlerPanicked
+            | LoquiError::HalfClosed
+            | LoquiError::RequestTimeout
+            | LoquiError::ConnectionClosing
+            | LoquiError::NotFlushed
+            | LoquiError::Renegotiating
+            | LoquiError::RenegotiationDisabled
+            | LoquiError::RenegotiationRefused { .. }
+            | LoquiError::ErrorResponse { .. }
+            | LoquiError::ReachedMaxBackoffElapsedTime
+            | LoquiError::InvalidTransportOptions { .. }
+            | LoquiError::InvalidRequestFlags { .. }
+            | LoquiError::NoClientEncoding
+            | LoquiError::EncodeFailed { .. }
+            | LoquiError::CompressFailed { .. } => CloseReason::LocalError,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure::err_msg;
+    use loqui_protocol::frames::GoAway;
+    use loqui_protocol::upgrade::UpgradeFrame;
+    use std::io::{Error as IoError, ErrorKind};
+
+    fn reason(error: LoquiError) -> CloseReason {
+        CloseReason::from(&Error::from(error))
+    }
+
+    fn io_error() -> IoError {
+        IoError::new(ErrorKind::ConnectionReset, "reset")
+    }
+
+    #[test]
+    fn it_tells_closes_and_go_aways_apart() {
+        assert_eq!(
+            reason(LoquiError::ConnectionCloseRequested { reason: None }),
+            CloseReason::LocalClose
+        );
+        assert_eq!(
+            reason(LoquiError::ShutDown {
+                code: GoAwayCode::Shutdown
+            }),
+            CloseReason::LocalClose
+        );
+        assert_eq!(
+            reason(LoquiError::ConnectionClosed),
+            CloseReason::LocalClose
+        );
+        let go_away = GoAway {
+            flags: 0,
+            code: GoAwayCode::Shutdown.into(),
+            payload: vec![],
+        };
+        assert_eq!(
+            reason(LoquiError::told_to_go_away(go_away)),
+            CloseReason::RemoteGoAway(GoAwayCode::Shutdown)
+        );
+        assert_eq!(
+            reason(LoquiError::PeerHalfClosed),
+            CloseReason::RemoteGoAway(GoAwayCode::Normal)
+        );
+        assert_eq!(reason(LoquiError::PingTimeout), CloseReason::PingTimeout);
+    }
+
+    #[test]
+    fn it_tells_socket_failures_apart() {
+        let errors = vec![
+            LoquiError::TcpStreamClosed,
+            LoquiError::SocketWrite {
+                source: io_error(),
+                bytes_written: 0,
+            },
+            LoquiError::SocketRead {
+                source: io_error(),
+                bytes_read: 0,
+            },
+            LoquiError::WriteStalled,
+        ];
+        for error in errors {
+            assert_eq!(reason(error), CloseReason::IoError);
+        }
+        assert_eq!(
+            CloseReason::from(&Error::from(io_error())),
+            CloseReason::IoError
+        );
+    }
+
+    #[test]
+    fn it_blames_the_other_side_only_for_what_it_sent() {
+        let errors = vec![
+            LoquiError::InvalidUpgradeFrame {
+                frame: UpgradeFrame::Response,
+            },
+            LoquiError::InvalidOpcode {
+                actual: 9,
+                expected: None,
+            },
+            LoquiError::UnsupportedVersion {
+                expected: 1,
+                actual: 2,
+            },
+            LoquiError::InvalidEncoding,
+            LoquiError::InvalidCompression,
+            LoquiError::PingTokenMismatch {
+                expected: Some(1),
+                actual: None,
+            },
+            LoquiError::ChecksumMismatch {
+                expected: 1,
+                actual: 2,
+            },
+            LoquiError::UnknownEncodingId { id: 3 },
+            LoquiError::UnknownMandatoryFrame { opcode: 200 },
+            LoquiError::DecodeFailed {
+                encoding: "json",
+                reason: "eof".to_string(),
+            },
+            LoquiError::DecompressFailed {
+                compression: "snappy",
+                reason: "corrupt".to_string(),
+            },
+        ];
+        for error in errors {
+            assert_eq!(reason(error), CloseReason::ProtocolError);
+        }
+        let error = ProtocolError::InvalidOpcode { opcode: 9 };
+        assert_eq!(
+            CloseReason::from(&Error::from(error)),
+            CloseReason::ProtocolError
+        );
+    }
+
+    #[test]
+    fn it_blames_this_side_for_its_own_failures() {
+        let errors = vec![
+            LoquiError::InternalServerError {
+                error: err_msg("boom"),
+            },
+            LoquiError::HandlerPanicked,
+            LoquiError::EventReceiveError,
+            LoquiError::NotFlushed,
+            LoquiError::EncodeFailed {
+                encoding: "json",
+                reason: "unsupported".to_string(),
+            },
+            LoquiError::CompressFailed {
+                compression: "snappy",
+                reason: "too large".to_string(),
+            },
+            LoquiError::HandshakeTimeout,
+            LoquiError::HandshakeFailed {
+                reason: "closed".to_string(),
+            },
+            LoquiError::Unauthorized {
+                code: GoAwayCode::Normal,
+            },
+        ];
+        for error in errors {
+            assert_eq!(reason(error), CloseReason::LocalError);
+        }
+        assert_eq!(
+            CloseReason::from(&err_msg("handler failed")),
+            CloseReason::LocalError
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use crate::LoquiError;
+use failure::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Compresses the payloads of `Request`, `Response` and `Push` frames once a compression has
+/// been negotiated during the handshake. Control frames are never compressed.
+pub trait Compressor: Debug + Send + Sync + 'static {
+    /// The name advertised during the handshake.
+    fn name(&self) -> &'static str;
+    /// Compresses the payload of a frame that will be sent.
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, Error>;
+    /// Decompresses the payload of a received frame that has the compressed flag set.
+    fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, Error>;
+    /// The same compression primed with a pre-shared dictionary, see
+    /// `TransportOptions::dictionaries`. `None` if it doesn't support dictionaries, which is the
+    /// default, so frames are compressed without one.
+    fn with_dictionary(&self, _dictionary: &[u8]) -> Option<Arc<dyn Compressor>> {
+        None
+    }
+}
+
+/// Pre-shared compression dictionaries by id, see `TransportOptions::dictionaries`. Both sides
+/// must register the same bytes under an id.
+#[derive(Debug, Clone, Default)]
+pub struct DictionaryRegistry {
+    /// In the order they were registered.
+    dictionaries: Vec<(u32, Arc<Vec<u8>>)>,
+}
+
+impl DictionaryRegistry {
+    /// Registers a dictionary, replacing the one with the same id.
+    pub fn register(&mut self, id: u32, dictionary: Vec<u8>) {
+        let dictionary = Arc::new(dictionary);
+        match self.dictionaries.iter_mut().find(|(known, _)| *known == id) {
+            Some((_id, known)) => *known = dictionary,
+            None => self.dictionaries.push((id, dictionary)),
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&[u8]> {
+        self.dictionaries
+            .iter()
+            .find(|(known, _dictionary)| *known == id)
+            .map(|(_id, dictionary)| &dictionary[..])
+    }
+
+    /// The registered ids, in the order they were registered.
+    pub fn ids(&self) -> Vec<u32> {
+        self.dictionaries
+            .iter()
+            .map(|(id, _dictionary)| *id)
+            .collect()
+    }
+
+    /// Picks the first of the client's dictionaries that is registered. No common dictionary
+    /// isn't an error, frames are just compressed without one.
+    pub fn negotiate(&self, client_ids: &[u32]) -> Option<u32> {
+        client_ids
+            .iter()
+            .copied()
+            .find(|client_id| self.get(*client_id).is_some())
+    }
+}
+
+/// Finds the compressor with the given name.
+pub fn find_compressor<S: AsRef<str>>(
+    name: S,
+    compressors: &[Arc<dyn Compressor>],
+) -> Option<&Arc<dyn Compressor>> {
+    let name = name.as_ref();
+    compressors
+        .iter()
+        .find(|compressor| compressor.name() == name)
+}
+
+/// The compressor for a negotiated compression, primed with the negotiated dictionary if there
+/// is one the compression supports.
+pub fn find_dictionary_compressor<S: AsRef<str>>(
+    name: S,
+    dictionary_id: Option<u32>,
+    compressors: &[Arc<dyn Compressor>],
+    dictionaries: &DictionaryRegistry,
+) -> Option<Arc<dyn Compressor>> {
+    let compressor = find_compressor(name, compressors)?;
+    dictionary_id
+        .and_then(|dictionary_id| dictionaries.get(dictionary_id))
+        .and_then(|dictionary| compressor.with_dictionary(dictionary))
+        .or_else(|| Some(compressor.clone()))
+}
+
+/// Picks the first of the client's compressions that is supported. No common compression isn't
+/// an error, frames are just sent uncompressed.
+pub fn negotiate_compression(
+    client_compressions: &[String],
+    supported_compressions: &[&'static str],
+) -> Option<&'static str> {
+    client_compressions.iter().find_map(|client_compression| {
+        supported_compressions
+            .iter()
+            .find(|supported_compression| client_compression == *supported_compression)
+            .copied()
+    })
+}
+
+/// Like `negotiate_compression`, but fails with `LoquiError::NoCommonCompression` if there is no
+/// common compression, e.g. from `RequestHandler::select_compression` to require one.
+pub fn require_compression(
+    client_compressions: &[String],
+    supported_compressions: &[&'static str],
+) -> Result<&'static str, Error> {
+    negotiate_compression(client_compressions, supported_compressions).ok_or_else(|| {
+        LoquiError::NoCommonCompression {
+            offered: client_compressions.to_vec(),
+            supported: supported_compressions.to_vec(),
+        }
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_negotiates_the_first_common_dictionary() {
+        let mut dictionaries = DictionaryRegistry::default();
+        dictionaries.register(3, b"three".to_vec());
+        dictionaries.register(5, b"five".to_vec());
+        dictionaries.register(3, b"THREE".to_vec());
+        assert_eq!(dictionaries.ids(), vec![3, 5]);
+        assert_eq!(dictionaries.get(3), Some(&b"THREE"[..]));
+        assert_eq!(dictionaries.negotiate(&[9, 5, 3]), Some(5));
+        assert_eq!(dictionaries.negotiate(&[9]), None);
+    }
+
+    #[test]
+    fn it_requires_a_common_compression() {
+        let offered = vec!["gzip".to_string(), "deflate".to_string()];
+        assert_eq!(
+            require_compression(&offered, &["snappy", "deflate"]).unwrap(),
+            "deflate"
+        );
+        let error = require_compression(&offered, &["snappy"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No common compression. offered=[\"gzip\", \"deflate\"] supported=[\"snappy\"]"
+        );
+    }
+}
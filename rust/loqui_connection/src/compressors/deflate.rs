@@ -0,0 +1,164 @@
+use crate::compressor::Compressor;
+use crate::error::LoquiError;
+use failure::Error;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+const NAME: &str = "deflate";
+
+/// Compresses payloads with raw deflate (RFC 1951). The name used during negotiation is
+/// "deflate".
+///
+/// Supports pre-shared dictionaries. Deflate only looks back 32 KiB, so only the end of a larger
+/// dictionary is used.
+#[derive(Debug, Clone, Default)]
+pub struct DeflateCompressor {
+    level: Compression,
+    dictionary: Option<Arc<PresetDictionary>>,
+}
+
+/// miniz has no preset dictionaries, so the dictionary is compressed in front of every payload
+/// and only the output after it is sent. The other side decodes the same compressed dictionary
+/// in front of what it received, so back references into the dictionary resolve.
+#[derive(Debug)]
+struct PresetDictionary {
+    bytes: Vec<u8>,
+    /// `bytes` compressed and sync flushed, which ends the deflate block on a byte boundary.
+    primed: Vec<u8>,
+}
+
+impl DeflateCompressor {
+    /// Compression level from 0 (none) to 9 (best).
+    pub fn new(level: u32) -> Self {
+        Self {
+            level: Compression::new(level),
+            dictionary: None,
+        }
+    }
+}
+
+fn compress_failed(e: io::Error) -> Error {
+    LoquiError::CompressFailed {
+        compression: NAME,
+        reason: e.to_string(),
+    }
+    .into()
+}
+
+fn decompress_failed(reason: String) -> Error {
+    LoquiError::DecompressFailed {
+        compression: NAME,
+        reason,
+    }
+    .into()
+}
+
+impl Compressor for DeflateCompressor {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(payload.len() / 2), self.level);
+        let primed = match &self.dictionary {
+            Some(dictionary) => {
+                encoder
+                    .write_all(&dictionary.bytes)
+                    .and_then(|()| encoder.flush())
+                    .map_err(compress_failed)?;
+                encoder.get_ref().len()
+            }
+            None => 0,
+        };
+        encoder
+            .write_all(payload)
+            .and_then(|()| encoder.finish())
+            .map(|mut compressed| compressed.split_off(primed))
+            .map_err(compress_failed)
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let (primed, dictionary_len) = match &self.dictionary {
+            Some(dictionary) => (&dictionary.primed[..], dictionary.bytes.len()),
+            None => (&[][..], 0),
+        };
+        let mut decompressed = Vec::with_capacity(dictionary_len + payload.len() * 2);
+        DeflateDecoder::new(primed.chain(payload))
+            .read_to_end(&mut decompressed)
+            .map_err(|e| decompress_failed(e.to_string()))?;
+        if decompressed.len() < dictionary_len {
+            return Err(decompress_failed("Truncated dictionary.".to_string()));
+        }
+        Ok(decompressed.split_off(dictionary_len))
+    }
+
+    fn with_dictionary(&self, dictionary: &[u8]) -> Option<Arc<dyn Compressor>> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), self.level);
+        encoder.write_all(dictionary).ok()?;
+        encoder.flush().ok()?;
+        let primed = encoder.get_ref().clone();
+        let dictionary = PresetDictionary {
+            bytes: dictionary.to_vec(),
+            primed,
+        };
+        Some(Arc::new(Self {
+            level: self.level,
+            dictionary: Some(Arc::new(dictionary)),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_large_repetitive_payloads() {
+        let payload = b"loqui loqui loqui ".repeat(4096);
+        let compressor = DeflateCompressor::default();
+        let compressed = compressor.compress(&payload).unwrap();
+        assert!(compressed.len() < payload.len() / 10);
+        assert_eq!(compressor.decompress(&compressed).unwrap(), payload);
+    }
+
+    #[test]
+    fn it_round_trips_empty_payloads() {
+        let compressor = DeflateCompressor::new(9);
+        let compressed = compressor.compress(&[]).unwrap();
+        assert!(compressor.decompress(&compressed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_round_trips_with_a_dictionary() {
+        let dictionary = b"{\"user_id\": , \"name\": \"\", \"email\": \"@example.com\"}";
+        let payload = b"{\"user_id\": 42, \"name\": \"jake\", \"email\": \"jake@example.com\"}";
+        let plain = DeflateCompressor::default();
+        let primed = plain.with_dictionary(dictionary).unwrap();
+        let compressed = primed.compress(payload).unwrap();
+        assert!(compressed.len() < plain.compress(payload).unwrap().len());
+        assert_eq!(primed.decompress(&compressed).unwrap(), &payload[..]);
+
+        // A peer primed with the same dictionary decodes it too, one without it doesn't.
+        let peer = DeflateCompressor::new(1)
+            .with_dictionary(dictionary)
+            .unwrap();
+        assert_eq!(peer.decompress(&compressed).unwrap(), &payload[..]);
+        assert_ne!(plain.decompress(&compressed).ok(), Some(payload.to_vec()));
+    }
+
+    #[test]
+    fn it_fails_to_decompress_garbage() {
+        let error = DeflateCompressor::default()
+            .decompress(b"\xff\xfe\xfd garbage")
+            .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::DecompressFailed { compression, .. }) => {
+                assert_eq!(*compression, "deflate")
+            }
+            other => panic!("expected decompress failure. {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,13 @@
+#[cfg(feature = "deflate")]
+mod deflate;
+#[cfg(feature = "snappy")]
+mod snappy;
+#[cfg(feature = "zstd")]
+mod zstd;
+
+#[cfg(feature = "deflate")]
+pub use self::deflate::DeflateCompressor;
+#[cfg(feature = "snappy")]
+pub use self::snappy::SnappyCompressor;
+#[cfg(feature = "zstd")]
+pub use self::zstd::ZstdCompressor;
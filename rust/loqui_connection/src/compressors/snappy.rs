@@ -0,0 +1,98 @@
+use crate::compressor::Compressor;
+use crate::error::LoquiError;
+use failure::Error;
+use snap::raw::{Decoder, Encoder};
+
+const NAME: &str = "snappy";
+
+/// Compresses payloads with raw snappy, without the framing format. Faster than deflate at a
+/// lower ratio. The name used during negotiation is "snappy".
+#[derive(Debug, Clone, Default)]
+pub struct SnappyCompressor {}
+
+impl Compressor for SnappyCompressor {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        Encoder::new().compress_vec(payload).map_err(|e| {
+            LoquiError::CompressFailed {
+                compression: NAME,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        Decoder::new().decompress_vec(payload).map_err(|e| {
+            LoquiError::DecompressFailed {
+                compression: NAME,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response listing users, shaped like the JSON payloads services tend to send.
+    fn json_payload() -> Vec<u8> {
+        let users: Vec<String> = (0..200)
+            .map(|id| {
+                format!(
+                    r#"{{"id":"{}","username":"user{}","avatar":null,"bot":false,"flags":{}}}"#,
+                    80_351_110_224_678_912u64 + id,
+                    id,
+                    id % 4
+                )
+            })
+            .collect();
+        format!(r#"{{"users":[{}]}}"#, users.join(",")).into_bytes()
+    }
+
+    #[test]
+    fn it_round_trips_json_payloads() {
+        let payload = json_payload();
+        let compressor = SnappyCompressor::default();
+        let compressed = compressor.compress(&payload).unwrap();
+        assert!(compressed.len() < payload.len() / 2);
+        assert_eq!(compressor.decompress(&compressed).unwrap(), payload);
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn it_trades_ratio_for_speed_against_deflate() {
+        use crate::compressors::DeflateCompressor;
+
+        let payload = json_payload();
+        let snappy = SnappyCompressor::default().compress(&payload).unwrap();
+        let deflate = DeflateCompressor::default().compress(&payload).unwrap();
+        assert!(deflate.len() < snappy.len());
+        assert!(snappy.len() < payload.len() / 2);
+    }
+
+    #[test]
+    fn it_round_trips_empty_payloads() {
+        let compressor = SnappyCompressor::default();
+        let compressed = compressor.compress(&[]).unwrap();
+        assert!(compressor.decompress(&compressed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_fails_to_decompress_garbage() {
+        let error = SnappyCompressor::default()
+            .decompress(b"\xff\xfe\xfd garbage")
+            .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::DecompressFailed { compression, .. }) => {
+                assert_eq!(*compression, "snappy")
+            }
+            other => panic!("expected decompress failure. {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::compressor::Compressor;
+use crate::error::LoquiError;
+use failure::Error;
+
+const NAME: &str = "zstd";
+
+/// Compresses payloads with zstd, usually at a better ratio than deflate for the same speed. The
+/// name used during negotiation is "zstd".
+#[derive(Debug, Clone)]
+pub struct ZstdCompressor {
+    level: i32,
+}
+
+impl ZstdCompressor {
+    /// Compression level from 1 (fastest) to 22 (best). Levels out of range are clamped to it.
+    pub fn new(level: i32) -> Self {
+        Self { level }
+    }
+}
+
+impl Default for ZstdCompressor {
+    fn default() -> Self {
+        Self::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+impl Compressor for ZstdCompressor {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        zstd::stream::encode_all(payload, self.level).map_err(|e| {
+            LoquiError::CompressFailed {
+                compression: NAME,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        zstd::stream::decode_all(payload).map_err(|e| {
+            LoquiError::DecompressFailed {
+                compression: NAME,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_large_payloads_at_any_level() {
+        let payload = b"the quick brown fox jumps over the lazy dog. ".repeat(4096);
+        for level in &[1, 3, 19] {
+            let compressor = ZstdCompressor::new(*level);
+            let compressed = compressor.compress(&payload).unwrap();
+            assert!(compressed.len() < payload.len() / 10);
+            // Any level decompresses what another level compressed.
+            let decompressed = ZstdCompressor::default().decompress(&compressed).unwrap();
+            assert_eq!(decompressed, payload);
+        }
+    }
+
+    #[test]
+    fn it_round_trips_empty_payloads() {
+        let compressor = ZstdCompressor::default();
+        let compressed = compressor.compress(&[]).unwrap();
+        assert!(compressor.decompress(&compressed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_fails_to_decompress_garbage() {
+        let error = ZstdCompressor::default()
+            .decompress(b"\xff\xfe\xfd garbage")
+            .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::DecompressFailed { compression, .. }) => {
+                assert_eq!(*compression, "zstd")
+            }
+            other => panic!("expected decompress failure. {:?}", other),
+        }
+    }
+}
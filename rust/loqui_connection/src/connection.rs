@@ -1,24 +1,47 @@
+use crate::compressor::find_dictionary_compressor;
+use crate::connection_tag::ConnectionTag;
 use crate::event_handler::EventHandler;
-use crate::framed_io::ReaderWriter;
-use crate::handler::{Handler, Ready};
+use crate::framed_io::{ReaderWriter, Writer};
+use crate::handler::{ConnectionHealth, ConnectionState, Handler, HandshakeTiming, Ready};
+use crate::id_sequence::IdSequence;
+use crate::lifecycle::{lifecycle, LifecycleReceiver, LifecycleSender};
+use crate::metrics::RequestTiming;
+use crate::read_gate::{Gated, ReadGate};
 use crate::select_break::StreamExt as SelectBreakStreamExt;
 use crate::sender::Sender;
+use crate::stats::{ConnectionStats, StatsCounters};
 use crate::timeout_at;
-use crate::LoquiError;
+use crate::wire_tap::Tap;
+use crate::write_watchdog::WriteWatchdog;
+use crate::{GoAwayCode, LoquiError};
+use bytesize::ByteSize;
 use failure::Error;
 use futures::channel::mpsc::UnboundedReceiver;
 use futures::channel::oneshot;
-use futures::{Future, StreamExt};
-use loqui_protocol::frames::{LoquiFrame, Response};
+use futures::{FutureExt, StreamExt};
+use loqui_protocol::frames::{Error as ErrorFrame, LoquiFrame, Push, Request, Response};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::task::spawn;
-use tokio::time::interval;
 use tokio::time::Instant;
+use tokio::time::{interval, timeout};
 
 #[derive(Debug)]
 pub struct Connection<H: Handler> {
     self_sender: Sender<H::InternalEvent>,
+    stats: Arc<StatsCounters>,
+    lifecycle: LifecycleReceiver,
+}
+
+impl<H: Handler> Clone for Connection<H> {
+    fn clone(&self) -> Self {
+        Self {
+            self_sender: self.self_sender.clone(),
+            stats: self.stats.clone(),
+            lifecycle: self.lifecycle.clone(),
+        }
+    }
 }
 
 impl<H: Handler> Connection<H> {
@@ -38,8 +61,12 @@ impl<H: Handler> Connection<H> {
         ready_tx: Option<oneshot::Sender<&'static str>>,
     ) -> Self {
         let (self_sender, self_rx) = Sender::new();
+        let stats = Arc::new(StatsCounters::default());
+        let (lifecycle_tx, lifecycle) = lifecycle();
         let connection = Self {
             self_sender: self_sender.clone(),
+            stats: stats.clone(),
+            lifecycle,
         };
         spawn(async move {
             match timeout_at(handshake_deadline, TcpStream::connect(&address)).await {
@@ -49,6 +76,10 @@ impl<H: Handler> Connection<H> {
                         tcp_stream,
                         self_sender,
                         self_rx,
+                        Observed {
+                            stats,
+                            lifecycle: lifecycle_tx,
+                        },
                         handler,
                         handshake_deadline,
                         ready_tx,
@@ -58,7 +89,10 @@ impl<H: Handler> Connection<H> {
                         warn!("Connection closed. ip={:?} error={:?}", address, e)
                     }
                 }
-                Err(e) => error!("Connect failed. error={:?}", e),
+                Err(e) => {
+                    error!("Connect failed. error={:?}", e);
+                    lifecycle_tx.handshake_failed(&e);
+                }
             };
         });
         connection
@@ -80,8 +114,12 @@ impl<H: Handler> Connection<H> {
         ready_tx: Option<oneshot::Sender<&'static str>>,
     ) -> Self {
         let (self_sender, self_rx) = Sender::new();
+        let stats = Arc::new(StatsCounters::default());
+        let (lifecycle_tx, lifecycle) = lifecycle();
         let connection = Self {
             self_sender: self_sender.clone(),
+            stats: stats.clone(),
+            lifecycle,
         };
         spawn(async move {
             let ip = tcp_stream.peer_addr();
@@ -89,6 +127,10 @@ impl<H: Handler> Connection<H> {
                 tcp_stream,
                 self_sender,
                 self_rx,
+                Observed {
+                    stats,
+                    lifecycle: lifecycle_tx,
+                },
                 handler,
                 handshake_deadline,
                 ready_tx,
@@ -101,17 +143,104 @@ impl<H: Handler> Connection<H> {
         connection
     }
 
-    pub fn send(&self, event: H::InternalEvent) -> Result<(), Error> {
+    pub fn send(&self, event: H::InternalEvent) -> Result<(), LoquiError> {
         self.self_sender.internal(event)
     }
 
-    pub fn close(&self) -> Result<(), Error> {
-        self.self_sender.close()
+    /// Send an event whose frame the caller wants to know was flushed to the socket. The receiver
+    /// resolves once it was, and is cancelled if the frame was dropped or the connection closed
+    /// first.
+    pub fn send_flushed(
+        &self,
+        event: H::InternalEvent,
+    ) -> Result<oneshot::Receiver<()>, LoquiError> {
+        let (waiter, flushed) = oneshot::channel();
+        self.self_sender.internal_flushed(event, waiter)?;
+        Ok(flushed)
+    }
+
+    pub fn close(&self) -> Result<(), LoquiError> {
+        self.self_sender.close(None)
+    }
+
+    /// Close, telling the other side why in the `GoAway`, e.g. "deploying".
+    pub fn close_with_reason<R: Into<String>>(&self, reason: R) -> Result<(), LoquiError> {
+        self.self_sender.close(Some(reason.into()))
+    }
+
+    /// Tell the other side to go away with the code, e.g. `GoAwayCode::Shutdown`, then close once
+    /// the in flight requests drained or the drain timeout elapsed.
+    pub fn initiate_graceful_shutdown(&self, code: GoAwayCode) -> Result<(), LoquiError> {
+        self.self_sender.initiate_graceful_shutdown(code)
+    }
+
+    /// Abort the response futures of every request of the other side, without responding, and
+    /// fail the requests this side is waiting on with `LoquiError::ConnectionClosing`, e.g.
+    /// before shutting down. The connection stays open. Cancelling again does nothing.
+    pub fn cancel_all_inflight(&self) -> Result<(), LoquiError> {
+        self.self_sender.cancel_all_inflight()
+    }
+
+    /// Stop sending requests and pushes while still receiving. The other side closes the
+    /// connection once it has responded to everything in flight.
+    pub fn half_close(&self) -> Result<(), LoquiError> {
+        self.self_sender.half_close()
+    }
+
+    /// Ask the other side to switch to another encoding, see `TransportOptions::renegotiation`.
+    /// `Handler::on_encoding_renegotiated` is called once it answered.
+    pub fn renegotiate_encoding(&self, encoding: &'static str) -> Result<(), LoquiError> {
+        self.self_sender.renegotiate_encoding(encoding)
+    }
+
+    /// Ask the other side for the stats of the connection. It answers without involving the
+    /// application, so it tells that the connection is alive and that the other side handles
+    /// frames, e.g. before a pool hands the connection out.
+    pub async fn health_check(&self) -> Result<ConnectionHealth, LoquiError> {
+        let (waiter, health) = oneshot::channel();
+        self.self_sender.health_check(waiter)?;
+        health
+            .await
+            .map_err(|_canceled| LoquiError::ConnectionClosed)
+    }
+
+    /// Stop handing received frames to the handler, e.g. while the application can't keep up with
+    /// what it was sent. Pings and pongs are still handled, so neither side drops the connection.
+    /// Other frames are held until `resume_reading`. Once 1024 are held the socket isn't read
+    /// anymore, so the other side is eventually held back by TCP flow control. From then on its
+    /// pings go unanswered too, and a long pause may get the connection dropped by it.
+    ///
+    /// The connection isn't timed out for missing pongs while paused.
+    pub fn pause_reading(&self) -> Result<(), LoquiError> {
+        self.self_sender.pause_reading()
+    }
+
+    /// Read from the socket again after `pause_reading`.
+    pub fn resume_reading(&self) -> Result<(), LoquiError> {
+        self.self_sender.resume_reading()
+    }
+
+    /// A snapshot of the counters of the connection, e.g. for dashboards. Taken without going
+    /// through the connection, so it is cheap and doesn't wait for queued events.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
     }
 
     pub fn is_closed(&self) -> bool {
         self.self_sender.is_closed()
     }
+
+    /// Where the connection is in its lifecycle.
+    pub fn state(&self) -> ConnectionState {
+        self.lifecycle.state()
+    }
+
+    /// Resolves once the handshake completed, so requests can be sent. Fails with
+    /// `LoquiError::HandshakeFailed` if connecting or negotiating failed, and with
+    /// `LoquiError::ConnectionClosed` if the connection closed since.
+    pub async fn ready(&self) -> Result<(), LoquiError> {
+        self.lifecycle.ready().await
+    }
 }
 
 /// The events that can be received by the core connection loop once it begins running.
@@ -123,10 +252,55 @@ pub enum Event<InternalEvent: Send + 'static> {
     Ping,
     /// Generic event that will be delegated to the connection handler.
     InternalEvent(InternalEvent),
+    /// Like `InternalEvent`, and the sender is told once the frame it produced was flushed.
+    InternalEventFlushed(InternalEvent, oneshot::Sender<()>),
     /// A response for a request was computed and should be sent back over the socket.
-    ResponseComplete(Result<Response, (Error, u32)>),
-    /// Close the connection gracefully.
-    Close,
+    ResponseComplete(Result<Response, (Error, u32)>, RequestTiming),
+    /// Close the connection gracefully, going away with the reason, if any.
+    Close(Option<String>),
+    /// Send a `GoAway` with the code, then close once in flight requests drained.
+    GracefulShutdown(GoAwayCode),
+    /// Tell the other side no more requests or pushes will be sent.
+    HalfClose,
+    /// In flight requests didn't finish draining in time after being told to go away.
+    DrainTimeout,
+    /// The future of a cancelled request was aborted before it completed.
+    RequestCancelled,
+    /// A response of a streamed response is ready and should be sent over the socket.
+    StreamItem(Response),
+    /// A frame that `Handler::before_send` delayed is due to be sent, with the encoding its
+    /// payload is encoded with if it isn't the one in use.
+    SendDelayed(
+        LoquiFrame,
+        Option<&'static str>,
+        Option<oneshot::Sender<()>>,
+    ),
+    /// The frame with this sequence id was flushed to the socket.
+    Flushed(u32),
+    /// Ask the other side to switch to this encoding.
+    Renegotiate(&'static str),
+    /// Ask the other side for the stats of the connection.
+    HealthCheck(oneshot::Sender<ConnectionHealth>),
+    /// A payload was published to a subscription of the other side, see `SubscribeOutcome`.
+    SubscriptionPush(Push),
+    /// The stream of the subscription with this id ended.
+    SubscriptionEnded(u32),
+    /// Hold received frames back, but pings and pongs, until `ResumeReading`.
+    PauseReading,
+    /// Hand the held frames and the ones received from now on to the handler.
+    ResumeReading,
+    /// The pong coalescing window is over. Answer the latest ping received within it.
+    PongDue,
+    /// Abort every in flight request and fail the pending ones.
+    CancelAllInflight,
+}
+
+/// What the handles of a connection read without going through it.
+struct Observed {
+    /// The counters `Connection::stats` reads.
+    stats: Arc<StatsCounters>,
+    /// The state changes `Connection::ready` waits for.
+    lifecycle: LifecycleSender,
 }
 
 /// The core run loop for a connection.
@@ -136,8 +310,9 @@ pub enum Event<InternalEvent: Send + 'static> {
 ///
 /// * `tcp_stream` - the tcp socket
 /// * `self_sender` - a sender that is used to for the connection to enqueue an event to itself.
-///                   This is used when a response for a request is computed asynchronously in a task.
+///   This is used when a response for a request is computed asynchronously in a task.
 /// * `self_rx` - a receiver that InternalEvents will be sent over
+/// * `observed` - what the handles of the connection read without going through it
 /// * `handler` - implements logic for the client or server specific things
 /// * `handshake_deadline` - how long until we fail due to handshake not completing
 /// * `ready_tx` - a sender used to notify that the connection is ready for requests
@@ -145,43 +320,220 @@ async fn run<H: Handler>(
     tcp_stream: TcpStream,
     self_sender: Sender<H::InternalEvent>,
     self_rx: UnboundedReceiver<Event<H::InternalEvent>>,
-    handler: H,
+    observed: Observed,
+    mut handler: H,
     handshake_deadline: Instant,
     ready_tx: Option<oneshot::Sender<&'static str>>,
 ) -> Result<(), Error> {
-    let (ready, reader_writer, handler) =
-        timeout_at(handshake_deadline, negotiate(tcp_stream, handler, ready_tx)).await?;
+    let Observed { stats, lifecycle } = observed;
+    let connection = ConnectionTag::next(handler.transport_options().labels.clone());
+    handler.on_connection_start(&connection);
+    let started_at = handler.transport_options().clock.now();
+    let handshake_timeout = handler.transport_options().handshake_timeout;
+    let negotiate = negotiate(tcp_stream, handler, started_at, ready_tx);
+    // The timer runs while connecting and is dropped once the handshake completed.
+    let negotiate = async move {
+        match handshake_timeout {
+            Some(handshake_timeout) => timeout(handshake_timeout, negotiate)
+                .await
+                .unwrap_or_else(|_elapsed| Err(LoquiError::HandshakeTimeout.into())),
+            None => negotiate.await,
+        }
+    };
+    let (ready, reader_writer, handler) = match timeout_at(handshake_deadline, negotiate).await {
+        Ok(negotiated) => negotiated,
+        Err(error) => {
+            lifecycle.handshake_failed(&error);
+            return Err(error);
+        }
+    };
     debug!("Ready. {:?}", ready);
     let (reader, mut writer) = reader_writer.split();
+    let (bytes_read, bytes_written) = (reader.bytes_read(), writer.bytes_written());
 
     let Ready {
         ping_interval,
         encoding,
+        compression,
+        dictionary_id,
+        peer_version: _peer_version,
+        batches: _batches,
+        ping_timestamps,
+        checksums,
+        encodings,
     } = ready;
     // Convert each stream into a Result<Event, Error> stream.
     let ping_stream = interval(ping_interval).map(|_| Ok(Event::Ping));
-    let framed_reader = reader.map(|result| result.map(Event::SocketReceive));
-    let self_rx = self_rx.map(|event| Ok(event));
+    let read_gate = Arc::new(ReadGate::default());
+    let framed_reader =
+        Gated::new(reader, read_gate.clone()).map(|result| result.map(Event::SocketReceive));
+    let queue_sender = self_sender.clone();
+    let flush_sender = self_sender.clone();
+    // Borrowed, so the events still queued once the connection closed can be taken off it.
+    let mut self_rx = self_rx;
+    let queued_events = self_rx.by_ref().map(move |event| {
+        queue_sender.dequeued();
+        Ok(event)
+    });
 
     let mut stream = framed_reader
-        .select_break(self_rx)
+        .select_break(queued_events)
         .select_break(ping_stream);
 
-    let mut event_handler = EventHandler::new(self_sender, handler, encoding);
-    while let Some(event) = stream.next().await {
-        let event = event?;
+    let transport_options = handler.transport_options();
+    let notify_flush = transport_options.notify_flush;
+    let write_coalescing = transport_options.write_coalescing;
+    let max_coalesce_bytes = transport_options.max_coalesce_bytes;
+    let write_stall_timeout = transport_options.write_stall_timeout;
+    let metrics = transport_options.metrics.clone();
+    let metrics = metrics.for_connection(&connection).unwrap_or(metrics);
+    let compressor = compression.and_then(|compression| {
+        find_dictionary_compressor(
+            compression,
+            dictionary_id,
+            &transport_options.compressors,
+            &transport_options.dictionaries,
+        )
+    });
+    stats.ready(
+        transport_options.clock.clone(),
+        encoding,
+        compressor.as_ref().map(|compressor| compressor.name()),
+        bytes_read,
+        bytes_written,
+    );
+    let id_strategy = transport_options.id_strategy.make(IdSequence::next_epoch());
+    let mut event_handler = EventHandler::new(self_sender, handler, encoding, compressor, metrics);
+    // Seeded once the handshake completed so ids don't repeat those of a previous connection.
+    event_handler.seed_id_sequence(IdSequence::new(id_strategy));
+    event_handler.set_ping_timestamps(ping_timestamps);
+    event_handler.set_checksums(checksums);
+    event_handler.set_encoding_ids(encodings);
+    event_handler.set_connection(connection);
+    event_handler.set_read_gate(read_gate);
+    event_handler.set_stats(stats);
+    event_handler.set_lifecycle(lifecycle);
+    event_handler.set_state(ConnectionState::Ready);
+    // The frames fed to the writer that weren't flushed yet.
+    let mut pending_flushes: Vec<PendingFlush> = vec![];
+    let mut write_watchdog = WriteWatchdog::new(write_stall_timeout);
+    // Ok with the error the connection was closed for, or the error it failed with.
+    let result = loop {
+        let next = if pending_flushes.is_empty() {
+            stream.next().await
+        } else {
+            // Coalesce the frames of the events that are ready, flushing once none is.
+            match stream.next().now_or_never() {
+                Some(next) => next,
+                None => {
+                    let flushed = flush(writer, &mut pending_flushes, &flush_sender);
+                    match write_watchdog.guard(flushed).await {
+                        Ok(new_writer) => writer = new_writer,
+                        Err(error) => break Err(error),
+                    }
+                    write_watchdog.flushed();
+                    stream.next().await
+                }
+            }
+        };
+        let event = match next {
+            Some(Ok(event)) => event,
+            Some(Err(error)) => break Err(error),
+            None => break Err(LoquiError::ConnectionClosed.into()),
+        };
+        if let Event::Ping = event {
+            if let Err(error) = write_watchdog.check(Instant::now()) {
+                break Err(error.into());
+            }
+        }
 
-        match event_handler.handle_event(event) {
-            Ok(Some(frame)) => writer = writer.write(frame).await?,
+        let result = event_handler.handle_event(event);
+        // Dropped unless the frame is written, which cancels the receiver.
+        let flush_waiter = event_handler.take_flush_waiter();
+        match result {
+            Ok(Some(frame)) => {
+                let flushed_id = if notify_flush {
+                    flushed_sequence_id(&frame)
+                } else {
+                    None
+                };
+                pending_flushes.push((flushed_id, flush_waiter));
+                write_watchdog.fed(Instant::now());
+                if !write_coalescing {
+                    match write_watchdog.guard(writer.write(frame)).await {
+                        Ok(new_writer) => writer = new_writer,
+                        Err(error) => break Err(error),
+                    }
+                } else if let Err(error) = writer.feed(frame) {
+                    break Err(error);
+                }
+                // Without coalescing the frame was written, so this only notifies its flush.
+                if !write_coalescing || writer.buffered_bytes() >= max_coalesce_bytes {
+                    let flushed = flush(writer, &mut pending_flushes, &flush_sender);
+                    match write_watchdog.guard(flushed).await {
+                        Ok(new_writer) => writer = new_writer,
+                        Err(error) => break Err(error),
+                    }
+                    write_watchdog.flushed();
+                }
+            }
             Ok(None) => {}
             Err(error) => {
-                writer.close(Some(&error), None).await;
-                return Ok(());
+                close(writer, &event_handler, &error).await;
+                break Ok(error);
             }
         }
+
+        if let Some(error) = event_handler
+            .drain_complete()
+            .or_else(|| event_handler.half_close_complete())
+        {
+            close(writer, &event_handler, &error).await;
+            break Ok(error);
+        }
+    };
+    drop(stream);
+    let unflushed = flush_sender.drain_unflushed(&mut self_rx);
+    match &result {
+        Ok(error) | Err(error) => event_handler.close(error, unflushed),
     }
+    result.map(|_closed_with| ())
+}
 
-    Err(LoquiError::ConnectionClosed.into())
+/// The sequence id to report to `Handler::on_flush` and the waiter to tell once a frame fed to
+/// the writer was flushed.
+type PendingFlush = (Option<u32>, Option<oneshot::Sender<()>>);
+
+/// Writes the frames fed to the writer to the socket, then notifies their flushes.
+async fn flush<T: Send + 'static>(
+    writer: Writer,
+    pending_flushes: &mut Vec<PendingFlush>,
+    flush_sender: &Sender<T>,
+) -> Result<Writer, LoquiError> {
+    let writer = writer.flush().await?;
+    for (flushed_id, flush_waiter) in pending_flushes.drain(..) {
+        if let Some(sequence_id) = flushed_id {
+            // It's okay to ignore this result. The connection closed.
+            let _result = flush_sender.flushed(sequence_id);
+        }
+        if let Some(flush_waiter) = flush_waiter {
+            // It's okay to ignore this result. The caller stopped waiting.
+            let _result = flush_waiter.send(());
+        }
+    }
+    Ok(writer)
+}
+
+/// Closes the socket with a `GoAway` for the error, unless the other side was already told to go
+/// away. Frames held back to coalesce writes are written first.
+async fn close<H: Handler>(writer: Writer, event_handler: &EventHandler<H>, error: &Error) {
+    if event_handler.is_shutting_down() {
+        debug!("Closing after shutting down. error={:?}", error);
+        // It's okay to ignore this result. The connection is closing.
+        let _result = writer.flush().await;
+        return;
+    }
+    writer.close(Some(error), None).await;
 }
 
 /// Negotiates the connection.
@@ -190,33 +542,63 @@ async fn run<H: Handler>(
 ///
 /// * `tcp_stream` - the tcp socket
 /// * `handler` - implements logic for the client or server specific things
+/// * `started_at` - when the connection loop started, to time the handshake
 /// * `ready_tx` - a sender used to notify that the connection is ready for requests
-fn negotiate<H: Handler>(
+async fn negotiate<H: Handler>(
     tcp_stream: TcpStream,
     mut handler: H,
+    started_at: Instant,
     ready_tx: Option<oneshot::Sender<&'static str>>,
-) -> impl Future<Output = Result<(Ready, ReaderWriter, H), Error>> {
-    async move {
-        let tcp_stream = handler.upgrade(tcp_stream).await?;
-        let max_payload_size = handler.max_payload_size();
-        let reader_writer = ReaderWriter::new(tcp_stream, max_payload_size, H::SEND_GO_AWAY);
-
-        match handler.handshake(reader_writer).await {
-            Ok((ready, reader_writer)) => {
-                if let Some(ready_tx) = ready_tx {
-                    ready_tx
-                        .send(ready.encoding)
-                        .map_err(|_| Error::from(LoquiError::ReadySendFailed))?;
-                }
-                Ok((ready, reader_writer, handler))
+) -> Result<(Ready, ReaderWriter, H), Error> {
+    handler.transport_options().configure_socket(&tcp_stream)?;
+    let tcp_stream = handler.upgrade(tcp_stream).await?;
+    let max_payload_size = match handler.transport_options().max_payload_bytes {
+        Some(max_payload_bytes) => {
+            ByteSize::b(max_payload_bytes as u64).min(handler.max_payload_size())
+        }
+        None => handler.max_payload_size(),
+    };
+    let tap = Tap::new(handler.transport_options());
+    let buffer_pool = handler.transport_options().buffer_pool.clone();
+    let reader_writer = ReaderWriter::configured(
+        tcp_stream,
+        max_payload_size,
+        H::SEND_GO_AWAY,
+        tap,
+        buffer_pool,
+    );
+
+    match handler.handshake(reader_writer).await {
+        Ok((ready, reader_writer)) => {
+            let timing = HandshakeTiming {
+                duration: handler.transport_options().clock.now() - started_at,
+                resumed: false,
+            };
+            handler.on_handshake_complete(&ready.negotiated(), &timing);
+            if let Some(ready_tx) = ready_tx {
+                ready_tx
+                    .send(ready.encoding)
+                    .map_err(|_| Error::from(LoquiError::ReadySendFailed))?;
             }
-            Err((error, reader_writer)) => {
-                debug!("Not ready. e={:?}", error);
-                if let Some(reader_writer) = reader_writer {
-                    reader_writer.close(Some(&error)).await;
-                }
-                Err(error)
+            Ok((ready, reader_writer, handler))
+        }
+        Err((error, reader_writer)) => {
+            debug!("Not ready. e={:?}", error);
+            if let Some(reader_writer) = reader_writer {
+                reader_writer.close(Some(&error)).await;
             }
+            Err(error)
         }
     }
 }
+
+/// The sequence id to report to `Handler::on_flush` once the frame was written.
+fn flushed_sequence_id(frame: &LoquiFrame) -> Option<u32> {
+    match frame {
+        LoquiFrame::Request(Request { sequence_id, .. })
+        | LoquiFrame::Response(Response { sequence_id, .. })
+        | LoquiFrame::Error(ErrorFrame { sequence_id, .. }) => Some(*sequence_id),
+        LoquiFrame::Push(Push { sequence_id, .. }) => *sequence_id,
+        _ => None,
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Each connection of the process takes the next id.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies a connection in logs and metrics, e.g. to tell apart the connections of an app that
+/// holds many. Its `tracing` spans carry the id and labels, and it is passed to
+/// `Handler::on_connection_start` and `Metrics::for_connection`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionTag {
+    /// Unique within the process, in the order the connections started. Opaque otherwise.
+    pub connection_id: u64,
+    /// The labels of `TransportOptions::labels`, in the order they were added.
+    pub labels: Vec<(String, String)>,
+}
+
+impl ConnectionTag {
+    /// Takes the id for a new connection.
+    pub(crate) fn next(labels: Vec<(String, String)>) -> Self {
+        Self {
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst),
+            labels,
+        }
+    }
+
+    /// The value of the first label with the key.
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(known, _value)| known == key)
+            .map(|(_key, value)| &value[..])
+    }
+}
+
+/// Formats the labels as `key=value` pairs separated by spaces.
+impl fmt::Display for ConnectionTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (key, value)) in self.labels.iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_takes_increasing_ids() {
+        let first = ConnectionTag::next(vec![]);
+        let second = ConnectionTag::next(vec![]);
+        assert!(second.connection_id > first.connection_id);
+    }
+
+    #[test]
+    fn it_formats_the_labels() {
+        let tag = ConnectionTag::next(vec![
+            ("region".to_string(), "us-east".to_string()),
+            ("shard".to_string(), "3".to_string()),
+        ]);
+        assert_eq!(tag.label("shard"), Some("3"));
+        assert_eq!(tag.label("missing"), None);
+        assert_eq!(tag.to_string(), "region=us-east shard=3");
+    }
+}
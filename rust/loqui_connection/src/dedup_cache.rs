@@ -0,0 +1,153 @@
+use loqui_protocol::frames::{IdempotencyKey, Response};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The latest responses to idempotent requests, keyed by their idempotency key, so a retried
+/// request is answered without running the handler again, see `TransportOptions::dedup_ttl`.
+#[derive(Debug)]
+pub(crate) struct DedupCache {
+    ttl: Duration,
+    capacity: usize,
+    responses: HashMap<IdempotencyKey, CachedResponse>,
+    /// The keys in the order they were cached, oldest first.
+    cached_order: VecDeque<(IdempotencyKey, Instant)>,
+    /// The keys of the idempotent requests being computed, keyed by `sequence_id`.
+    in_flight: HashMap<u32, IdempotencyKey>,
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    response: Response,
+    cached_at: Instant,
+}
+
+impl DedupCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            responses: HashMap::new(),
+            cached_order: VecDeque::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// The response cached for the key, unless it expired.
+    pub fn get(&mut self, idempotency_key: &IdempotencyKey, now: Instant) -> Option<Response> {
+        self.expire(now);
+        self.responses
+            .get(idempotency_key)
+            .map(|cached| cached.response.clone())
+    }
+
+    /// Remembers the key of a request whose response is being computed, so it's cached once
+    /// computed.
+    pub fn track(&mut self, sequence_id: u32, idempotency_key: IdempotencyKey) {
+        self.in_flight.insert(sequence_id, idempotency_key);
+    }
+
+    /// Caches the response if it answers a tracked request. The oldest response is evicted at
+    /// capacity.
+    pub fn complete(&mut self, response: &Response, now: Instant) {
+        let idempotency_key = match self.in_flight.remove(&response.sequence_id) {
+            Some(idempotency_key) => idempotency_key,
+            None => return,
+        };
+        self.expire(now);
+        if self.responses.len() >= self.capacity {
+            self.evict_oldest();
+        }
+        let cached = CachedResponse {
+            response: response.clone(),
+            cached_at: now,
+        };
+        self.responses.insert(idempotency_key, cached);
+        self.cached_order.push_back((idempotency_key, now));
+    }
+
+    /// Stops tracking a request that failed or was cancelled. Failures aren't cached so a retry
+    /// runs the handler again.
+    pub fn forget(&mut self, sequence_id: u32) {
+        self.in_flight.remove(&sequence_id);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((_idempotency_key, cached_at)) = self.cached_order.front() {
+            if now.saturating_duration_since(*cached_at) < self.ttl {
+                return;
+            }
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        while let Some((idempotency_key, cached_at)) = self.cached_order.pop_front() {
+            // Skip keys that were cached again since.
+            let current = self
+                .responses
+                .get(&idempotency_key)
+                .map(|cached| cached.cached_at);
+            if current == Some(cached_at) {
+                self.responses.remove(&idempotency_key);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(sequence_id: u32, payload: &[u8]) -> Response {
+        Response {
+            flags: 0,
+            sequence_id,
+            trace_id: None,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn it_caches_responses_of_tracked_requests() {
+        let now = Instant::now();
+        let mut cache = DedupCache::new(Duration::from_secs(10), 8);
+        cache.track(1, [1; 16]);
+        cache.complete(&response(1, b"one"), now);
+        // Untracked responses aren't cached.
+        cache.complete(&response(2, b"two"), now);
+        assert_eq!(cache.get(&[1; 16], now), Some(response(1, b"one")));
+        assert_eq!(cache.get(&[2; 16], now), None);
+
+        cache.track(3, [3; 16]);
+        cache.forget(3);
+        cache.complete(&response(3, b"three"), now);
+        assert_eq!(cache.get(&[3; 16], now), None);
+    }
+
+    #[test]
+    fn it_expires_responses_after_the_ttl() {
+        let now = Instant::now();
+        let mut cache = DedupCache::new(Duration::from_secs(10), 8);
+        cache.track(1, [1; 16]);
+        cache.complete(&response(1, b"one"), now);
+        assert!(cache.get(&[1; 16], now + Duration::from_secs(9)).is_some());
+        assert!(cache.get(&[1; 16], now + Duration::from_secs(10)).is_none());
+        assert!(cache.responses.is_empty());
+    }
+
+    #[test]
+    fn it_evicts_the_oldest_response_at_capacity() {
+        let now = Instant::now();
+        let mut cache = DedupCache::new(Duration::from_secs(10), 2);
+        for sequence_id in 1..=3u8 {
+            cache.track(u32::from(sequence_id), [sequence_id; 16]);
+            cache.complete(&response(u32::from(sequence_id), b""), now);
+        }
+        assert_eq!(cache.responses.len(), 2);
+        assert!(cache.get(&[1; 16], now).is_none());
+        assert!(cache.get(&[2; 16], now).is_some());
+        assert!(cache.get(&[3; 16], now).is_some());
+    }
+}
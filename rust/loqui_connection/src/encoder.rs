@@ -0,0 +1,225 @@
+use crate::Metrics;
+use failure::Error;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Payloads at least this big are decoded from a reader by `Encoder::decode_from`. Smaller ones
+/// are cheaper to read whole and decode in one shot.
+pub const STREAMING_DECODE_MIN_BYTES: usize = 64 * 1024;
+
+/// Converts between the raw bytes of a frame payload and application types.
+pub trait Encoder: Send + Sync + 'static {
+    /// The type a received payload is decoded into.
+    type Decoded: Send;
+    /// The type that is encoded into a payload before sending.
+    type Encoded: Send;
+
+    /// Decodes the payload of a received frame.
+    fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error>;
+    /// Decodes a payload of `len` bytes as it is read from `reader`, so a big one needn't be held
+    /// whole next to what it decodes into. Reads no further than the payload, whatever follows it.
+    /// By default it's read whole and decoded with `decode`.
+    fn decode_reader(&self, reader: &mut dyn Read, len: usize) -> Result<Self::Decoded, Error> {
+        let mut payload = Vec::with_capacity(len);
+        reader.take(len as u64).read_to_end(&mut payload)?;
+        self.decode(payload)
+    }
+    /// Decodes a payload of `len` bytes from `reader`, with `decode_reader` from
+    /// `STREAMING_DECODE_MIN_BYTES` and with `decode` below.
+    fn decode_from(&self, reader: &mut dyn Read, len: usize) -> Result<Self::Decoded, Error> {
+        if len >= STREAMING_DECODE_MIN_BYTES {
+            return self.decode_reader(reader, len);
+        }
+        let mut payload = Vec::with_capacity(len);
+        reader.take(len as u64).read_to_end(&mut payload)?;
+        self.decode(payload)
+    }
+    /// Encodes a value into the payload of a frame that will be sent.
+    fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error>;
+    /// Encodes a value by appending it to `buffer`, e.g. one from a `BufferPool`. By default it's
+    /// encoded with `encode` and copied over.
+    fn encode_into(&self, value: Self::Encoded, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        buffer.extend_from_slice(&self.encode(value)?);
+        Ok(())
+    }
+}
+
+/// Makes `Encoder`s for the encodings negotiated during the handshake.
+pub trait Factory: Send + Sync + 'static {
+    type Encoder: Encoder;
+
+    /// The encoding names advertised during the handshake. Use these as the
+    /// `supported_encodings` of a client or server config.
+    const ENCODINGS: &'static [&'static str];
+
+    /// Makes an `Encoder` for the negotiated encoding. Returns `None` if the factory doesn't
+    /// support the encoding.
+    fn make(encoding: &str) -> Option<Self::Encoder>;
+}
+
+/// Wraps an `Encoder`, reporting how long each encode and decode took to
+/// `Metrics::observe_encode` and `Metrics::observe_decode`. Encoders that aren't wrapped aren't
+/// timed.
+pub struct TimedEncoder<E: Encoder> {
+    encoder: E,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl<E: Encoder> TimedEncoder<E> {
+    pub fn new(encoder: E, metrics: Arc<dyn Metrics>) -> Self {
+        Self { encoder, metrics }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.encoder
+    }
+}
+
+impl<E: Encoder> Encoder for TimedEncoder<E> {
+    type Decoded = E::Decoded;
+    type Encoded = E::Encoded;
+
+    fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+        let started_at = Instant::now();
+        let result = self.encoder.decode(payload);
+        self.metrics.observe_decode(started_at.elapsed());
+        result
+    }
+
+    fn decode_reader(&self, reader: &mut dyn Read, len: usize) -> Result<Self::Decoded, Error> {
+        let started_at = Instant::now();
+        let result = self.encoder.decode_reader(reader, len);
+        self.metrics.observe_decode(started_at.elapsed());
+        result
+    }
+
+    fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error> {
+        let started_at = Instant::now();
+        let result = self.encoder.encode(value);
+        self.metrics.observe_encode(started_at.elapsed());
+        result
+    }
+
+    fn encode_into(&self, value: Self::Encoded, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        let started_at = Instant::now();
+        let result = self.encoder.encode_into(value, buffer);
+        self.metrics.observe_encode(started_at.elapsed());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoquiError;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    struct BytesEncoder;
+
+    impl Encoder for BytesEncoder {
+        type Decoded = Vec<u8>;
+        type Encoded = &'static [u8];
+
+        fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+            if payload.is_empty() {
+                return Err(LoquiError::DecodeFailed {
+                    encoding: "bytes",
+                    reason: "empty payload".to_string(),
+                }
+                .into());
+            }
+            Ok(payload)
+        }
+
+        fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error> {
+            Ok(value.to_vec())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct TimingMetrics {
+        encodes: Mutex<Vec<Duration>>,
+        decodes: Mutex<Vec<Duration>>,
+    }
+
+    impl Metrics for TimingMetrics {
+        fn observe_encode(&self, duration: Duration) {
+            self.encodes.lock().unwrap().push(duration);
+        }
+
+        fn observe_decode(&self, duration: Duration) {
+            self.decodes.lock().unwrap().push(duration);
+        }
+    }
+
+    #[test]
+    fn it_reports_every_call() {
+        let metrics = Arc::new(TimingMetrics::default());
+        let encoder = TimedEncoder::new(BytesEncoder, metrics.clone());
+
+        assert_eq!(encoder.encode(b"hello").unwrap(), b"hello");
+        let mut buffer = vec![];
+        encoder.encode_into(b"hey", &mut buffer).unwrap();
+        assert_eq!(buffer, b"hey");
+        assert_eq!(encoder.decode(b"hello".to_vec()).unwrap(), b"hello");
+        assert!(encoder.decode(vec![]).is_err());
+
+        assert_eq!(metrics.encodes.lock().unwrap().len(), 2);
+        assert_eq!(metrics.decodes.lock().unwrap().len(), 2);
+    }
+
+    /// Tells which of its decodes was used.
+    struct ReaderEncoder;
+
+    impl Encoder for ReaderEncoder {
+        type Decoded = (&'static str, usize);
+        type Encoded = ();
+
+        fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+            Ok(("decode", payload.len()))
+        }
+
+        fn decode_reader(
+            &self,
+            reader: &mut dyn Read,
+            _len: usize,
+        ) -> Result<Self::Decoded, Error> {
+            let read = std::io::copy(reader, &mut std::io::sink())?;
+            Ok(("decode_reader", read as usize))
+        }
+
+        fn encode(&self, _value: Self::Encoded) -> Result<Vec<u8>, Error> {
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn it_decodes_big_payloads_from_the_reader() {
+        let small = [1; 10];
+        let decoded = ReaderEncoder.decode_from(&mut &small[..], small.len());
+        assert_eq!(decoded.unwrap(), ("decode", 10));
+        let big = vec![1; STREAMING_DECODE_MIN_BYTES];
+        let decoded = ReaderEncoder.decode_from(&mut &big[..], big.len());
+        assert_eq!(decoded.unwrap(), ("decode_reader", big.len()));
+
+        // Encoders without a streaming decode read the payload whole.
+        let decoded = BytesEncoder.decode_reader(&mut &b"hello"[..], 5);
+        assert_eq!(decoded.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn it_reads_no_further_than_the_payload() {
+        let mut reader = &b"hellotrailing"[..];
+        assert_eq!(
+            BytesEncoder.decode_reader(&mut reader, 5).unwrap(),
+            b"hello"
+        );
+        assert_eq!(reader, b"trailing");
+
+        let mut reader = &b"hellotrailing"[..];
+        assert_eq!(BytesEncoder.decode_from(&mut reader, 5).unwrap(), b"hello");
+        assert_eq!(reader, b"trailing");
+    }
+}
@@ -0,0 +1,156 @@
+use crate::encoder::{Encoder, Factory};
+use crate::error::LoquiError;
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Read;
+use std::marker::PhantomData;
+
+const ENCODING: &str = "bincode";
+
+/// Makes `BincodeEncoder`s. The name used during negotiation is "bincode".
+pub struct BincodeFactory<D, E> {
+    _types: PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> Factory for BincodeFactory<D, E>
+where
+    D: DeserializeOwned + Send + 'static,
+    E: Serialize + Send + 'static,
+{
+    type Encoder = BincodeEncoder<D, E>;
+
+    const ENCODINGS: &'static [&'static str] = &[ENCODING];
+
+    fn make(encoding: &str) -> Option<Self::Encoder> {
+        if encoding == ENCODING {
+            Some(BincodeEncoder {
+                _types: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Encodes and decodes payloads with `bincode`. The format isn't self-describing, so both sides
+/// must agree on the types. Best suited to services where both ends are Rust.
+pub struct BincodeEncoder<D, E> {
+    _types: PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> Encoder for BincodeEncoder<D, E>
+where
+    D: DeserializeOwned + Send + 'static,
+    E: Serialize + Send + 'static,
+{
+    type Decoded = D;
+    type Encoded = E;
+
+    fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+        ::bincode::deserialize(&payload).map_err(|e| {
+            LoquiError::DecodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn decode_reader(&self, reader: &mut dyn Read, len: usize) -> Result<Self::Decoded, Error> {
+        ::bincode::deserialize_from(reader.take(len as u64)).map_err(|e| {
+            LoquiError::DecodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error> {
+        ::bincode::serialize(&value).map_err(|e| {
+            LoquiError::EncodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn encode_into(&self, value: Self::Encoded, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        ::bincode::serialize_into(buffer, &value).map_err(|e| {
+            LoquiError::EncodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Command {
+        Stop,
+        Move { x: i32, y: i32 },
+        Say(String),
+        Batch(Vec<Command>),
+    }
+
+    fn make_encoder() -> BincodeEncoder<Command, Command> {
+        BincodeFactory::<Command, Command>::make("bincode").expect("bincode not supported")
+    }
+
+    #[test]
+    fn it_round_trips_enum_variants() {
+        let encoder = make_encoder();
+        let commands = vec![
+            Command::Stop,
+            Command::Move { x: -3, y: 7 },
+            Command::Say("hello".to_string()),
+            Command::Batch(vec![Command::Stop, Command::Say(String::new())]),
+        ];
+        for command in commands {
+            let payload = encoder.encode(command).unwrap();
+            let decoded = encoder.decode(payload.clone()).unwrap();
+            assert_eq!(encoder.encode(decoded).unwrap(), payload);
+            let decoded = encoder
+                .decode_reader(&mut &payload[..], payload.len())
+                .unwrap();
+            assert_eq!(encoder.encode(decoded).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn it_decodes_no_further_than_the_payload() {
+        let encoder = make_encoder();
+        let payload = encoder.encode(Command::Say("hello".to_string())).unwrap();
+        let mut followed = payload.clone();
+        followed.extend_from_slice(b"trailing");
+        let mut reader = &followed[..];
+        let decoded = encoder.decode_reader(&mut reader, payload.len()).unwrap();
+        assert_eq!(encoder.encode(decoded).unwrap(), payload);
+        assert_eq!(reader, b"trailing");
+    }
+
+    #[test]
+    fn it_fails_to_decode_garbage() {
+        // An out of range variant index followed by truncated data.
+        let error = make_encoder()
+            .decode(b"\xff\xff\xff\x7f\x01".to_vec())
+            .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::DecodeFailed { encoding, .. }) => assert_eq!(*encoding, "bincode"),
+            other => panic!("expected decode failure. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_only_makes_bincode() {
+        assert!(BincodeFactory::<Command, Command>::make("json").is_none());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::encoder::{Encoder, Factory};
+use crate::error::LoquiError;
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Read;
+use std::marker::PhantomData;
+
+const ENCODING: &str = "cbor";
+
+/// Makes `CborEncoder`s. The name used during negotiation is "cbor".
+pub struct CborFactory<D, E> {
+    _types: PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> Factory for CborFactory<D, E>
+where
+    D: DeserializeOwned + Send + 'static,
+    E: Serialize + Send + 'static,
+{
+    type Encoder = CborEncoder<D, E>;
+
+    const ENCODINGS: &'static [&'static str] = &[ENCODING];
+
+    fn make(encoding: &str) -> Option<Self::Encoder> {
+        if encoding == ENCODING {
+            Some(CborEncoder {
+                _types: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Encodes and decodes payloads in the (https://cbor.io) format using `serde`.
+pub struct CborEncoder<D, E> {
+    _types: PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> Encoder for CborEncoder<D, E>
+where
+    D: DeserializeOwned + Send + 'static,
+    E: Serialize + Send + 'static,
+{
+    type Decoded = D;
+    type Encoded = E;
+
+    fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+        serde_cbor::from_slice(&payload).map_err(|e| {
+            LoquiError::DecodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn decode_reader(&self, reader: &mut dyn Read, len: usize) -> Result<Self::Decoded, Error> {
+        serde_cbor::from_reader(reader.take(len as u64)).map_err(|e| {
+            LoquiError::DecodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error> {
+        serde_cbor::to_vec(&value).map_err(|e| {
+            LoquiError::EncodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn encode_into(&self, value: Self::Encoded, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        serde_cbor::to_writer(buffer, &value).map_err(|e| {
+            LoquiError::EncodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        name: String,
+        value: f64,
+        tags: BTreeMap<String, BTreeMap<String, f32>>,
+    }
+
+    fn make_encoder() -> CborEncoder<Reading, Reading> {
+        CborFactory::<Reading, Reading>::make("cbor").expect("cbor not supported")
+    }
+
+    #[test]
+    fn it_round_trips_nested_maps_and_floats() {
+        let mut inner = BTreeMap::new();
+        inner.insert("p99".to_string(), 12.5);
+        inner.insert("p50".to_string(), -0.25);
+        let mut tags = BTreeMap::new();
+        tags.insert("latency".to_string(), inner);
+        tags.insert("empty".to_string(), BTreeMap::new());
+        let reading = Reading {
+            name: "api".to_string(),
+            value: std::f64::consts::PI,
+            tags,
+        };
+
+        let encoder = make_encoder();
+        let payload = encoder.encode(reading).unwrap();
+        let decoded = encoder.decode(payload).unwrap();
+        assert_eq!(decoded.value, std::f64::consts::PI);
+        assert_eq!(decoded.tags["latency"]["p50"], -0.25);
+        assert_eq!(decoded.tags["empty"].len(), 0);
+    }
+
+    #[test]
+    fn it_decodes_from_a_reader() {
+        let reading = Reading {
+            name: "api".to_string(),
+            value: 1.5,
+            tags: BTreeMap::new(),
+        };
+        let encoder = make_encoder();
+        let payload = encoder.encode(reading).unwrap();
+        let decoded = encoder
+            .decode_reader(&mut &payload[..], payload.len())
+            .unwrap();
+        assert_eq!(decoded.name, "api");
+        assert!(encoder.decode_reader(&mut &payload[..3], 3).is_err());
+
+        // Stops at the end of the payload, whatever follows it.
+        let mut followed = payload.clone();
+        followed.extend_from_slice(b"trailing");
+        let mut reader = &followed[..];
+        let decoded = encoder.decode_reader(&mut reader, payload.len()).unwrap();
+        assert_eq!(decoded.name, "api");
+        assert_eq!(reader, b"trailing");
+    }
+
+    #[test]
+    fn it_fails_to_decode_garbage() {
+        let error = make_encoder()
+            .decode(b"\xff\x00garbage".to_vec())
+            .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::DecodeFailed { encoding, .. }) => assert_eq!(*encoding, "cbor"),
+            other => panic!("expected decode failure. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_only_makes_cbor() {
+        assert!(CborFactory::<Reading, Reading>::make("json").is_none());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::encoder::{Encoder, Factory};
+use crate::error::LoquiError;
+use ::flatbuffers::{Follow, Verifiable};
+use failure::Error;
+use std::marker::PhantomData;
+
+const ENCODING: &str = "flatbuffers";
+
+/// Names the root table of a FlatBuffers schema, e.g. for a generated `Greeting<'a>`:
+/// `impl RootTable for GreetingRoot { type Table<'buf> = Greeting<'buf>; }`.
+pub trait RootTable: Send + Sync + 'static {
+    type Table<'buf>: Follow<'buf, Inner = Self::Table<'buf>> + Verifiable + 'buf;
+}
+
+/// A received payload that passed the generated verifier. Reading the table doesn't copy.
+pub struct FlatBuffer<R: RootTable> {
+    buf: Vec<u8>,
+    _root: PhantomData<fn() -> R>,
+}
+
+impl<R: RootTable> std::fmt::Debug for FlatBuffer<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FlatBuffer")
+            .field("len", &self.buf.len())
+            .finish()
+    }
+}
+
+impl<R: RootTable> FlatBuffer<R> {
+    /// The root table of the buffer.
+    pub fn root(&self) -> R::Table<'_> {
+        // Safe because the buffer was verified when it was decoded.
+        unsafe { ::flatbuffers::root_unchecked::<R::Table<'_>>(&self.buf) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Makes `FlatBufferEncoder`s. The name used during negotiation is "flatbuffers".
+pub struct FlatBufferFactory<R> {
+    _root: PhantomData<fn() -> R>,
+}
+
+impl<R: RootTable> Factory for FlatBufferFactory<R> {
+    type Encoder = FlatBufferEncoder<R>;
+
+    const ENCODINGS: &'static [&'static str] = &[ENCODING];
+
+    fn make(encoding: &str) -> Option<Self::Encoder> {
+        if encoding == ENCODING {
+            Some(FlatBufferEncoder { _root: PhantomData })
+        } else {
+            None
+        }
+    }
+}
+
+/// Decodes payloads into verified `FlatBuffer`s of the root table `R`. Values are encoded by
+/// building them with a `FlatBufferBuilder` and passing its finished bytes.
+pub struct FlatBufferEncoder<R> {
+    _root: PhantomData<fn() -> R>,
+}
+
+impl<R: RootTable> Encoder for FlatBufferEncoder<R> {
+    type Decoded = FlatBuffer<R>;
+    type Encoded = Vec<u8>;
+
+    fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+        if let Err(e) = ::flatbuffers::root::<R::Table<'_>>(&payload) {
+            return Err(LoquiError::DecodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into());
+        }
+        Ok(FlatBuffer {
+            buf: payload,
+            _root: PhantomData,
+        })
+    }
+
+    fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error> {
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::flatbuffers::FlatBufferBuilder;
+    use greeting_generated::{Greeting, GreetingArgs};
+
+    /// What `flatc --rust` generates for this schema, trimmed to what the tests use:
+    ///
+    /// ```text
+    /// table Greeting {
+    ///   name: string;
+    ///   count: uint32;
+    /// }
+    /// root_type Greeting;
+    /// ```
+    #[allow(clippy::all)]
+    mod greeting_generated {
+        use ::flatbuffers;
+
+        #[derive(Copy, Clone, PartialEq)]
+        pub struct Greeting<'a> {
+            pub _tab: flatbuffers::Table<'a>,
+        }
+
+        impl<'a> flatbuffers::Follow<'a> for Greeting<'a> {
+            type Inner = Greeting<'a>;
+            #[inline]
+            unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+                Self {
+                    _tab: flatbuffers::Table::new(buf, loc),
+                }
+            }
+        }
+
+        impl<'a> Greeting<'a> {
+            pub const VT_NAME: flatbuffers::VOffsetT = 4;
+            pub const VT_COUNT: flatbuffers::VOffsetT = 6;
+
+            pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+                _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+                args: &'args GreetingArgs<'args>,
+            ) -> flatbuffers::WIPOffset<Greeting<'bldr>> {
+                let mut builder = GreetingBuilder::new(_fbb);
+                builder.add_count(args.count);
+                if let Some(x) = args.name {
+                    builder.add_name(x);
+                }
+                builder.finish()
+            }
+
+            #[inline]
+            pub fn name(&self) -> Option<&'a str> {
+                unsafe {
+                    self._tab
+                        .get::<flatbuffers::ForwardsUOffset<&str>>(Greeting::VT_NAME, None)
+                }
+            }
+
+            #[inline]
+            pub fn count(&self) -> u32 {
+                unsafe { self._tab.get::<u32>(Greeting::VT_COUNT, Some(0)).unwrap() }
+            }
+        }
+
+        impl flatbuffers::Verifiable for Greeting<'_> {
+            #[inline]
+            fn run_verifier(
+                v: &mut flatbuffers::Verifier,
+                pos: usize,
+            ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+                v.visit_table(pos)?
+                    .visit_field::<flatbuffers::ForwardsUOffset<&str>>(
+                        "name",
+                        Self::VT_NAME,
+                        false,
+                    )?
+                    .visit_field::<u32>("count", Self::VT_COUNT, false)?
+                    .finish();
+                Ok(())
+            }
+        }
+
+        pub struct GreetingArgs<'a> {
+            pub name: Option<flatbuffers::WIPOffset<&'a str>>,
+            pub count: u32,
+        }
+
+        pub struct GreetingBuilder<'a: 'b, 'b> {
+            fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+            start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+        }
+
+        impl<'a: 'b, 'b> GreetingBuilder<'a, 'b> {
+            #[inline]
+            pub fn add_name(&mut self, name: flatbuffers::WIPOffset<&'b str>) {
+                self.fbb_
+                    .push_slot_always::<flatbuffers::WIPOffset<_>>(Greeting::VT_NAME, name);
+            }
+
+            #[inline]
+            pub fn add_count(&mut self, count: u32) {
+                self.fbb_.push_slot::<u32>(Greeting::VT_COUNT, count, 0);
+            }
+
+            #[inline]
+            pub fn new(
+                _fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+            ) -> GreetingBuilder<'a, 'b> {
+                let start = _fbb.start_table();
+                GreetingBuilder {
+                    fbb_: _fbb,
+                    start_: start,
+                }
+            }
+
+            #[inline]
+            pub fn finish(self) -> flatbuffers::WIPOffset<Greeting<'a>> {
+                let o = self.fbb_.end_table(self.start_);
+                flatbuffers::WIPOffset::new(o.value())
+            }
+        }
+    }
+
+    struct GreetingRoot;
+
+    impl RootTable for GreetingRoot {
+        type Table<'buf> = Greeting<'buf>;
+    }
+
+    fn make_encoder() -> FlatBufferEncoder<GreetingRoot> {
+        FlatBufferFactory::<GreetingRoot>::make("flatbuffers").expect("flatbuffers not supported")
+    }
+
+    fn build_greeting(name: &str, count: u32) -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let name = builder.create_string(name);
+        let greeting = Greeting::create(
+            &mut builder,
+            &GreetingArgs {
+                name: Some(name),
+                count,
+            },
+        );
+        builder.finish(greeting, None);
+        builder.finished_data().to_vec()
+    }
+
+    #[test]
+    fn it_round_trips_a_table() {
+        let encoder = make_encoder();
+        let payload = encoder.encode(build_greeting("hello", 3)).unwrap();
+        let decoded = encoder.decode(payload).unwrap();
+        let greeting = decoded.root();
+        assert_eq!(greeting.name(), Some("hello"));
+        assert_eq!(greeting.count(), 3);
+    }
+
+    #[test]
+    fn it_rejects_unverified_buffers() {
+        let mut payload = build_greeting("hello", 3);
+        // Point the root table past the end of the buffer.
+        payload[0] = 0xff;
+        let error = make_encoder().decode(payload).unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::DecodeFailed { encoding, .. }) => {
+                assert_eq!(*encoding, "flatbuffers")
+            }
+            other => panic!("expected decode failure. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_only_makes_flatbuffers() {
+        assert!(FlatBufferFactory::<GreetingRoot>::make("json").is_none());
+    }
+}
@@ -0,0 +1,17 @@
+#[cfg(feature = "bincode")]
+mod bincode;
+#[cfg(feature = "cbor")]
+mod cbor;
+#[cfg(feature = "flatbuffers")]
+mod flatbuffers;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+
+#[cfg(feature = "bincode")]
+pub use self::bincode::{BincodeEncoder, BincodeFactory};
+#[cfg(feature = "cbor")]
+pub use self::cbor::{CborEncoder, CborFactory};
+#[cfg(feature = "flatbuffers")]
+pub use self::flatbuffers::{FlatBuffer, FlatBufferEncoder, FlatBufferFactory, RootTable};
+#[cfg(feature = "protobuf")]
+pub use self::protobuf::{ProstEncoder, ProstFactory};
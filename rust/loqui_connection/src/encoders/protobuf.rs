@@ -0,0 +1,137 @@
+use crate::encoder::{Encoder, Factory};
+use crate::error::LoquiError;
+use failure::Error;
+use prost::Message;
+use std::marker::PhantomData;
+
+const ENCODING: &str = "protobuf";
+
+/// Makes `ProstEncoder`s. The name used during negotiation is "protobuf".
+pub struct ProstFactory<D, E> {
+    _types: PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> Factory for ProstFactory<D, E>
+where
+    D: Message + Default + 'static,
+    E: Message + 'static,
+{
+    type Encoder = ProstEncoder<D, E>;
+
+    const ENCODINGS: &'static [&'static str] = &[ENCODING];
+
+    fn make(encoding: &str) -> Option<Self::Encoder> {
+        if encoding == ENCODING {
+            Some(ProstEncoder {
+                _types: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Encodes and decodes payloads as (https://protobuf.dev) messages using `prost`, so schemas can
+/// be shared with services written in other languages.
+pub struct ProstEncoder<D, E> {
+    _types: PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> Encoder for ProstEncoder<D, E>
+where
+    D: Message + Default + 'static,
+    E: Message + 'static,
+{
+    type Decoded = D;
+    type Encoded = E;
+
+    fn decode(&self, payload: Vec<u8>) -> Result<Self::Decoded, Error> {
+        D::decode(payload.as_slice()).map_err(|e| {
+            LoquiError::DecodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn encode(&self, value: Self::Encoded) -> Result<Vec<u8>, Error> {
+        let mut payload = Vec::with_capacity(value.encoded_len());
+        self.encode_into(value, &mut payload)?;
+        Ok(payload)
+    }
+
+    fn encode_into(&self, value: Self::Encoded, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        value.encode(buffer).map_err(|e| {
+            LoquiError::EncodeFailed {
+                encoding: ENCODING,
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    enum Status {
+        Unknown = 0,
+        Online = 1,
+        Idle = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Presence {
+        #[prost(uint64, tag = "1")]
+        user_id: u64,
+        #[prost(enumeration = "Status", tag = "2")]
+        status: i32,
+        #[prost(string, repeated, tag = "3")]
+        activities: Vec<String>,
+        #[prost(uint32, repeated, tag = "4")]
+        guild_ids: Vec<u32>,
+    }
+
+    fn make_encoder() -> ProstEncoder<Presence, Presence> {
+        ProstFactory::<Presence, Presence>::make("protobuf").expect("protobuf not supported")
+    }
+
+    fn presence() -> Presence {
+        Presence {
+            user_id: 80_351_110_224_678_912,
+            status: Status::Idle as i32,
+            activities: vec!["listening".to_string(), "streaming".to_string()],
+            guild_ids: vec![1, 300, 70_000],
+        }
+    }
+
+    #[test]
+    fn it_round_trips_repeated_fields_and_enums() {
+        let encoder = make_encoder();
+        let payload = encoder.encode(presence()).unwrap();
+        let decoded = encoder.decode(payload).unwrap();
+        assert_eq!(decoded, presence());
+        assert_eq!(decoded.status(), Status::Idle);
+    }
+
+    #[test]
+    fn it_fails_to_decode_a_truncated_buffer() {
+        let encoder = make_encoder();
+        let mut payload = encoder.encode(presence()).unwrap();
+        payload.truncate(payload.len() - 3);
+        let error = encoder.decode(payload).unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::DecodeFailed { encoding, .. }) => assert_eq!(*encoding, "protobuf"),
+            other => panic!("expected decode failure. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_only_makes_protobuf() {
+        assert!(ProstFactory::<Presence, Presence>::make("cbor").is_none());
+    }
+}
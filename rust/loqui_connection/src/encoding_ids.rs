@@ -0,0 +1,207 @@
+use crate::LoquiError;
+use failure::Error;
+use loqui_protocol::frames::{LoquiFrame, Push, Request, Response};
+use loqui_protocol::{is_stream_end, is_streaming};
+use std::collections::HashMap;
+
+/// The encodings data frames select with the 1 byte id their payload starts with, once both sides
+/// agreed on ids, see `TransportOptions::encoding_ids`. Responses use the encoding of their
+/// request.
+#[derive(Debug)]
+pub(crate) struct EncodingIds {
+    /// The encodings by id. The one the handshake settled on is `0`.
+    encodings: Vec<&'static str>,
+    /// The encodings of the other side's requests that weren't answered yet, keyed by
+    /// `sequence_id`.
+    requests: HashMap<u32, &'static str>,
+}
+
+impl EncodingIds {
+    pub fn new(encoding: &'static str, others: Vec<&'static str>) -> Self {
+        let mut encodings = vec![encoding];
+        encodings.extend(others);
+        Self {
+            encodings,
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, encoding: &str) -> bool {
+        self.encodings.contains(&encoding)
+    }
+
+    /// The encoding of a request that wasn't answered yet.
+    pub fn request_encoding(&self, sequence_id: u32) -> Option<&'static str> {
+        self.requests.get(&sequence_id).copied()
+    }
+
+    /// Forgets the encoding of a request that won't be answered, e.g. because it was cancelled.
+    pub fn forget(&mut self, sequence_id: u32) {
+        self.requests.remove(&sequence_id);
+    }
+
+    /// Strips the encoding id off the payload of a received `Request`, `Response` or `Push`,
+    /// returning the encoding it selects. Fails with `LoquiError::UnknownEncodingId` if it doesn't
+    /// select one.
+    pub fn strip(&mut self, frame: &mut LoquiFrame) -> Result<Option<&'static str>, Error> {
+        let (sequence_id, payload) = match frame {
+            LoquiFrame::Request(Request {
+                sequence_id,
+                payload,
+                ..
+            }) => (Some(*sequence_id), payload),
+            LoquiFrame::Response(Response { payload, .. })
+            | LoquiFrame::Push(Push { payload, .. }) => (None, payload),
+            _ => return Ok(None),
+        };
+        // An empty payload can't select an encoding, which `u8::MAX` never does either.
+        let id = if payload.is_empty() {
+            u8::MAX
+        } else {
+            payload.remove(0)
+        };
+        let encoding = *self
+            .encodings
+            .get(id as usize)
+            .ok_or(LoquiError::UnknownEncodingId { id })?;
+        if let Some(sequence_id) = sequence_id {
+            self.requests.insert(sequence_id, encoding);
+        }
+        Ok(Some(encoding))
+    }
+
+    /// Starts the payload of a `Request`, `Response` or `Push` about to be sent with the id of its
+    /// encoding. A response uses the encoding of its request, anything else `encoding`. Errors
+    /// answering a request forget its encoding. Fails with `LoquiError::InvalidEncoding` if the
+    /// encoding wasn't negotiated.
+    pub fn tag(&mut self, frame: &mut LoquiFrame, encoding: &'static str) -> Result<(), Error> {
+        let (encoding, payload) = match frame {
+            LoquiFrame::Response(Response {
+                flags,
+                sequence_id,
+                payload,
+                ..
+            }) => {
+                // A stream keeps its encoding until it ends.
+                let request_encoding = if is_streaming(*flags) && !is_stream_end(*flags) {
+                    self.request_encoding(*sequence_id)
+                } else {
+                    self.requests.remove(sequence_id)
+                };
+                (request_encoding.unwrap_or(encoding), payload)
+            }
+            LoquiFrame::Request(Request { payload, .. })
+            | LoquiFrame::Push(Push { payload, .. }) => (encoding, payload),
+            LoquiFrame::Error(error) => {
+                self.forget(error.sequence_id);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        let id = self
+            .encodings
+            .iter()
+            .position(|negotiated| *negotiated == encoding)
+            .ok_or(LoquiError::InvalidEncoding)?;
+        payload.insert(0, id as u8);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loqui_protocol::frames::{Error as ErrorFrame, Priority};
+    use loqui_protocol::Flags;
+
+    fn request(sequence_id: u32, payload: Vec<u8>) -> LoquiFrame {
+        Request {
+            flags: 0,
+            sequence_id,
+            trace_id: None,
+            idempotency_key: None,
+            priority: Priority::Normal,
+            timeout_ms: 0,
+            payload,
+        }
+        .into()
+    }
+
+    fn response(flags: u8, sequence_id: u32) -> LoquiFrame {
+        Response {
+            flags,
+            sequence_id,
+            trace_id: None,
+            payload: b"ok".to_vec(),
+        }
+        .into()
+    }
+
+    fn payload(frame: &LoquiFrame) -> &[u8] {
+        match frame {
+            LoquiFrame::Request(request) => &request.payload,
+            LoquiFrame::Response(response) => &response.payload,
+            frame => panic!("not a data frame. {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn it_selects_the_encoding_by_id() {
+        let mut encoding_ids = EncodingIds::new("msgpack", vec!["json"]);
+        let mut frame = request(1, vec![1, 7]);
+        assert_eq!(encoding_ids.strip(&mut frame).unwrap(), Some("json"));
+        assert_eq!(payload(&frame), &[7]);
+
+        let mut frame = request(2, vec![0]);
+        assert_eq!(encoding_ids.strip(&mut frame).unwrap(), Some("msgpack"));
+        assert_eq!(payload(&frame), &[] as &[u8]);
+    }
+
+    #[test]
+    fn it_rejects_unknown_ids() {
+        let mut encoding_ids = EncodingIds::new("msgpack", vec!["json"]);
+        for frame_payload in [vec![2], vec![]] {
+            let error = encoding_ids
+                .strip(&mut request(1, frame_payload))
+                .unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<LoquiError>(),
+                Some(LoquiError::UnknownEncodingId { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn it_answers_with_the_encoding_of_the_request() {
+        let mut encoding_ids = EncodingIds::new("msgpack", vec!["json"]);
+        encoding_ids.strip(&mut request(1, vec![1])).unwrap();
+
+        let mut item = response(Flags::Streaming as u8, 1);
+        encoding_ids.tag(&mut item, "msgpack").unwrap();
+        assert_eq!(payload(&item), b"\x01ok");
+        let mut end = response(Flags::Streaming as u8 | Flags::StreamEnd as u8, 1);
+        encoding_ids.tag(&mut end, "msgpack").unwrap();
+        assert_eq!(payload(&end), b"\x01ok");
+        assert_eq!(encoding_ids.request_encoding(1), None);
+
+        let mut frame = request(2, b"hi".to_vec());
+        encoding_ids.tag(&mut frame, "json").unwrap();
+        assert_eq!(payload(&frame), b"\x01hi");
+        assert!(encoding_ids.tag(&mut frame, "cbor").is_err());
+    }
+
+    #[test]
+    fn it_forgets_requests_answered_with_an_error() {
+        let mut encoding_ids = EncodingIds::new("msgpack", vec!["json"]);
+        encoding_ids.strip(&mut request(1, vec![1])).unwrap();
+        let mut error = ErrorFrame {
+            flags: 0,
+            sequence_id: 1,
+            code: 0,
+            payload: vec![],
+        }
+        .into();
+        encoding_ids.tag(&mut error, "msgpack").unwrap();
+        assert_eq!(encoding_ids.request_encoding(1), None);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::LoquiError;
+use failure::Error;
+
+/// Splits an encoding into its name and schema version, e.g. `json@2` is `("json", Some(2))`.
+/// An encoding without a numeric version is returned whole, e.g. `("json", None)`.
+pub fn split_encoding_version(encoding: &str) -> (&str, Option<u32>) {
+    if let Some(index) = encoding.rfind('@') {
+        if let Ok(version) = encoding[index + 1..].parse() {
+            return (&encoding[..index], Some(version));
+        }
+    }
+    (encoding, None)
+}
+
+/// Picks an encoding from those offered by the client.
+///
+/// Encodings are considered by name in the client's order of preference. For the first name
+/// the server also supports, the highest schema version offered by both sides is chosen.
+/// Fails with `LoquiError::NoCommonEncodingVersion` if the names overlap but none of the
+/// versions do and with `LoquiError::NoCommonEncoding` if no names overlap.
+pub fn negotiate_encoding(
+    client_encodings: &[String],
+    supported_encodings: &'static [&'static str],
+) -> Result<&'static str, Error> {
+    let mut common_name = false;
+    for client_encoding in client_encodings {
+        let (name, _version) = split_encoding_version(client_encoding);
+        let mut same_name = supported_encodings
+            .iter()
+            .filter(|supported_encoding| split_encoding_version(supported_encoding).0 == name)
+            .peekable();
+        if same_name.peek().is_none() {
+            continue;
+        }
+        common_name = true;
+        let best = same_name
+            .filter(|supported_encoding| {
+                client_encodings
+                    .iter()
+                    .any(|client_encoding| client_encoding == **supported_encoding)
+            })
+            .max_by_key(|supported_encoding| split_encoding_version(supported_encoding).1);
+        if let Some(encoding) = best {
+            return Ok(encoding);
+        }
+    }
+    if common_name {
+        Err(LoquiError::NoCommonEncodingVersion.into())
+    } else {
+        Err(LoquiError::NoCommonEncoding {
+            offered: client_encodings.to_vec(),
+            supported: supported_encodings.to_vec(),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(encodings: &[&str]) -> Vec<String> {
+        encodings
+            .iter()
+            .map(|encoding| encoding.to_string())
+            .collect()
+    }
+
+    fn error(result: Result<&'static str, Error>) -> LoquiError {
+        result.unwrap_err().downcast::<LoquiError>().unwrap()
+    }
+
+    #[test]
+    fn it_splits_versions() {
+        assert_eq!(split_encoding_version("json@2"), ("json", Some(2)));
+        assert_eq!(split_encoding_version("json"), ("json", None));
+        assert_eq!(split_encoding_version("json@beta"), ("json@beta", None));
+    }
+
+    #[test]
+    fn it_picks_the_highest_common_version() {
+        let supported = &["json@1", "json@2", "json@3"];
+        let encoding = negotiate_encoding(&offer(&["json@1", "json@2", "json@4"]), supported);
+        assert_eq!(encoding.unwrap(), "json@2");
+    }
+
+    #[test]
+    fn it_prefers_the_clients_first_encoding() {
+        let supported = &["msgpack", "json@1", "json@2"];
+        let encoding = negotiate_encoding(&offer(&["bincode", "json@2", "msgpack"]), supported);
+        assert_eq!(encoding.unwrap(), "json@2");
+    }
+
+    #[test]
+    fn it_fails_without_a_common_version() {
+        let supported = &["json@3"];
+        match error(negotiate_encoding(&offer(&["json@1", "json@2"]), supported)) {
+            LoquiError::NoCommonEncodingVersion => {}
+            error => panic!("unexpected error. error={:?}", error),
+        }
+    }
+
+    #[test]
+    fn it_fails_without_a_common_encoding() {
+        let supported = &["json@1"];
+        match error(negotiate_encoding(&offer(&["msgpack"]), supported)) {
+            LoquiError::NoCommonEncoding { offered, supported } => {
+                assert_eq!(offered, vec!["msgpack"]);
+                assert_eq!(supported, vec!["json@1"]);
+            }
+            error => panic!("unexpected error. error={:?}", error),
+        }
+    }
+}
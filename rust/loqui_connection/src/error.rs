@@ -6,16 +6,47 @@ use loqui_protocol::upgrade::UpgradeFrame;
 pub enum LoquiError {
     #[fail(display = "TCP Connection closed.")]
     TcpStreamClosed,
-    #[fail(display = "Connection close requested.")]
-    ConnectionCloseRequested,
+    #[fail(
+        display = "Socket write failed. bytes_written={} error={}",
+        bytes_written, source
+    )]
+    SocketWrite {
+        #[fail(cause)]
+        source: std::io::Error,
+        /// The bytes written to the socket before the failure.
+        bytes_written: u64,
+    },
+    #[fail(
+        display = "Socket read failed. bytes_read={} error={}",
+        bytes_read, source
+    )]
+    SocketRead {
+        #[fail(cause)]
+        source: std::io::Error,
+        /// The bytes read from the socket before the failure.
+        bytes_read: u64,
+    },
+    /// Goes away with the reason, if any, in the payload of the `GoAway`.
+    #[fail(display = "Connection close requested. reason={:?}", reason)]
+    ConnectionCloseRequested { reason: Option<String> },
+    #[fail(display = "Shut down gracefully. code={:?}", code)]
+    ShutDown { code: GoAwayCode },
     #[fail(display = "Connection closed.")]
     ConnectionClosed,
     #[fail(display = "Invalid upgrade frame. frame={:?}", frame)]
     InvalidUpgradeFrame { frame: UpgradeFrame },
     #[fail(display = "Connection not ready.")]
     NotReady,
-    #[fail(display = "Told to go away. go_away={:?}", go_away)]
-    ToldToGoAway { go_away: GoAway },
+    /// `reason` is the human-readable reason in the payload of the `GoAway`, if any.
+    #[fail(
+        display = "Told to go away. code={:?} reason={:?} go_away={:?}",
+        code, reason, go_away
+    )]
+    ToldToGoAway {
+        code: GoAwayCode,
+        reason: Option<String>,
+        go_away: GoAway,
+    },
     #[fail(
         display = "Invalid Opcode. actual={:?} expected={:?}",
         actual, expected
@@ -26,30 +57,149 @@ pub enum LoquiError {
         expected, actual
     )]
     UnsupportedVersion { expected: u8, actual: u8 },
-    #[fail(display = "No common encoding.")]
-    NoCommonEncoding,
-    #[fail(display = "No common compression.")]
-    NoCommonCompression,
+    /// The server refused the peer during the handshake, see `RequestHandler::authorize`. Goes
+    /// away with the code.
+    #[fail(display = "Unauthorized. code={:?}", code)]
+    Unauthorized { code: GoAwayCode },
+    /// None of the encodings the client `offered` are `supported` by the server.
+    #[fail(
+        display = "No common encoding. offered={:?} supported={:?}",
+        offered, supported
+    )]
+    NoCommonEncoding {
+        offered: Vec<String>,
+        supported: Vec<&'static str>,
+    },
+    #[fail(display = "No common encoding schema version.")]
+    NoCommonEncodingVersion,
+    /// None of the compressions the client `offered` are `supported` by the server, which
+    /// requires one, see `RequestHandler::select_compression`.
+    #[fail(
+        display = "No common compression. offered={:?} supported={:?}",
+        offered, supported
+    )]
+    NoCommonCompression {
+        offered: Vec<String>,
+        supported: Vec<&'static str>,
+    },
     #[fail(display = "Invalid encoding.")]
     InvalidEncoding,
     #[fail(display = "Invalid compression.")]
     InvalidCompression,
+    #[fail(display = "Handshake timeout.")]
+    HandshakeTimeout,
+    /// The connection closed before the handshake completed, see `Connection::ready`.
+    #[fail(display = "Handshake failed. reason={}", reason)]
+    HandshakeFailed { reason: String },
     #[fail(display = "Ping timeout.")]
     PingTimeout,
+    /// A frame wasn't flushed within `TransportOptions::write_stall_timeout`.
+    #[fail(display = "Write stalled.")]
+    WriteStalled,
+    #[fail(
+        display = "Pong didn't echo the ping token. expected={:?} actual={:?}",
+        expected, actual
+    )]
+    PingTokenMismatch {
+        expected: Option<u32>,
+        actual: Option<u32>,
+    },
+    /// The CRC32 trailer of a data frame didn't match its payload, see
+    /// `TransportOptions::checksums`.
+    #[fail(
+        display = "Checksum mismatch. expected={:#010x} actual={:#010x}",
+        expected, actual
+    )]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// A data frame selected an encoding that wasn't negotiated, see
+    /// `TransportOptions::encoding_ids`.
+    #[fail(display = "Unknown encoding id. id={}", id)]
+    UnknownEncodingId { id: u8 },
+    /// The other side sent a frame this version doesn't know and flagged it `Flags::MANDATORY`.
+    #[fail(display = "Unknown mandatory frame. opcode={}", opcode)]
+    UnknownMandatoryFrame { opcode: u8 },
     #[fail(display = "Internal server error. error={:?}", error)]
-    InternalServerError { error: Error },
+    InternalServerError {
+        #[fail(cause)]
+        error: Error,
+    },
     #[fail(display = "Event receive error.")]
     EventReceiveError,
     #[fail(display = "Ready send failed.")]
     ReadySendFailed,
+    #[fail(display = "Request handler panicked.")]
+    HandlerPanicked,
+    #[fail(display = "Half closed. No more requests or pushes can be sent.")]
+    HalfClosed,
+    #[fail(display = "Peer half closed and every request was responded to.")]
+    PeerHalfClosed,
     #[fail(display = "Request timeout.")]
     RequestTimeout,
+    /// The request was cancelled by `Connection::cancel_all_inflight`.
+    #[fail(display = "Connection closing.")]
+    ConnectionClosing,
+    /// The frame was dropped, or the connection closed, before it was flushed to the socket.
+    #[fail(display = "Not flushed.")]
+    NotFlushed,
+    #[fail(display = "Renegotiating the encoding. No requests or pushes can be sent meanwhile.")]
+    Renegotiating,
+    #[fail(display = "Renegotiation is disabled.")]
+    RenegotiationDisabled,
+    #[fail(display = "Renegotiation refused. requested={}", requested)]
+    RenegotiationRefused { requested: &'static str },
+    /// The other side answered a request with an `Error` frame. Displays as its reason, the
+    /// payload read as a string. Decode the payload with `ClientError::decode`.
+    #[fail(display = "{}", reason)]
+    ErrorResponse {
+        code: u16,
+        reason: String,
+        sequence_id: u32,
+        payload: Vec<u8>,
+    },
     #[fail(display = "Reached max backoff elapsed time.")]
     ReachedMaxBackoffElapsedTime,
+    #[fail(display = "Invalid transport options. reason={}", reason)]
+    InvalidTransportOptions { reason: String },
+    /// A request had flags the connection sets itself, see `CONNECTION_REQUEST_FLAGS`.
+    #[fail(display = "Invalid request flags. flags={:#b}", flags)]
+    InvalidRequestFlags { flags: u8 },
     #[fail(display = "No client encoding.")]
     NoClientEncoding,
+    #[fail(
+        display = "Failed to decode payload. encoding={} reason={}",
+        encoding, reason
+    )]
+    DecodeFailed {
+        encoding: &'static str,
+        reason: String,
+    },
+    #[fail(
+        display = "Failed to encode payload. encoding={} reason={}",
+        encoding, reason
+    )]
+    EncodeFailed {
+        encoding: &'static str,
+        reason: String,
+    },
+    #[fail(
+        display = "Failed to compress payload. compression={} reason={}",
+        compression, reason
+    )]
+    CompressFailed {
+        compression: &'static str,
+        reason: String,
+    },
+    #[fail(
+        display = "Failed to decompress payload. compression={} reason={}",
+        compression, reason
+    )]
+    DecompressFailed {
+        compression: &'static str,
+        reason: String,
+    },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LoquiErrorCode {
     // Normal is sent when the connection is closing cleanly.
     Normal = 0,
@@ -67,20 +217,257 @@ pub enum LoquiErrorCode {
     PingTimeout = 6,
     // InternalServerError is sent when a single request dies due to an error.
     InternalServerError = 7,
+    // RequestTimeout is sent when a single request takes longer than the handler timeout.
+    RequestTimeout = 8,
+    // ServiceUnavailable is sent when a request is rejected before it is handled, e.g. overload.
+    ServiceUnavailable = 9,
+    // NoCommonEncodingVersion is sent when encodings are common but none of their schema versions.
+    NoCommonEncodingVersion = 10,
+    // PayloadTooLarge is sent when a frame declares a payload larger than the max payload size.
+    PayloadTooLarge = 11,
+    // ProtocolViolation is sent when a frame breaks the protocol but the connection stays open.
+    ProtocolViolation = 12,
+    // Shutdown is sent when the connection closes because the sender is shutting down.
+    Shutdown = 13,
+    // BadRequest is sent when the payload of a request can't be decoded with the encoding.
+    BadRequest = 14,
+    // RateLimited is sent when a request is rejected because its opcode is over its rate limit.
+    RateLimited = 15,
+    // Unauthorized is sent when the server refuses a client during the handshake, e.g. a bad token.
+    Unauthorized = 16,
+    // NoCommonCompression is sent when the server requires a compression the client doesn't offer.
+    NoCommonCompression = 17,
+}
+
+/// Why the other side went away, decoded from the code of a `GoAway` frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GoAwayCode {
+    /// The connection is closing cleanly.
+    Normal,
+    /// A frame broke the protocol, e.g. it had an opcode that can't be handled.
+    ProtocolError,
+    UnsupportedVersion,
+    NoCommonEncoding,
+    NoCommonEncodingVersion,
+    /// The server requires a compression the client doesn't offer.
+    NoCommonCompression,
+    InvalidEncoding,
+    InvalidCompression,
+    /// A pong wasn't received in time.
+    PingTimeout,
+    /// The connection died due to an error on the other side.
+    InternalError,
+    /// A frame declared a payload larger than the max payload size.
+    PayloadTooLarge,
+    /// The other side is shutting down, e.g. to be restarted.
+    Shutdown,
+    /// The server refused the client during the handshake, e.g. because of its auth token.
+    Unauthorized,
+    /// A code this version doesn't know about.
+    Unknown(u16),
+}
+
+impl GoAwayCode {
+    /// Whether the other side went away on purpose, e.g. to restart, rather than due to an
+    /// error.
+    pub fn is_graceful(self) -> bool {
+        matches!(self, GoAwayCode::Normal | GoAwayCode::Shutdown)
+    }
+}
+
+impl From<u16> for GoAwayCode {
+    fn from(code: u16) -> GoAwayCode {
+        match code {
+            0 => GoAwayCode::Normal,
+            1 => GoAwayCode::ProtocolError,
+            2 => GoAwayCode::UnsupportedVersion,
+            3 => GoAwayCode::NoCommonEncoding,
+            4 => GoAwayCode::InvalidEncoding,
+            5 => GoAwayCode::InvalidCompression,
+            6 => GoAwayCode::PingTimeout,
+            7 => GoAwayCode::InternalError,
+            10 => GoAwayCode::NoCommonEncodingVersion,
+            11 => GoAwayCode::PayloadTooLarge,
+            13 => GoAwayCode::Shutdown,
+            16 => GoAwayCode::Unauthorized,
+            17 => GoAwayCode::NoCommonCompression,
+            code => GoAwayCode::Unknown(code),
+        }
+    }
+}
+
+impl From<GoAwayCode> for u16 {
+    fn from(code: GoAwayCode) -> u16 {
+        match code {
+            GoAwayCode::Normal => LoquiErrorCode::Normal as u16,
+            GoAwayCode::ProtocolError => LoquiErrorCode::InvalidOpcode as u16,
+            GoAwayCode::UnsupportedVersion => LoquiErrorCode::UnsupportedVersion as u16,
+            GoAwayCode::NoCommonEncoding => LoquiErrorCode::NoCommonEncoding as u16,
+            GoAwayCode::InvalidEncoding => LoquiErrorCode::InvalidEncoding as u16,
+            GoAwayCode::InvalidCompression => LoquiErrorCode::InvalidCompression as u16,
+            GoAwayCode::PingTimeout => LoquiErrorCode::PingTimeout as u16,
+            GoAwayCode::InternalError => LoquiErrorCode::InternalServerError as u16,
+            GoAwayCode::NoCommonEncodingVersion => LoquiErrorCode::NoCommonEncodingVersion as u16,
+            GoAwayCode::PayloadTooLarge => LoquiErrorCode::PayloadTooLarge as u16,
+            GoAwayCode::Shutdown => LoquiErrorCode::Shutdown as u16,
+            GoAwayCode::Unauthorized => LoquiErrorCode::Unauthorized as u16,
+            GoAwayCode::NoCommonCompression => LoquiErrorCode::NoCommonCompression as u16,
+            GoAwayCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl LoquiErrorCode {
+    /// The code sent in an `Error` or `GoAway` frame. `None` if this version doesn't know it.
+    pub fn from_u16(code: u16) -> Option<LoquiErrorCode> {
+        let code = match code {
+            0 => LoquiErrorCode::Normal,
+            1 => LoquiErrorCode::InvalidOpcode,
+            2 => LoquiErrorCode::UnsupportedVersion,
+            3 => LoquiErrorCode::NoCommonEncoding,
+            4 => LoquiErrorCode::InvalidEncoding,
+            5 => LoquiErrorCode::InvalidCompression,
+            6 => LoquiErrorCode::PingTimeout,
+            7 => LoquiErrorCode::InternalServerError,
+            8 => LoquiErrorCode::RequestTimeout,
+            9 => LoquiErrorCode::ServiceUnavailable,
+            10 => LoquiErrorCode::NoCommonEncodingVersion,
+            11 => LoquiErrorCode::PayloadTooLarge,
+            12 => LoquiErrorCode::ProtocolViolation,
+            13 => LoquiErrorCode::Shutdown,
+            14 => LoquiErrorCode::BadRequest,
+            15 => LoquiErrorCode::RateLimited,
+            16 => LoquiErrorCode::Unauthorized,
+            17 => LoquiErrorCode::NoCommonCompression,
+            _ => return None,
+        };
+        Some(code)
+    }
+}
+
+impl From<LoquiErrorCode> for GoAwayCode {
+    fn from(code: LoquiErrorCode) -> GoAwayCode {
+        match code {
+            LoquiErrorCode::Normal => GoAwayCode::Normal,
+            LoquiErrorCode::InvalidOpcode | LoquiErrorCode::ProtocolViolation => {
+                GoAwayCode::ProtocolError
+            }
+            LoquiErrorCode::UnsupportedVersion => GoAwayCode::UnsupportedVersion,
+            LoquiErrorCode::NoCommonEncoding => GoAwayCode::NoCommonEncoding,
+            LoquiErrorCode::NoCommonEncodingVersion => GoAwayCode::NoCommonEncodingVersion,
+            LoquiErrorCode::InvalidEncoding => GoAwayCode::InvalidEncoding,
+            LoquiErrorCode::InvalidCompression => GoAwayCode::InvalidCompression,
+            LoquiErrorCode::PingTimeout => GoAwayCode::PingTimeout,
+            LoquiErrorCode::PayloadTooLarge => GoAwayCode::PayloadTooLarge,
+            LoquiErrorCode::Shutdown => GoAwayCode::Shutdown,
+            LoquiErrorCode::Unauthorized => GoAwayCode::Unauthorized,
+            LoquiErrorCode::NoCommonCompression => GoAwayCode::NoCommonCompression,
+            // Errors of a single request only close the connection when something went wrong.
+            LoquiErrorCode::InternalServerError
+            | LoquiErrorCode::RequestTimeout
+            | LoquiErrorCode::ServiceUnavailable
+            | LoquiErrorCode::BadRequest
+            | LoquiErrorCode::RateLimited => GoAwayCode::InternalError,
+        }
+    }
 }
 
 impl LoquiError {
+    /// The other side sent a `GoAway`.
+    pub fn told_to_go_away(go_away: GoAway) -> LoquiError {
+        LoquiError::ToldToGoAway {
+            code: go_away.code.into(),
+            reason: go_away.reason().map(str::to_string),
+            go_away,
+        }
+    }
+
+    /// Fails a request before it is sent, like the other side rejects one it can't serve, so
+    /// retries tell the two apart no more than the caller needs to. It never got a `sequence_id`.
+    pub fn service_unavailable(reason: &str) -> LoquiError {
+        LoquiError::ErrorResponse {
+            code: LoquiErrorCode::ServiceUnavailable as u16,
+            reason: reason.to_string(),
+            sequence_id: 0,
+            payload: vec![],
+        }
+    }
+
     pub(crate) fn code(&self) -> LoquiErrorCode {
         match self {
-            LoquiError::InvalidOpcode { .. } => LoquiErrorCode::InvalidOpcode,
+            LoquiError::InvalidOpcode { .. } | LoquiError::UnknownMandatoryFrame { .. } => {
+                LoquiErrorCode::InvalidOpcode
+            }
             LoquiError::UnsupportedVersion { .. } => LoquiErrorCode::UnsupportedVersion,
             LoquiError::NoCommonEncoding { .. } => LoquiErrorCode::NoCommonEncoding,
-            LoquiError::InvalidEncoding => LoquiErrorCode::InvalidEncoding,
+            LoquiError::NoCommonCompression { .. } => LoquiErrorCode::NoCommonCompression,
+            LoquiError::NoCommonEncodingVersion => LoquiErrorCode::NoCommonEncodingVersion,
+            LoquiError::InvalidEncoding | LoquiError::UnknownEncodingId { .. } => {
+                LoquiErrorCode::InvalidEncoding
+            }
             LoquiError::InvalidCompression => LoquiErrorCode::InvalidCompression,
             LoquiError::PingTimeout => LoquiErrorCode::PingTimeout,
+            LoquiError::PingTokenMismatch { .. } | LoquiError::ChecksumMismatch { .. } => {
+                LoquiErrorCode::InvalidOpcode
+            }
+            LoquiError::RequestTimeout => LoquiErrorCode::RequestTimeout,
+            LoquiError::DecodeFailed { .. } => LoquiErrorCode::BadRequest,
             // Normal close.
-            LoquiError::ConnectionCloseRequested => LoquiErrorCode::Normal,
+            LoquiError::ConnectionCloseRequested { .. }
+            | LoquiError::PeerHalfClosed
+            | LoquiError::ShutDown { .. } => LoquiErrorCode::Normal,
             _ => LoquiErrorCode::InternalServerError,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_go_away_codes() {
+        for code in 0..=20 {
+            assert_eq!(u16::from(GoAwayCode::from(code)), code);
+        }
+    }
+
+    #[test]
+    fn it_decodes_unknown_go_away_codes() {
+        assert_eq!(GoAwayCode::from(7), GoAwayCode::InternalError);
+        assert_eq!(GoAwayCode::from(13), GoAwayCode::Shutdown);
+        assert_eq!(GoAwayCode::from(16), GoAwayCode::Unauthorized);
+        assert_eq!(GoAwayCode::from(17), GoAwayCode::NoCommonCompression);
+        assert_eq!(GoAwayCode::from(8), GoAwayCode::Unknown(8));
+        assert_eq!(GoAwayCode::from(999), GoAwayCode::Unknown(999));
+    }
+
+    #[test]
+    fn it_round_trips_error_codes() {
+        for code in 0..=17 {
+            assert_eq!(LoquiErrorCode::from_u16(code).unwrap() as u16, code);
+        }
+        assert_eq!(LoquiErrorCode::from_u16(18), None);
+    }
+
+    #[test]
+    fn it_tells_graceful_go_aways_from_errors() {
+        assert!(GoAwayCode::Normal.is_graceful());
+        assert!(GoAwayCode::Shutdown.is_graceful());
+        assert!(!GoAwayCode::PingTimeout.is_graceful());
+        assert!(!GoAwayCode::Unknown(999).is_graceful());
+    }
+
+    #[test]
+    fn it_keeps_the_cause_of_internal_errors() {
+        let error = LoquiError::InternalServerError {
+            error: LoquiError::RequestTimeout.into(),
+        };
+        let cause = error.cause().unwrap();
+        assert_eq!(cause.to_string(), "Request timeout.");
+        assert!(matches!(
+            cause.downcast_ref::<LoquiError>(),
+            Some(LoquiError::RequestTimeout)
+        ));
+    }
+}
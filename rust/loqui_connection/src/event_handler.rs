@@ -1,22 +1,168 @@
+use super::clock::Clock;
+use super::close_reason::CloseReason;
+use super::compressor::Compressor;
 use super::connection::Event;
-use super::error::LoquiError;
-use super::handler::{DelegatedFrame, Handler};
+use super::connection_tag::ConnectionTag;
+use super::dedup_cache::DedupCache;
+use super::encoding_ids::EncodingIds;
+use super::error::{GoAwayCode, LoquiError};
+use super::handler::{
+    ClockSkew, ConnectionHealth, ConnectionState, DelegatedFrame, FrameOutcome, Handler,
+    ResponseFuture, ResponseStream, Role, SendDecision, SubscribeOutcome, TimeoutAction,
+};
 use super::id_sequence::IdSequence;
+use super::lifecycle::LifecycleSender;
+use super::metrics::{Metrics, RequestTiming};
+use super::pending_batches::PendingBatches;
+use super::rate_limiter::RateLimiter;
+use super::read_gate::ReadGate;
+use super::request_queue::RequestQueue;
 use super::sender::Sender;
+use super::spans::{self, Span};
+use super::spawner::Spawn;
+use super::stats::StatsCounters;
+use crate::transport_options::{ProtocolViolationPolicy, UnexpectedFramePolicy};
 use crate::LoquiErrorCode;
 use failure::Error;
-use loqui_protocol::frames::{Error as ErrorFrame, LoquiFrame, Ping, Pong, Response};
+use futures::channel::oneshot;
+use futures::future::{abortable, AbortHandle, Aborted, FutureExt};
+use futures::stream::StreamExt;
+use loqui_protocol::frames::{
+    BatchEntry, Cancel, Error as ErrorFrame, GoAway, HealthCheck, HealthStatus, LoquiFrame, Ping,
+    Pong, PongTimestamps, Priority, Push, PushAck, Renegotiate, Request, RequestBatch, Response,
+    ResponseBatch, Subscribe, UnknownFrame, Unsubscribe, WindowUpdate,
+};
+use loqui_protocol::{
+    is_compressed, is_flow_controlled, is_half_closed, is_mandatory, is_no_compress, is_streaming,
+    Flags,
+};
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 use tokio::task::spawn;
+use tokio::time::{delay_for, timeout, Instant};
 
 /// Main handler of connection `Event`s.
 pub struct EventHandler<H: Handler> {
     handler: H,
-    pong_received: bool,
+    /// Each `Ping` that is waiting for a `Pong`, keyed by `sequence_id`.
+    in_flight_pings: HashMap<u32, InFlightPing>,
     id_sequence: IdSequence,
     self_sender: Sender<H::InternalEvent>,
     encoding: &'static str,
+    /// Compresses data frame payloads when a compression was negotiated.
+    compressor: Option<Arc<dyn Compressor>>,
+    /// The number of delegated futures that haven't completed yet.
+    in_flight_requests: usize,
+    /// The sum of the payload sizes of in flight requests.
+    in_flight_bytes: usize,
+    /// The payload size of each in flight request, keyed by `sequence_id`.
+    request_bytes: HashMap<u32, usize>,
+    /// Set once the other side told us to go away. New requests are ignored while the in flight
+    /// requests drain.
+    go_away: Option<GoAway>,
+    /// Set once we told the other side to go away. Closes once the in flight requests drained.
+    shutdown: Option<GoAwayCode>,
+    /// Set once we went away because of `TransportOptions::idle_timeout`.
+    went_idle: bool,
+    metrics: Arc<dyn Metrics>,
+    clock: Arc<dyn Clock>,
+    spawner: Arc<dyn Spawn>,
+    /// Set when `TransportOptions::dedup_ttl` is.
+    dedup_cache: Option<DedupCache>,
+    /// When the connection became ready, for the uptime of `HealthStatus`es.
+    ready_at: Instant,
+    /// Each `HealthCheck` that is waiting for its `HealthStatus`, keyed by `sequence_id`.
+    health_checks: HashMap<u32, oneshot::Sender<ConnectionHealth>>,
+    /// When the last frame was received from the socket.
+    last_activity: Instant,
+    /// When the last frame other than a `Ping` or `Pong` was sent or received. Keepalives flow on
+    /// idle connections too, so they don't count towards `TransportOptions::idle_timeout`.
+    last_data_activity: Instant,
+    /// Set while the outbound queue is above the high water mark and hasn't drained to the low
+    /// water mark yet. Requests are rejected meanwhile.
+    overloaded: bool,
+    /// When the outbound queue got at least `slow_consumer_depth` deep, while it stays there.
+    slow_consumer_since: Option<Instant>,
+    state: ConnectionState,
+    /// Aborts the futures of in flight requests, keyed by `sequence_id`, when they're cancelled.
+    abort_handles: HashMap<u32, AbortHandle>,
+    /// The credits of each flow controlled stream, keyed by `sequence_id`. The stream waits for a
+    /// credit before sending each response.
+    stream_windows: HashMap<u32, Arc<Semaphore>>,
+    /// Aborts the publishing of the subscriptions the other side made, keyed by subscription id,
+    /// when it unsubscribes.
+    subscriptions: HashMap<u32, AbortHandle>,
+    /// Every event is handled within it, so logs carry the connection's id and labels.
+    connection_span: Span,
+    /// The responses to received `RequestBatch`es that are still being computed.
+    pending_batches: PendingBatches,
+    /// Throttles received frames, see `TransportOptions::rate_limits`.
+    rate_limiter: RateLimiter,
+    /// Requests waiting for a slot, see `TransportOptions::request_queue_depth`. Only holds
+    /// requests while at `max_concurrent_requests`.
+    request_queue: RequestQueue,
+    /// The `sequence_id` and encoding of the `Renegotiate` we sent, until it is answered.
+    renegotiating: Option<(u32, &'static str)>,
+    /// The `sequence_id` and encoding of a `Renegotiate` we accepted. The encoding is switched, and
+    /// the `Renegotiate` answered, once the requests in flight with the old encoding drained.
+    accepted_encoding: Option<(u32, &'static str)>,
+    /// Told once the frame of the event being handled was flushed. Taken by the connection after
+    /// every event.
+    flush_waiter: Option<oneshot::Sender<()>>,
+    /// Set once both sides agreed to timestamp pings, see `TransportOptions::ping_timestamps`.
+    ping_timestamps: bool,
+    /// Set once both sides agreed on checksums, see `TransportOptions::checksums`.
+    checksums: bool,
+    /// Set once both sides agreed on encoding ids, see `TransportOptions::encoding_ids`.
+    encoding_ids: Option<EncodingIds>,
+    /// The encoding of the data frame being received, when encoding ids were negotiated.
+    received_encoding: Option<&'static str>,
+    /// The encoding of the request or push about to be sent, if it isn't the one in use, see
+    /// `Handler::internal_event_encoding`.
+    send_encoding: Option<&'static str>,
+    /// Set once we told the other side we won't send any more requests or pushes.
+    local_half_closed: bool,
+    /// Set once the other side told us it won't send any more requests or pushes.
+    remote_half_closed: bool,
+    /// Paused by `Connection::pause_reading`, shared with the read loop.
+    read_gate: Arc<ReadGate>,
+    /// Read by `Connection::stats`.
+    stats: Arc<StatsCounters>,
+    /// Tells the handles of the connection about state changes, see `Connection::ready`.
+    lifecycle: LifecycleSender,
+    /// When the last `Pong` was sent, see `TransportOptions::pong_coalescing_window`.
+    last_pong_at: Option<Instant>,
+    /// The pong for the latest ping received within the coalescing window, sent once it's over.
+    coalesced_pong: Option<Pong>,
 }
 
+/// A `Ping` that was sent and is waiting for its `Pong`.
+struct InFlightPing {
+    sent_at: Instant,
+    /// The token the `Pong` must echo.
+    token: Option<u32>,
+    /// Set once `Handler::on_ping_timeout` extended it.
+    extended: bool,
+}
+
+impl InFlightPing {
+    /// Whether the `Pong` is overdue. Without a `ping_timeout` every ping must be answered before
+    /// the next tick. An extended ping gets another `ping_timeout`.
+    fn timed_out(&self, now: Instant, ping_timeout: Option<Duration>) -> bool {
+        match ping_timeout {
+            Some(ping_timeout) if self.extended => now - self.sent_at >= ping_timeout * 2,
+            Some(ping_timeout) => now - self.sent_at >= ping_timeout,
+            None => true,
+        }
+    }
+}
+
+/// The size of the CRC32 trailer of a data frame's payload.
+const CHECKSUM_SIZE: usize = 4;
+
 /// Standard return type for handler functions.
 ///
 /// Event handler functions return an optional `LoquiFrame` that will
@@ -24,123 +170,4632 @@ pub struct EventHandler<H: Handler> {
 type MaybeFrameResult = Result<Option<LoquiFrame>, Error>;
 
 impl<H: Handler> EventHandler<H> {
-    pub fn new(self_sender: Sender<H::InternalEvent>, handler: H, encoding: &'static str) -> Self {
+    pub fn new(
+        self_sender: Sender<H::InternalEvent>,
+        handler: H,
+        encoding: &'static str,
+        compressor: Option<Arc<dyn Compressor>>,
+        metrics: Arc<dyn Metrics>,
+    ) -> Self {
+        let clock = handler.transport_options().clock.clone();
+        let spawner = handler.transport_options().spawner.clone();
+        let dedup_cache = handler
+            .transport_options()
+            .dedup_ttl
+            .map(|ttl| DedupCache::new(ttl, handler.transport_options().dedup_capacity));
+        let rate_limiter = RateLimiter::new(&handler.transport_options().rate_limits, clock.now());
         Self {
             handler,
-            pong_received: true,
+            in_flight_pings: HashMap::new(),
             id_sequence: IdSequence::default(),
             self_sender,
             encoding,
+            compressor,
+            in_flight_requests: 0,
+            in_flight_bytes: 0,
+            request_bytes: HashMap::new(),
+            go_away: None,
+            shutdown: None,
+            went_idle: false,
+            metrics,
+            ready_at: clock.now(),
+            health_checks: HashMap::new(),
+            last_activity: clock.now(),
+            last_data_activity: clock.now(),
+            clock,
+            spawner,
+            dedup_cache,
+            overloaded: false,
+            slow_consumer_since: None,
+            abort_handles: HashMap::new(),
+            state: ConnectionState::Connecting,
+            stream_windows: HashMap::new(),
+            subscriptions: HashMap::new(),
+            connection_span: spans::connection_span(&ConnectionTag::default()),
+            pending_batches: PendingBatches::default(),
+            rate_limiter,
+            request_queue: RequestQueue::default(),
+            renegotiating: None,
+            accepted_encoding: None,
+            flush_waiter: None,
+            ping_timestamps: false,
+            checksums: false,
+            encoding_ids: None,
+            received_encoding: None,
+            send_encoding: None,
+            local_half_closed: false,
+            remote_half_closed: false,
+            read_gate: Arc::new(ReadGate::default()),
+            stats: Arc::new(StatsCounters::default()),
+            lifecycle: LifecycleSender::default(),
+            last_pong_at: None,
+            coalesced_pong: None,
+        }
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// The waiter to tell once the frame just returned by `handle_event` was flushed. `None` if
+    /// the event didn't ask, or its frame was delayed.
+    pub fn take_flush_waiter(&mut self) -> Option<oneshot::Sender<()>> {
+        self.flush_waiter.take()
+    }
+
+    /// Replaces the id sequence before any ids were allocated.
+    pub fn seed_id_sequence(&mut self, id_sequence: IdSequence) {
+        self.id_sequence = id_sequence;
+    }
+
+    /// Timestamps the pings sent from now on, once the handshake settled on it.
+    pub fn set_ping_timestamps(&mut self, ping_timestamps: bool) {
+        self.ping_timestamps = ping_timestamps;
+    }
+
+    /// Tags the logs of the connection from now on.
+    pub fn set_connection(&mut self, connection: ConnectionTag) {
+        self.connection_span = spans::connection_span(&connection);
+    }
+
+    /// Checksums data frames from now on, once the handshake settled on it.
+    pub fn set_checksums(&mut self, checksums: bool) {
+        self.checksums = checksums;
+    }
+
+    /// Tags data frames with encoding ids from now on, once the handshake settled on the other
+    /// encodings they may select.
+    pub fn set_encoding_ids(&mut self, encodings: Option<Vec<&'static str>>) {
+        self.encoding_ids = encodings.map(|encodings| EncodingIds::new(self.encoding, encodings));
+    }
+
+    /// Shares the gate the read loop polls the socket through.
+    pub fn set_read_gate(&mut self, read_gate: Arc<ReadGate>) {
+        self.read_gate = read_gate;
+    }
+
+    /// Shares the counters `Connection::stats` reads.
+    pub fn set_stats(&mut self, stats: Arc<StatsCounters>) {
+        self.stats = stats;
+    }
+
+    /// Shares the state changes `Connection::ready` waits for.
+    pub fn set_lifecycle(&mut self, lifecycle: LifecycleSender) {
+        self.lifecycle = lifecycle;
+    }
+
+    /// Moves to a new state, notifying the handler if it changed.
+    pub fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            let old = self.state;
+            self.state = state;
+            debug!("Connection state changed. old={:?} new={:?}", old, state);
+            self.handler.on_state_change(old, state);
+            self.lifecycle.set_state(state);
         }
     }
 
+    /// Moves to `ConnectionState::Closed` and tells the handler why, from the error the connection
+    /// closed with, and which frames were never sent.
+    pub fn close(&mut self, error: &Error, unflushed: Vec<LoquiFrame>) {
+        self.set_state(ConnectionState::Closed);
+        for (_subscription_id, abort_handle) in self.subscriptions.drain() {
+            abort_handle.abort();
+        }
+        if !unflushed.is_empty() {
+            debug!(
+                "Closed with unflushed frames. unflushed={}",
+                unflushed.len()
+            );
+        }
+        let reason = match CloseReason::from(error) {
+            CloseReason::LocalClose if self.went_idle => CloseReason::IdleTimeout,
+            reason => reason,
+        };
+        debug!("Closed. reason={:?}", reason);
+        self.handler.on_close(reason, unflushed);
+    }
+
     /// High level event handler entry point. This is called by the connection whenever an
     /// event comes in.
     pub fn handle_event(&mut self, event: Event<H::InternalEvent>) -> MaybeFrameResult {
-        match event {
-            Event::Ping => self.send_ping(),
+        let connection_span = self.connection_span.clone();
+        let _connection = connection_span.enter();
+        let span = spans::event_span(&event);
+        let _entered = span.enter();
+        // Delayed frames already passed through `before_send` when they were delayed.
+        let delayed = matches!(event, Event::SendDelayed(..));
+        let result = match event {
+            Event::Ping => {
+                self.check_slow_consumer();
+                if self.is_idle() {
+                    debug!("Idle. Going away.");
+                    self.went_idle = true;
+                    self.handle_graceful_shutdown(GoAwayCode::Normal)
+                } else {
+                    self.send_ping()
+                }
+            }
             Event::SocketReceive(frame) => self.handle_frame(frame),
             Event::InternalEvent(internal_event) => self.handle_internal_event(internal_event),
-            Event::ResponseComplete(response) => self.handle_response_complete(response),
-            Event::Close => self.handle_close(),
+            Event::InternalEventFlushed(internal_event, flush_waiter) => {
+                self.flush_waiter = Some(flush_waiter);
+                self.handle_internal_event(internal_event)
+            }
+            Event::ResponseComplete(response, timing) => {
+                self.handle_response_complete(response, timing)
+            }
+            Event::Close(reason) => self.handle_close(reason),
+            Event::GracefulShutdown(code) => self.handle_graceful_shutdown(code),
+            Event::HalfClose => self.handle_half_close(),
+            Event::DrainTimeout => self.handle_drain_timeout(),
+            Event::RequestCancelled => self.handle_request_cancelled(),
+            Event::SendDelayed(frame, encoding, flush_waiter) => {
+                self.flush_waiter = flush_waiter;
+                self.send_encoding = encoding;
+                Ok(Some(frame))
+            }
+            Event::StreamItem(response) => Ok(Some(response.into())),
+            Event::Flushed(sequence_id) => self.handle_flushed(sequence_id),
+            Event::Renegotiate(encoding) => self.handle_renegotiate(encoding),
+            Event::HealthCheck(waiter) => self.handle_health_check(waiter),
+            Event::SubscriptionPush(push) => Ok(Some(push.into())),
+            Event::SubscriptionEnded(subscription_id) => {
+                self.handle_subscription_ended(subscription_id)
+            }
+            Event::PauseReading => {
+                debug!("Pausing reading.");
+                self.read_gate.pause();
+                Ok(None)
+            }
+            Event::ResumeReading => self.handle_resume_reading(),
+            Event::PongDue => Ok(self.coalesced_pong.take().map(|pong| self.send_pong(pong))),
+            Event::CancelAllInflight => self.handle_cancel_all_inflight(),
+        }
+        .map(|frame| frame.and_then(|frame| self.pending_batches.collect(frame)))
+        .map(|frame| match frame {
+            Some(frame) if !delayed => self.before_send(frame),
+            frame => frame,
+        })
+        .map(|frame| frame.map(|frame| self.observe_payload_size(frame)))
+        .and_then(|frame| frame.map(|frame| self.compress_frame(frame)).transpose())
+        .and_then(|frame| frame.map(|frame| self.tag_encoding_id(frame)).transpose())
+        .map(|frame| frame.map(|frame| self.append_checksum(frame)));
+        self.send_encoding = None;
+        match &result {
+            Ok(Some(frame)) => {
+                if !is_keepalive(frame) {
+                    self.last_data_activity = self.clock.now();
+                }
+                self.metrics.frame_sent(frame.opcode());
+                self.stats.frame_sent(frame.opcode())
+            }
+            Ok(None) => {}
+            Err(_error) => self.set_state(ConnectionState::Closed),
+        }
+        result
+    }
+
+    /// Lets the handler veto, delay or change a frame before it is sent. Returns the frame if it
+    /// should be sent right away.
+    fn before_send(&mut self, mut frame: LoquiFrame) -> Option<LoquiFrame> {
+        match self.handler.before_send(&mut frame) {
+            SendDecision::Send => Some(frame),
+            SendDecision::Drop => {
+                debug!("Dropping frame. opcode={}", frame.opcode());
+                if let LoquiFrame::Ping(ping) = &frame {
+                    self.in_flight_pings.remove(&ping.sequence_id);
+                }
+                None
+            }
+            SendDecision::Delay(delay) => {
+                let connection_sender = self.self_sender.clone();
+                let encoding = self.send_encoding.take();
+                let flush_waiter = self.flush_waiter.take();
+                spawn(async move {
+                    delay_for(delay).await;
+                    // It's okay to ignore this result. The connection closed.
+                    let _result = connection_sender.send_delayed(frame, encoding, flush_waiter);
+                });
+                None
+            }
+        }
+    }
+
+    /// Returns the error to close the connection with once either side told the other to go away
+    /// and all in flight and queued requests have been responded to.
+    pub fn drain_complete(&mut self) -> Option<Error> {
+        if self.in_flight_requests > 0 || !self.request_queue.is_empty() {
+            return None;
+        }
+        let error = match (&self.go_away, self.shutdown) {
+            (Some(go_away), _) => LoquiError::told_to_go_away(go_away.clone()),
+            (None, Some(code)) => LoquiError::ShutDown { code },
+            (None, None) => return None,
+        };
+        self.set_state(ConnectionState::Closed);
+        Some(error.into())
+    }
+
+    /// Whether we told the other side to go away, so it mustn't be told again when closing.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_some()
+    }
+
+    /// Returns the error to close the connection with once the other side half closed and all in
+    /// flight and queued requests have been responded to.
+    pub fn half_close_complete(&mut self) -> Option<Error> {
+        if !self.remote_half_closed || self.in_flight_requests > 0 || !self.request_queue.is_empty()
+        {
+            return None;
         }
+        self.set_state(ConnectionState::Closed);
+        Some(LoquiError::PeerHalfClosed.into())
     }
 
     /// Handles a request to ping the other side. Returns an `Error` if a `Pong` hasn't been
-    /// received since the last ping.
+    /// received in time for any in flight ping, unless the handler chose to wait or reading is
+    /// paused. Skips the ping if the connection isn't idle yet.
     fn send_ping(&mut self) -> MaybeFrameResult {
-        if self.pong_received {
-            let sequence_id = self.id_sequence.next();
-            let ping = Ping {
-                sequence_id,
-                flags: 0,
-            };
-            self.pong_received = false;
-            Ok(Some(ping.into()))
+        let now = self.clock.now();
+        let ping_timeout = self.handler.transport_options().ping_timeout;
+        // Pongs can't arrive while reading is paused.
+        let read_paused = self.read_gate.is_paused();
+        let timed_out: Vec<u32> = self
+            .in_flight_pings
+            .iter()
+            .filter(|(_sequence_id, ping)| !read_paused && ping.timed_out(now, ping_timeout))
+            .map(|(sequence_id, _ping)| *sequence_id)
+            .collect();
+        if !timed_out.is_empty() {
+            self.handle_ping_timeout(&timed_out)?;
+        }
+
+        if let Some(idle_ping_interval) = self.handler.transport_options().idle_ping_interval {
+            if now - self.last_activity < idle_ping_interval {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(self.make_ping(0)))
+    }
+
+    /// The timeout a request asked for, clamped to `TransportOptions::max_request_timeout`, or
+    /// `TransportOptions::handler_timeout` if it didn't ask for one.
+    fn handler_timeout(&self, timeout_ms: u32) -> Option<Duration> {
+        let transport_options = self.handler.transport_options();
+        if timeout_ms == 0 {
+            return transport_options.handler_timeout;
+        }
+        let timeout = Duration::from_millis(u64::from(timeout_ms));
+        Some(match transport_options.max_request_timeout {
+            Some(max_request_timeout) => timeout.min(max_request_timeout),
+            None => timeout,
+        })
+    }
+
+    /// Hands received frames on again. The pings in flight are forgotten, as their pongs may be
+    /// stuck behind the frames held while paused. Pongs of forgotten pings are ignored.
+    fn handle_resume_reading(&mut self) -> MaybeFrameResult {
+        debug!(
+            "Resuming reading. forgotten_pings={}",
+            self.in_flight_pings.len()
+        );
+        self.in_flight_pings.clear();
+        self.read_gate.resume();
+        Ok(None)
+    }
+
+    /// Asks the handler what to do about overdue pings. Fails with `LoquiError::PingTimeout` to
+    /// close the connection, including when a ping that was already extended is overdue again.
+    fn handle_ping_timeout(&mut self, timed_out: &[u32]) -> Result<(), Error> {
+        match self.handler.on_ping_timeout() {
+            TimeoutAction::Close => Err(LoquiError::PingTimeout.into()),
+            TimeoutAction::Extend => {
+                let pings: Vec<&mut InFlightPing> = self
+                    .in_flight_pings
+                    .iter_mut()
+                    .filter(|(sequence_id, _ping)| timed_out.contains(sequence_id))
+                    .map(|(_sequence_id, ping)| ping)
+                    .collect();
+                if pings.iter().any(|ping| ping.extended) {
+                    debug!("Ping timeout was already extended. Closing.");
+                    return Err(LoquiError::PingTimeout.into());
+                }
+                for ping in pings {
+                    ping.extended = true;
+                }
+                debug!("Extended ping timeout. sequence_ids={:?}", timed_out);
+                Ok(())
+            }
+            TimeoutAction::Continue => {
+                warn!("Ping timed out. Continuing. sequence_ids={:?}", timed_out);
+                for sequence_id in timed_out {
+                    self.in_flight_pings.remove(sequence_id);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The timestamp of a ping sent now, if pings are timestamped.
+    fn ping_sent_at(&self) -> Option<u64> {
+        if self.ping_timestamps {
+            Some(unix_micros(&*self.clock))
         } else {
-            Err(LoquiError::PingTimeout.into())
+            None
+        }
+    }
+
+    /// Allocates a `Ping` and starts waiting for its `Pong`.
+    fn make_ping(&mut self, flags: u8) -> LoquiFrame {
+        let sequence_id = self.id_sequence.next();
+        let token = self.handler.ping_token();
+        let in_flight_ping = InFlightPing {
+            sent_at: self.clock.now(),
+            token,
+            extended: false,
+        };
+        self.in_flight_pings.insert(sequence_id, in_flight_ping);
+        Ping {
+            sequence_id,
+            flags,
+            token,
+            sent_at: self.ping_sent_at(),
+        }
+        .into()
+    }
+
+    /// Tells the other side we won't send any more requests or pushes with a `Ping` flagged
+    /// `Flags::HalfClosed`. Responses keep flowing in until the other side goes away.
+    fn handle_half_close(&mut self) -> MaybeFrameResult {
+        if self.local_half_closed {
+            return Ok(None);
         }
+        debug!("Half closing.");
+        self.local_half_closed = true;
+        Ok(Some(self.make_ping(Flags::HalfClosed as u8)))
     }
 
     /// Handles a frame received from the socket. Delegates some frames to the `ConnectionHandler`.
     /// Optionally returns a `LoquiFrame` that will be sent back over the socket.
     fn handle_frame(&mut self, frame: LoquiFrame) -> MaybeFrameResult {
+        let span = spans::frame_span(&frame);
+        let _entered = span.enter();
+        self.metrics.frame_received(frame.opcode());
+        self.stats.frame_received(frame.opcode());
+        self.last_activity = self.clock.now();
+        if !is_keepalive(&frame) {
+            self.last_data_activity = self.last_activity;
+            if !self
+                .rate_limiter
+                .try_acquire(frame.opcode(), self.last_activity)
+            {
+                return Ok(self.handle_rate_limited(frame));
+            }
+        }
+        let peer_requests = self.handler.transport_options().peer_requests;
+        if is_unexpected(H::ROLE, &frame, peer_requests) {
+            return self.handle_unexpected_frame(frame);
+        }
+        let frame = self.verify_checksum(frame)?;
+        let frame = self.strip_encoding_id(frame)?;
+        let frame = self.observe_payload_size(self.decompress_frame(frame)?);
         match frame {
             LoquiFrame::Hello(_) | LoquiFrame::HelloAck(_) => self.handle_handshake_frame(frame),
             LoquiFrame::Ping(ping) => self.handle_ping_frame(ping),
             LoquiFrame::Pong(pong) => self.handle_pong_frame(pong),
             LoquiFrame::Request(request) => self.delegate_frame(request),
             LoquiFrame::Response(response) => self.delegate_frame(response),
-            LoquiFrame::Push(push) => self.delegate_frame(push),
-            LoquiFrame::GoAway(go_away) => Err(LoquiError::ToldToGoAway { go_away }.into()),
+            LoquiFrame::Push(push) => self.handle_push_frame(push),
+            LoquiFrame::GoAway(go_away) => self.handle_go_away_frame(go_away),
             LoquiFrame::Error(error) => self.delegate_frame(error),
+            LoquiFrame::Cancel(cancel) => self.handle_cancel_frame(cancel),
+            LoquiFrame::PushAck(push_ack) => self.handle_push_ack_frame(push_ack),
+            LoquiFrame::WindowUpdate(window_update) => {
+                self.handle_window_update_frame(window_update)
+            }
+            LoquiFrame::RequestBatch(request_batch) => {
+                self.handle_request_batch_frame(request_batch)
+            }
+            LoquiFrame::ResponseBatch(response_batch) => {
+                self.handle_response_batch_frame(response_batch)
+            }
+            LoquiFrame::Renegotiate(renegotiate) => self.handle_renegotiate_frame(renegotiate),
+            LoquiFrame::HealthCheck(health_check) => self.handle_health_check_frame(health_check),
+            LoquiFrame::HealthStatus(health_status) => {
+                self.handle_health_status_frame(health_status)
+            }
+            LoquiFrame::Subscribe(subscribe) => self.handle_subscribe_frame(subscribe),
+            LoquiFrame::Unsubscribe(unsubscribe) => self.handle_unsubscribe_frame(unsubscribe),
+            LoquiFrame::Unknown(unknown) => self.handle_unknown_frame(unknown),
+        }
+    }
+
+    /// Rejects a request over its rate limit. Other frames are dropped, except acked pushes when
+    /// `TransportOptions::reject_rate_limited_pushes` is set.
+    fn handle_rate_limited(&mut self, frame: LoquiFrame) -> Option<LoquiFrame> {
+        let sequence_id = match &frame {
+            LoquiFrame::Request(request) => Some(request.sequence_id),
+            LoquiFrame::Push(push)
+                if self.handler.transport_options().reject_rate_limited_pushes =>
+            {
+                push.sequence_id
+            }
+            _ => None,
+        };
+        match sequence_id {
+            Some(sequence_id) => {
+                debug!("Rate limited. Rejecting. sequence_id={}", sequence_id);
+                let error = ErrorFrame {
+                    flags: 0,
+                    sequence_id,
+                    code: LoquiErrorCode::RateLimited as u16,
+                    payload: b"Rate limited.".to_vec(),
+                };
+                Some(error.into())
+            }
+            None => {
+                debug!("Rate limited. Dropping. opcode={}", frame.opcode());
+                None
+            }
+        }
+    }
+
+    /// Reports the uncompressed payload size of a `Request`, `Response` or `Push`.
+    fn observe_payload_size(&self, frame: LoquiFrame) -> LoquiFrame {
+        match &frame {
+            LoquiFrame::Request(Request { payload, .. })
+            | LoquiFrame::Response(Response { payload, .. })
+            | LoquiFrame::Push(Push { payload, .. }) => self
+                .metrics
+                .observe_payload_size(frame.opcode(), payload.len()),
+            _ => {}
+        }
+        frame
+    }
+
+    /// Compresses the payload of a `Request`, `Response` or `Push` and sets its compressed flag.
+    /// Other frames, small payloads and frames flagged with `Flags::NO_COMPRESS` are sent as is.
+    fn compress_frame(&self, mut frame: LoquiFrame) -> Result<LoquiFrame, Error> {
+        let compression_min_bytes = self.handler.transport_options().compression_min_bytes;
+        let opcode = frame.opcode();
+        if let Some((flags, payload)) = data_payload(&mut frame) {
+            let compressor = match &self.compressor {
+                Some(compressor) => compressor,
+                None => {
+                    *flags &= !Flags::NO_COMPRESS;
+                    return Ok(frame);
+                }
+            };
+            if is_no_compress(*flags) {
+                *flags &= !Flags::NO_COMPRESS;
+                return Ok(frame);
+            }
+            if payload.len() < compression_min_bytes {
+                return Ok(frame);
+            }
+            let uncompressed_bytes = payload.len();
+            *payload = compressor.compress(payload)?;
+            *flags |= Flags::Compressed as u8;
+            self.metrics
+                .observe_compression(opcode, uncompressed_bytes, payload.len());
+        }
+        Ok(frame)
+    }
+
+    /// Appends the CRC32 of the payload of a `Request`, `Response` or `Push`, as it is sent, once
+    /// checksums were negotiated.
+    fn append_checksum(&self, mut frame: LoquiFrame) -> LoquiFrame {
+        if !self.checksums {
+            return frame;
+        }
+        if let Some((_flags, payload)) = data_payload(&mut frame) {
+            let checksum = crc32fast::hash(payload);
+            payload.extend_from_slice(&checksum.to_be_bytes());
+        }
+        frame
+    }
+
+    /// Checks and strips the CRC32 trailer of a `Request`, `Response` or `Push` once checksums
+    /// were negotiated. A mismatch means the frame got corrupted on the way, so the connection
+    /// closes.
+    fn verify_checksum(&self, mut frame: LoquiFrame) -> Result<LoquiFrame, Error> {
+        if !self.checksums {
+            return Ok(frame);
+        }
+        if let Some((_flags, payload)) = data_payload(&mut frame) {
+            // Too short to end with a checksum.
+            if payload.len() < CHECKSUM_SIZE {
+                return Err(LoquiError::ChecksumMismatch {
+                    expected: 0,
+                    actual: crc32fast::hash(payload),
+                }
+                .into());
+            }
+            let trailer = payload.split_off(payload.len() - CHECKSUM_SIZE);
+            let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let actual = crc32fast::hash(payload);
+            if expected != actual {
+                return Err(LoquiError::ChecksumMismatch { expected, actual }.into());
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Starts the payload of a `Request`, `Response` or `Push`, as it is sent, with the id of its
+    /// encoding once encoding ids were negotiated.
+    fn tag_encoding_id(&mut self, mut frame: LoquiFrame) -> Result<LoquiFrame, Error> {
+        let encoding = self.send_encoding.take().unwrap_or(self.encoding);
+        if let Some(encoding_ids) = self.encoding_ids.as_mut() {
+            encoding_ids.tag(&mut frame, encoding)?;
         }
+        Ok(frame)
     }
 
-    /// Handshake should have already completed. This is an error at this point.
+    /// Strips the encoding id off the payload of a `Request`, `Response` or `Push` once encoding
+    /// ids were negotiated, remembering the encoding it selects for delegating the frame.
+    fn strip_encoding_id(&mut self, mut frame: LoquiFrame) -> Result<LoquiFrame, Error> {
+        if let Some(encoding_ids) = self.encoding_ids.as_mut() {
+            self.received_encoding = encoding_ids.strip(&mut frame)?;
+        }
+        Ok(frame)
+    }
+
+    /// The encoding to delegate a frame with. Requests, which may have been queued, use the one
+    /// they selected, other frames the one of the frame being received.
+    fn delegated_encoding(&mut self, delegated_frame: &DelegatedFrame) -> &'static str {
+        let request_encoding = match (&self.encoding_ids, delegated_frame) {
+            (Some(encoding_ids), DelegatedFrame::Request(request)) => {
+                encoding_ids.request_encoding(request.sequence_id)
+            }
+            _ => None,
+        };
+        request_encoding
+            .or_else(|| self.received_encoding.take())
+            .unwrap_or(self.encoding)
+    }
+
+    /// Decompresses the payload of a `Request`, `Response` or `Push` that has its compressed flag
+    /// set, then clears the flag.
+    fn decompress_frame(&self, mut frame: LoquiFrame) -> Result<LoquiFrame, Error> {
+        if let Some((flags, payload)) = data_payload(&mut frame) {
+            if is_compressed(*flags) {
+                match &self.compressor {
+                    Some(compressor) => *payload = compressor.decompress(payload)?,
+                    None => return Err(LoquiError::InvalidCompression.into()),
+                }
+                *flags &= !(Flags::Compressed as u8);
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Handshake should have already completed. This is an error at this point, which closes the
+    /// connection unless the policy is lenient.
     fn handle_handshake_frame(&mut self, frame: LoquiFrame) -> MaybeFrameResult {
-        Err(LoquiError::InvalidOpcode {
+        let error = LoquiError::InvalidOpcode {
+            actual: frame.opcode(),
+            expected: None,
+        };
+        match self.handler.transport_options().protocol_violation_policy {
+            ProtocolViolationPolicy::Strict => Err(error.into()),
+            ProtocolViolationPolicy::Lenient => {
+                warn!("Protocol violation. Continuing. error={}", error);
+                let error = ErrorFrame {
+                    flags: 0,
+                    sequence_id: 0,
+                    code: LoquiErrorCode::ProtocolViolation as u16,
+                    payload: error.to_string().into_bytes(),
+                };
+                Ok(Some(error.into()))
+            }
+        }
+    }
+
+    /// A frame only this side should send, see `TransportOptions::unexpected_frame_policy`.
+    fn handle_unexpected_frame(&mut self, frame: LoquiFrame) -> MaybeFrameResult {
+        let error = LoquiError::InvalidOpcode {
             actual: frame.opcode(),
             expected: None,
+        };
+        match self.handler.transport_options().unexpected_frame_policy {
+            UnexpectedFramePolicy::Drop => {
+                warn!("Unexpected frame. Dropping. error={}", error);
+                Ok(None)
+            }
+            UnexpectedFramePolicy::Error => {
+                let sequence_id = match &frame {
+                    LoquiFrame::Request(request) => request.sequence_id,
+                    _ => 0,
+                };
+                let error = ErrorFrame {
+                    flags: 0,
+                    sequence_id,
+                    code: LoquiErrorCode::ProtocolViolation as u16,
+                    payload: error.to_string().into_bytes(),
+                };
+                Ok(Some(error.into()))
+            }
+            UnexpectedFramePolicy::GoAway => Err(error.into()),
+        }
+    }
+
+    /// Starts draining the in flight requests. The connection closes once they have completed or
+    /// the drain timeout elapses, whichever happens first.
+    fn handle_go_away_frame(&mut self, go_away: GoAway) -> MaybeFrameResult {
+        let code = GoAwayCode::from(go_away.code);
+        let reason = go_away.reason();
+        if code.is_graceful() {
+            debug!(
+                "Told to go away. Draining. code={:?} reason={:?} go_away={:?} \
+                 in_flight_requests={}",
+                code, reason, go_away, self.in_flight_requests
+            );
+        } else {
+            warn!(
+                "Told to go away due to an error. Draining. code={:?} reason={:?} go_away={:?} \
+                 in_flight_requests={}",
+                code, reason, go_away, self.in_flight_requests
+            );
+        }
+        self.handler.handle_go_away(go_away.clone());
+        self.go_away = Some(go_away.clone());
+        self.set_state(ConnectionState::Draining);
+        if self.in_flight_requests == 0 {
+            return Err(LoquiError::told_to_go_away(go_away).into());
+        }
+        self.spawn_drain_timeout();
+        Ok(None)
+    }
+
+    /// Tells the other side to go away and starts draining the in flight requests. The
+    /// connection closes once they have completed or the drain timeout elapses, whichever happens
+    /// first.
+    fn handle_graceful_shutdown(&mut self, code: GoAwayCode) -> MaybeFrameResult {
+        if self.shutdown.is_some() || self.go_away.is_some() {
+            debug!("Already going away. Ignoring shutdown. code={:?}", code);
+            return Ok(None);
         }
-        .into())
+        debug!(
+            "Shutting down. Draining. code={:?} in_flight_requests={}",
+            code, self.in_flight_requests
+        );
+        self.shutdown = Some(code);
+        self.set_state(ConnectionState::Draining);
+        if self.in_flight_requests > 0 {
+            self.spawn_drain_timeout();
+        }
+        let go_away = GoAway {
+            flags: 0,
+            code: code.into(),
+            payload: vec![],
+        };
+        Ok(Some(go_away.into()))
+    }
+
+    fn spawn_drain_timeout(&self) {
+        let drain_timeout = self.handler.transport_options().drain_timeout;
+        let connection_sender = self.self_sender.clone();
+        spawn(async move {
+            delay_for(drain_timeout).await;
+            // It's okay to ignore this result. The connection closed.
+            let _result = connection_sender.drain_timeout();
+        });
+    }
+
+    /// The in flight requests didn't drain in time. Return an `Error` to close the connection.
+    fn handle_drain_timeout(&mut self) -> MaybeFrameResult {
+        let error = match (self.go_away.take(), self.shutdown) {
+            (Some(go_away), _) => LoquiError::told_to_go_away(go_away),
+            (None, Some(code)) => LoquiError::ShutDown { code },
+            (None, None) => return Ok(None),
+        };
+        warn!(
+            "Drain timed out. in_flight_requests={}",
+            self.in_flight_requests
+        );
+        Err(error.into())
     }
 
     /// Delegates a frame to the connection handler.
     fn delegate_frame<D: Into<DelegatedFrame>>(&mut self, delegated_frame: D) -> MaybeFrameResult {
         let delegated_frame = delegated_frame.into();
-        let maybe_future = self.handler.handle_frame(delegated_frame, self.encoding);
-        // If the connection handler returns a future, execute the future async and send it back
-        // to the main event loop. The main event loop will send it through the socket.
-        if let Some(future) = maybe_future {
-            let connection_sender = self.self_sender.clone();
-            spawn(async move {
-                let response = future.await;
-                // It's okay to ignore this result. The connection closed.
-                let _result = connection_sender.response_complete(response);
-            });
+        let span = spans::delegate_span(&delegated_frame);
+        let _entered = span.enter();
+        let (sequence_id, payload_bytes) = match &delegated_frame {
+            DelegatedFrame::Request(request) => (Some(request.sequence_id), request.payload.len()),
+            _ => (None, 0),
+        };
+        if self.state == ConnectionState::Draining && sequence_id.is_some() {
+            debug!("Draining. Ignoring request. sequence_id={:?}", sequence_id);
+            return Ok(None);
         }
-        Ok(None)
+        if self.remote_half_closed && sequence_id.is_some() {
+            debug!(
+                "Peer half closed. Ignoring request. sequence_id={:?}",
+                sequence_id
+            );
+            return Ok(None);
+        }
+        if let DelegatedFrame::Request(request) = &delegated_frame {
+            if let Some(response) = self.cached_response(request) {
+                return Ok(Some(response.into()));
+            }
+        }
+        if let Some(sequence_id) = sequence_id {
+            if self.is_overloaded() {
+                debug!("Overloaded. Rejecting request. sequence_id={}", sequence_id);
+                return Ok(Some(service_unavailable(
+                    sequence_id,
+                    "Outbound queue is full.",
+                )));
+            }
+            if self.at_open_sequence_id_limit() {
+                debug!(
+                    "Too many open sequence ids. Rejecting request. sequence_id={}",
+                    sequence_id
+                );
+                return Ok(Some(service_unavailable(
+                    sequence_id,
+                    "Too many open sequence ids.",
+                )));
+            }
+            if self.at_concurrency_limit() {
+                let request_queue_depth = self.handler.transport_options().request_queue_depth;
+                if self.request_queue.len() < request_queue_depth.unwrap_or(0) {
+                    if let DelegatedFrame::Request(request) = delegated_frame {
+                        debug!(
+                            "Too many concurrent requests. Queueing request. sequence_id={}",
+                            sequence_id
+                        );
+                        self.request_queue.push(request, self.clock.now());
+                        return Ok(None);
+                    }
+                }
+                debug!(
+                    "Too many concurrent requests. Rejecting request. sequence_id={}",
+                    sequence_id
+                );
+                return Ok(Some(service_unavailable(
+                    sequence_id,
+                    "Too many concurrent requests.",
+                )));
+            }
+            if self.at_in_flight_bytes_limit(payload_bytes) {
+                debug!(
+                    "Too many in flight bytes. Rejecting request. sequence_id={} in_flight_bytes={}",
+                    sequence_id, self.in_flight_bytes
+                );
+                return Ok(Some(service_unavailable(
+                    sequence_id,
+                    "Too many in flight bytes.",
+                )));
+            }
+        }
+        Ok(self.spawn_delegated_frame(delegated_frame, &span))
     }
 
-    fn handle_ping_frame(&mut self, ping: Ping) -> MaybeFrameResult {
-        let pong = Pong {
-            flags: ping.flags,
-            sequence_id: ping.sequence_id,
+    /// The response kept for an earlier attempt of the request, if it has an idempotency key.
+    fn cached_response(&mut self, request: &Request) -> Option<Response> {
+        let dedup_cache = self.dedup_cache.as_mut()?;
+        let idempotency_key = request.idempotency_key.as_ref()?;
+        let response = dedup_cache.get(idempotency_key, self.clock.now())?;
+        debug!(
+            "Answering retried request from cache. sequence_id={}",
+            request.sequence_id
+        );
+        Some(ready_response(response, request.sequence_id))
+    }
+
+    /// Hands a frame that passed the limits to the handler, spawning the future that computes its
+    /// response. Returns the frame to send back if the handler answered right away.
+    fn spawn_delegated_frame(
+        &mut self,
+        delegated_frame: DelegatedFrame,
+        span: &Span,
+    ) -> Option<LoquiFrame> {
+        let (sequence_id, flow_controlled, payload_bytes, timeout_ms) = match &delegated_frame {
+            DelegatedFrame::Request(request) => (
+                Some(request.sequence_id),
+                is_flow_controlled(request.flags),
+                request.payload.len(),
+                request.timeout_ms,
+            ),
+            _ => (None, false, 0, 0),
         };
-        self.handler.on_ping_received();
-        Ok(Some(pong.into()))
+        // Streamed responses aren't kept.
+        let idempotency_key = match &delegated_frame {
+            DelegatedFrame::Request(request) if !is_streaming(request.flags) => {
+                request.idempotency_key
+            }
+            _ => None,
+        };
+        let encoding = self.delegated_encoding(&delegated_frame);
+        let future = match self.handler.handle_frame(delegated_frame, encoding) {
+            FrameOutcome::Respond(future) => future,
+            FrameOutcome::Reject { code, message } => {
+                return match sequence_id {
+                    Some(sequence_id) => {
+                        let error = ErrorFrame {
+                            flags: 0,
+                            sequence_id,
+                            code: code as u16,
+                            payload: message.into_bytes(),
+                        };
+                        Some(error.into())
+                    }
+                    None => {
+                        debug!("Can only reject requests. Ignoring. message={}", message);
+                        None
+                    }
+                };
+            }
+            FrameOutcome::Ready(response) => {
+                return match sequence_id {
+                    Some(sequence_id) => Some(ready_response(response, sequence_id).into()),
+                    None => {
+                        debug!("Can only respond to requests. Ignoring.");
+                        None
+                    }
+                };
+            }
+            FrameOutcome::Stream(stream) => match sequence_id {
+                Some(sequence_id) => self.forward_stream(sequence_id, stream, flow_controlled),
+                None => {
+                    debug!("Can only stream responses to requests. Ignoring.");
+                    return None;
+                }
+            },
+            FrameOutcome::Ignore => {
+                if let (Some(encoding_ids), Some(sequence_id)) =
+                    (self.encoding_ids.as_mut(), sequence_id)
+                {
+                    encoding_ids.forget(sequence_id);
+                }
+                return None;
+            }
+        };
+        // Execute the future async and send it back to the main event loop. The main event loop
+        // will send it through the socket.
+        self.in_flight_requests += 1;
+        self.metrics.in_flight_requests(self.in_flight_requests);
+        self.stats.in_flight_requests(self.in_flight_requests);
+        if let (Some(dedup_cache), Some(sequence_id), Some(idempotency_key)) =
+            (self.dedup_cache.as_mut(), sequence_id, idempotency_key)
+        {
+            dedup_cache.track(sequence_id, idempotency_key);
+        }
+        let handler_timeout = self.handler_timeout(timeout_ms);
+        let connection_sender = self.self_sender.clone();
+        let (future, abort_handle) = abortable(future);
+        if let Some(sequence_id) = sequence_id {
+            self.abort_handles.insert(sequence_id, abort_handle);
+            self.in_flight_bytes += payload_bytes;
+            self.request_bytes.insert(sequence_id, payload_bytes);
+        }
+        let clock = self.clock.clone();
+        let delegated_at = clock.now();
+        // The span is entered whenever the response is polled, so its logs are attributed to it.
+        self.spawner.spawn(Box::pin(spans::instrument(
+            async move {
+                let started_at = clock.now();
+                let response = AssertUnwindSafe(async move {
+                    match (handler_timeout, sequence_id) {
+                        // Dropping the future on timeout cancels it, so only the error is sent back.
+                        (Some(handler_timeout), Some(sequence_id)) => {
+                            timeout(handler_timeout, future)
+                                .await
+                                .unwrap_or_else(|_elapsed| {
+                                    Ok(Err((LoquiError::RequestTimeout.into(), sequence_id)))
+                                })
+                        }
+                        _ => future.await,
+                    }
+                })
+                .catch_unwind()
+                .await;
+                let timing = RequestTiming {
+                    queued: started_at - delegated_at,
+                    handled: clock.now() - started_at,
+                };
+                // A panic must still reach the connection, or the request would count as in flight
+                // forever.
+                // It's okay to ignore these results. The connection closed.
+                let _result = match (response, sequence_id) {
+                    (Ok(Ok(response)), _) => connection_sender.response_complete(response, timing),
+                    (Ok(Err(Aborted)), _) | (Err(_), None) => connection_sender.request_cancelled(),
+                    (Err(_), Some(sequence_id)) => connection_sender.response_complete(
+                        Err((LoquiError::HandlerPanicked.into(), sequence_id)),
+                        timing,
+                    ),
+                };
+            },
+            span.clone(),
+        )));
+        None
     }
 
-    fn handle_pong_frame(&mut self, _pong: Pong) -> MaybeFrameResult {
-        self.pong_received = true;
-        Ok(None)
+    /// Serves queued requests, highest priority first, while there are slots for them. Queued
+    /// requests were accepted before any `GoAway` or half close, so they're served regardless.
+    fn serve_queued_requests(&mut self) {
+        let priority_aging = self.handler.transport_options().priority_aging;
+        while !self.at_concurrency_limit() {
+            let request = match self.request_queue.pop(self.clock.now(), priority_aging) {
+                Some(request) => request,
+                None => return,
+            };
+            let delegated_frame = DelegatedFrame::Request(request);
+            let span = spans::delegate_span(&delegated_frame);
+            let _entered = span.enter();
+            // Answered right away. It goes out on its own event, as it would have when handled.
+            let frame = self
+                .spawn_delegated_frame(delegated_frame, &span)
+                .and_then(|frame| self.pending_batches.collect(frame))
+                .and_then(|frame| self.before_send(frame));
+            if let Some(frame) = frame {
+                // It's okay to ignore this result. The connection closed.
+                let _result = self.self_sender.send_delayed(frame, None, None);
+            }
+        }
     }
 
-    /// A response was computed. Send it back over the socket.
-    fn handle_response_complete(&self, result: Result<Response, (Error, u32)>) -> MaybeFrameResult {
-        match result {
-            Ok(response) => Ok(Some(response.into())),
-            Err((error, sequence_id)) => {
+    /// Delegates each entry of the batch as a `Request`. Their responses are sent back together in
+    /// a `ResponseBatch` once the last one completed. Requests that are ignored, e.g. while
+    /// draining, are left out of it.
+    fn handle_request_batch_frame(&mut self, request_batch: RequestBatch) -> MaybeFrameResult {
+        let RequestBatch {
+            sequence_id: batch_id,
+            entries,
+            ..
+        } = request_batch;
+        if entries.is_empty() {
+            let response_batch = ResponseBatch {
+                flags: 0,
+                sequence_id: batch_id,
+                entries: vec![],
+            };
+            return Ok(Some(response_batch.into()));
+        }
+        let mut response_batch = None;
+        for BatchEntry {
+            sequence_id,
+            payload,
+            ..
+        } in self.pending_batches.start(batch_id, entries)
+        {
+            let request = Request {
+                flags: 0,
+                sequence_id,
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                payload,
+            };
+            let frame = match self.delegate_frame(request)? {
+                Some(frame) => self.pending_batches.collect(frame),
+                None if self.abort_handles.contains_key(&sequence_id)
+                    || self.request_queue.contains(sequence_id) =>
+                {
+                    None
+                }
+                None => self.pending_batches.remove(sequence_id),
+            };
+            response_batch = response_batch.or(frame);
+        }
+        Ok(response_batch)
+    }
+
+    /// Delegates each entry of the batch as the `Response` or `Error` it stands for.
+    fn handle_response_batch_frame(&mut self, response_batch: ResponseBatch) -> MaybeFrameResult {
+        for BatchEntry {
+            sequence_id,
+            code,
+            payload,
+        } in response_batch.entries
+        {
+            // Responses and errors never send anything back.
+            let _frame = if code == 0 {
+                let response = Response {
+                    flags: 0,
+                    sequence_id,
+                    trace_id: None,
+                    payload,
+                };
+                self.delegate_frame(response)?
+            } else {
                 let error = ErrorFrame {
                     flags: 0,
                     sequence_id,
-                    code: LoquiErrorCode::InternalServerError as u16,
-                    payload: format!("{:?}", error.to_string()).as_bytes().to_vec(),
+                    code,
+                    payload,
                 };
-                Ok(Some(error.into()))
+                self.delegate_frame(error)?
+            };
+        }
+        Ok(None)
+    }
+
+    /// Turns a stream of response payloads into a future that sends each of them as a `Response`
+    /// as soon as it is ready. The future resolves to the `Response` that ends the stream, so a
+    /// stream is in flight, times out and is cancelled like any other request. A flow controlled
+    /// stream waits for a credit before each response, so only it pauses.
+    fn forward_stream(
+        &mut self,
+        sequence_id: u32,
+        mut stream: ResponseStream,
+        flow_controlled: bool,
+    ) -> ResponseFuture {
+        let connection_sender = self.self_sender.clone();
+        let window = match self.handler.transport_options().stream_window {
+            Some(stream_window) if flow_controlled => {
+                let window = Arc::new(Semaphore::new(stream_window as usize));
+                self.stream_windows.insert(sequence_id, window.clone());
+                Some(window)
+            }
+            _ => None,
+        };
+        Box::pin(async move {
+            while let Some(item) = stream.next().await {
+                let payload = item.map_err(|error| (error, sequence_id))?;
+                if let Some(window) = &window {
+                    window.acquire().await.forget();
+                }
+                let response = Response {
+                    flags: Flags::Streaming as u8,
+                    sequence_id,
+                    trace_id: None,
+                    payload,
+                };
+                // Items go through the same queue as the end of the stream, so they stay in
+                // order. A closed connection is noticed by the response that ends the stream.
+                let _result = connection_sender.stream_item(response);
+            }
+            Ok(Response {
+                flags: Flags::Streaming as u8 | Flags::StreamEnd as u8,
+                sequence_id,
+                trace_id: None,
+                payload: vec![],
+            })
+        })
+    }
+
+    /// Delegates a push. Acks it once delegated if it was sent with a sequence id, while pushes
+    /// without one stay fire and forget.
+    fn handle_push_frame(&mut self, push: Push) -> MaybeFrameResult {
+        let sequence_id = push.sequence_id;
+        let frame = match push.subscription_id {
+            Some(subscription_id) => self.route_subscription_push(subscription_id, push.payload),
+            None => self.delegate_frame(push)?,
+        };
+        match sequence_id {
+            // Only requests produce a frame when delegated, and pushes published to a subscription
+            // aren't acked, so the ack never replaces one.
+            Some(sequence_id) => {
+                let push_ack = PushAck {
+                    flags: 0,
+                    sequence_id,
+                };
+                Ok(Some(push_ack.into()))
             }
+            None => Ok(frame),
         }
     }
 
-    fn handle_internal_event(&mut self, internal_event: H::InternalEvent) -> MaybeFrameResult {
-        Ok(self
+    /// Hands a push to the subscription it was published to. Tells the other side to unsubscribe
+    /// if the subscription is no longer wanted.
+    fn route_subscription_push(
+        &mut self,
+        subscription_id: u32,
+        payload: Vec<u8>,
+    ) -> Option<LoquiFrame> {
+        if self
             .handler
-            .handle_internal_event(internal_event, &mut self.id_sequence))
+            .handle_subscription_push(subscription_id, payload)
+        {
+            return None;
+        }
+        debug!(
+            "Unwanted subscription push. Unsubscribing. subscription_id={}",
+            subscription_id
+        );
+        let unsubscribe = Unsubscribe {
+            flags: 0,
+            sequence_id: subscription_id,
+        };
+        Some(unsubscribe.into())
     }
 
-    /// Close requested. Return an `Error` to close the connection.
-    fn handle_close(&mut self) -> MaybeFrameResult {
-        Err(LoquiError::ConnectionCloseRequested.into())
+    /// Starts publishing to a subscription the handler accepted. Each payload of its stream is
+    /// pushed, tagged with the subscription id, until the other side unsubscribes or the stream
+    /// ends.
+    fn handle_subscribe_frame(&mut self, subscribe: Subscribe) -> MaybeFrameResult {
+        let subscription_id = subscribe.sequence_id;
+        if self.state == ConnectionState::Draining || self.remote_half_closed {
+            debug!(
+                "Going away. Rejecting subscription. subscription_id={}",
+                subscription_id
+            );
+            return Ok(Some(service_unavailable(subscription_id, "Going away.")));
+        }
+        if self.subscriptions.contains_key(&subscription_id) {
+            debug!(
+                "Already subscribed. Ignoring. subscription_id={}",
+                subscription_id
+            );
+            return Ok(None);
+        }
+        let outcome =
+            self.handler
+                .handle_subscribe(subscription_id, subscribe.payload, self.encoding);
+        let mut stream = match outcome {
+            SubscribeOutcome::Accept(stream) => stream,
+            SubscribeOutcome::Reject { code, message } => {
+                let error = ErrorFrame {
+                    flags: 0,
+                    sequence_id: subscription_id,
+                    code: code as u16,
+                    payload: message.into_bytes(),
+                };
+                return Ok(Some(error.into()));
+            }
+        };
+        let connection_sender = self.self_sender.clone();
+        let (future, abort_handle) = abortable(async move {
+            while let Some(item) = stream.next().await {
+                let payload = match item {
+                    Ok(payload) => payload,
+                    Err(error) => {
+                        warn!(
+                            "Subscription failed. subscription_id={} error={:?}",
+                            subscription_id, error
+                        );
+                        break;
+                    }
+                };
+                let push = Push {
+                    flags: 0,
+                    sequence_id: None,
+                    subscription_id: Some(subscription_id),
+                    payload,
+                };
+                if connection_sender.subscription_push(push).is_err() {
+                    // The connection closed.
+                    return;
+                }
+            }
+            // It's okay to ignore this result. The connection closed.
+            let _result = connection_sender.subscription_ended(subscription_id);
+        });
+        self.subscriptions.insert(subscription_id, abort_handle);
+        self.spawner.spawn(Box::pin(future.map(|_result| ())));
+        Ok(None)
+    }
+
+    /// The stream of a subscription ended. Tells the other side, unless it already unsubscribed.
+    fn handle_subscription_ended(&mut self, subscription_id: u32) -> MaybeFrameResult {
+        if self.subscriptions.remove(&subscription_id).is_none() {
+            return Ok(None);
+        }
+        self.handler.handle_unsubscribe(subscription_id);
+        let unsubscribe = Unsubscribe {
+            flags: 0,
+            sequence_id: subscription_id,
+        };
+        Ok(Some(unsubscribe.into()))
+    }
+
+    /// Stops publishing to a subscription the other side made, or, on the subscribing side, ends
+    /// one the other side stopped publishing to.
+    fn handle_unsubscribe_frame(&mut self, unsubscribe: Unsubscribe) -> MaybeFrameResult {
+        if let Some(abort_handle) = self.subscriptions.remove(&unsubscribe.sequence_id) {
+            abort_handle.abort();
+        }
+        self.handler.handle_unsubscribe(unsubscribe.sequence_id);
+        Ok(None)
+    }
+
+    /// Ignores a frame from a newer peer this version doesn't know, unless it's mandatory, which
+    /// closes the connection.
+    fn handle_unknown_frame(&mut self, unknown: UnknownFrame) -> MaybeFrameResult {
+        if is_mandatory(unknown.flags) {
+            return Err(LoquiError::UnknownMandatoryFrame {
+                opcode: unknown.opcode,
+            }
+            .into());
+        }
+        debug!(
+            "Ignoring unknown frame. opcode={} flags={} payload_size={}",
+            unknown.opcode,
+            unknown.flags,
+            unknown.payload.len()
+        );
+        Ok(None)
+    }
+
+    /// Grants credits to a flow controlled stream. Updates for streams that already ended are
+    /// ignored.
+    fn handle_window_update_frame(&mut self, window_update: WindowUpdate) -> MaybeFrameResult {
+        match self.stream_windows.get(&window_update.sequence_id) {
+            Some(window) => window.add_permits(window_update.increment as usize),
+            None => debug!(
+                "No stream for window update. window_update={:?}",
+                window_update
+            ),
+        }
+        Ok(None)
+    }
+
+    fn handle_push_ack_frame(&mut self, push_ack: PushAck) -> MaybeFrameResult {
+        self.handler.handle_push_ack(push_ack.sequence_id);
+        Ok(None)
+    }
+
+    /// Whether the outbound queue is too deep to take on more requests. Uses the high and low
+    /// water marks so it doesn't flap around a single threshold.
+    fn is_overloaded(&mut self) -> bool {
+        let transport_options = self.handler.transport_options();
+        let high_water_mark = match transport_options.outbound_high_water_mark {
+            Some(high_water_mark) => high_water_mark,
+            None => return false,
+        };
+        let depth = self.self_sender.depth();
+        if depth >= high_water_mark {
+            self.overloaded = true;
+        } else if depth <= transport_options.outbound_low_water_mark {
+            self.overloaded = false;
+        }
+        self.overloaded
+    }
+
+    /// Whether nothing but keepalives went either way for `TransportOptions::idle_timeout`, while
+    /// no request is being computed.
+    fn is_idle(&self) -> bool {
+        match self.handler.transport_options().idle_timeout {
+            Some(idle_timeout) => {
+                self.in_flight_requests == 0
+                    && self.clock.now() - self.last_data_activity >= idle_timeout
+            }
+            None => false,
+        }
+    }
+
+    /// Reports to the handler when the outbound queue has stayed too deep for too long. Runs on
+    /// the ping tick, so the duration is only as precise as the ping interval.
+    fn check_slow_consumer(&mut self) {
+        let transport_options = self.handler.transport_options();
+        let slow_consumer_depth = match transport_options.slow_consumer_depth {
+            Some(slow_consumer_depth) => slow_consumer_depth,
+            None => return,
+        };
+        let slow_consumer_duration = transport_options.slow_consumer_duration;
+        let depth = self.self_sender.depth();
+        if depth < slow_consumer_depth {
+            self.slow_consumer_since = None;
+            return;
+        }
+        let now = self.clock.now();
+        let since = *self.slow_consumer_since.get_or_insert(now);
+        let duration = now - since;
+        if duration >= slow_consumer_duration {
+            warn!("Slow consumer. depth={:?} duration={:?}", depth, duration);
+            self.handler.on_slow_consumer(depth, duration);
+        }
+    }
+
+    /// Whether as many delegated futures are in flight as `max_concurrent_requests` allows.
+    fn at_concurrency_limit(&self) -> bool {
+        match self.handler.transport_options().max_concurrent_requests {
+            Some(max_concurrent_requests) => self.in_flight_requests >= max_concurrent_requests,
+            None => false,
+        }
+    }
+
+    /// Whether as many requests are in flight or queued as `TransportOptions::max_open_sequence_ids`
+    /// allows.
+    fn at_open_sequence_id_limit(&self) -> bool {
+        match self.handler.transport_options().max_open_sequence_ids {
+            Some(max_open_sequence_ids) => {
+                self.abort_handles.len() + self.request_queue.len() >= max_open_sequence_ids
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a request with this many payload bytes would go over
+    /// `TransportOptions::max_in_flight_bytes`. It always fits when nothing else is in flight, so
+    /// a big request isn't rejected forever.
+    fn at_in_flight_bytes_limit(&self, payload_bytes: usize) -> bool {
+        match self.handler.transport_options().max_in_flight_bytes {
+            Some(max_in_flight_bytes) => {
+                self.in_flight_bytes > 0
+                    && self.in_flight_bytes + payload_bytes > max_in_flight_bytes
+            }
+            None => false,
+        }
+    }
+
+    /// A request stopped being in flight. Its payload no longer counts towards
+    /// `TransportOptions::max_in_flight_bytes`.
+    fn release_request_bytes(&mut self, sequence_id: u32) {
+        if let Some(payload_bytes) = self.request_bytes.remove(&sequence_id) {
+            self.in_flight_bytes -= payload_bytes;
+        }
+    }
+
+    /// Stops computing the response for a request the other side no longer cares about. Nothing is
+    /// sent back. Cancels for requests that already completed are ignored.
+    fn handle_cancel_frame(&mut self, cancel: Cancel) -> MaybeFrameResult {
+        self.stream_windows.remove(&cancel.sequence_id);
+        if let Some(encoding_ids) = self.encoding_ids.as_mut() {
+            encoding_ids.forget(cancel.sequence_id);
+        }
+        self.release_request_bytes(cancel.sequence_id);
+        if let Some(dedup_cache) = self.dedup_cache.as_mut() {
+            dedup_cache.forget(cancel.sequence_id);
+        }
+        match self.abort_handles.remove(&cancel.sequence_id) {
+            Some(abort_handle) => {
+                abort_handle.abort();
+                self.handler.handle_cancel(cancel.sequence_id);
+            }
+            // Queued requests never reached the handler, so it isn't told.
+            None if self.request_queue.remove(cancel.sequence_id) => {
+                debug!("Cancelled queued request. cancel={:?}", cancel)
+            }
+            None => debug!("Nothing to cancel. cancel={:?}", cancel),
+        }
+        // The batch is sent without the cancelled request.
+        Ok(self.pending_batches.remove(cancel.sequence_id))
+    }
+
+    /// Cancels every request of the other side as if it sent a `Cancel` for each, dropping the
+    /// queued ones too, then lets the handler fail the requests this side is waiting on.
+    /// Responses batched with a cancelled request are dropped with it.
+    fn handle_cancel_all_inflight(&mut self) -> MaybeFrameResult {
+        debug!(
+            "Cancelling all in flight requests. in_flight_requests={} queued={}",
+            self.in_flight_requests,
+            self.request_queue.len()
+        );
+        self.request_queue.clear();
+        let sequence_ids: Vec<u32> = self.abort_handles.keys().copied().collect();
+        for sequence_id in sequence_ids {
+            let cancel = Cancel {
+                flags: 0,
+                sequence_id,
+            };
+            let _batch = self.handle_cancel_frame(cancel)?;
+        }
+        self.handler.on_cancel_all();
+        Ok(None)
+    }
+
+    /// An aborted request stopped computing. It only needs to stop counting as in flight.
+    fn handle_request_cancelled(&mut self) -> MaybeFrameResult {
+        self.in_flight_requests -= 1;
+        self.metrics.in_flight_requests(self.in_flight_requests);
+        self.stats.in_flight_requests(self.in_flight_requests);
+        self.serve_queued_requests();
+        self.send_renegotiated();
+        Ok(None)
+    }
+
+    /// Asks the other side for the stats of the connection. The waiter is told once it answered.
+    fn handle_health_check(
+        &mut self,
+        waiter: oneshot::Sender<ConnectionHealth>,
+    ) -> MaybeFrameResult {
+        let sequence_id = self.id_sequence.next();
+        self.health_checks.insert(sequence_id, waiter);
+        let health_check = HealthCheck {
+            flags: 0,
+            sequence_id,
+        };
+        Ok(Some(health_check.into()))
+    }
+
+    /// Answers a `HealthCheck` with the stats of the connection, without involving the handler.
+    fn handle_health_check_frame(&mut self, health_check: HealthCheck) -> MaybeFrameResult {
+        let uptime = self.clock.now() - self.ready_at;
+        let health_status = HealthStatus {
+            flags: 0,
+            sequence_id: health_check.sequence_id,
+            uptime_ms: uptime.as_millis().min(u128::from(u64::MAX)) as u64,
+            in_flight_requests: self.in_flight_requests.min(u32::MAX as usize) as u32,
+            encoding: self.encoding.to_string(),
+        };
+        Ok(Some(health_status.into()))
+    }
+
+    fn handle_health_status_frame(&mut self, health_status: HealthStatus) -> MaybeFrameResult {
+        match self.health_checks.remove(&health_status.sequence_id) {
+            Some(waiter) => {
+                let health = ConnectionHealth {
+                    uptime: Duration::from_millis(health_status.uptime_ms),
+                    in_flight_requests: health_status.in_flight_requests as usize,
+                    encoding: health_status.encoding,
+                };
+                // It's okay to ignore this result. The caller stopped waiting.
+                let _result = waiter.send(health);
+            }
+            None => debug!(
+                "No health check for status. sequence_id={}",
+                health_status.sequence_id
+            ),
+        }
+        Ok(None)
+    }
+
+    /// Asks the other side to switch to the encoding. The handler is told right away the encoding
+    /// stays the same if renegotiation is off or one is already in progress.
+    fn handle_renegotiate(&mut self, encoding: &'static str) -> MaybeFrameResult {
+        if !self.handler.transport_options().renegotiation
+            || self.renegotiating.is_some()
+            || self.accepted_encoding.is_some()
+        {
+            debug!("Can't renegotiate. encoding={}", encoding);
+            self.handler.on_encoding_renegotiated(self.encoding);
+            return Ok(None);
+        }
+        let sequence_id = self.id_sequence.next();
+        self.renegotiating = Some((sequence_id, encoding));
+        let renegotiate = Renegotiate {
+            flags: 0,
+            sequence_id,
+            encoding: encoding.to_string(),
+        };
+        Ok(Some(renegotiate.into()))
+    }
+
+    /// Either the answer to our `Renegotiate`, which switches the encoding unless it was refused,
+    /// or the other side asking to switch. A switch we accept is answered once the requests in
+    /// flight drained, others are refused right away by answering with the current encoding.
+    fn handle_renegotiate_frame(&mut self, renegotiate: Renegotiate) -> MaybeFrameResult {
+        if let Some((sequence_id, encoding)) = self.renegotiating {
+            if sequence_id == renegotiate.sequence_id {
+                self.renegotiating = None;
+                if renegotiate.encoding == encoding {
+                    self.encoding = encoding;
+                    self.stats.encoding(encoding);
+                } else {
+                    debug!("Renegotiation refused. encoding={}", encoding);
+                }
+                self.handler.on_encoding_renegotiated(self.encoding);
+                return Ok(None);
+            }
+        }
+        let accepted = if self.handler.transport_options().renegotiation
+            && self.renegotiating.is_none()
+            && self.accepted_encoding.is_none()
+        {
+            // With encoding ids, only to an encoding frames can select.
+            self.handler
+                .accept_encoding(&renegotiate.encoding)
+                .filter(|encoding| match &self.encoding_ids {
+                    Some(encoding_ids) => encoding_ids.contains(encoding),
+                    None => true,
+                })
+        } else {
+            None
+        };
+        match accepted {
+            Some(encoding) => {
+                self.accepted_encoding = Some((renegotiate.sequence_id, encoding));
+                Ok(self.complete_renegotiation())
+            }
+            None => {
+                debug!("Refusing renegotiation. renegotiate={:?}", renegotiate);
+                let refusal = Renegotiate {
+                    flags: 0,
+                    sequence_id: renegotiate.sequence_id,
+                    encoding: self.encoding.to_string(),
+                };
+                Ok(Some(refusal.into()))
+            }
+        }
+    }
+
+    /// Switches to the accepted encoding once no requests are in flight with the old one.
+    /// Returns the `Renegotiate` that tells the other side it switched.
+    fn complete_renegotiation(&mut self) -> Option<LoquiFrame> {
+        if self.in_flight_requests > 0 {
+            return None;
+        }
+        let (sequence_id, encoding) = self.accepted_encoding.take()?;
+        debug!("Renegotiated. old={} new={}", self.encoding, encoding);
+        self.encoding = encoding;
+        self.stats.encoding(encoding);
+        self.handler.on_encoding_renegotiated(encoding);
+        let renegotiate = Renegotiate {
+            flags: 0,
+            sequence_id,
+            encoding: encoding.to_string(),
+        };
+        Some(renegotiate.into())
+    }
+
+    /// Queues the answer to an accepted `Renegotiate` if the last request in flight just
+    /// completed, so it is sent after that request's response.
+    fn send_renegotiated(&mut self) {
+        if let Some(frame) = self.complete_renegotiation() {
+            // It's okay to ignore this result. The connection closed.
+            let _result = self.self_sender.send_delayed(frame, None, None);
+        }
+    }
+
+    fn handle_ping_frame(&mut self, ping: Ping) -> MaybeFrameResult {
+        if is_half_closed(ping.flags) && !self.remote_half_closed {
+            debug!(
+                "Peer half closed. in_flight_requests={}",
+                self.in_flight_requests
+            );
+            self.remote_half_closed = true;
+            self.handler.on_peer_half_close();
+        }
+        // Answered even if we didn't turn them on, since the other side only sends them when
+        // we agreed to.
+        let timestamps = ping.sent_at.map(|ping_sent_at| PongTimestamps {
+            ping_sent_at,
+            received_at: unix_micros(&*self.clock),
+        });
+        let pong = Pong {
+            flags: ping.flags,
+            sequence_id: ping.sequence_id,
+            token: ping.token,
+            timestamps,
+        };
+        self.handler.on_ping_received();
+        // A ping that half closes is answered right away.
+        if is_half_closed(ping.flags) {
+            return Ok(Some(self.send_pong(pong)));
+        }
+        Ok(self.coalesce_pong(pong))
+    }
+
+    /// Returns the pong if the coalescing window of the last one is over. Otherwise holds it
+    /// until the window is, replacing the one held already.
+    fn coalesce_pong(&mut self, pong: Pong) -> Option<LoquiFrame> {
+        let window = self.handler.transport_options().pong_coalescing_window;
+        let now = self.clock.now();
+        let remaining = match (window, self.last_pong_at) {
+            (Some(window), Some(last_pong_at)) if now - last_pong_at < window => {
+                window - (now - last_pong_at)
+            }
+            _ => return Some(self.send_pong(pong)),
+        };
+        if let Some(coalesced) = self.coalesced_pong.replace(pong) {
+            debug!("Coalescing pong. sequence_id={}", coalesced.sequence_id);
+            return None;
+        }
+        let connection_sender = self.self_sender.clone();
+        spawn(async move {
+            delay_for(remaining).await;
+            // It's okay to ignore this result. The connection closed.
+            let _result = connection_sender.pong_due();
+        });
+        None
+    }
+
+    /// Supersedes the coalesced pong, if any.
+    fn send_pong(&mut self, pong: Pong) -> LoquiFrame {
+        self.coalesced_pong = None;
+        self.last_pong_at = Some(self.clock.now());
+        pong.into()
+    }
+
+    /// Clears the matching in flight ping and reports the round-trip time to the handler. Pings
+    /// sent before it are cleared too, without a round-trip time, since the other side may have
+    /// coalesced their pongs into this one, see `TransportOptions::pong_coalescing_window`. They're
+    /// matched on when they were sent rather than on `sequence_id`, which an `IdStrategy` needn't
+    /// increment. A `Pong` that doesn't match any in flight ping is ignored. Returns an `Error` if
+    /// it didn't echo the ping's token.
+    fn handle_pong_frame(&mut self, pong: Pong) -> MaybeFrameResult {
+        match self.in_flight_pings.remove(&pong.sequence_id) {
+            Some(ping) if ping.token != pong.token => Err(LoquiError::PingTokenMismatch {
+                expected: ping.token,
+                actual: pong.token,
+            }
+            .into()),
+            Some(ping) => {
+                self.in_flight_pings
+                    .retain(|_sequence_id, superseded| superseded.sent_at > ping.sent_at);
+                let rtt = self.clock.now() - ping.sent_at;
+                self.stats.rtt(rtt);
+                self.handler.observe_rtt(rtt);
+                if let Some(timestamps) = pong.timestamps {
+                    let clock_skew = ClockSkew::estimate(timestamps, unix_micros(&*self.clock));
+                    self.handler.observe_clock_skew(clock_skew);
+                }
+                Ok(None)
+            }
+            None => {
+                debug!("Ignoring unexpected pong. pong={:?}", pong);
+                Ok(None)
+            }
+        }
+    }
+
+    /// A response was computed. Send it back over the socket.
+    fn handle_response_complete(
+        &mut self,
+        result: Result<Response, (Error, u32)>,
+        timing: RequestTiming,
+    ) -> MaybeFrameResult {
+        self.in_flight_requests -= 1;
+        self.metrics.in_flight_requests(self.in_flight_requests);
+        self.stats.in_flight_requests(self.in_flight_requests);
+        let sequence_id = match &result {
+            Ok(response) => response.sequence_id,
+            Err((_error, sequence_id)) => *sequence_id,
+        };
+        self.metrics.request_timing(sequence_id, &timing);
+        self.abort_handles.remove(&sequence_id);
+        self.stream_windows.remove(&sequence_id);
+        self.release_request_bytes(sequence_id);
+        self.serve_queued_requests();
+        self.send_renegotiated();
+        if let Some(dedup_cache) = self.dedup_cache.as_mut() {
+            match &result {
+                Ok(response) => dedup_cache.complete(response, self.clock.now()),
+                Err((_error, sequence_id)) => dedup_cache.forget(*sequence_id),
+            }
+        }
+        match result {
+            Ok(response) => Ok(Some(response.into())),
+            Err((error, sequence_id)) => {
+                let (code, payload) = self
+                    .handler
+                    .error_payload(&error, self.encoding)
+                    .unwrap_or_else(|| {
+                        let code = match error.downcast_ref::<LoquiError>() {
+                            Some(loqui_error) => loqui_error.code(),
+                            None => LoquiErrorCode::InternalServerError,
+                        };
+                        (code, format!("{:?}", error.to_string()).as_bytes().to_vec())
+                    });
+                let error = ErrorFrame {
+                    flags: 0,
+                    sequence_id,
+                    code: code as u16,
+                    payload,
+                };
+                Ok(Some(error.into()))
+            }
+        }
+    }
+
+    fn handle_internal_event(&mut self, internal_event: H::InternalEvent) -> MaybeFrameResult {
+        if self.encoding_ids.is_some() {
+            self.send_encoding = self.handler.internal_event_encoding(&internal_event);
+        }
+        Ok(self
+            .handler
+            .handle_internal_event(internal_event, &mut self.id_sequence))
+    }
+
+    fn handle_flushed(&mut self, sequence_id: u32) -> MaybeFrameResult {
+        self.handler.on_flush(sequence_id);
+        Ok(None)
+    }
+
+    /// Close requested. Return an `Error` to close the connection, which goes away with the
+    /// reason.
+    fn handle_close(&mut self, reason: Option<String>) -> MaybeFrameResult {
+        Err(LoquiError::ConnectionCloseRequested { reason }.into())
+    }
+}
+
+/// The wall clock time of the clock, as ping timestamps carry it.
+fn unix_micros(clock: &dyn Clock) -> u64 {
+    clock.unix_time().as_micros() as u64
+}
+
+/// Rejects a request before it is delegated.
+fn service_unavailable(sequence_id: u32, message: &str) -> LoquiFrame {
+    ErrorFrame {
+        flags: 0,
+        sequence_id,
+        code: LoquiErrorCode::ServiceUnavailable as u16,
+        payload: message.as_bytes().to_vec(),
+    }
+    .into()
+}
+
+/// Readies a response the handler computed earlier to be sent for the request with this sequence
+/// id. The compressed flag is cleared since it's set again when the payload is compressed.
+fn ready_response(mut response: Response, sequence_id: u32) -> Response {
+    response.sequence_id = sequence_id;
+    response.flags &= !(Flags::Compressed as u8);
+    response
+}
+
+/// Whether the frame is one only a handler with the role sends, so receiving it is a mistake of the
+/// other side. With `TransportOptions::peer_requests` the server sends requests too.
+fn is_unexpected(role: Option<Role>, frame: &LoquiFrame, peer_requests: bool) -> bool {
+    match (role, frame) {
+        (Some(Role::Server), LoquiFrame::Response(_))
+        | (Some(Role::Client), LoquiFrame::Request(_)) => !peer_requests,
+        (Some(Role::Client), LoquiFrame::Subscribe(_)) => true,
+        _ => false,
+    }
+}
+
+/// Whether the frame only keeps the connection alive, so it doesn't keep it from being idle.
+fn is_keepalive(frame: &LoquiFrame) -> bool {
+    matches!(frame, LoquiFrame::Ping(_) | LoquiFrame::Pong(_))
+}
+
+/// The flags and payload of the frames that may be compressed.
+fn data_payload(frame: &mut LoquiFrame) -> Option<(&mut u8, &mut Vec<u8>)> {
+    match frame {
+        LoquiFrame::Request(Request { flags, payload, .. })
+        | LoquiFrame::Response(Response { flags, payload, .. })
+        | LoquiFrame::Push(Push { flags, payload, .. }) => Some((flags, payload)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::framed_io::ReaderWriter;
+    use crate::handler::{HandshakeFuture, IntoErrorPayload};
+    use crate::metrics::NoopMetrics;
+    use crate::rate_limiter::RateLimit;
+    use crate::spawner::{Task, TokioSpawn};
+    use crate::transport_options::TransportOptions;
+    use bytesize::ByteSize;
+    use futures::channel::mpsc::UnboundedReceiver;
+    use futures::future::pending;
+    use futures::stream::{self, iter};
+    use futures::StreamExt;
+    use loqui_protocol::frames::{Frame, Hello, Request};
+    use loqui_protocol::{is_stream_end, is_streaming};
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::sync::Mutex;
+    use tokio::net::TcpStream;
+    use tokio::runtime::Runtime;
+    use tokio::time::timeout;
+
+    #[derive(Default)]
+    struct TestHandler {
+        rtts: Arc<Mutex<Vec<Duration>>>,
+        clock_skews: Vec<ClockSkew>,
+        go_aways: Arc<Mutex<Vec<GoAway>>>,
+        transport_options: TransportOptions,
+        structured_errors: bool,
+        /// Requests with these sequence ids are rejected as overloaded.
+        rejected: Vec<u32>,
+        /// Requests with these sequence ids panic while computing their response.
+        panics: Vec<u32>,
+        /// Requests with these sequence ids get a streamed response of two payloads.
+        streams: Vec<u32>,
+        /// Requests with these sequence ids are answered from a cache, as cached for another
+        /// request.
+        cached: Vec<u32>,
+        cancels: Arc<Mutex<Vec<u32>>>,
+        states: Arc<Mutex<Vec<(ConnectionState, ConnectionState)>>>,
+        push_acks: Vec<u32>,
+        peer_half_closes: usize,
+        flushed: Vec<u32>,
+        ping_token: Option<u32>,
+        /// Returned from `before_send` for frames with this opcode. Others are sent.
+        send_decision: Option<(u8, SendDecision)>,
+        slow_consumers: Vec<(usize, Duration)>,
+        renegotiated: Vec<&'static str>,
+        /// Subscriptions with these ids are published two payloads, then kept open. Others are
+        /// rejected.
+        published: Vec<u32>,
+        /// This side is subscribed with these ids.
+        subscribed: Vec<u32>,
+        subscription_pushes: Vec<(u32, Vec<u8>)>,
+        unsubscribes: Vec<u32>,
+        cancelled_all: usize,
+        /// Returned from `on_ping_timeout`. Closes when unset.
+        timeout_action: Option<TimeoutAction>,
+        ping_timeouts: usize,
+        close_reasons: Vec<CloseReason>,
+    }
+
+    impl IntoErrorPayload for TestHandler {
+        fn error_payload(
+            &self,
+            error: &Error,
+            encoding: &'static str,
+        ) -> Option<(LoquiErrorCode, Vec<u8>)> {
+            if !self.structured_errors {
+                return None;
+            }
+            let payload = format!("{}:{}", encoding, error);
+            Some((LoquiErrorCode::InternalServerError, payload.into_bytes()))
+        }
+    }
+
+    impl Handler for TestHandler {
+        type InternalEvent = ();
+        const SEND_GO_AWAY: bool = false;
+
+        fn max_payload_size(&self) -> ByteSize {
+            ByteSize::kb(5)
+        }
+
+        fn transport_options(&self) -> &TransportOptions {
+            &self.transport_options
+        }
+
+        fn upgrade(
+            &self,
+            _tcp_stream: TcpStream,
+        ) -> Pin<Box<dyn Future<Output = Result<TcpStream, Error>> + Send>> {
+            unreachable!()
+        }
+
+        fn handshake(&mut self, _reader_writer: ReaderWriter) -> HandshakeFuture {
+            unreachable!()
+        }
+
+        fn handle_frame(&mut self, frame: DelegatedFrame, _encoding: &'static str) -> FrameOutcome {
+            match frame {
+                DelegatedFrame::Request(request)
+                    if self.rejected.contains(&request.sequence_id) =>
+                {
+                    FrameOutcome::Reject {
+                        code: LoquiErrorCode::ServiceUnavailable,
+                        message: "overloaded".to_string(),
+                    }
+                }
+                DelegatedFrame::Request(request) if self.panics.contains(&request.sequence_id) => {
+                    FrameOutcome::Respond(Box::pin(async { panic!("handler panicked") }))
+                }
+                DelegatedFrame::Request(request) if self.streams.contains(&request.sequence_id) => {
+                    let payloads = vec![Ok(b"one".to_vec()), Ok(b"two".to_vec())];
+                    FrameOutcome::Stream(Box::pin(iter(payloads)))
+                }
+                DelegatedFrame::Request(request) if self.cached.contains(&request.sequence_id) => {
+                    FrameOutcome::Ready(Response {
+                        flags: Flags::Compressed as u8,
+                        sequence_id: 1,
+                        trace_id: None,
+                        payload: b"cached".to_vec(),
+                    })
+                }
+                // Requests never finish computing.
+                DelegatedFrame::Request(_) => FrameOutcome::Respond(Box::pin(pending())),
+                _ => FrameOutcome::Ignore,
+            }
+        }
+
+        fn handle_internal_event(
+            &mut self,
+            _event: (),
+            _id_sequence: &mut IdSequence,
+        ) -> Option<LoquiFrame> {
+            None
+        }
+
+        fn ping_token(&mut self) -> Option<u32> {
+            self.ping_token
+        }
+
+        fn on_ping_received(&mut self) {}
+
+        fn observe_rtt(&mut self, rtt: Duration) {
+            self.rtts.lock().unwrap().push(rtt);
+        }
+
+        fn observe_clock_skew(&mut self, clock_skew: ClockSkew) {
+            self.clock_skews.push(clock_skew);
+        }
+
+        fn handle_go_away(&mut self, go_away: GoAway) {
+            self.go_aways.lock().unwrap().push(go_away);
+        }
+
+        fn handle_cancel(&mut self, sequence_id: u32) {
+            self.cancels.lock().unwrap().push(sequence_id);
+        }
+
+        fn on_cancel_all(&mut self) {
+            self.cancelled_all += 1;
+        }
+
+        fn handle_push_ack(&mut self, sequence_id: u32) {
+            self.push_acks.push(sequence_id);
+        }
+
+        fn on_peer_half_close(&mut self) {
+            self.peer_half_closes += 1;
+        }
+
+        fn on_flush(&mut self, sequence_id: u32) {
+            self.flushed.push(sequence_id);
+        }
+
+        fn before_send(&mut self, frame: &mut LoquiFrame) -> SendDecision {
+            match self.send_decision {
+                Some((opcode, send_decision)) if opcode == frame.opcode() => send_decision,
+                _ => SendDecision::Send,
+            }
+        }
+
+        fn on_state_change(&mut self, old: ConnectionState, new: ConnectionState) {
+            self.states.lock().unwrap().push((old, new));
+        }
+
+        fn on_slow_consumer(&mut self, depth: usize, duration: Duration) {
+            self.slow_consumers.push((depth, duration));
+        }
+
+        fn accept_encoding(&mut self, encoding: &str) -> Option<&'static str> {
+            match encoding {
+                "msgpack" => Some("msgpack"),
+                _ => None,
+            }
+        }
+
+        fn on_encoding_renegotiated(&mut self, encoding: &'static str) {
+            self.renegotiated.push(encoding);
+        }
+
+        fn on_close(&mut self, reason: CloseReason, _unflushed: Vec<LoquiFrame>) {
+            self.close_reasons.push(reason);
+        }
+
+        fn handle_subscribe(
+            &mut self,
+            subscription_id: u32,
+            _payload: Vec<u8>,
+            _encoding: &'static str,
+        ) -> SubscribeOutcome {
+            if !self.published.contains(&subscription_id) {
+                return SubscribeOutcome::Reject {
+                    code: LoquiErrorCode::BadRequest,
+                    message: "unknown topic".to_string(),
+                };
+            }
+            let payloads = iter(vec![Ok(b"one".to_vec()), Ok(b"two".to_vec())]);
+            SubscribeOutcome::Accept(Box::pin(payloads.chain(stream::pending())))
+        }
+
+        fn handle_unsubscribe(&mut self, subscription_id: u32) {
+            self.unsubscribes.push(subscription_id);
+        }
+
+        fn on_ping_timeout(&mut self) -> TimeoutAction {
+            self.ping_timeouts += 1;
+            self.timeout_action.unwrap_or(TimeoutAction::Close)
+        }
+
+        fn handle_subscription_push(&mut self, subscription_id: u32, payload: Vec<u8>) -> bool {
+            if !self.subscribed.contains(&subscription_id) {
+                return false;
+            }
+            self.subscription_pushes.push((subscription_id, payload));
+            true
+        }
+    }
+
+    fn make_event_handler() -> (EventHandler<TestHandler>, Arc<Mutex<Vec<Duration>>>) {
+        let handler = TestHandler::default();
+        let rtts = handler.rtts.clone();
+        let (self_sender, _self_rx) = Sender::new();
+        (
+            EventHandler::new(
+                self_sender,
+                handler,
+                "identity",
+                None,
+                Arc::new(NoopMetrics),
+            ),
+            rtts,
+        )
+    }
+
+    fn make_go_away() -> LoquiFrame {
+        GoAway {
+            flags: 0,
+            code: GoAwayCode::Normal.into(),
+            payload: vec![],
+        }
+        .into()
+    }
+
+    fn assert_told_to_go_away(error: Error) {
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::ToldToGoAway {
+                code: GoAwayCode::Normal,
+                ..
+            }) => {}
+            other => panic!("expected told to go away. {:?}", other),
+        }
+    }
+
+    fn make_request(sequence_id: u32) -> LoquiFrame {
+        Request {
+            trace_id: None,
+            idempotency_key: None,
+            priority: Priority::Normal,
+            timeout_ms: 0,
+            flags: 0,
+            sequence_id,
+            payload: vec![],
+        }
+        .into()
+    }
+
+    fn send_ping(event_handler: &mut EventHandler<TestHandler>) -> Ping {
+        match event_handler.handle_event(Event::Ping) {
+            Ok(Some(LoquiFrame::Ping(ping))) => ping,
+            other => panic!("ping not sent. {:?}", other),
+        }
+    }
+
+    fn receive_pong(event_handler: &mut EventHandler<TestHandler>, sequence_id: u32) {
+        let pong = Pong {
+            flags: 0,
+            sequence_id,
+            token: None,
+            timestamps: None,
+        };
+        let result = event_handler.handle_event(Event::SocketReceive(pong.into()));
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn it_observes_rtt() {
+        let (mut event_handler, rtts) = make_event_handler();
+        let ping = send_ping(&mut event_handler);
+        receive_pong(&mut event_handler, ping.sequence_id);
+        assert_eq!(rtts.lock().unwrap().len(), 1);
+
+        // The next ping can be sent once the pong arrived.
+        let ping = send_ping(&mut event_handler);
+        receive_pong(&mut event_handler, ping.sequence_id);
+        assert_eq!(rtts.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn it_ignores_unknown_pong() {
+        let (mut event_handler, rtts) = make_event_handler();
+        let ping = send_ping(&mut event_handler);
+        receive_pong(&mut event_handler, ping.sequence_id + 100);
+        assert!(rtts.lock().unwrap().is_empty());
+        assert!(event_handler.handle_event(Event::Ping).is_err());
+    }
+
+    #[test]
+    fn it_ignores_unknown_frames_unless_mandatory() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let unknown = |flags| UnknownFrame {
+            opcode: 42,
+            flags,
+            payload: b"new".to_vec(),
+        };
+        let result = event_handler.handle_event(Event::SocketReceive(unknown(0).into()));
+        assert!(result.unwrap().is_none());
+
+        let error = event_handler
+            .handle_event(Event::SocketReceive(unknown(Flags::MANDATORY).into()))
+            .unwrap_err();
+        let error = error.downcast_ref::<LoquiError>().unwrap();
+        assert!(matches!(
+            error,
+            LoquiError::UnknownMandatoryFrame { opcode: 42 }
+        ));
+        assert_eq!(error.code(), LoquiErrorCode::InvalidOpcode);
+    }
+
+    #[test]
+    fn it_validates_echoed_ping_tokens() {
+        let (mut event_handler, rtts) = make_event_handler();
+        event_handler.handler.ping_token = Some(42);
+        let ping = send_ping(&mut event_handler);
+        assert_eq!(ping.token, Some(42));
+        let pong = Pong {
+            flags: ping.flags,
+            sequence_id: ping.sequence_id,
+            token: Some(42),
+            timestamps: None,
+        };
+        let result = event_handler.handle_event(Event::SocketReceive(pong.into()));
+        assert!(result.unwrap().is_none());
+        assert_eq!(rtts.lock().unwrap().len(), 1);
+
+        let ping = send_ping(&mut event_handler);
+        let pong = Pong {
+            flags: 0,
+            sequence_id: ping.sequence_id,
+            token: None,
+            timestamps: None,
+        };
+        let error = event_handler
+            .handle_event(Event::SocketReceive(pong.into()))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::PingTokenMismatch {
+                expected: Some(42),
+                actual: None,
+            })
+        ));
+    }
+
+    #[test]
+    fn it_echoes_ping_tokens() {
+        let (mut event_handler, clock) = make_event_handler_at(Duration::from_secs(1_000));
+        for token in [None, Some(7)].iter() {
+            clock.advance(Duration::from_secs(1));
+            let ping = Ping {
+                flags: 0,
+                sequence_id: 1,
+                token: *token,
+                sent_at: None,
+            };
+            match event_handler.handle_event(Event::SocketReceive(ping.into())) {
+                Ok(Some(LoquiFrame::Pong(pong))) => assert_eq!(pong.token, *token),
+                other => panic!("pong not sent. {:?}", other),
+            }
+        }
+    }
+
+    /// An event handler that coalesces pongs within a 10ms window.
+    fn make_coalescing_handler(clock: Arc<ManualClock>) -> EventHandler<TestHandler> {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock,
+                pong_coalescing_window: Some(Duration::from_millis(10)),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        )
+    }
+
+    #[test]
+    fn it_coalesces_pongs_to_pings_within_the_window() {
+        let clock = Arc::new(ManualClock::new());
+        let mut event_handler = make_coalescing_handler(clock.clone());
+        let ping = |sequence_id| {
+            let ping = Ping {
+                flags: 0,
+                sequence_id,
+                token: Some(sequence_id),
+                sent_at: None,
+            };
+            Event::SocketReceive(ping.into())
+        };
+        Runtime::new().unwrap().block_on(async move {
+            assert!(matches!(
+                event_handler.handle_event(ping(1)),
+                Ok(Some(LoquiFrame::Pong(_)))
+            ));
+            assert!(event_handler.handle_event(ping(2)).unwrap().is_none());
+            assert!(event_handler.handle_event(ping(3)).unwrap().is_none());
+
+            clock.advance(Duration::from_millis(10));
+            match event_handler.handle_event(Event::PongDue) {
+                Ok(Some(LoquiFrame::Pong(pong))) => {
+                    assert_eq!(pong.sequence_id, 3);
+                    assert_eq!(pong.token, Some(3));
+                }
+                other => panic!("coalesced pong not sent. {:?}", other),
+            }
+            assert!(event_handler
+                .handle_event(Event::PongDue)
+                .unwrap()
+                .is_none());
+
+            // A peer pinging slower than the window is answered right away.
+            clock.advance(Duration::from_millis(10));
+            assert!(matches!(
+                event_handler.handle_event(ping(4)),
+                Ok(Some(LoquiFrame::Pong(_)))
+            ));
+        });
+    }
+
+    /// An event handler on a clock stopped at the wall clock time.
+    fn make_event_handler_at(unix_time: Duration) -> (EventHandler<TestHandler>, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        clock.set_unix_time(unix_time);
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock: clock.clone(),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        (event_handler, clock)
+    }
+
+    #[test]
+    fn it_estimates_the_clock_skew_from_timestamped_pongs() {
+        let (mut event_handler, clock) = make_event_handler_at(Duration::from_secs(1_000));
+        // Pings are plain until the handshake settled on timestamps.
+        let ping = send_ping(&mut event_handler);
+        assert_eq!(ping.sent_at, None);
+        receive_pong(&mut event_handler, ping.sequence_id);
+
+        event_handler.set_ping_timestamps(true);
+        let ping = send_ping(&mut event_handler);
+        assert_eq!(ping.sent_at, Some(1_000_000_000));
+        clock.advance(Duration::from_millis(20));
+        // The ping took 10ms to arrive, at which point the other side's clock read 15ms later.
+        let pong = Pong {
+            flags: 0,
+            sequence_id: ping.sequence_id,
+            token: None,
+            timestamps: Some(PongTimestamps {
+                ping_sent_at: 1_000_000_000,
+                received_at: 1_000_015_000,
+            }),
+        };
+        let result = event_handler.handle_event(Event::SocketReceive(pong.into()));
+        assert!(result.unwrap().is_none());
+        assert_eq!(
+            event_handler.handler.clock_skews,
+            vec![ClockSkew {
+                one_way_delay: Duration::from_millis(10),
+                offset_micros: 5_000,
+            }]
+        );
+    }
+
+    #[test]
+    fn it_answers_timestamped_pings() {
+        let (mut event_handler, _clock) = make_event_handler_at(Duration::from_secs(2_000));
+        let ping = Ping {
+            flags: Flags::TIMESTAMPS,
+            sequence_id: 1,
+            token: None,
+            sent_at: Some(7),
+        };
+        match event_handler.handle_event(Event::SocketReceive(ping.into())) {
+            Ok(Some(LoquiFrame::Pong(pong))) => assert_eq!(
+                pong.timestamps,
+                Some(PongTimestamps {
+                    ping_sent_at: 7,
+                    received_at: 2_000_000_000,
+                })
+            ),
+            other => panic!("pong not sent. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_appends_checksums_to_data_frames() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        event_handler.set_checksums(true);
+        let response = Response {
+            flags: 0,
+            sequence_id: 1,
+            trace_id: None,
+            payload: b"hello".to_vec(),
+        };
+        match event_handler.handle_event(Event::StreamItem(response)) {
+            Ok(Some(LoquiFrame::Response(response))) => {
+                let mut payload = b"hello".to_vec();
+                payload.extend_from_slice(&crc32fast::hash(b"hello").to_be_bytes());
+                assert_eq!(response.payload, payload);
+            }
+            other => panic!("response not sent. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_detects_a_bit_flip_in_the_payload() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        event_handler.set_checksums(true);
+        let push = |payload: &[u8]| {
+            let mut payload = payload.to_vec();
+            payload.extend_from_slice(&crc32fast::hash(b"hello").to_be_bytes());
+            Push {
+                flags: 0,
+                sequence_id: None,
+                subscription_id: None,
+                payload,
+            }
+        };
+        let result = event_handler.handle_event(Event::SocketReceive(push(b"hello").into()));
+        assert!(result.unwrap().is_none());
+
+        // 'h' with its lowest bit flipped.
+        let error = event_handler
+            .handle_event(Event::SocketReceive(push(b"iello").into()))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn it_times_out_without_pong() {
+        let (mut event_handler, rtts) = make_event_handler();
+        send_ping(&mut event_handler);
+        let error = event_handler.handle_event(Event::Ping).unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::PingTimeout) => {}
+            other => panic!("expected ping timeout. {:?}", other),
+        }
+        assert!(rtts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_times_out_slow_requests() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                handler_timeout: Some(Duration::from_millis(10)),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+
+        let frame = Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(7)));
+            assert!(result.unwrap().is_none());
+            let event = self_rx.next().await.expect("no response");
+            event_handler.handle_event(event).unwrap()
+        });
+        match frame {
+            Some(LoquiFrame::Error(error)) => {
+                assert_eq!(error.sequence_id, 7);
+                assert_eq!(error.code, LoquiErrorCode::RequestTimeout as u16);
+            }
+            other => panic!("expected error frame. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_times_out_requests_with_the_timeout_they_asked_for() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                handler_timeout: Some(Duration::from_secs(5)),
+                max_request_timeout: Some(Duration::from_secs(30)),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        assert_eq!(
+            event_handler.handler_timeout(0),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            event_handler.handler_timeout(20),
+            Some(Duration::from_millis(20))
+        );
+        // Clamped to the maximum.
+        assert_eq!(
+            event_handler.handler_timeout(u32::MAX),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn it_goes_away_immediately_without_in_flight_requests() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let error = event_handler
+            .handle_event(Event::SocketReceive(make_go_away()))
+            .unwrap_err();
+        assert_told_to_go_away(error);
+        assert_eq!(event_handler.handler.go_aways.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_surfaces_the_reason_of_a_go_away() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let go_away = GoAway {
+            flags: 0,
+            code: GoAwayCode::Shutdown.into(),
+            payload: b"deploying".to_vec(),
+        };
+        let error = event_handler
+            .handle_event(Event::SocketReceive(go_away.into()))
+            .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::ToldToGoAway {
+                code: GoAwayCode::Shutdown,
+                reason: Some(reason),
+                ..
+            }) => assert_eq!(reason, "deploying"),
+            other => panic!("expected told to go away. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_drains_in_flight_requests_on_go_away() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(1)));
+            assert!(result.unwrap().is_none());
+
+            let result = event_handler.handle_event(Event::SocketReceive(make_go_away()));
+            assert!(result.unwrap().is_none());
+            assert!(event_handler.drain_complete().is_none());
+            assert_eq!(event_handler.handler.go_aways.lock().unwrap().len(), 1);
+
+            // New requests are ignored while draining.
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(2)));
+            assert!(result.unwrap().is_none());
+
+            let response = Response {
+                trace_id: None,
+                flags: 0,
+                sequence_id: 1,
+                payload: vec![],
+            };
+            let frame = event_handler
+                .handle_event(Event::ResponseComplete(
+                    Ok(response),
+                    RequestTiming::default(),
+                ))
+                .unwrap();
+            assert!(matches!(frame, Some(LoquiFrame::Response(_))));
+            assert_told_to_go_away(event_handler.drain_complete().expect("not drained"));
+        });
+    }
+
+    #[test]
+    fn it_sends_go_away_before_shutting_down() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(1)));
+            assert!(result.unwrap().is_none());
+
+            match event_handler.handle_event(Event::GracefulShutdown(GoAwayCode::Shutdown)) {
+                Ok(Some(LoquiFrame::GoAway(go_away))) => {
+                    assert_eq!(GoAwayCode::from(go_away.code), GoAwayCode::Shutdown)
+                }
+                other => panic!("go away not sent. {:?}", other),
+            }
+            assert_eq!(event_handler.state, ConnectionState::Draining);
+            assert!(event_handler.is_shutting_down());
+            assert!(event_handler.drain_complete().is_none());
+
+            // Shutting down again or new requests send nothing.
+            let result = event_handler.handle_event(Event::GracefulShutdown(GoAwayCode::Normal));
+            assert!(result.unwrap().is_none());
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(2)));
+            assert!(result.unwrap().is_none());
+
+            let response = Response {
+                trace_id: None,
+                flags: 0,
+                sequence_id: 1,
+                payload: vec![],
+            };
+            let frame = event_handler
+                .handle_event(Event::ResponseComplete(
+                    Ok(response),
+                    RequestTiming::default(),
+                ))
+                .unwrap();
+            assert!(matches!(frame, Some(LoquiFrame::Response(_))));
+            let error = event_handler.drain_complete().expect("not drained");
+            assert!(matches!(
+                error.downcast_ref::<LoquiError>(),
+                Some(LoquiError::ShutDown {
+                    code: GoAwayCode::Shutdown
+                })
+            ));
+        });
+    }
+
+    #[test]
+    fn it_half_closes_with_a_flagged_ping() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let ping = match event_handler.handle_event(Event::HalfClose) {
+            Ok(Some(LoquiFrame::Ping(ping))) => ping,
+            other => panic!("half close not sent. {:?}", other),
+        };
+        assert!(is_half_closed(ping.flags));
+        // Half closing again doesn't send anything.
+        assert!(event_handler
+            .handle_event(Event::HalfClose)
+            .unwrap()
+            .is_none());
+
+        // Its pong is handled like any other.
+        receive_pong(&mut event_handler, ping.sequence_id);
+        assert!(event_handler.in_flight_pings.is_empty());
+        assert!(event_handler.half_close_complete().is_none());
+    }
+
+    #[test]
+    fn it_closes_once_half_closed_peer_is_responded_to() {
+        let (mut event_handler, clock) = make_event_handler_at(Duration::from_secs(1_000));
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(1)));
+            assert!(result.unwrap().is_none());
+
+            let half_close = Ping {
+                flags: Flags::HalfClosed as u8,
+                sequence_id: 100,
+                token: None,
+                sent_at: None,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(half_close.into()));
+            assert!(matches!(result, Ok(Some(LoquiFrame::Pong(_)))));
+            assert_eq!(event_handler.handler.peer_half_closes, 1);
+            assert!(event_handler.half_close_complete().is_none());
+
+            // Pings are still answered and sent while half closed.
+            clock.advance(Duration::from_secs(1));
+            let ping = Ping {
+                flags: 0,
+                sequence_id: 101,
+                token: None,
+                sent_at: None,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(ping.into()));
+            assert!(matches!(result, Ok(Some(LoquiFrame::Pong(_)))));
+            let ping = send_ping(&mut event_handler);
+            receive_pong(&mut event_handler, ping.sequence_id);
+
+            let response = Response {
+                trace_id: None,
+                flags: 0,
+                sequence_id: 1,
+                payload: vec![],
+            };
+            let frame = event_handler
+                .handle_event(Event::ResponseComplete(
+                    Ok(response),
+                    RequestTiming::default(),
+                ))
+                .unwrap();
+            assert!(matches!(frame, Some(LoquiFrame::Response(_))));
+            let error = event_handler
+                .half_close_complete()
+                .expect("not closed after half close");
+            assert!(matches!(
+                error.downcast_ref::<LoquiError>(),
+                Some(LoquiError::PeerHalfClosed)
+            ));
+        });
+    }
+
+    #[test]
+    fn it_reports_flushed_frames() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let result = event_handler.handle_event(Event::Flushed(7));
+        assert!(result.unwrap().is_none());
+        assert_eq!(event_handler.handler.flushed, vec![7]);
+    }
+
+    #[test]
+    fn it_closes_when_drain_times_out() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                drain_timeout: Duration::from_millis(10),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+
+        let result = Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(1)));
+            assert!(result.unwrap().is_none());
+            let result = event_handler.handle_event(Event::SocketReceive(make_go_away()));
+            assert!(result.unwrap().is_none());
+            let event = self_rx.next().await.expect("no drain timeout");
+            event_handler.handle_event(event)
+        });
+        assert_told_to_go_away(result.unwrap_err());
+    }
+
+    fn complete_with_error(event_handler: &mut EventHandler<TestHandler>) -> ErrorFrame {
+        // Pretend a request was delegated so the in flight count balances.
+        event_handler.in_flight_requests += 1;
+        let result = Err((LoquiError::RequestTimeout.into(), 3));
+        match event_handler.handle_event(Event::ResponseComplete(result, RequestTiming::default()))
+        {
+            Ok(Some(LoquiFrame::Error(error))) => error,
+            other => panic!("expected error frame. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_sends_error_message_by_default() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let error = complete_with_error(&mut event_handler);
+        assert_eq!(error.sequence_id, 3);
+        assert_eq!(error.code, LoquiErrorCode::RequestTimeout as u16);
+        assert_eq!(error.payload, b"\"Request timeout.\"".to_vec());
+    }
+
+    #[test]
+    fn it_sends_structured_error_payload() {
+        let handler = TestHandler {
+            structured_errors: true,
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let error = complete_with_error(&mut event_handler);
+        assert_eq!(error.sequence_id, 3);
+        assert_eq!(error.code, LoquiErrorCode::InternalServerError as u16);
+        assert_eq!(error.payload, b"identity:Request timeout.".to_vec());
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingMetrics {
+        received: Mutex<HashMap<u8, usize>>,
+        sent: Mutex<HashMap<u8, usize>>,
+        in_flight: Mutex<Vec<usize>>,
+        timed: Mutex<Vec<u32>>,
+        payload_sizes: Mutex<Vec<(u8, usize)>>,
+        compressions: Mutex<Vec<(u8, usize, usize)>>,
+    }
+
+    impl Metrics for CountingMetrics {
+        fn frame_received(&self, opcode: u8) {
+            *self.received.lock().unwrap().entry(opcode).or_default() += 1;
+        }
+
+        fn frame_sent(&self, opcode: u8) {
+            *self.sent.lock().unwrap().entry(opcode).or_default() += 1;
+        }
+
+        fn in_flight_requests(&self, count: usize) {
+            self.in_flight.lock().unwrap().push(count);
+        }
+
+        fn request_timing(&self, sequence_id: u32, _timing: &RequestTiming) {
+            self.timed.lock().unwrap().push(sequence_id);
+        }
+
+        fn observe_payload_size(&self, opcode: u8, bytes: usize) {
+            self.payload_sizes.lock().unwrap().push((opcode, bytes));
+        }
+
+        fn observe_compression(
+            &self,
+            opcode: u8,
+            uncompressed_bytes: usize,
+            compressed_bytes: usize,
+        ) {
+            self.compressions
+                .lock()
+                .unwrap()
+                .push((opcode, uncompressed_bytes, compressed_bytes));
+        }
+    }
+
+    #[test]
+    fn it_observes_payload_sizes() {
+        let metrics = Arc::new(CountingMetrics::default());
+        let handler = TestHandler {
+            cached: vec![2],
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler =
+            EventHandler::new(self_sender, handler, "identity", None, metrics.clone());
+        let request = Request {
+            trace_id: None,
+            idempotency_key: None,
+            priority: Priority::Normal,
+            timeout_ms: 0,
+            flags: 0,
+            sequence_id: 2,
+            payload: b"hello".to_vec(),
+        };
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(request.into()));
+            assert!(matches!(result, Ok(Some(LoquiFrame::Response(_)))));
+            // Keepalives carry no payload to observe.
+            send_ping(&mut event_handler);
+        });
+        assert_eq!(
+            *metrics.payload_sizes.lock().unwrap(),
+            vec![(Request::OPCODE, 5), (Response::OPCODE, 6)]
+        );
+    }
+
+    #[test]
+    fn it_records_request_timing() {
+        let metrics = Arc::new(CountingMetrics::default());
+        let handler = TestHandler {
+            streams: vec![6],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler =
+            EventHandler::new(self_sender, handler, "identity", None, metrics.clone());
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(6)));
+            assert!(result.unwrap().is_none());
+            while event_handler.in_flight_requests > 0 {
+                let event = self_rx.next().await.expect("request not completed");
+                assert!(!matches!(event, Event::ResponseComplete(Err(_), _)));
+                event_handler.handle_event(event).unwrap();
+            }
+        });
+        assert_eq!(*metrics.timed.lock().unwrap(), vec![6]);
+    }
+
+    #[test]
+    fn it_records_metrics() {
+        let metrics = Arc::new(CountingMetrics::default());
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            TestHandler::default(),
+            "identity",
+            None,
+            metrics.clone(),
+        );
+        Runtime::new().unwrap().block_on(async move {
+            let ping = send_ping(&mut event_handler);
+            receive_pong(&mut event_handler, ping.sequence_id);
+            let ping = Ping {
+                flags: 0,
+                sequence_id: 9,
+                token: None,
+                sent_at: None,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(ping.into()));
+            assert!(result.unwrap().is_some());
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(1)));
+            assert!(result.unwrap().is_none());
+            complete_with_error(&mut event_handler);
+        });
+
+        let received = metrics.received.lock().unwrap();
+        assert_eq!(received[&Pong::OPCODE], 1);
+        assert_eq!(received[&Ping::OPCODE], 1);
+        assert_eq!(received[&Request::OPCODE], 1);
+        let sent = metrics.sent.lock().unwrap();
+        assert_eq!(sent[&Ping::OPCODE], 1);
+        assert_eq!(sent[&Pong::OPCODE], 1);
+        assert_eq!(sent[&ErrorFrame::OPCODE], 1);
+        // The pending request is still in flight after the error completes.
+        assert_eq!(*metrics.in_flight.lock().unwrap(), vec![1, 1]);
+    }
+
+    #[test]
+    fn it_only_pings_when_idle() {
+        let clock = Arc::new(ManualClock::new());
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock: clock.clone(),
+                idle_ping_interval: Some(Duration::from_millis(20)),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+
+        // Traffic keeps the connection alive without pinging.
+        receive_pong(&mut event_handler, 100);
+        assert!(event_handler.handle_event(Event::Ping).unwrap().is_none());
+
+        // The other side went silent.
+        clock.advance(Duration::from_millis(25));
+        send_ping(&mut event_handler);
+        let error = event_handler.handle_event(Event::Ping).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::PingTimeout)
+        ));
+    }
+
+    #[test]
+    fn it_times_out_when_active_without_pong() {
+        let clock = Arc::new(ManualClock::new());
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock: clock.clone(),
+                idle_ping_interval: Some(Duration::from_millis(20)),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        clock.advance(Duration::from_millis(25));
+        let ping = send_ping(&mut event_handler);
+        // Frames other than the pong don't satisfy the in flight ping.
+        receive_pong(&mut event_handler, ping.sequence_id + 1);
+        assert!(event_handler.handle_event(Event::Ping).is_err());
+    }
+
+    #[test]
+    fn it_goes_away_once_idle() {
+        let clock = Arc::new(ManualClock::new());
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock: clock.clone(),
+                idle_timeout: Some(Duration::from_millis(50)),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+
+        // Pings and pongs don't keep the connection from being idle, a push does.
+        clock.advance(Duration::from_millis(30));
+        let ping = send_ping(&mut event_handler);
+        receive_pong(&mut event_handler, ping.sequence_id);
+        let push = Push {
+            flags: 0,
+            sequence_id: None,
+            subscription_id: None,
+            payload: vec![],
+        };
+        event_handler
+            .handle_event(Event::SocketReceive(push.into()))
+            .unwrap();
+        clock.advance(Duration::from_millis(30));
+        let ping = send_ping(&mut event_handler);
+        receive_pong(&mut event_handler, ping.sequence_id);
+
+        clock.advance(Duration::from_millis(30));
+        match event_handler.handle_event(Event::Ping) {
+            Ok(Some(LoquiFrame::GoAway(go_away))) => {
+                assert_eq!(GoAwayCode::from(go_away.code), GoAwayCode::Normal)
+            }
+            other => panic!("expected go away. {:?}", other),
+        }
+        match event_handler.drain_complete() {
+            Some(error) => {
+                assert!(matches!(
+                    error.downcast_ref::<LoquiError>(),
+                    Some(LoquiError::ShutDown {
+                        code: GoAwayCode::Normal
+                    })
+                ));
+                event_handler.close(&error, vec![]);
+            }
+            None => panic!("idle connection not closed"),
+        }
+        assert_eq!(
+            event_handler.handler.close_reasons,
+            vec![CloseReason::IdleTimeout]
+        );
+    }
+
+    #[test]
+    fn it_reports_a_queue_that_stays_deep() {
+        let clock = Arc::new(ManualClock::new());
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock: clock.clone(),
+                slow_consumer_depth: Some(2),
+                slow_consumer_duration: Duration::from_millis(50),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let queue_sender = self_sender.clone();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let tick = |event_handler: &mut EventHandler<TestHandler>| {
+            event_handler.handle_event(Event::Ping).unwrap();
+            // Answer the ping so the next tick doesn't time out.
+            let sequence_id = event_handler.in_flight_pings.keys().next().copied();
+            if let Some(sequence_id) = sequence_id {
+                receive_pong(event_handler, sequence_id);
+            }
+        };
+
+        queue_sender.close(None).unwrap();
+        queue_sender.close(None).unwrap();
+        tick(&mut event_handler);
+        clock.advance(Duration::from_millis(30));
+        tick(&mut event_handler);
+        assert!(event_handler.handler().slow_consumers.is_empty());
+
+        clock.advance(Duration::from_millis(30));
+        tick(&mut event_handler);
+        assert_eq!(
+            event_handler.handler().slow_consumers,
+            vec![(2, Duration::from_millis(60))]
+        );
+
+        // Draining below the depth starts over.
+        queue_sender.dequeued();
+        tick(&mut event_handler);
+        queue_sender.close(None).unwrap();
+        clock.advance(Duration::from_millis(30));
+        tick(&mut event_handler);
+        assert_eq!(event_handler.handler().slow_consumers.len(), 1);
+    }
+
+    #[test]
+    fn it_rejects_requests_without_spawning() {
+        let handler = TestHandler {
+            rejected: vec![7],
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        match event_handler.handle_event(Event::SocketReceive(make_request(7))) {
+            Ok(Some(LoquiFrame::Error(error))) => {
+                assert_eq!(error.sequence_id, 7);
+                assert_eq!(error.code, LoquiErrorCode::ServiceUnavailable as u16);
+                assert_eq!(error.payload, b"overloaded".to_vec());
+            }
+            other => panic!("request not rejected. {:?}", other),
+        }
+        assert_eq!(event_handler.in_flight_requests, 0);
+    }
+
+    #[test]
+    fn it_rejects_requests_until_the_queue_drains() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                outbound_high_water_mark: Some(3),
+                outbound_low_water_mark: 1,
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let queue_sender = self_sender.clone();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let is_rejected =
+            |event_handler: &mut EventHandler<TestHandler>, sequence_id| match event_handler
+                .handle_event(Event::SocketReceive(make_request(sequence_id)))
+            {
+                Ok(Some(LoquiFrame::Error(error))) => {
+                    assert_eq!(error.sequence_id, sequence_id);
+                    assert_eq!(error.code, LoquiErrorCode::ServiceUnavailable as u16);
+                    true
+                }
+                Ok(None) => false,
+                other => panic!("unexpected result. {:?}", other),
+            };
+
+        Runtime::new().unwrap().block_on(async move {
+            queue_sender.close(None).unwrap();
+            queue_sender.close(None).unwrap();
+            assert!(!is_rejected(&mut event_handler, 1));
+            queue_sender.close(None).unwrap();
+            assert!(is_rejected(&mut event_handler, 2));
+            // Below the high water mark but still above the low water mark.
+            queue_sender.dequeued();
+            assert_eq!(queue_sender.depth(), 2);
+            assert!(is_rejected(&mut event_handler, 3));
+            queue_sender.dequeued();
+            assert!(!is_rejected(&mut event_handler, 4));
+        });
+    }
+
+    #[test]
+    fn it_aborts_cancelled_requests() {
+        let handler = TestHandler::default();
+        let cancels = handler.cancels.clone();
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let cancel = |sequence_id| {
+            Event::SocketReceive(
+                Cancel {
+                    flags: 0,
+                    sequence_id,
+                }
+                .into(),
+            )
+        };
+
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(4)));
+            assert!(result.unwrap().is_none());
+            assert!(event_handler.handle_event(cancel(4)).unwrap().is_none());
+            match self_rx.next().await {
+                Some(event @ Event::RequestCancelled) => {
+                    assert!(event_handler.handle_event(event).unwrap().is_none())
+                }
+                other => panic!("request not cancelled. {:?}", other),
+            }
+            assert_eq!(event_handler.in_flight_requests, 0);
+
+            // Already cancelled.
+            assert!(event_handler.handle_event(cancel(4)).unwrap().is_none());
+        });
+        assert_eq!(*cancels.lock().unwrap(), vec![4]);
+    }
+
+    #[test]
+    fn it_cancels_all_in_flight_requests() {
+        let handler = TestHandler::default();
+        let cancels = handler.cancels.clone();
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+
+        Runtime::new().unwrap().block_on(async move {
+            for sequence_id in 1..=2 {
+                let result =
+                    event_handler.handle_event(Event::SocketReceive(make_request(sequence_id)));
+                assert!(result.unwrap().is_none());
+            }
+            let result = event_handler.handle_event(Event::CancelAllInflight);
+            assert!(result.unwrap().is_none());
+            assert!(event_handler.abort_handles.is_empty());
+            for _ in 1..=2 {
+                match self_rx.next().await {
+                    Some(event @ Event::RequestCancelled) => {
+                        assert!(event_handler.handle_event(event).unwrap().is_none())
+                    }
+                    other => panic!("request not cancelled. {:?}", other),
+                }
+            }
+            assert_eq!(event_handler.in_flight_requests, 0);
+
+            // Nothing left to cancel.
+            let result = event_handler.handle_event(Event::CancelAllInflight);
+            assert!(result.unwrap().is_none());
+            assert_eq!(event_handler.handler.cancelled_all, 2);
+        });
+        let mut cancels = cancels.lock().unwrap().clone();
+        cancels.sort_unstable();
+        assert_eq!(cancels, vec![1, 2]);
+    }
+
+    #[test]
+    fn it_responds_to_a_batch_once_every_request_completed() {
+        let handler = TestHandler {
+            rejected: vec![1],
+            cached: vec![2],
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let entry = |sequence_id| BatchEntry {
+            sequence_id,
+            code: 0,
+            payload: b"hello".to_vec(),
+        };
+        let request_batch = RequestBatch {
+            flags: 0,
+            sequence_id: 9,
+            entries: vec![entry(1), entry(2), entry(3)],
+        };
+
+        Runtime::new().unwrap().block_on(async move {
+            // The third request is still being computed.
+            let result = event_handler.handle_event(Event::SocketReceive(request_batch.into()));
+            assert!(result.unwrap().is_none());
+            let cancel = Cancel {
+                flags: 0,
+                sequence_id: 3,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(cancel.into()));
+            let expected = ResponseBatch {
+                flags: 0,
+                sequence_id: 9,
+                entries: vec![
+                    BatchEntry {
+                        sequence_id: 1,
+                        code: LoquiErrorCode::ServiceUnavailable as u16,
+                        payload: b"overloaded".to_vec(),
+                    },
+                    BatchEntry {
+                        sequence_id: 2,
+                        code: 0,
+                        payload: b"cached".to_vec(),
+                    },
+                ],
+            };
+            assert_eq!(result.unwrap(), Some(expected.into()));
+        });
+    }
+
+    fn make_renegotiating_event_handler(
+    ) -> (EventHandler<TestHandler>, UnboundedReceiver<Event<()>>) {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                renegotiation: true,
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, self_rx) = Sender::new();
+        let event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        (event_handler, self_rx)
+    }
+
+    fn renegotiate(sequence_id: u32, encoding: &str) -> Event<()> {
+        let renegotiate = Renegotiate {
+            flags: 0,
+            sequence_id,
+            encoding: encoding.to_string(),
+        };
+        Event::SocketReceive(renegotiate.into())
+    }
+
+    #[test]
+    fn it_switches_encoding_once_in_flight_requests_drained() {
+        let (mut event_handler, mut self_rx) = make_renegotiating_event_handler();
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(1)));
+            assert!(result.unwrap().is_none());
+            let result = event_handler.handle_event(renegotiate(5, "msgpack"));
+            assert!(result.unwrap().is_none());
+            assert_eq!(event_handler.encoding, "identity");
+
+            let cancel = Cancel {
+                flags: 0,
+                sequence_id: 1,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(cancel.into()));
+            assert!(result.unwrap().is_none());
+            let event = self_rx.next().await.expect("request not cancelled");
+            assert!(event_handler.handle_event(event).unwrap().is_none());
+            assert_eq!(event_handler.encoding, "msgpack");
+
+            let event = self_rx.next().await.expect("renegotiation not answered");
+            let expected = Renegotiate {
+                flags: 0,
+                sequence_id: 5,
+                encoding: "msgpack".to_string(),
+            };
+            assert_eq!(
+                event_handler.handle_event(event).unwrap(),
+                Some(expected.into())
+            );
+            assert_eq!(event_handler.handler.renegotiated, vec!["msgpack"]);
+        });
+    }
+
+    #[test]
+    fn it_refuses_unsupported_encodings() {
+        let (mut event_handler, _self_rx) = make_renegotiating_event_handler();
+        let expected = Renegotiate {
+            flags: 0,
+            sequence_id: 5,
+            encoding: "identity".to_string(),
+        };
+        let result = event_handler.handle_event(renegotiate(5, "xml"));
+        assert_eq!(result.unwrap(), Some(expected.into()));
+        assert_eq!(event_handler.encoding, "identity");
+    }
+
+    #[test]
+    fn it_switches_encoding_once_the_other_side_answered() {
+        let (mut event_handler, _self_rx) = make_renegotiating_event_handler();
+        let sequence_id = match event_handler.handle_event(Event::Renegotiate("msgpack")) {
+            Ok(Some(LoquiFrame::Renegotiate(renegotiate))) => {
+                assert_eq!(renegotiate.encoding, "msgpack");
+                renegotiate.sequence_id
+            }
+            other => panic!("renegotiation not sent. {:?}", other),
+        };
+        // Only one renegotiation at a time.
+        assert!(event_handler
+            .handle_event(Event::Renegotiate("json"))
+            .unwrap()
+            .is_none());
+        let result = event_handler.handle_event(renegotiate(sequence_id, "msgpack"));
+        assert!(result.unwrap().is_none());
+        assert_eq!(event_handler.encoding, "msgpack");
+        assert_eq!(
+            event_handler.handler.renegotiated,
+            vec!["identity", "msgpack"]
+        );
+    }
+
+    #[test]
+    fn it_acks_pushes_with_a_sequence_id() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let push = |sequence_id| {
+            Event::SocketReceive(
+                Push {
+                    flags: 0,
+                    sequence_id,
+                    subscription_id: None,
+                    payload: b"abc".to_vec(),
+                }
+                .into(),
+            )
+        };
+
+        let result = event_handler.handle_event(push(None));
+        assert!(result.unwrap().is_none());
+        match event_handler.handle_event(push(Some(7))) {
+            Ok(Some(LoquiFrame::PushAck(push_ack))) => assert_eq!(push_ack.sequence_id, 7),
+            other => panic!("push not acked. {:?}", other),
+        }
+
+        let push_ack = PushAck {
+            flags: 0,
+            sequence_id: 3,
+        };
+        let result = event_handler.handle_event(Event::SocketReceive(push_ack.into()));
+        assert!(result.unwrap().is_none());
+        assert_eq!(event_handler.handler.push_acks, vec![3]);
+    }
+
+    fn subscribe(subscription_id: u32) -> Event<()> {
+        let subscribe = Subscribe {
+            flags: 0,
+            sequence_id: subscription_id,
+            payload: b"topic".to_vec(),
+        };
+        Event::SocketReceive(subscribe.into())
+    }
+
+    #[test]
+    fn it_publishes_to_subscriptions_until_unsubscribed() {
+        let handler = TestHandler {
+            published: vec![5],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(subscribe(5));
+            assert!(result.unwrap().is_none());
+            let mut payloads = vec![];
+            while payloads.len() < 2 {
+                let event = self_rx.next().await.unwrap();
+                match event_handler.handle_event(event) {
+                    Ok(Some(LoquiFrame::Push(push))) => {
+                        assert_eq!(push.subscription_id, Some(5));
+                        assert_eq!(push.sequence_id, None);
+                        payloads.push(push.payload);
+                    }
+                    other => panic!("push not published. {:?}", other),
+                }
+            }
+            assert_eq!(payloads, vec![b"one".to_vec(), b"two".to_vec()]);
+
+            // Subscribing again with the same id is ignored.
+            let result = event_handler.handle_event(subscribe(5));
+            assert!(result.unwrap().is_none());
+
+            let unsubscribe = Unsubscribe {
+                flags: 0,
+                sequence_id: 5,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(unsubscribe.into()));
+            assert!(result.unwrap().is_none());
+            assert!(event_handler.subscriptions.is_empty());
+            assert_eq!(event_handler.handler.unsubscribes, vec![5]);
+        });
+    }
+
+    #[test]
+    fn it_rejects_subscriptions_the_handler_refuses() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        match event_handler.handle_event(subscribe(8)) {
+            Ok(Some(LoquiFrame::Error(error))) => {
+                assert_eq!(error.sequence_id, 8);
+                assert_eq!(error.code, LoquiErrorCode::BadRequest as u16);
+                assert_eq!(error.payload, b"unknown topic".to_vec());
+            }
+            other => panic!("subscription not rejected. {:?}", other),
+        }
+        assert!(event_handler.subscriptions.is_empty());
+    }
+
+    #[test]
+    fn it_routes_pushes_to_their_subscription() {
+        let handler = TestHandler {
+            subscribed: vec![3],
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let push = |subscription_id| {
+            Event::SocketReceive(
+                Push {
+                    flags: 0,
+                    sequence_id: None,
+                    subscription_id: Some(subscription_id),
+                    payload: b"news".to_vec(),
+                }
+                .into(),
+            )
+        };
+
+        let result = event_handler.handle_event(push(3));
+        assert!(result.unwrap().is_none());
+        assert_eq!(
+            event_handler.handler.subscription_pushes,
+            vec![(3, b"news".to_vec())]
+        );
+        // Pushes nobody is subscribed to are answered with an `Unsubscribe`.
+        match event_handler.handle_event(push(4)) {
+            Ok(Some(LoquiFrame::Unsubscribe(unsubscribe))) => {
+                assert_eq!(unsubscribe.sequence_id, 4)
+            }
+            other => panic!("not unsubscribed. {:?}", other),
+        }
+        assert_eq!(event_handler.handler.subscription_pushes.len(), 1);
+    }
+
+    /// Counts the tasks it spawns on tokio.
+    #[derive(Debug, Default)]
+    struct CountingSpawn {
+        spawned: AtomicUsize,
+    }
+
+    impl Spawn for CountingSpawn {
+        fn spawn(&self, task: Task) {
+            self.spawned.fetch_add(1, SeqCst);
+            TokioSpawn.spawn(task);
+        }
+    }
+
+    #[test]
+    fn it_runs_responses_on_the_spawner() {
+        let spawner = Arc::new(CountingSpawn::default());
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                spawner: spawner.clone(),
+                ..TransportOptions::default()
+            },
+            streams: vec![6],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(6)));
+            assert!(result.unwrap().is_none());
+            let event = self_rx.next().await.unwrap();
+            match event_handler.handle_event(event) {
+                Ok(Some(LoquiFrame::Response(response))) => assert_eq!(response.sequence_id, 6),
+                other => panic!("response not sent. {:?}", other),
+            }
+        });
+        assert_eq!(spawner.spawned.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn it_answers_retried_requests_from_the_cache() {
+        let handler = TestHandler {
+            transport_options: TransportOptions::builder()
+                .dedup_window(Duration::from_secs(10), 8)
+                .build()
+                .unwrap(),
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let idempotent_request = |sequence_id| {
+            let request = Request {
+                trace_id: None,
+                idempotency_key: Some([7; 16]),
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                flags: 0,
+                sequence_id,
+                payload: vec![],
+            };
+            Event::SocketReceive(request.into())
+        };
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(idempotent_request(1));
+            assert!(result.unwrap().is_none());
+            assert_eq!(event_handler.in_flight_requests, 1);
+            let response = Response {
+                flags: 0,
+                sequence_id: 1,
+                trace_id: None,
+                payload: b"computed".to_vec(),
+            };
+            let result = event_handler.handle_event(Event::ResponseComplete(
+                Ok(response),
+                RequestTiming::default(),
+            ));
+            assert!(matches!(result, Ok(Some(LoquiFrame::Response(_)))));
+
+            // The retry is answered without reaching the handler.
+            match event_handler.handle_event(idempotent_request(2)) {
+                Ok(Some(LoquiFrame::Response(response))) => {
+                    assert_eq!(response.sequence_id, 2);
+                    assert_eq!(response.payload, b"computed".to_vec());
+                }
+                other => panic!("not answered from the cache. {:?}", other),
+            }
+            assert_eq!(event_handler.in_flight_requests, 0);
+
+            // Requests without a key always reach the handler.
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(3)));
+            assert!(result.unwrap().is_none());
+            assert_eq!(event_handler.in_flight_requests, 1);
+        });
+    }
+
+    #[test]
+    fn it_streams_responses_in_order() {
+        let handler = TestHandler {
+            streams: vec![6],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let responses = Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(6)));
+            assert!(result.unwrap().is_none());
+            let mut responses = vec![];
+            while let Some(event) = self_rx.next().await {
+                match event_handler.handle_event(event) {
+                    Ok(Some(LoquiFrame::Response(response))) => responses.push(response),
+                    other => panic!("response not streamed. {:?}", other),
+                }
+                if event_handler.in_flight_requests == 0 {
+                    break;
+                }
+            }
+            responses
+        });
+        let payloads: Vec<&[u8]> = responses.iter().map(|r| &r.payload[..]).collect();
+        assert_eq!(payloads, vec![&b"one"[..], &b"two"[..], &b""[..]]);
+        assert!(responses.iter().all(|response| response.sequence_id == 6));
+        assert!(responses
+            .iter()
+            .all(|response| is_streaming(response.flags)));
+        let ends: Vec<bool> = responses.iter().map(|r| is_stream_end(r.flags)).collect();
+        assert_eq!(ends, vec![false, false, true]);
+    }
+
+    #[test]
+    fn it_pauses_flow_controlled_streams_until_the_window_is_updated() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                stream_window: Some(1),
+                ..TransportOptions::default()
+            },
+            streams: vec![6],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let request = Request {
+            trace_id: None,
+            idempotency_key: None,
+            priority: Priority::Normal,
+            timeout_ms: 0,
+            flags: Flags::Streaming as u8 | Flags::FlowControlled as u8,
+            sequence_id: 6,
+            payload: vec![],
+        };
+        let next_payload =
+            |event_handler: &mut EventHandler<TestHandler>, event| match event_handler
+                .handle_event(event)
+            {
+                Ok(Some(LoquiFrame::Response(response))) => response.payload,
+                other => panic!("response not streamed. {:?}", other),
+            };
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(request.into()));
+            assert!(result.unwrap().is_none());
+            let event = self_rx.next().await.expect("first response not streamed");
+            assert_eq!(next_payload(&mut event_handler, event), b"one");
+
+            // The window is exhausted.
+            let paused = timeout(Duration::from_millis(50), self_rx.next()).await;
+            assert!(paused.is_err());
+
+            let window_update = WindowUpdate {
+                flags: 0,
+                sequence_id: 6,
+                increment: 1,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(window_update.into()));
+            assert!(result.unwrap().is_none());
+            let event = self_rx.next().await.expect("stream not resumed");
+            assert_eq!(next_payload(&mut event_handler, event), b"two");
+            let event = self_rx.next().await.expect("stream not ended");
+            assert!(next_payload(&mut event_handler, event).is_empty());
+            assert!(event_handler.stream_windows.is_empty());
+        });
+    }
+
+    #[test]
+    fn it_limits_concurrent_requests() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                max_concurrent_requests: Some(2),
+                ..TransportOptions::default()
+            },
+            panics: vec![2],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(1)));
+            assert!(result.unwrap().is_none());
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(2)));
+            assert!(result.unwrap().is_none());
+            match event_handler.handle_event(Event::SocketReceive(make_request(3))) {
+                Ok(Some(LoquiFrame::Error(error))) => {
+                    assert_eq!(error.sequence_id, 3);
+                    assert_eq!(error.code, LoquiErrorCode::ServiceUnavailable as u16);
+                }
+                other => panic!("request not rejected. {:?}", other),
+            }
+
+            // The panicked request gives its slot back.
+            let event = self_rx.next().await.expect("panic not reported");
+            match event_handler.handle_event(event) {
+                Ok(Some(LoquiFrame::Error(error))) => {
+                    assert_eq!(error.sequence_id, 2);
+                    assert_eq!(error.code, LoquiErrorCode::InternalServerError as u16);
+                }
+                other => panic!("panic not sent back. {:?}", other),
+            }
+            assert_eq!(event_handler.in_flight_requests, 1);
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(4)));
+            assert!(result.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn it_queues_requests_by_priority() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                max_concurrent_requests: Some(1),
+                request_queue_depth: Some(3),
+                ..TransportOptions::default()
+            },
+            panics: vec![1],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let request = |sequence_id, priority| {
+            let request = Request {
+                trace_id: None,
+                idempotency_key: None,
+                priority,
+                timeout_ms: 0,
+                flags: 0,
+                sequence_id,
+                payload: vec![],
+            };
+            Event::SocketReceive(request.into())
+        };
+        Runtime::new().unwrap().block_on(async move {
+            for (sequence_id, priority) in [
+                (1, Priority::Normal),
+                (2, Priority::Low),
+                (3, Priority::Critical),
+                (4, Priority::Normal),
+            ]
+            .iter()
+            {
+                let result = event_handler.handle_event(request(*sequence_id, *priority));
+                assert!(result.unwrap().is_none());
+            }
+            // The queue is full.
+            match event_handler.handle_event(request(5, Priority::Critical)) {
+                Ok(Some(LoquiFrame::Error(error))) => {
+                    assert_eq!(error.sequence_id, 5);
+                    assert_eq!(error.code, LoquiErrorCode::ServiceUnavailable as u16);
+                }
+                other => panic!("request not rejected. {:?}", other),
+            }
+
+            // The panicked request's slot goes to the most urgent one.
+            let event = self_rx.next().await.expect("panic not reported");
+            assert!(event_handler.handle_event(event).unwrap().is_some());
+            assert!(event_handler.abort_handles.contains_key(&3));
+            assert_eq!(event_handler.in_flight_requests, 1);
+
+            // Cancelling a queued request only takes it out of the queue.
+            let cancel = Cancel {
+                flags: 0,
+                sequence_id: 4,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(cancel.into()));
+            assert!(result.unwrap().is_none());
+            assert!(event_handler.handler.cancels.lock().unwrap().is_empty());
+
+            let cancel = Cancel {
+                flags: 0,
+                sequence_id: 3,
+            };
+            let result = event_handler.handle_event(Event::SocketReceive(cancel.into()));
+            assert!(result.unwrap().is_none());
+            let event = self_rx.next().await.expect("cancel not reported");
+            assert!(event_handler.handle_event(event).unwrap().is_none());
+            assert!(event_handler.abort_handles.contains_key(&2));
+            assert!(event_handler.request_queue.is_empty());
+        });
+    }
+
+    #[test]
+    fn it_limits_in_flight_bytes() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                max_in_flight_bytes: Some(10),
+                ..TransportOptions::default()
+            },
+            panics: vec![2],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let request = |sequence_id, payload_bytes| {
+            let request = Request {
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                flags: 0,
+                sequence_id,
+                payload: vec![0; payload_bytes],
+            };
+            Event::SocketReceive(request.into())
+        };
+        Runtime::new().unwrap().block_on(async move {
+            assert!(event_handler.handle_event(request(1, 4)).unwrap().is_none());
+            assert!(event_handler.handle_event(request(2, 6)).unwrap().is_none());
+            match event_handler.handle_event(request(3, 1)) {
+                Ok(Some(LoquiFrame::Error(error))) => {
+                    assert_eq!(error.sequence_id, 3);
+                    assert_eq!(error.code, LoquiErrorCode::ServiceUnavailable as u16);
+                }
+                other => panic!("request not rejected. {:?}", other),
+            }
+
+            // The panicked request gives its bytes back.
+            let event = self_rx.next().await.expect("panic not reported");
+            assert!(event_handler.handle_event(event).unwrap().is_some());
+            assert_eq!(event_handler.in_flight_bytes, 4);
+            assert!(event_handler.handle_event(request(4, 6)).unwrap().is_none());
+
+            // And so do cancelled ones.
+            let cancel = Cancel {
+                flags: 0,
+                sequence_id: 1,
+            };
+            assert!(event_handler
+                .handle_event(Event::SocketReceive(cancel.into()))
+                .unwrap()
+                .is_none());
+            assert_eq!(event_handler.in_flight_bytes, 6);
+        });
+    }
+
+    #[test]
+    fn it_limits_open_sequence_ids() {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                max_concurrent_requests: Some(1),
+                request_queue_depth: Some(5),
+                max_open_sequence_ids: Some(2),
+                ..TransportOptions::default()
+            },
+            panics: vec![1],
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let request = |sequence_id| {
+            let request = Request {
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                flags: 0,
+                sequence_id,
+                payload: vec![],
+            };
+            Event::SocketReceive(request.into())
+        };
+        Runtime::new().unwrap().block_on(async move {
+            assert!(event_handler.handle_event(request(1)).unwrap().is_none());
+            assert!(event_handler.handle_event(request(2)).unwrap().is_none());
+            assert_eq!(event_handler.request_queue.len(), 1);
+            // The queue has room, but the open sequence ids don't.
+            match event_handler.handle_event(request(3)) {
+                Ok(Some(LoquiFrame::Error(error))) => {
+                    assert_eq!(error.sequence_id, 3);
+                    assert_eq!(error.code, LoquiErrorCode::ServiceUnavailable as u16);
+                }
+                other => panic!("request not rejected. {:?}", other),
+            }
+
+            // Once the first request completes, another one fits.
+            let event = self_rx.next().await.expect("panic not reported");
+            assert!(event_handler.handle_event(event).unwrap().is_some());
+            assert!(event_handler.abort_handles.contains_key(&2));
+            assert!(event_handler.handle_event(request(4)).unwrap().is_none());
+            assert_eq!(event_handler.request_queue.len(), 1);
+        });
+    }
+
+    #[test]
+    fn it_answers_health_checks() {
+        let clock = Arc::new(ManualClock::new());
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock: clock.clone(),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut other = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let (mut event_handler, _rtts) = make_event_handler();
+        let (waiter, mut health) = oneshot::channel();
+        let health_check = event_handler
+            .handle_event(Event::HealthCheck(waiter))
+            .unwrap()
+            .expect("health check not sent");
+        clock.advance(Duration::from_millis(1500));
+        let health_status = other
+            .handle_event(Event::SocketReceive(health_check))
+            .unwrap()
+            .expect("health check not answered");
+        assert!(event_handler
+            .handle_event(Event::SocketReceive(health_status))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            health.try_recv().unwrap(),
+            Some(ConnectionHealth {
+                uptime: Duration::from_millis(1500),
+                in_flight_requests: 0,
+                encoding: "identity".to_string(),
+            })
+        );
+        assert!(event_handler.health_checks.is_empty());
+    }
+
+    #[test]
+    fn it_rate_limits_frames_but_not_keepalives() {
+        let clock = Arc::new(ManualClock::new());
+        let rate_limit = RateLimit {
+            per_second: 1,
+            burst: 1,
+        };
+        let mut rate_limits = HashMap::new();
+        rate_limits.insert(Request::OPCODE, rate_limit);
+        rate_limits.insert(Push::OPCODE, rate_limit);
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock: clock.clone(),
+                rate_limits,
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let request = |sequence_id| {
+            let request = Request {
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                flags: 0,
+                sequence_id,
+                payload: vec![],
+            };
+            Event::SocketReceive(request.into())
+        };
+        let push = |sequence_id| {
+            let push = Push {
+                flags: 0,
+                sequence_id,
+                subscription_id: None,
+                payload: vec![],
+            };
+            Event::SocketReceive(push.into())
+        };
+        let ping = |sequence_id| {
+            let ping = Ping {
+                flags: 0,
+                sequence_id,
+                token: None,
+                sent_at: None,
+            };
+            Event::SocketReceive(ping.into())
+        };
+        Runtime::new().unwrap().block_on(async move {
+            assert!(event_handler.handle_event(request(1)).unwrap().is_none());
+            match event_handler.handle_event(request(2)) {
+                Ok(Some(LoquiFrame::Error(error))) => {
+                    assert_eq!(error.sequence_id, 2);
+                    assert_eq!(error.code, LoquiErrorCode::RateLimited as u16);
+                }
+                other => panic!("request not rate limited. {:?}", other),
+            }
+
+            assert!(matches!(
+                event_handler.handle_event(push(Some(3))),
+                Ok(Some(LoquiFrame::PushAck(_)))
+            ));
+            // Pushes over the limit are dropped, even acked ones.
+            assert!(event_handler.handle_event(push(Some(4))).unwrap().is_none());
+            for sequence_id in 5..10 {
+                clock.advance(Duration::from_millis(10));
+                assert!(matches!(
+                    event_handler.handle_event(ping(sequence_id)),
+                    Ok(Some(LoquiFrame::Pong(_)))
+                ));
+            }
+
+            clock.advance(Duration::from_secs(1));
+            assert!(event_handler.handle_event(request(10)).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn it_survives_handshake_frames_when_lenient() {
+        let hello = || {
+            let hello = Hello {
+                flags: 0,
+                version: 1,
+                encodings: vec![],
+                compressions: vec![],
+                ping_interval_ms: None,
+                dictionary_ids: vec![],
+                auth_token: None,
+            };
+            Event::SocketReceive(hello.into())
+        };
+        let (mut strict, _rtts) = make_event_handler();
+        assert!(strict.handle_event(hello()).is_err());
+
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                protocol_violation_policy: ProtocolViolationPolicy::Lenient,
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut lenient = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        match lenient.handle_event(hello()) {
+            Ok(Some(LoquiFrame::Error(error))) => {
+                assert_eq!(error.sequence_id, 0);
+                assert_eq!(error.code, LoquiErrorCode::ProtocolViolation as u16);
+            }
+            other => panic!("violation not reported. {:?}", other),
+        }
+        // No sequence id was used up.
+        assert_eq!(send_ping(&mut lenient).sequence_id, 1);
+    }
+
+    #[test]
+    fn it_handles_frames_only_this_side_sends_by_policy() {
+        let request = || -> LoquiFrame {
+            Request {
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                flags: 0,
+                sequence_id: 6,
+                payload: vec![],
+            }
+            .into()
+        };
+        let response: LoquiFrame = Response {
+            flags: 0,
+            sequence_id: 6,
+            trace_id: None,
+            payload: vec![],
+        }
+        .into();
+        assert!(is_unexpected(Some(Role::Client), &request(), false));
+        assert!(is_unexpected(Some(Role::Server), &response, false));
+        assert!(!is_unexpected(Some(Role::Server), &request(), false));
+        assert!(!is_unexpected(None, &response, false));
+        assert!(!is_unexpected(Some(Role::Client), &request(), true));
+        assert!(!is_unexpected(Some(Role::Server), &response, true));
+
+        let event_handler = |unexpected_frame_policy| {
+            let handler = TestHandler {
+                transport_options: TransportOptions {
+                    unexpected_frame_policy,
+                    ..TransportOptions::default()
+                },
+                ..TestHandler::default()
+            };
+            let (self_sender, _self_rx) = Sender::new();
+            EventHandler::new(
+                self_sender,
+                handler,
+                "identity",
+                None,
+                Arc::new(NoopMetrics),
+            )
+        };
+        let mut dropping = event_handler(UnexpectedFramePolicy::Drop);
+        assert!(dropping
+            .handle_unexpected_frame(request())
+            .unwrap()
+            .is_none());
+        match event_handler(UnexpectedFramePolicy::Error).handle_unexpected_frame(request()) {
+            Ok(Some(LoquiFrame::Error(error))) => {
+                assert_eq!(error.sequence_id, 6);
+                assert_eq!(error.code, LoquiErrorCode::ProtocolViolation as u16);
+            }
+            other => panic!("request not answered. {:?}", other),
+        }
+        match event_handler(UnexpectedFramePolicy::Error).handle_unexpected_frame(response) {
+            Ok(Some(LoquiFrame::Error(error))) => assert_eq!(error.sequence_id, 0),
+            other => panic!("response not answered. {:?}", other),
+        }
+        assert!(event_handler(UnexpectedFramePolicy::GoAway)
+            .handle_unexpected_frame(request())
+            .is_err());
+    }
+
+    #[test]
+    fn it_drops_frames_without_breaking_the_sequence() {
+        let handler = TestHandler {
+            send_decision: Some((Ping::OPCODE, SendDecision::Drop)),
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        assert!(event_handler.handle_event(Event::Ping).unwrap().is_none());
+        // The dropped ping isn't waited on, so the next one doesn't time out.
+        assert!(event_handler.in_flight_pings.is_empty());
+
+        event_handler.handler.send_decision = None;
+        let first = send_ping(&mut event_handler);
+        receive_pong(&mut event_handler, first.sequence_id);
+        let second = send_ping(&mut event_handler);
+        assert_eq!(second.sequence_id, first.sequence_id + 1);
+    }
+
+    #[test]
+    fn it_delays_frames() {
+        let handler = TestHandler {
+            send_decision: Some((Pong::OPCODE, SendDecision::Delay(Duration::from_millis(10)))),
+            ..TestHandler::default()
+        };
+        let (self_sender, mut self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+        let ping = Ping {
+            flags: 0,
+            sequence_id: 9,
+            token: None,
+            sent_at: None,
+        };
+        Runtime::new().unwrap().block_on(async move {
+            let result = event_handler.handle_event(Event::SocketReceive(ping.into()));
+            assert!(result.unwrap().is_none());
+            let event = self_rx.next().await.expect("no delayed frame");
+            // Delayed frames aren't vetoed again.
+            match event_handler.handle_event(event) {
+                Ok(Some(LoquiFrame::Pong(pong))) => assert_eq!(pong.sequence_id, 9),
+                other => panic!("pong not sent. {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn it_transitions_through_graceful_shutdown() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let states = event_handler.handler.states.clone();
+        Runtime::new().unwrap().block_on(async move {
+            event_handler.set_state(ConnectionState::Ready);
+            let result = event_handler.handle_event(Event::SocketReceive(make_request(1)));
+            assert!(result.unwrap().is_none());
+            let result = event_handler.handle_event(Event::SocketReceive(make_go_away()));
+            assert!(result.unwrap().is_none());
+            assert_eq!(event_handler.state, ConnectionState::Draining);
+
+            let response = Response {
+                trace_id: None,
+                flags: 0,
+                sequence_id: 1,
+                payload: vec![],
+            };
+            let result = event_handler.handle_event(Event::ResponseComplete(
+                Ok(response),
+                RequestTiming::default(),
+            ));
+            assert!(result.unwrap().is_some());
+            assert!(event_handler.drain_complete().is_some());
+            // Closing again doesn't repeat the transition.
+            event_handler.set_state(ConnectionState::Closed);
+        });
+        assert_eq!(
+            *states.lock().unwrap(),
+            vec![
+                (ConnectionState::Connecting, ConnectionState::Ready),
+                (ConnectionState::Ready, ConnectionState::Draining),
+                (ConnectionState::Draining, ConnectionState::Closed),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_times_out_pings_independently() {
+        let clock = Arc::new(ManualClock::new());
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock: clock.clone(),
+                ping_timeout: Some(Duration::from_millis(50)),
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let rtts = handler.rtts.clone();
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        );
+
+        // A pong clears the pings sent before its own too, so a late pong of one is ignored.
+        let first = send_ping(&mut event_handler);
+        let second = send_ping(&mut event_handler);
+        receive_pong(&mut event_handler, second.sequence_id);
+        assert!(event_handler.in_flight_pings.is_empty());
+        receive_pong(&mut event_handler, first.sequence_id);
+        assert_eq!(rtts.lock().unwrap().len(), 1);
+
+        send_ping(&mut event_handler);
+        clock.advance(Duration::from_millis(60));
+        let error = event_handler.handle_event(Event::Ping).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::PingTimeout)
+        ));
+    }
+
+    fn make_ping_timeout_handler(
+        clock: Arc<ManualClock>,
+        timeout_action: TimeoutAction,
+    ) -> EventHandler<TestHandler> {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                clock,
+                ping_timeout: Some(Duration::from_millis(50)),
+                ..TransportOptions::default()
+            },
+            timeout_action: Some(timeout_action),
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            None,
+            Arc::new(NoopMetrics),
+        )
+    }
+
+    #[test]
+    fn it_doesnt_time_out_pings_whose_pongs_were_coalesced() {
+        let clock = Arc::new(ManualClock::new());
+        let mut pinger = make_ping_timeout_handler(clock.clone(), TimeoutAction::Close);
+        let ponger_clock = Arc::new(ManualClock::new());
+        let mut ponger = make_coalescing_handler(ponger_clock.clone());
+        Runtime::new().unwrap().block_on(async move {
+            // Both later pings arrive within the window of the first pong.
+            let mut pongs = vec![];
+            for _ in 0..3 {
+                let ping = send_ping(&mut pinger);
+                clock.advance(Duration::from_millis(1));
+                if let Some(LoquiFrame::Pong(pong)) = ponger
+                    .handle_event(Event::SocketReceive(ping.into()))
+                    .unwrap()
+                {
+                    pongs.push(pong);
+                }
+            }
+            ponger_clock.advance(Duration::from_millis(10));
+            match ponger.handle_event(Event::PongDue) {
+                Ok(Some(LoquiFrame::Pong(pong))) => pongs.push(pong),
+                other => panic!("coalesced pong not sent. {:?}", other),
+            }
+            assert_eq!(pongs.len(), 2);
+
+            for pong in pongs {
+                let result = pinger.handle_event(Event::SocketReceive(pong.into()));
+                assert!(result.unwrap().is_none());
+            }
+            assert!(pinger.in_flight_pings.is_empty());
+            assert_eq!(pinger.handler.rtts.lock().unwrap().len(), 2);
+            clock.advance(Duration::from_millis(60));
+            send_ping(&mut pinger);
+            assert_eq!(pinger.handler.ping_timeouts, 0);
+        });
+    }
+
+    #[test]
+    fn it_extends_the_ping_timeout_only_once() {
+        let clock = Arc::new(ManualClock::new());
+        let mut event_handler = make_ping_timeout_handler(clock.clone(), TimeoutAction::Extend);
+
+        let ping = send_ping(&mut event_handler);
+        clock.advance(Duration::from_millis(60));
+        send_ping(&mut event_handler);
+        assert_eq!(event_handler.handler.ping_timeouts, 1);
+        assert!(event_handler.in_flight_pings[&ping.sequence_id].extended);
+
+        clock.advance(Duration::from_millis(60));
+        let error = event_handler.handle_event(Event::Ping).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::PingTimeout)
+        ));
+        assert_eq!(event_handler.handler.ping_timeouts, 2);
+    }
+
+    #[test]
+    fn it_answers_an_extended_ping_in_time() {
+        let clock = Arc::new(ManualClock::new());
+        let mut event_handler = make_ping_timeout_handler(clock.clone(), TimeoutAction::Extend);
+
+        let ping = send_ping(&mut event_handler);
+        clock.advance(Duration::from_millis(60));
+        let next = send_ping(&mut event_handler);
+        receive_pong(&mut event_handler, ping.sequence_id);
+        receive_pong(&mut event_handler, next.sequence_id);
+        assert!(event_handler.in_flight_pings.is_empty());
+        assert_eq!(event_handler.handler.rtts.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn it_continues_after_a_ping_timeout() {
+        let clock = Arc::new(ManualClock::new());
+        let mut event_handler = make_ping_timeout_handler(clock.clone(), TimeoutAction::Continue);
+
+        let ping = send_ping(&mut event_handler);
+        clock.advance(Duration::from_millis(60));
+        let next = send_ping(&mut event_handler);
+        assert!(!event_handler
+            .in_flight_pings
+            .contains_key(&ping.sequence_id));
+        assert!(event_handler
+            .in_flight_pings
+            .contains_key(&next.sequence_id));
+        clock.advance(Duration::from_millis(60));
+        send_ping(&mut event_handler);
+        assert_eq!(event_handler.handler.ping_timeouts, 2);
+    }
+
+    #[test]
+    fn it_keeps_pinging_without_timing_out_while_reading_is_paused() {
+        let clock = Arc::new(ManualClock::new());
+        let mut event_handler = make_ping_timeout_handler(clock.clone(), TimeoutAction::Close);
+        let read_gate = Arc::new(ReadGate::default());
+        event_handler.set_read_gate(read_gate.clone());
+
+        let ping = send_ping(&mut event_handler);
+        event_handler.handle_event(Event::PauseReading).unwrap();
+        assert!(read_gate.is_paused());
+        clock.advance(Duration::from_millis(60));
+        send_ping(&mut event_handler);
+        assert_eq!(event_handler.handler.ping_timeouts, 0);
+
+        event_handler.handle_event(Event::ResumeReading).unwrap();
+        assert!(!read_gate.is_paused());
+        assert!(event_handler.in_flight_pings.is_empty());
+        // The late pong of a ping sent before the pause is ignored.
+        receive_pong(&mut event_handler, ping.sequence_id);
+        clock.advance(Duration::from_millis(60));
+        send_ping(&mut event_handler);
+        assert_eq!(event_handler.handler.ping_timeouts, 0);
+    }
+
+    /// Reverses the payload so compression is visible without a real codec.
+    #[derive(Debug)]
+    struct ReverseCompressor;
+
+    impl Compressor for ReverseCompressor {
+        fn name(&self) -> &'static str {
+            "reverse"
+        }
+
+        fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(payload.iter().rev().copied().collect())
+        }
+
+        fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+            self.compress(payload)
+        }
+    }
+
+    fn make_compressing_event_handler(compression_min_bytes: usize) -> EventHandler<TestHandler> {
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                compression_min_bytes,
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            Some(Arc::new(ReverseCompressor)),
+            Arc::new(NoopMetrics),
+        )
+    }
+
+    fn complete_with_payload(
+        event_handler: &mut EventHandler<TestHandler>,
+        payload: Vec<u8>,
+    ) -> Response {
+        event_handler.in_flight_requests += 1;
+        let response = Response {
+            trace_id: None,
+            flags: 0,
+            sequence_id: 1,
+            payload,
+        };
+        match event_handler.handle_event(Event::ResponseComplete(
+            Ok(response),
+            RequestTiming::default(),
+        )) {
+            Ok(Some(LoquiFrame::Response(response))) => response,
+            other => panic!("expected response. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_compresses_data_frames_only() {
+        let mut event_handler = make_compressing_event_handler(0);
+        let response = complete_with_payload(&mut event_handler, b"abc".to_vec());
+        assert!(is_compressed(response.flags));
+        assert_eq!(response.payload, b"cba".to_vec());
+
+        let ping = send_ping(&mut event_handler);
+        assert!(!is_compressed(ping.flags));
+    }
+
+    #[test]
+    fn it_only_compresses_payloads_above_the_threshold() {
+        let mut event_handler = make_compressing_event_handler(1024);
+        let small = complete_with_payload(&mut event_handler, b"0123456789".to_vec());
+        assert!(!is_compressed(small.flags));
+        assert_eq!(small.payload, b"0123456789".to_vec());
+
+        let mut large = vec![0; 10 * 1024];
+        large[0] = 1;
+        let large = complete_with_payload(&mut event_handler, large);
+        assert!(is_compressed(large.flags));
+        assert_eq!(large.payload[large.payload.len() - 1], 1);
+
+        // Received frames are only decompressed when their own flag says so.
+        let push = Push {
+            flags: 0,
+            sequence_id: None,
+            subscription_id: None,
+            payload: b"abc".to_vec(),
+        };
+        let result = event_handler.handle_event(Event::SocketReceive(push.into()));
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn it_observes_compressed_sizes() {
+        let metrics = Arc::new(CountingMetrics::default());
+        let handler = TestHandler {
+            transport_options: TransportOptions {
+                compression_min_bytes: 4,
+                ..TransportOptions::default()
+            },
+            ..TestHandler::default()
+        };
+        let (self_sender, _self_rx) = Sender::new();
+        let mut event_handler = EventHandler::new(
+            self_sender,
+            handler,
+            "identity",
+            Some(Arc::new(ReverseCompressor)),
+            metrics.clone(),
+        );
+        complete_with_payload(&mut event_handler, b"abc".to_vec());
+        complete_with_payload(&mut event_handler, b"abcdef".to_vec());
+        // Only the payload that was compressed is reported.
+        assert_eq!(
+            *metrics.compressions.lock().unwrap(),
+            vec![(Response::OPCODE, 6, 6)]
+        );
+    }
+
+    #[test]
+    fn it_sends_responses_flagged_no_compress_as_is() {
+        let mut event_handler = make_compressing_event_handler(0);
+        event_handler.in_flight_requests += 1;
+        let response = Response {
+            trace_id: None,
+            flags: Flags::NO_COMPRESS,
+            sequence_id: 1,
+            payload: b"abc".to_vec(),
+        };
+        match event_handler.handle_event(Event::ResponseComplete(
+            Ok(response),
+            RequestTiming::default(),
+        )) {
+            Ok(Some(LoquiFrame::Response(response))) => {
+                assert_eq!(response.flags, 0);
+                assert_eq!(response.payload, b"abc".to_vec());
+            }
+            other => panic!("expected response. {:?}", other),
+        }
+
+        // Others are still compressed.
+        let response = complete_with_payload(&mut event_handler, b"abc".to_vec());
+        assert!(is_compressed(response.flags));
+    }
+
+    #[test]
+    fn it_sends_cached_responses_without_spawning() {
+        let mut event_handler = make_compressing_event_handler(1024);
+        event_handler.handler.cached = vec![7, 8];
+        match event_handler.handle_event(Event::SocketReceive(make_request(7))) {
+            Ok(Some(LoquiFrame::Response(response))) => {
+                assert_eq!(response.sequence_id, 7);
+                assert!(!is_compressed(response.flags));
+                assert_eq!(response.payload, b"cached".to_vec());
+            }
+            other => panic!("cached response not sent. {:?}", other),
+        }
+        assert_eq!(event_handler.in_flight_requests, 0);
+
+        event_handler
+            .handler
+            .transport_options
+            .compression_min_bytes = 0;
+        match event_handler.handle_event(Event::SocketReceive(make_request(8))) {
+            Ok(Some(LoquiFrame::Response(response))) => {
+                assert_eq!(response.sequence_id, 8);
+                assert!(is_compressed(response.flags));
+                assert_eq!(response.payload, b"dehcac".to_vec());
+            }
+            other => panic!("cached response not compressed. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_rejects_compressed_frames_without_compression() {
+        let (mut event_handler, _rtts) = make_event_handler();
+        let push = Push {
+            flags: Flags::Compressed as u8,
+            sequence_id: None,
+            subscription_id: None,
+            payload: b"abc".to_vec(),
+        };
+        let error = event_handler
+            .handle_event(Event::SocketReceive(push.into()))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::InvalidCompression)
+        ));
     }
 }
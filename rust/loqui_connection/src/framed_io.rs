@@ -1,24 +1,168 @@
-use crate::error::{LoquiError, LoquiErrorCode};
+use crate::buffer_pool::BufferPool;
+use crate::error::{GoAwayCode, LoquiError};
+use crate::wire_tap::Tap;
+use bytes::BytesMut;
 use bytesize::ByteSize;
 use failure::Error;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use futures::stream::{SplitSink, SplitStream};
+use futures::Stream;
 use loqui_protocol::{
     codec::Codec,
     error::ProtocolError,
     frames::{GoAway, LoquiFrame},
 };
+use std::io;
+use std::mem::MaybeUninit;
 use std::net::Shutdown;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
-/// Used to read frames off the tcp socket.
-pub type Reader = SplitStream<Framed<TcpStream, Codec>>;
+/// A tcp socket that counts the bytes read from and written to it, so socket errors can tell how
+/// far the connection got, and hands them to its tap.
+struct CountingStream {
+    inner: TcpStream,
+    bytes_read: Arc<AtomicU64>,
+    bytes_written: Arc<AtomicU64>,
+    tap: Tap,
+}
+
+impl AsyncRead for CountingStream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = poll {
+            self.bytes_read.fetch_add(read as u64, Relaxed);
+            self.tap.read(&buf[..read]);
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for CountingStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.bytes_written.fetch_add(written as u64, Relaxed);
+            self.tap.written(&buf[..written]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// What the writer hands the socket: a frame to encode, or frames it encoded ahead to write them
+/// together, see `Writer::feed`.
+enum Outbound {
+    Frame(LoquiFrame),
+    Encoded(BytesMut),
+}
+
+/// The loqui codec, which can also write frames encoded ahead of time. Hands the payloads of
+/// the frames it encodes to the pool, if any.
+struct SocketCodec(Codec, Option<BufferPool>);
+
+impl Decoder for SocketCodec {
+    type Item = LoquiFrame;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.0.decode(buf)
+    }
+}
+
+impl Encoder for SocketCodec {
+    type Item = Outbound;
+    type Error = Error;
+
+    fn encode(&mut self, outbound: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match outbound {
+            Outbound::Frame(frame) => {
+                let payload = self.0.encode_reusing(frame, dst);
+                reuse(&self.1, payload);
+                Ok(())
+            }
+            Outbound::Encoded(encoded) => {
+                dst.extend_from_slice(&encoded);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Returns the payload of an encoded frame to the pool, if any.
+fn reuse(buffer_pool: &Option<BufferPool>, payload: Option<Vec<u8>>) {
+    if let (Some(buffer_pool), Some(payload)) = (buffer_pool, payload) {
+        buffer_pool.give(payload);
+    }
+}
+
+/// Used to read frames off the tcp socket. IO errors are reported as `LoquiError::SocketRead`.
+pub struct Reader {
+    inner: SplitStream<Framed<CountingStream, SocketCodec>>,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl Reader {
+    /// Counts the bytes read from the socket.
+    pub(crate) fn bytes_read(&self) -> Arc<AtomicU64> {
+        self.bytes_read.clone()
+    }
+}
+
+impl Stream for Reader {
+    type Item = Result<LoquiFrame, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx).map(|item| {
+            item.map(|result| {
+                result.map_err(|error| match error.downcast::<io::Error>() {
+                    Ok(source) => LoquiError::SocketRead {
+                        source,
+                        bytes_read: self.bytes_read.load(Relaxed),
+                    }
+                    .into(),
+                    Err(error) => error,
+                })
+            })
+        })
+    }
+}
 
 /// Used to write frames to the tcp socket.
 pub struct Writer {
-    inner: SplitSink<Framed<TcpStream, Codec>, LoquiFrame>,
+    inner: SplitSink<Framed<CountingStream, SocketCodec>, Outbound>,
+    bytes_written: Arc<AtomicU64>,
+    /// Encodes fed frames into `buffer`.
+    codec: Codec,
+    /// The frames fed but not flushed yet.
+    buffer: BytesMut,
+    /// Where the payloads of fed frames go once they were encoded.
+    buffer_pool: Option<BufferPool>,
     /// If true, send a go away when the socket is closed.
     send_go_away: bool,
 }
@@ -29,27 +173,86 @@ impl Writer {
     /// # Arguments
     ///
     /// * `writer` - framed sink
+    /// * `bytes_written` - counts the bytes written to the socket
+    /// * `codec` - encodes fed frames
+    /// * `buffer_pool` - reuses the payloads of fed frames
     /// * `send_go_away` - whether or not to send a go away when the connection closes
-    pub fn new(
-        writer: SplitSink<Framed<TcpStream, Codec>, LoquiFrame>,
+    fn new(
+        writer: SplitSink<Framed<CountingStream, SocketCodec>, Outbound>,
+        bytes_written: Arc<AtomicU64>,
+        codec: Codec,
+        buffer_pool: Option<BufferPool>,
         send_go_away: bool,
     ) -> Self {
         Self {
             inner: writer,
+            bytes_written,
+            codec,
+            buffer: BytesMut::new(),
+            buffer_pool,
             send_go_away,
         }
     }
 
-    /// Tries to write a `LoquiFrame` to the socket. Returns an error if the socket has closed.
+    /// Counts the bytes written to the socket.
+    pub(crate) fn bytes_written(&self) -> Arc<AtomicU64> {
+        self.bytes_written.clone()
+    }
+
+    /// Tries to write a `LoquiFrame` to the socket. Returns `LoquiError::SocketWrite`, with the
+    /// bytes written before the failure, if the socket failed.
     pub async fn write<F: Into<LoquiFrame>>(mut self, frame: F) -> Result<Self, LoquiError> {
-        match self.inner.send(frame.into()).await {
+        self = self.flush().await?;
+        let result = self.inner.send(Outbound::Frame(frame.into())).await;
+        self.map_write_result(result)
+    }
+
+    /// Encodes a `LoquiFrame` without writing it, so the frames fed until the next `flush` go out
+    /// in a single write.
+    pub fn feed<F: Into<LoquiFrame>>(&mut self, frame: F) -> Result<(), Error> {
+        let payload = self.codec.encode_reusing(frame.into(), &mut self.buffer);
+        reuse(&self.buffer_pool, payload);
+        Ok(())
+    }
+
+    /// Writes the frames fed so far to the socket.
+    pub async fn flush(mut self) -> Result<Self, LoquiError> {
+        if self.buffer.is_empty() {
+            return Ok(self);
+        }
+        let encoded = self.buffer.split();
+        let result = self.inner.send(Outbound::Encoded(encoded)).await;
+        self.map_write_result(result)
+    }
+
+    /// The bytes of the frames fed since the last `flush`.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn map_write_result(self, result: Result<(), Error>) -> Result<Self, LoquiError> {
+        match result {
             Ok(()) => Ok(self),
-            Err(_error) => Err(LoquiError::TcpStreamClosed),
+            Err(error) => match error.downcast::<io::Error>() {
+                Ok(source) => Err(LoquiError::SocketWrite {
+                    source,
+                    bytes_written: self.bytes_written.load(Relaxed),
+                }),
+                Err(_error) => Err(LoquiError::TcpStreamClosed),
+            },
         }
     }
 
     /// Gracefully closes the socket. Optionally sends a `GoAway` frame before closing.
     pub async fn close(mut self, error: Option<&Error>, reader: Option<Reader>) {
+        // Frames fed before closing go out first.
+        self = match self.flush().await {
+            Ok(writer) => writer,
+            Err(error) => {
+                error!("Error when writing fed frames. error={:?}", error);
+                return;
+            }
+        };
         if !self.send_go_away {
             debug!("Closing. Not sending GoAway. error={:?}", error);
             return;
@@ -57,17 +260,19 @@ impl Writer {
 
         let go_away = GoAway {
             flags: 0,
-            code: go_away_code(error) as u16,
-            payload: vec![],
+            code: go_away_code(error).into(),
+            payload: go_away_reason(error).into_bytes(),
         };
         debug!("Closing. Sending GoAway. go_away={:?}", go_away);
-        match self.inner.send(go_away.into()).await {
+        match self.inner.send(Outbound::Frame(go_away.into())).await {
             Ok(()) => {
                 if let Some(reader) = reader {
-                    if let Ok(tcp_stream) =
-                        self.inner.reunite(reader).map(|framed| framed.into_inner())
+                    if let Ok(stream) = self
+                        .inner
+                        .reunite(reader.inner)
+                        .map(|framed| framed.into_inner())
                     {
-                        let _result = tcp_stream.shutdown(Shutdown::Both);
+                        let _result = stream.inner.shutdown(Shutdown::Both);
                     }
                 }
             }
@@ -83,26 +288,41 @@ impl Writer {
 /// # Arguments
 ///
 /// * `error` - optional error to determine the code from
-fn go_away_code(error: Option<&Error>) -> LoquiErrorCode {
+fn go_away_code(error: Option<&Error>) -> GoAwayCode {
     match error {
-        None => LoquiErrorCode::Normal,
+        None => GoAwayCode::Normal,
         Some(error) => {
             if let Some(protocol_error) = error.downcast_ref::<ProtocolError>() {
-                let error_code = match protocol_error {
-                    ProtocolError::InvalidOpcode { .. } => LoquiErrorCode::InvalidOpcode,
-                    ProtocolError::PayloadTooLarge { .. }
-                    | ProtocolError::InvalidPayload { .. } => LoquiErrorCode::InternalServerError,
+                let code = match protocol_error {
+                    ProtocolError::InvalidOpcode { .. } => GoAwayCode::ProtocolError,
+                    ProtocolError::PayloadTooLarge { .. } => GoAwayCode::PayloadTooLarge,
+                    ProtocolError::InvalidPayload { .. } => GoAwayCode::InternalError,
                 };
-                return error_code;
+                return code;
             }
-            if let Some(loqui_error) = error.downcast_ref::<LoquiError>() {
-                return loqui_error.code();
+            match error.downcast_ref::<LoquiError>() {
+                Some(LoquiError::Unauthorized { code }) => return *code,
+                Some(loqui_error) => return loqui_error.code().into(),
+                None => {}
             }
-            LoquiErrorCode::InternalServerError
+            GoAwayCode::InternalError
         }
     }
 }
 
+/// The reason to go away with, empty if there's none to tell.
+fn go_away_reason(error: Option<&Error>) -> String {
+    match error.and_then(|error| error.downcast_ref::<LoquiError>()) {
+        Some(LoquiError::ConnectionCloseRequested {
+            reason: Some(reason),
+        }) => reason.clone(),
+        // Both lists, so the client can tell how it's misconfigured.
+        Some(error @ LoquiError::NoCommonEncoding { .. })
+        | Some(error @ LoquiError::NoCommonCompression { .. }) => error.to_string(),
+        _ => String::new(),
+    }
+}
+
 pub struct ReaderWriter {
     pub reader: Reader,
     writer: Writer,
@@ -117,21 +337,55 @@ impl ReaderWriter {
     /// * `max_payload_size` - the maximum bytes a frame payload can be
     /// * `send_go_away` - whether or not to send a go away when the connection closes
     pub fn new(tcp_stream: TcpStream, max_payload_size: ByteSize, send_go_away: bool) -> Self {
-        let framed_socket = Framed::new(tcp_stream, Codec::new(max_payload_size));
+        Self::configured(
+            tcp_stream,
+            max_payload_size,
+            send_go_away,
+            Tap::untapped(),
+            None,
+        )
+    }
+
+    /// Like `new`, handing the bytes read from and written to the socket to `tap` and the
+    /// payloads of written frames to `buffer_pool`.
+    pub(crate) fn configured(
+        tcp_stream: TcpStream,
+        max_payload_size: ByteSize,
+        send_go_away: bool,
+        tap: Tap,
+        buffer_pool: Option<BufferPool>,
+    ) -> Self {
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        let stream = CountingStream {
+            inner: tcp_stream,
+            bytes_read: bytes_read.clone(),
+            bytes_written: bytes_written.clone(),
+            tap,
+        };
+        let framed_socket = Framed::new(
+            stream,
+            SocketCodec(Codec::new(max_payload_size), buffer_pool.clone()),
+        );
         let (writer, reader) = framed_socket.split();
-        let writer = Writer::new(writer, send_go_away);
+        let reader = Reader {
+            inner: reader,
+            bytes_read,
+        };
+        let writer = Writer::new(
+            writer,
+            bytes_written,
+            Codec::new(max_payload_size),
+            buffer_pool,
+            send_go_away,
+        );
         Self { reader, writer }
     }
 
-    /// Tries to write a `LoquiFrame` to the socket. Returns an error if the socket has closed.
+    /// Tries to write a `LoquiFrame` to the socket. Returns an error if the socket failed.
     pub async fn write<F: Into<LoquiFrame>>(mut self, frame: F) -> Result<Self, LoquiError> {
-        match self.writer.write(frame.into()).await {
-            Ok(new_writer) => {
-                self.writer = new_writer;
-                Ok(self)
-            }
-            Err(_error) => Err(LoquiError::TcpStreamClosed),
-        }
+        self.writer = self.writer.write(frame.into()).await?;
+        Ok(self)
     }
 
     /// Split this `ReaderWriter`, returning the `Reader` and `Writer` parts.
@@ -145,3 +399,213 @@ impl ReaderWriter {
         self.writer.close(error, Some(self.reader)).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loqui_protocol::frames::{Ping, Response};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+    use tokio::time::delay_for;
+
+    #[test]
+    fn it_goes_away_with_the_code_of_an_unauthorized_peer() {
+        let error = LoquiError::Unauthorized {
+            code: GoAwayCode::Unknown(4003),
+        }
+        .into();
+        assert_eq!(go_away_code(Some(&error)), GoAwayCode::Unknown(4003));
+        let error = LoquiError::PingTimeout.into();
+        assert_eq!(go_away_code(Some(&error)), GoAwayCode::PingTimeout);
+    }
+
+    #[test]
+    fn it_goes_away_with_the_reason_of_a_requested_close() {
+        let error = LoquiError::ConnectionCloseRequested {
+            reason: Some("deploying".to_string()),
+        }
+        .into();
+        assert_eq!(go_away_code(Some(&error)), GoAwayCode::Normal);
+        assert_eq!(go_away_reason(Some(&error)), "deploying");
+        let error = LoquiError::ConnectionCloseRequested { reason: None }.into();
+        assert_eq!(go_away_reason(Some(&error)), "");
+        assert_eq!(go_away_reason(None), "");
+    }
+
+    #[test]
+    fn it_writes_fed_frames_once_flushed() {
+        Runtime::new().unwrap().block_on(async {
+            let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let mut listener = TcpListener::bind(&address).await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let tcp_stream = TcpStream::connect(&address).await.unwrap();
+            let (peer, _address) = listener.accept().await.unwrap();
+
+            let (_reader, mut writer) =
+                ReaderWriter::new(tcp_stream, ByteSize::kb(1), false).split();
+            for sequence_id in 1..=2 {
+                let ping = Ping {
+                    flags: 0,
+                    sequence_id,
+                    token: None,
+                    sent_at: None,
+                };
+                writer.feed(ping).unwrap();
+            }
+            // Pings are 6 bytes.
+            assert_eq!(writer.buffered_bytes(), 12);
+            assert_eq!(writer.bytes_written.load(Relaxed), 0);
+
+            let writer = writer.flush().await.unwrap();
+            assert_eq!(writer.buffered_bytes(), 0);
+            assert_eq!(writer.bytes_written.load(Relaxed), 12);
+            let (mut peer_reader, _peer_writer) =
+                ReaderWriter::new(peer, ByteSize::kb(1), false).split();
+            for sequence_id in 1..=2 {
+                match peer_reader.next().await {
+                    Some(Ok(LoquiFrame::Ping(ping))) => assert_eq!(ping.sequence_id, sequence_id),
+                    other => panic!("ping not received. {:?}", other),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn it_returns_written_payloads_to_the_pool() {
+        Runtime::new().unwrap().block_on(async {
+            let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let mut listener = TcpListener::bind(&address).await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let tcp_stream = TcpStream::connect(&address).await.unwrap();
+            let (_peer, _address) = listener.accept().await.unwrap();
+
+            let buffer_pool = BufferPool::new(1024);
+            let reader_writer = ReaderWriter::configured(
+                tcp_stream,
+                ByteSize::kb(1),
+                false,
+                Tap::untapped(),
+                Some(buffer_pool.clone()),
+            );
+            let (_reader, mut writer) = reader_writer.split();
+            let response = |payload: Vec<u8>| Response {
+                flags: 0,
+                sequence_id: 1,
+                trace_id: None,
+                payload,
+            };
+            let payload = buffer_pool.take();
+            writer.feed(response(b"fed".to_vec())).unwrap();
+            let _writer = writer.write(response(payload)).await.unwrap();
+            // Empty buffers are dropped.
+            assert_eq!(buffer_pool.len(), 1);
+            assert_eq!(buffer_pool.take().capacity(), 3);
+        });
+    }
+
+    #[cfg(feature = "wire-tap")]
+    #[test]
+    fn it_taps_the_bytes_crossing_the_socket() {
+        use crate::transport_options::TransportOptions;
+        use crate::wire_tap::{WireDirection, WireTap};
+        use std::sync::Mutex;
+
+        #[derive(Debug, Default)]
+        struct Recorder(Mutex<Vec<(WireDirection, Vec<u8>)>>);
+
+        impl WireTap for Recorder {
+            fn observe(&self, direction: WireDirection, bytes: &[u8]) {
+                self.0.lock().unwrap().push((direction, bytes.to_vec()));
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let options = TransportOptions::builder()
+            .wire_tap(recorder.clone())
+            .build()
+            .unwrap();
+        Runtime::new().unwrap().block_on(async {
+            let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let mut listener = TcpListener::bind(&address).await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let tcp_stream = TcpStream::connect(&address).await.unwrap();
+            let (peer, _address) = listener.accept().await.unwrap();
+
+            let ping = Ping {
+                flags: 0,
+                sequence_id: 7,
+                token: None,
+                sent_at: None,
+            };
+            let tap = Tap::new(&options);
+            let reader_writer =
+                ReaderWriter::configured(tcp_stream, ByteSize::kb(1), false, tap, None);
+            let _reader_writer = reader_writer.write(ping.clone()).await.unwrap();
+            let tap = Tap::new(&options);
+            let mut peer_reader_writer =
+                ReaderWriter::configured(peer, ByteSize::kb(1), false, tap, None);
+            match peer_reader_writer.reader.next().await {
+                Some(Ok(LoquiFrame::Ping(received))) => assert_eq!(received, ping),
+                other => panic!("ping not received. {:?}", other),
+            }
+        });
+
+        let mut encoded = BytesMut::new();
+        Codec::new(ByteSize::kb(1))
+            .encode(
+                LoquiFrame::Ping(Ping {
+                    flags: 0,
+                    sequence_id: 7,
+                    token: None,
+                    sent_at: None,
+                }),
+                &mut encoded,
+            )
+            .unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                (WireDirection::Written, encoded.to_vec()),
+                (WireDirection::Read, encoded.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_counts_bytes_written_before_the_socket_failed() {
+        let error = Runtime::new().unwrap().block_on(async {
+            let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let mut listener = TcpListener::bind(&address).await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let tcp_stream = TcpStream::connect(&address).await.unwrap();
+            let (peer, _address) = listener.accept().await.unwrap();
+            drop(peer);
+
+            let mut reader_writer = ReaderWriter::new(tcp_stream, ByteSize::kb(1), false);
+            loop {
+                let ping = Ping {
+                    flags: 0,
+                    sequence_id: 1,
+                    token: None,
+                    sent_at: None,
+                };
+                match reader_writer.write(ping).await {
+                    Ok(new_reader_writer) => reader_writer = new_reader_writer,
+                    Err(error) => break error,
+                }
+                // Gives the peer's reset time to arrive.
+                delay_for(Duration::from_millis(10)).await;
+            }
+        });
+        match error {
+            LoquiError::SocketWrite { bytes_written, .. } => {
+                assert!(bytes_written > 0);
+                // Pings are 6 bytes and each is written in full or not at all.
+                assert_eq!(bytes_written % 6, 0);
+            }
+            other => panic!("expected a socket write error. {:?}", other),
+        }
+    }
+}
@@ -1,8 +1,15 @@
+use crate::close_reason::CloseReason;
+use crate::connection_tag::ConnectionTag;
+use crate::error::LoquiErrorCode;
 use crate::framed_io::ReaderWriter;
 use crate::id_sequence::IdSequence;
+use crate::transport_options::TransportOptions;
 use bytesize::ByteSize;
 use failure::Error;
-use loqui_protocol::frames::{Error as ErrorFrame, LoquiFrame, Push, Request, Response};
+use futures::Stream;
+use loqui_protocol::frames::{
+    Error as ErrorFrame, GoAway, LoquiFrame, PongTimestamps, Push, Request, Response,
+};
 use std::future::Future;
 use std::pin::Pin;
 use std::time::Duration;
@@ -18,46 +25,252 @@ pub enum DelegatedFrame {
     Error(ErrorFrame),
 }
 
+/// The lifecycle of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Upgrading and handshaking.
+    Connecting,
+    /// Handshake completed. Frames are flowing.
+    Ready,
+    /// Either side told the other to go away. In flight requests are finishing, new ones are
+    /// ignored.
+    Draining,
+    /// The connection stopped handling events.
+    Closed,
+}
+
 /// Settings negotiated from handshake.
 #[derive(Debug)]
 pub struct Ready {
     pub ping_interval: Duration,
     pub encoding: &'static str,
+    pub compression: Option<&'static str>,
+    /// The pre-shared dictionary the compression is primed with, see
+    /// `TransportOptions::dictionaries`.
+    pub dictionary_id: Option<u32>,
+    /// The protocol version the other side speaks.
+    pub peer_version: u8,
+    /// Whether `RequestBatch`es may be sent.
+    pub batches: bool,
+    /// Whether pings carry timestamps, see `TransportOptions::ping_timestamps`.
+    pub ping_timestamps: bool,
+    /// Whether data frames carry a CRC32 trailer, see `TransportOptions::checksums`.
+    pub checksums: bool,
+    /// The other encodings data frames may select by id besides `encoding`, in the order of their
+    /// ids from 1, see `TransportOptions::encoding_ids`. `None` unless both sides agreed on ids.
+    pub encodings: Option<Vec<&'static str>>,
+}
+
+impl Ready {
+    /// The settings to report to `Handler::on_handshake_complete`.
+    pub fn negotiated(&self) -> Negotiated {
+        Negotiated {
+            encoding: self.encoding,
+            compression: self.compression,
+            dictionary_id: self.dictionary_id,
+            ping_interval: self.ping_interval,
+            peer_version: self.peer_version,
+            batches: self.batches,
+            ping_timestamps: self.ping_timestamps,
+            checksums: self.checksums,
+            encodings: self.encodings.clone(),
+        }
+    }
+}
+
+/// What the two sides settled on in the `Hello`/`HelloAck` handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Negotiated {
+    pub encoding: &'static str,
+    pub compression: Option<&'static str>,
+    /// The pre-shared dictionary the compression is primed with.
+    pub dictionary_id: Option<u32>,
+    pub ping_interval: Duration,
+    /// The protocol version the other side advertised, for compatibility workarounds. Only
+    /// handshakes with a supported version complete.
+    pub peer_version: u8,
+    /// Whether the client may send `RequestBatch`es.
+    pub batches: bool,
+    /// Whether both sides timestamp their pings to estimate the clock skew.
+    pub ping_timestamps: bool,
+    /// Whether the payloads of data frames end with a CRC32 trailer.
+    pub checksums: bool,
+    /// The other encodings data frames may select by id besides `encoding`.
+    pub encodings: Option<Vec<&'static str>>,
+}
+
+/// The stats of a connection as seen by the other side, answering `Connection::health_check`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionHealth {
+    /// How long the connection has been ready.
+    pub uptime: Duration,
+    /// The requests the other side is computing.
+    pub in_flight_requests: usize,
+    /// The encoding in use.
+    pub encoding: String,
+}
+
+/// How far the other side's clock is off from ours, estimated from a timestamped `Ping` and its
+/// `Pong` assuming the delay is the same both ways. See `TransportOptions::ping_timestamps`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSkew {
+    /// Half the round trip of the ping.
+    pub one_way_delay: Duration,
+    /// How many microseconds the other side's clock is ahead of ours. Negative if it's behind.
+    pub offset_micros: i64,
+}
+
+impl ClockSkew {
+    /// Estimates the skew from the timestamps of a `Pong` and when it was received, in
+    /// microseconds since the Unix epoch.
+    pub fn estimate(timestamps: PongTimestamps, received_at: u64) -> Self {
+        let one_way_delay = received_at.saturating_sub(timestamps.ping_sent_at) / 2;
+        let offset_micros = timestamps.received_at as i64
+            - timestamps.ping_sent_at.saturating_add(one_way_delay) as i64;
+        Self {
+            one_way_delay: Duration::from_micros(one_way_delay),
+            offset_micros,
+        }
+    }
+}
+
+/// How the handshake went, for monitoring how long connections take to establish.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeTiming {
+    /// From when the connection loop started, including the upgrade, until the handshake
+    /// completed.
+    pub duration: Duration,
+    /// Whether a previous session was resumed instead of negotiating from scratch. There is no
+    /// resumption yet, so it is always `false`.
+    pub resumed: bool,
+}
+
+/// Future returned from `Handler::handshake`. Resolves to the negotiated settings or an error
+/// along with the `ReaderWriter`, if it is still usable, so a `GoAway` can be sent.
+pub type HandshakeFuture = Pin<
+    Box<dyn Future<Output = Result<(Ready, ReaderWriter), (Error, Option<ReaderWriter>)>> + Send>,
+>;
+
+/// Future returned from `Handler::handle_frame`. Resolves to the `Response` for a request, or an
+/// error along with the `sequence_id` of the request that failed.
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response, (Error, u32)>> + Send>>;
+
+/// Stream returned in `FrameOutcome::Stream`. Each payload is sent as its own `Response` for the
+/// request. An error ends the stream with an `Error` frame.
+pub type ResponseStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>, Error>> + Send>>;
+
+/// What the connection should do with a delegated frame.
+pub enum FrameOutcome {
+    /// Spawn the future and send the `Response` it resolves to back to the other side.
+    Respond(ResponseFuture),
+    /// Send an already computed `Response`, e.g. from a cache, back right away without spawning
+    /// anything. It is sent for the request's `sequence_id` and compressed like any other, so the
+    /// `sequence_id` and compressed flag it was cached with don't matter.
+    Ready(Response),
+    /// Reject a `Request` without spawning anything. An `Error` frame with the code and message
+    /// is sent back for the request's `sequence_id`.
+    Reject {
+        code: LoquiErrorCode,
+        message: String,
+    },
+    /// Send every payload of the stream back to the other side as a `Response` for the request,
+    /// in order. The responses are flagged with `Flags::Streaming` and followed by an empty one
+    /// flagged with `Flags::StreamEnd`. `handler_timeout` applies to the stream as a whole.
+    Stream(ResponseStream),
+    /// Nothing to send back.
+    Ignore,
+}
+
+/// What the connection should do with a `Subscribe`, decided by `Handler::handle_subscribe`.
+pub enum SubscribeOutcome {
+    /// Push every payload of the stream to the other side, tagged with the subscription's id,
+    /// until either side unsubscribes. The subscription ends with an `Unsubscribe` once the stream
+    /// ends or fails.
+    Accept(ResponseStream),
+    /// Answer the `Subscribe` with an `Error` frame with the code and message.
+    Reject {
+        code: LoquiErrorCode,
+        message: String,
+    },
+}
+
+/// What the connection should do once a `Pong` is overdue, decided by `Handler::on_ping_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutAction {
+    /// Close the connection with `LoquiError::PingTimeout`.
+    Close,
+    /// Give the overdue pings another `TransportOptions::ping_timeout` to be answered, e.g. to
+    /// ride out a GC pause of the other side. A ping is only extended once. The connection closes
+    /// if it is still overdue after that.
+    Extend,
+    /// Stop waiting for the overdue pings and keep the connection open.
+    Continue,
+}
+
+/// What the connection should do with an outbound frame, decided by `Handler::before_send`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendDecision {
+    /// Write the frame to the socket.
+    Send,
+    /// Don't send the frame. A dropped `Ping` no longer waits for its `Pong`.
+    Drop,
+    /// Send the frame once the duration elapsed. It isn't passed to `before_send` again.
+    Delay(Duration),
+}
+
+/// Controls how the error of a failed request is sent back to the other side in an `Error` frame.
+pub trait IntoErrorPayload {
+    /// Maps a request error to the code and payload of the `Error` frame. The payload should be
+    /// encoded with the negotiated `encoding` so the other side can decode it. Returning `None`
+    /// sends the error's message as a string.
+    fn error_payload(
+        &self,
+        _error: &Error,
+        _encoding: &'static str,
+    ) -> Option<(LoquiErrorCode, Vec<u8>)> {
+        None
+    }
+}
+
+/// Which side of a connection a `Handler` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
 }
 
 /// A trait that handles the specific functionality of a connection. The client and server each
 /// implement this.
-pub trait Handler: Send + Sync + 'static {
+pub trait Handler: IntoErrorPayload + Send + Sync + 'static {
     /// Events specific to the implementing connection handler. They will be passed through to the
     /// handle_internal_event callback.
     type InternalEvent: Send;
     // Whether or not the connection should send a GoAway frame on close.
     const SEND_GO_AWAY: bool;
+    /// Which side this is, so frames only this side sends are caught when received, see
+    /// `TransportOptions::unexpected_frame_policy`. `None` takes every frame, e.g. in tests.
+    const ROLE: Option<Role> = None;
 
     /// The maximum payload size this connection can handle.
     fn max_payload_size(&self) -> ByteSize;
+    /// Connection level settings.
+    fn transport_options(&self) -> &TransportOptions;
     /// Takes a tcp stream and completes an HTTP upgrade.
     fn upgrade(
         &self,
         tcp_stream: TcpStream,
     ) -> Pin<Box<dyn Future<Output = Result<TcpStream, Error>> + Send>>;
+    /// Called once the connection started, before the upgrade, with the id and labels its logs
+    /// and metrics are tagged with.
+    fn on_connection_start(&mut self, _connection: &ConnectionTag) {}
     /// Hello/HelloAck handshake.
-    fn handshake(
-        &mut self,
-        reader_writer: ReaderWriter,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<(Ready, ReaderWriter), (Error, Option<ReaderWriter>)>>
-                + Send,
-        >,
-    >;
-    /// Handle a single delegated frame. Optionally returns a future that resolves to a
-    /// Response. The Response will be sent back through the socket to the other side.
-    fn handle_frame(
-        &mut self,
-        frame: DelegatedFrame,
-        encoding: &'static str,
-    ) -> Option<Pin<Box<dyn Future<Output = Result<Response, (Error, u32)>> + Send>>>;
+    fn handshake(&mut self, reader_writer: ReaderWriter) -> HandshakeFuture;
+    /// Called once the handshake completed, before any other frames are handled.
+    fn on_handshake_complete(&mut self, _negotiated: &Negotiated, _timing: &HandshakeTiming) {}
+    /// Handle a single delegated frame. Returns a future that resolves to a Response, which will
+    /// be sent back through the socket to the other side, or synchronously rejects the request.
+    /// A `Response` flagged with `Flags::NO_COMPRESS` is sent uncompressed.
+    fn handle_frame(&mut self, frame: DelegatedFrame, encoding: &'static str) -> FrameOutcome;
     /// Handle internal events for this connection. Completely opaque to the connection. Optionally
     /// return a `LoquiFrame` that will be sent back through the socket to the other side.
     fn handle_internal_event(
@@ -65,8 +278,98 @@ pub trait Handler: Send + Sync + 'static {
         event: Self::InternalEvent,
         id_sequence: &mut IdSequence,
     ) -> Option<LoquiFrame>;
+    /// The encoding the payload of the frame an internal event is handled into is encoded with,
+    /// when encoding ids were negotiated, see `TransportOptions::encoding_ids`. It must be one of
+    /// the negotiated encodings. `None`, the default, is the encoding in use.
+    fn internal_event_encoding(&self, _event: &Self::InternalEvent) -> Option<&'static str> {
+        None
+    }
+    /// A token to attach to each `Ping`, e.g. a node epoch. The `Pong` must echo it or the
+    /// connection closes. `None` sends plain pings, which peers without token support understand.
+    fn ping_token(&mut self) -> Option<u32> {
+        None
+    }
     /// Periodic callback that fires whenever a ping fires.
     fn on_ping_received(&mut self);
+    /// Called with the round-trip time between sending a `Ping` and receiving its `Pong`.
+    fn observe_rtt(&mut self, _rtt: Duration) {}
+    /// Called with the clock skew estimated from each timestamped `Pong`, once both sides turned
+    /// on `TransportOptions::ping_timestamps`.
+    fn observe_clock_skew(&mut self, _clock_skew: ClockSkew) {}
+    /// Called whenever the connection moves to a new `ConnectionState`.
+    fn on_state_change(&mut self, _old: ConnectionState, _new: ConnectionState) {}
+    /// Called when the other side cancels an in flight request. Its future has been dropped, so
+    /// release anything else held on its behalf.
+    fn handle_cancel(&mut self, _sequence_id: u32) {}
+    /// Called by `Connection::cancel_all_inflight` once the requests of the other side were
+    /// aborted. Fail the requests this side is waiting on with `LoquiError::ConnectionClosing`.
+    fn on_cancel_all(&mut self) {}
+    /// Called right before a frame is written to the socket, including `Ping`s and `Pong`s. The
+    /// frame may be changed in place, e.g. to set flags. Ids were already allocated from the
+    /// `IdSequence`, so dropping a frame leaves a gap rather than reusing its id.
+    fn before_send(&mut self, _frame: &mut LoquiFrame) -> SendDecision {
+        SendDecision::Send
+    }
+    /// Called when the other side acknowledges a `Push` that was sent with a sequence id, so it
+    /// can be marked as delivered instead of retried.
+    fn handle_push_ack(&mut self, _sequence_id: u32) {}
+    /// Called when the other side sends a `GoAway`. The connection stops accepting requests and
+    /// closes once the in flight requests have drained.
+    fn handle_go_away(&mut self, _go_away: GoAway) {}
+    /// Called when the other side half closed the connection. It won't send any more requests or
+    /// pushes, and the connection closes once the in flight requests have been responded to.
+    fn on_peer_half_close(&mut self) {}
+    /// Called once a request, response, error or push was flushed to the socket, with its
+    /// sequence id, when `TransportOptions::notify_flush` is set. Responses and errors carry the
+    /// id of the request they answer, so a connection sending both ways may see an id twice.
+    fn on_flush(&mut self, _sequence_id: u32) {}
+    /// Called on each ping tick while the outbound queue has stayed at least
+    /// `TransportOptions::slow_consumer_depth` deep for `slow_consumer_duration`, with its depth
+    /// and how long it has been that deep. Purely observational, e.g. to alert before buffers
+    /// run out of memory. The connection stays open.
+    fn on_slow_consumer(&mut self, _depth: usize, _duration: Duration) {}
+    /// Called on a ping tick when a `Pong` is overdue, before the connection closes. Closes by
+    /// default.
+    fn on_ping_timeout(&mut self) -> TimeoutAction {
+        TimeoutAction::Close
+    }
+    /// Picks the encoding to switch to when the other side asks to renegotiate it, see
+    /// `TransportOptions::renegotiation`. `None` refuses, which is the default.
+    fn accept_encoding(&mut self, _encoding: &str) -> Option<&'static str> {
+        None
+    }
+    /// Called once a renegotiation completed, with the encoding in use from then on. It is the
+    /// old one when the switch was refused.
+    fn on_encoding_renegotiated(&mut self, _encoding: &'static str) {}
+    /// Called once a connection that completed its handshake closed, with why it closed and the
+    /// frames that were queued to be sent but never written, e.g. responses that completed after
+    /// the socket reset. They're as they were queued, before compression. The frames are purely
+    /// diagnostic, since the connection is gone; a frame the socket failed to write is lost with
+    /// it.
+    fn on_close(&mut self, _reason: CloseReason, _unflushed: Vec<LoquiFrame>) {}
+    /// Called when the other side subscribes, with the id it picked and the payload of its
+    /// `Subscribe`, e.g. a topic. Rejects by default.
+    fn handle_subscribe(
+        &mut self,
+        _subscription_id: u32,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> SubscribeOutcome {
+        SubscribeOutcome::Reject {
+            code: LoquiErrorCode::InvalidOpcode,
+            message: "Subscriptions aren't supported.".to_string(),
+        }
+    }
+    /// Called once a subscription ended, on the side that published to it and on the side that
+    /// subscribed, whichever ended it. Not called for subscriptions open when the connection
+    /// closed.
+    fn handle_unsubscribe(&mut self, _subscription_id: u32) {}
+    /// Called with the payload of each `Push` published to a subscription this side subscribed
+    /// to. Returns `false` if the subscription is no longer wanted, e.g. because its consumer went
+    /// away, so the other side is told to unsubscribe.
+    fn handle_subscription_push(&mut self, _subscription_id: u32, _payload: Vec<u8>) -> bool {
+        false
+    }
 }
 
 impl From<Push> for DelegatedFrame {
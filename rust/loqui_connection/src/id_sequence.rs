@@ -1,18 +1,130 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Each connection of the process takes the next epoch, so ids cached across a reconnect don't
+/// collide with the new connection's.
+static NEXT_EPOCH: AtomicU32 = AtomicU32::new(0);
+
+/// Ids of an epoch start at `epoch << EPOCH_SHIFT`, so an epoch can allocate 2^24 ids before
+/// running into the next one's. Epochs repeat every 256.
+const EPOCH_SHIFT: u32 = 24;
+
+/// Allocates the `sequence_id`s of a connection, e.g. to avoid reusing ids whose late responses
+/// may still arrive.
+pub trait IdStrategy: Send + Sync + 'static {
+    /// The next id. `0` is skipped if returned.
+    fn next(&mut self) -> u32;
+}
+
+/// Makes the `IdStrategy` of each connection, see `TransportOptions::id_strategy`.
+pub trait IdStrategyFactory: Debug + Send + Sync + 'static {
+    /// `epoch` is taken by each connection of the process once its handshake completed.
+    fn make(&self, epoch: u32) -> Box<dyn IdStrategy>;
+}
+
 /// Generates `sequence_id`s for requests.
 pub struct IdSequence {
-    next: u32,
+    strategy: Box<dyn IdStrategy>,
 }
 
 impl IdSequence {
+    pub fn new(strategy: Box<dyn IdStrategy>) -> Self {
+        Self { strategy }
+    }
+
+    /// A sequence whose ids don't overlap those of recent epochs.
+    pub fn with_epoch(epoch: u32) -> Self {
+        Self::new(Box::new(IncrementingIds::with_epoch(epoch)))
+    }
+
+    /// Takes the epoch for a new connection.
+    pub(crate) fn next_epoch() -> u32 {
+        NEXT_EPOCH.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// `0` is never used as an id, e.g. it marks protocol violations in `Error` frames.
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u32 {
+        loop {
+            let next = self.strategy.next();
+            if next != 0 {
+                return next;
+            }
+        }
+    }
+}
+
+impl Default for IdSequence {
+    fn default() -> Self {
+        Self::new(Box::new(IncrementingIds { next: 1 }))
+    }
+}
+
+/// The default `IdStrategy`. Counts up from the start of the connection's epoch, wrapping around.
+#[derive(Debug)]
+pub struct IncrementingIds {
+    next: u32,
+}
+
+impl IncrementingIds {
+    pub fn with_epoch(epoch: u32) -> Self {
+        Self {
+            next: epoch << EPOCH_SHIFT,
+        }
+    }
+}
+
+impl IdStrategy for IncrementingIds {
+    fn next(&mut self) -> u32 {
         let next = self.next;
         self.next = self.next.wrapping_add(1);
         next
     }
 }
 
-impl Default for IdSequence {
-    fn default() -> Self {
-        Self { next: 1 }
+/// Makes an `IncrementingIds` for each connection.
+#[derive(Debug, Default)]
+pub struct IncrementingIdsFactory;
+
+impl IdStrategyFactory for IncrementingIdsFactory {
+    fn make(&self, epoch: u32) -> Box<dyn IdStrategy> {
+        Box::new(IncrementingIds::with_epoch(epoch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_starts_epochs_apart() {
+        assert_eq!(IdSequence::with_epoch(0).next(), 1);
+        assert_eq!(IdSequence::with_epoch(1).next(), 1 << 24);
+        assert_eq!(IdSequence::with_epoch(2).next(), 2 << 24);
+        // Epochs repeat.
+        assert_eq!(IdSequence::with_epoch(256).next(), 1);
+    }
+
+    #[test]
+    fn it_never_uses_zero() {
+        let mut id_sequence = IdSequence::new(Box::new(IncrementingIds { next: u32::MAX }));
+        assert_eq!(id_sequence.next(), u32::MAX);
+        assert_eq!(id_sequence.next(), 1);
+    }
+
+    /// Hands out the ids it was given, in order.
+    struct ScriptedIds(Vec<u32>);
+
+    impl IdStrategy for ScriptedIds {
+        fn next(&mut self) -> u32 {
+            self.0.remove(0)
+        }
+    }
+
+    #[test]
+    fn it_allocates_with_the_strategy() {
+        let mut id_sequence = IdSequence::new(Box::new(ScriptedIds(vec![7, 0, 3])));
+        assert_eq!(id_sequence.next(), 7);
+        assert_eq!(id_sequence.next(), 3);
     }
 }
@@ -1,3 +1,6 @@
+// `failure_derive` generates impls inside anonymous consts.
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate log;
 
@@ -5,32 +8,74 @@ use std::future::Future;
 use std::io::{Error as IoError, ErrorKind};
 use tokio::time::{timeout_at as tokio_timeout_at, Instant};
 
+mod buffer_pool;
+mod clock;
+mod close_reason;
+pub mod compressor;
+pub mod compressors;
 mod connection;
+mod connection_tag;
+mod dedup_cache;
+pub mod encoder;
+pub mod encoders;
+mod encoding_ids;
+mod encoding_version;
 mod error;
 mod event_handler;
 mod framed_io;
 mod id_sequence;
+mod lifecycle;
+mod metrics;
+mod pending_batches;
+mod rate_limiter;
+mod read_gate;
+mod request_queue;
 mod select_break;
 mod sender;
+mod spans;
+mod spawner;
+mod stats;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+mod transport_options;
+mod wire_tap;
+mod write_watchdog;
 
 pub mod handler;
 
+pub use buffer_pool::BufferPool;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use close_reason::CloseReason;
+pub use compressor::{Compressor, DictionaryRegistry};
 pub use connection::Connection;
-pub use error::{LoquiError, LoquiErrorCode};
+pub use connection_tag::ConnectionTag;
+pub use encoder::{Encoder, Factory, TimedEncoder};
+pub use encoding_version::{negotiate_encoding, split_encoding_version};
+pub use error::{GoAwayCode, LoquiError, LoquiErrorCode};
 pub use framed_io::ReaderWriter;
-pub use id_sequence::IdSequence;
+pub use id_sequence::{
+    IdSequence, IdStrategy, IdStrategyFactory, IncrementingIds, IncrementingIdsFactory,
+};
+pub use metrics::{Metrics, NoopMetrics, RequestTiming};
+pub use rate_limiter::RateLimit;
+pub use spawner::{Spawn, Task, TokioSpawn};
+pub use stats::ConnectionStats;
+pub use transport_options::{
+    ProtocolViolationPolicy, TransportOptions, TransportOptionsBuilder, UnexpectedFramePolicy,
+    CONNECTION_REQUEST_FLAGS,
+};
+#[cfg(feature = "wire-tap")]
+pub use wire_tap::{WireDirection, WireTap};
 
 pub fn find_encoding<S: AsRef<str>>(
     encoding: S,
     supported_encodings: &'static [&'static str],
 ) -> Option<&'static str> {
     let encoding = encoding.as_ref();
-    for supported_encoding in supported_encodings {
-        if encoding == *supported_encoding {
-            return Some(supported_encoding);
-        }
-    }
-    None
+    supported_encodings
+        .iter()
+        .find(|supported_encoding| encoding == **supported_encoding)
+        .copied()
 }
 
 /// Utility function for timing out a future without having to double unwrap the result.
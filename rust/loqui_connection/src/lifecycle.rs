@@ -0,0 +1,129 @@
+use crate::handler::ConnectionState;
+use crate::LoquiError;
+use std::fmt::Display;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Where a connection is in its lifecycle, as seen by its handles.
+#[derive(Debug, Clone)]
+struct Lifecycle {
+    state: ConnectionState,
+    /// Why the connection closed before the handshake completed, if it did.
+    handshake_error: Option<Arc<str>>,
+}
+
+/// Tells the handles of a connection about its state changes.
+#[derive(Debug)]
+pub(crate) struct LifecycleSender(watch::Sender<Lifecycle>);
+
+/// Follows the state changes of a connection, see `Connection::ready`.
+#[derive(Debug, Clone)]
+pub(crate) struct LifecycleReceiver(watch::Receiver<Lifecycle>);
+
+pub(crate) fn lifecycle() -> (LifecycleSender, LifecycleReceiver) {
+    let (tx, rx) = watch::channel(Lifecycle {
+        state: ConnectionState::Connecting,
+        handshake_error: None,
+    });
+    (LifecycleSender(tx), LifecycleReceiver(rx))
+}
+
+impl Default for LifecycleSender {
+    /// A sender nobody follows.
+    fn default() -> Self {
+        lifecycle().0
+    }
+}
+
+impl LifecycleSender {
+    pub fn set_state(&self, state: ConnectionState) {
+        // It's okay to ignore this result. No handle is left to tell.
+        let _result = self.0.broadcast(Lifecycle {
+            state,
+            handshake_error: None,
+        });
+    }
+
+    /// Closes the connection before the handshake completed, e.g. because connecting failed.
+    pub fn handshake_failed(&self, error: &dyn Display) {
+        let _result = self.0.broadcast(Lifecycle {
+            state: ConnectionState::Closed,
+            handshake_error: Some(error.to_string().into()),
+        });
+    }
+}
+
+impl LifecycleReceiver {
+    pub fn state(&self) -> ConnectionState {
+        self.0.borrow().state
+    }
+
+    /// Waits for the handshake to complete. Fails with `LoquiError::HandshakeFailed` if it
+    /// didn't and with `LoquiError::ConnectionClosed` if the connection closed since.
+    pub async fn ready(&self) -> Result<(), LoquiError> {
+        let mut rx = self.0.clone();
+        while let Some(lifecycle) = rx.recv().await {
+            match lifecycle.state {
+                ConnectionState::Connecting => continue,
+                ConnectionState::Ready | ConnectionState::Draining => return Ok(()),
+                ConnectionState::Closed => {
+                    return Err(match lifecycle.handshake_error {
+                        Some(reason) => LoquiError::HandshakeFailed {
+                            reason: reason.to_string(),
+                        },
+                        None => LoquiError::ConnectionClosed,
+                    })
+                }
+            }
+        }
+        // The connection stopped without closing, e.g. its task panicked.
+        Err(LoquiError::ConnectionClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn it_waits_for_the_handshake() {
+        Runtime::new().unwrap().block_on(async {
+            let (tx, rx) = lifecycle();
+            let ready = rx.ready();
+            futures::pin_mut!(ready);
+            assert!((&mut ready).now_or_never().is_none());
+
+            tx.set_state(ConnectionState::Ready);
+            assert!(ready.await.is_ok());
+            assert_eq!(rx.state(), ConnectionState::Ready);
+            tx.set_state(ConnectionState::Closed);
+            assert!(matches!(
+                rx.ready().await,
+                Err(LoquiError::ConnectionClosed)
+            ));
+        });
+    }
+
+    #[test]
+    fn it_fails_with_the_handshake_error() {
+        Runtime::new().unwrap().block_on(async {
+            let (tx, rx) = lifecycle();
+            tx.handshake_failed(&LoquiError::HandshakeTimeout);
+            match rx.ready().await {
+                Err(LoquiError::HandshakeFailed { reason }) => {
+                    assert_eq!(reason, "Handshake timeout.")
+                }
+                other => panic!("expected the handshake to fail. {:?}", other),
+            }
+
+            let (tx, rx) = lifecycle();
+            drop(tx);
+            assert!(matches!(
+                rx.ready().await,
+                Err(LoquiError::ConnectionClosed)
+            ));
+        });
+    }
+}
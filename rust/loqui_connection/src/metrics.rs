@@ -0,0 +1,62 @@
+use crate::connection_tag::ConnectionTag;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Observes the traffic of a connection. Every method is a no-op by default, so implementations
+/// only override what they export.
+pub trait Metrics: Debug + Send + Sync + 'static {
+    /// Called once a connection completed its handshake. Returns the metrics the connection should
+    /// report to instead, e.g. ones that label everything with the connection's labels. `None`,
+    /// the default, keeps reporting to these.
+    fn for_connection(&self, _connection: &ConnectionTag) -> Option<Arc<dyn Metrics>> {
+        None
+    }
+    /// Called for every frame received on the socket after the handshake. `opcode` is the
+    /// `Frame::OPCODE` of the frame.
+    fn frame_received(&self, _opcode: u8) {}
+    /// Called for every frame the connection sends after the handshake.
+    fn frame_sent(&self, _opcode: u8) {}
+    /// Called whenever the number of delegated requests waiting on a response changes.
+    fn in_flight_requests(&self, _count: usize) {}
+    /// Called once the response to a delegated request was computed, including failed ones.
+    fn request_timing(&self, _sequence_id: u32, _timing: &RequestTiming) {}
+    /// Called with the payload size of every `Request`, `Response` and `Push` sent or received,
+    /// e.g. to record a histogram. Sizes are uncompressed: received payloads are measured once
+    /// decompressed and sent ones before they're compressed.
+    fn observe_payload_size(&self, _opcode: u8, _bytes: usize) {}
+    /// Called when the other side answered a request that was already resolved a second time,
+    /// e.g. because of a bug in the peer. The second answer is dropped.
+    fn duplicate_response(&self, _sequence_id: u32) {}
+    /// Called for every `Request`, `Response` and `Push` compressed before it is sent, with its
+    /// payload size before and after compression, e.g. to tell whether compression is worth its
+    /// CPU. Payloads sent uncompressed, e.g. below `TransportOptions::compression_min_bytes`,
+    /// aren't reported.
+    fn observe_compression(
+        &self,
+        _opcode: u8,
+        _uncompressed_bytes: usize,
+        _compressed_bytes: usize,
+    ) {
+    }
+    /// Called after every encode of a `TimedEncoder`, including failed ones, with the time it took,
+    /// e.g. to tell whether a cheaper encoding is worthwhile.
+    fn observe_encode(&self, _duration: Duration) {}
+    /// Called after every decode of a `TimedEncoder`, including failed ones, with the time it took.
+    fn observe_decode(&self, _duration: Duration) {}
+}
+
+/// Where the time went for a delegated request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestTiming {
+    /// From delegating the request until its future first ran.
+    pub queued: Duration,
+    /// From its future first running until the response was computed.
+    pub handled: Duration,
+}
+
+/// `Metrics` that doesn't record anything.
+#[derive(Debug, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
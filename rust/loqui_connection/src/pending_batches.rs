@@ -0,0 +1,185 @@
+use loqui_protocol::frames::{
+    BatchEntry, Error as ErrorFrame, LoquiFrame, Response, ResponseBatch,
+};
+use loqui_protocol::is_streaming;
+use std::collections::HashMap;
+
+/// Collects the responses to the requests of each `RequestBatch`, so they're sent back together
+/// in a single `ResponseBatch` once the last one completed.
+#[derive(Debug, Default)]
+pub(crate) struct PendingBatches {
+    /// The `sequence_id` of the batch of each request that hasn't completed yet, keyed by the
+    /// request's `sequence_id`.
+    batch_ids: HashMap<u32, u32>,
+    batches: HashMap<u32, PendingBatch>,
+}
+
+#[derive(Debug, Default)]
+struct PendingBatch {
+    remaining: usize,
+    entries: Vec<BatchEntry>,
+}
+
+impl PendingBatches {
+    /// Starts collecting the responses to a batch. Returns the entries to handle. Entries whose
+    /// `sequence_id` is already waiting for a response are dropped, so every response is
+    /// collected once.
+    pub fn start(&mut self, batch_id: u32, entries: Vec<BatchEntry>) -> Vec<BatchEntry> {
+        let entries: Vec<BatchEntry> = entries
+            .into_iter()
+            .filter(|entry| match self.batch_ids.get(&entry.sequence_id) {
+                Some(_batch_id) => {
+                    debug!("Duplicate batch entry. sequence_id={}", entry.sequence_id);
+                    false
+                }
+                None => {
+                    self.batch_ids.insert(entry.sequence_id, batch_id);
+                    true
+                }
+            })
+            .collect();
+        let batch = PendingBatch {
+            remaining: entries.len(),
+            entries: Vec::with_capacity(entries.len()),
+        };
+        self.batches.insert(batch_id, batch);
+        entries
+    }
+
+    /// Collects a `Response` or `Error` to a batched request. Returns the `ResponseBatch` once it
+    /// is complete, or the frame itself if it doesn't answer a batched request.
+    pub fn collect(&mut self, frame: LoquiFrame) -> Option<LoquiFrame> {
+        let entry = match &frame {
+            LoquiFrame::Response(Response {
+                flags, sequence_id, ..
+            }) if !is_streaming(*flags) => *sequence_id,
+            LoquiFrame::Error(ErrorFrame { sequence_id, .. }) => *sequence_id,
+            _ => return Some(frame),
+        };
+        if !self.batch_ids.contains_key(&entry) {
+            return Some(frame);
+        }
+        let entry = match frame {
+            LoquiFrame::Response(Response {
+                sequence_id,
+                payload,
+                ..
+            }) => BatchEntry {
+                sequence_id,
+                code: 0,
+                payload,
+            },
+            LoquiFrame::Error(ErrorFrame {
+                sequence_id,
+                code,
+                payload,
+                ..
+            }) => BatchEntry {
+                sequence_id,
+                code,
+                payload,
+            },
+            _ => unreachable!("Only responses and errors are collected."),
+        };
+        self.complete(entry.sequence_id, Some(entry))
+    }
+
+    /// Stops waiting for the response to a batched request, e.g. because it was cancelled or
+    /// ignored. Returns the `ResponseBatch` if that completed it.
+    pub fn remove(&mut self, sequence_id: u32) -> Option<LoquiFrame> {
+        self.complete(sequence_id, None)
+    }
+
+    fn complete(&mut self, sequence_id: u32, entry: Option<BatchEntry>) -> Option<LoquiFrame> {
+        let batch_id = self.batch_ids.remove(&sequence_id)?;
+        let batch = self.batches.get_mut(&batch_id)?;
+        batch.remaining -= 1;
+        batch.entries.extend(entry);
+        if batch.remaining > 0 {
+            return None;
+        }
+        let batch = self.batches.remove(&batch_id)?;
+        let response_batch = ResponseBatch {
+            flags: 0,
+            sequence_id: batch_id,
+            entries: batch.entries,
+        };
+        Some(response_batch.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sequence_id: u32) -> BatchEntry {
+        BatchEntry {
+            sequence_id,
+            code: 0,
+            payload: vec![],
+        }
+    }
+
+    fn response(sequence_id: u32, payload: &[u8]) -> LoquiFrame {
+        Response {
+            flags: 0,
+            sequence_id,
+            trace_id: None,
+            payload: payload.to_vec(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn it_sends_the_batch_once_every_request_completed() {
+        let mut pending = PendingBatches::default();
+        assert_eq!(
+            pending.start(9, vec![entry(1), entry(2), entry(3)]).len(),
+            3
+        );
+
+        assert_eq!(pending.collect(response(2, b"two")), None);
+        let error = ErrorFrame {
+            flags: 0,
+            sequence_id: 1,
+            code: 8,
+            payload: b"timeout".to_vec(),
+        };
+        assert_eq!(pending.collect(error.into()), None);
+        let expected = ResponseBatch {
+            flags: 0,
+            sequence_id: 9,
+            entries: vec![
+                BatchEntry {
+                    sequence_id: 2,
+                    code: 0,
+                    payload: b"two".to_vec(),
+                },
+                BatchEntry {
+                    sequence_id: 1,
+                    code: 8,
+                    payload: b"timeout".to_vec(),
+                },
+            ],
+        };
+        assert_eq!(pending.remove(3), Some(expected.into()));
+        assert!(pending.batches.is_empty());
+    }
+
+    #[test]
+    fn it_passes_through_responses_to_unbatched_requests() {
+        let mut pending = PendingBatches::default();
+        pending.start(9, vec![entry(1)]);
+        assert_eq!(
+            pending.collect(response(4, b"four")),
+            Some(response(4, b"four"))
+        );
+    }
+
+    #[test]
+    fn it_drops_duplicate_entries() {
+        let mut pending = PendingBatches::default();
+        assert_eq!(pending.start(9, vec![entry(1), entry(1)]), vec![entry(1)]);
+        assert!(pending.collect(response(1, b"one")).is_some());
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+/// A token bucket limit on how often frames of an opcode may be received, see
+/// `TransportOptions::rate_limits`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// How many frames per second are let through once the burst is used up.
+    pub per_second: u32,
+    /// How many frames may arrive at once. The bucket starts full.
+    pub burst: u32,
+}
+
+/// Tracks the tokens left of each rate limited opcode of a connection.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    buckets: HashMap<u8, TokenBucket>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limits: &HashMap<u8, RateLimit>, now: Instant) -> Self {
+        let buckets = rate_limits
+            .iter()
+            .map(|(opcode, limit)| {
+                let bucket = TokenBucket {
+                    limit: *limit,
+                    tokens: f64::from(limit.burst),
+                    refilled_at: now,
+                };
+                (*opcode, bucket)
+            })
+            .collect();
+        Self { buckets }
+    }
+
+    /// Takes a token for a frame of the opcode. Returns `false` if the frame is over its limit.
+    /// Opcodes without a limit are always let through.
+    pub fn try_acquire(&mut self, opcode: u8, now: Instant) -> bool {
+        let bucket = match self.buckets.get_mut(&opcode) {
+            Some(bucket) => bucket,
+            None => return true,
+        };
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens
+            + elapsed.as_secs_f64() * f64::from(bucket.limit.per_second))
+        .min(f64::from(bucket.limit.burst));
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn rate_limiter(now: Instant) -> RateLimiter {
+        let mut rate_limits = HashMap::new();
+        rate_limits.insert(
+            7,
+            RateLimit {
+                per_second: 10,
+                burst: 2,
+            },
+        );
+        RateLimiter::new(&rate_limits, now)
+    }
+
+    #[test]
+    fn it_lets_a_burst_through_then_refills() {
+        let now = Instant::now();
+        let mut rate_limiter = rate_limiter(now);
+        assert!(rate_limiter.try_acquire(7, now));
+        assert!(rate_limiter.try_acquire(7, now));
+        assert!(!rate_limiter.try_acquire(7, now));
+
+        let now = now + Duration::from_millis(100);
+        assert!(rate_limiter.try_acquire(7, now));
+        assert!(!rate_limiter.try_acquire(7, now));
+
+        // Refills never go over the burst.
+        let now = now + Duration::from_secs(60);
+        assert!(rate_limiter.try_acquire(7, now));
+        assert!(rate_limiter.try_acquire(7, now));
+        assert!(!rate_limiter.try_acquire(7, now));
+    }
+
+    #[test]
+    fn it_lets_opcodes_without_a_limit_through() {
+        let now = Instant::now();
+        let mut rate_limiter = rate_limiter(now);
+        for _ in 0..100 {
+            assert!(rate_limiter.try_acquire(3, now));
+        }
+    }
+}
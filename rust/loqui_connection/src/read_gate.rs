@@ -0,0 +1,207 @@
+use failure::Error;
+use futures::stream::Stream;
+use futures::task::{AtomicWaker, Context, Poll};
+use loqui_protocol::frames::LoquiFrame;
+use loqui_protocol::is_half_closed;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How many frames are held back while reading is paused before the socket isn't polled anymore.
+/// From then on pings aren't answered either, until reading resumes.
+const MAX_HELD_FRAMES: usize = 1024;
+
+/// Whether the read loop of a connection hands frames on, see `Connection::pause_reading`.
+#[derive(Debug, Default)]
+pub(crate) struct ReadGate {
+    paused: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl ReadGate {
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Wakes the read loop if it was waiting for the gate to open.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.waker.wake();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+/// A stream of received frames that holds back everything but pings and pongs while its gate is
+/// paused, so the connection stays alive. Once `MAX_HELD_FRAMES` are held the socket isn't polled
+/// anymore, leaving what arrives to the kernel's buffers, so the other side is eventually held
+/// back by TCP flow control.
+pub(crate) struct Gated<S> {
+    stream: S,
+    gate: Arc<ReadGate>,
+    held: VecDeque<LoquiFrame>,
+}
+
+impl<S> Gated<S> {
+    pub fn new(stream: S, gate: Arc<ReadGate>) -> Self {
+        Self {
+            stream,
+            gate,
+            held: VecDeque::new(),
+        }
+    }
+}
+
+/// A ping that half closes has to be handled after the requests sent before it.
+fn passes_through(frame: &LoquiFrame) -> bool {
+    match frame {
+        LoquiFrame::Ping(ping) => !is_half_closed(ping.flags),
+        LoquiFrame::Pong(_) => true,
+        _ => false,
+    }
+}
+
+impl<S: Stream<Item = Result<LoquiFrame, Error>> + Unpin> Stream for Gated<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            if !self.gate.is_paused() {
+                if let Some(frame) = self.held.pop_front() {
+                    return Poll::Ready(Some(Ok(frame)));
+                }
+                return Pin::new(&mut self.stream).poll_next(cx);
+            }
+            self.gate.waker.register(cx.waker());
+            // Resumed between the check and the registration, which wouldn't have woken us.
+            if !self.gate.is_paused() {
+                continue;
+            }
+            if self.held.len() >= MAX_HELD_FRAMES {
+                return Poll::Pending;
+            }
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(frame))) if !passes_through(&frame) => {
+                    self.held.push_back(frame)
+                }
+                poll => return poll,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::{self, StreamExt};
+    use futures::task::{waker, ArcWake};
+    use futures::FutureExt;
+    use loqui_protocol::frames::{Ping, Push};
+    use loqui_protocol::Flags;
+    use std::sync::atomic::AtomicUsize;
+
+    fn push(payload: u8) -> LoquiFrame {
+        Push {
+            flags: 0,
+            sequence_id: None,
+            subscription_id: None,
+            payload: vec![payload],
+        }
+        .into()
+    }
+
+    fn ping(flags: u8) -> LoquiFrame {
+        Ping {
+            flags,
+            sequence_id: 1,
+            token: None,
+            sent_at: None,
+        }
+        .into()
+    }
+
+    fn next(
+        gated: &mut Gated<impl Stream<Item = Result<LoquiFrame, Error>> + Unpin>,
+    ) -> LoquiFrame {
+        match gated.next().now_or_never() {
+            Some(Some(Ok(frame))) => frame,
+            other => panic!("no frame. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_holds_frames_back_but_pings_while_paused() {
+        let gate = Arc::new(ReadGate::default());
+        let frames = vec![push(1), push(2), ping(0), push(3)];
+        let mut gated = Gated::new(stream::iter(frames.into_iter().map(Ok)), gate.clone());
+        assert_eq!(next(&mut gated), push(1));
+
+        gate.pause();
+        assert!(gate.is_paused());
+        assert_eq!(next(&mut gated), ping(0));
+        assert_eq!(gated.held.len(), 1);
+
+        gate.resume();
+        assert!(!gate.is_paused());
+        assert_eq!(next(&mut gated), push(2));
+        assert_eq!(next(&mut gated), push(3));
+        assert!(gated.next().now_or_never().unwrap().is_none());
+    }
+
+    #[test]
+    fn it_holds_back_pings_that_half_close() {
+        let gate = Arc::new(ReadGate::default());
+        gate.pause();
+        let frames = vec![push(1), ping(Flags::HalfClosed as u8)];
+        let mut gated = Gated::new(stream::iter(frames.into_iter().map(Ok)), gate.clone());
+        assert!(gated.next().now_or_never().unwrap().is_none());
+        assert_eq!(gated.held.len(), 2);
+    }
+
+    #[test]
+    fn it_stops_polling_once_enough_frames_are_held() {
+        let gate = Arc::new(ReadGate::default());
+        gate.pause();
+        let frames = (0..MAX_HELD_FRAMES + 1)
+            .map(|_| push(1))
+            .chain(vec![ping(0)]);
+        let mut gated = Gated::new(stream::iter(frames.map(Ok)), gate.clone());
+        assert!(gated.next().now_or_never().is_none());
+        assert_eq!(gated.held.len(), MAX_HELD_FRAMES);
+    }
+
+    /// Counts how often it was woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl ArcWake for CountingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn it_wakes_the_reader_on_resume() {
+        let gate = Arc::new(ReadGate::default());
+        gate.pause();
+        let mut gated = Gated::new(
+            stream::iter(vec![Ok(push(1))]).chain(stream::pending()),
+            gate.clone(),
+        );
+        let counting_waker = Arc::new(CountingWaker::default());
+        let waker = waker(counting_waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(gated.poll_next_unpin(&mut cx).is_pending());
+        assert_eq!(counting_waker.0.load(Ordering::SeqCst), 0);
+
+        gate.resume();
+        assert_eq!(counting_waker.0.load(Ordering::SeqCst), 1);
+        match gated.poll_next_unpin(&mut cx) {
+            Poll::Ready(Some(Ok(frame))) => assert_eq!(frame, push(1)),
+            other => panic!("no frame. {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use loqui_protocol::frames::Request;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Requests waiting for a slot at `TransportOptions::max_concurrent_requests`, see
+/// `TransportOptions::request_queue_depth`.
+#[derive(Debug, Default)]
+pub(crate) struct RequestQueue {
+    /// In the order they were queued.
+    requests: Vec<QueuedRequest>,
+}
+
+#[derive(Debug)]
+struct QueuedRequest {
+    request: Request,
+    queued_at: Instant,
+}
+
+impl RequestQueue {
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn push(&mut self, request: Request, now: Instant) {
+        self.requests.push(QueuedRequest {
+            request,
+            queued_at: now,
+        });
+    }
+
+    pub fn contains(&self, sequence_id: u32) -> bool {
+        self.requests
+            .iter()
+            .any(|queued| queued.request.sequence_id == sequence_id)
+    }
+
+    /// Takes the request to serve next: the highest priority once aged by how long each waited,
+    /// the one queued first among equals.
+    pub fn pop(&mut self, now: Instant, priority_aging: Duration) -> Option<Request> {
+        let (index, _priority) = self
+            .requests
+            .iter()
+            .enumerate()
+            .map(|(index, queued)| (index, aged_priority(queued, now, priority_aging)))
+            .fold(
+                None,
+                |best: Option<(usize, u128)>, (index, priority)| match best {
+                    Some((_index, best_priority)) if best_priority >= priority => best,
+                    _ => Some((index, priority)),
+                },
+            )?;
+        Some(self.requests.remove(index).request)
+    }
+
+    /// Drops every queued request.
+    pub fn clear(&mut self) {
+        self.requests.clear();
+    }
+
+    /// Takes the request out of the queue, e.g. because it was cancelled. Returns `false` if it
+    /// isn't queued.
+    pub fn remove(&mut self, sequence_id: u32) -> bool {
+        match self
+            .requests
+            .iter()
+            .position(|queued| queued.request.sequence_id == sequence_id)
+        {
+            Some(index) => {
+                self.requests.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The priority of the request plus one for every `priority_aging` it waited.
+fn aged_priority(queued: &QueuedRequest, now: Instant, priority_aging: Duration) -> u128 {
+    let waited = now.saturating_duration_since(queued.queued_at);
+    queued.request.priority as u128 + waited.as_nanos() / priority_aging.as_nanos().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loqui_protocol::frames::Priority;
+
+    const AGING: Duration = Duration::from_secs(1);
+
+    fn request(sequence_id: u32, priority: Priority) -> Request {
+        Request {
+            flags: 0,
+            sequence_id,
+            trace_id: None,
+            idempotency_key: None,
+            priority,
+            timeout_ms: 0,
+            payload: vec![],
+        }
+    }
+
+    fn pop_all(queue: &mut RequestQueue, now: Instant) -> Vec<u32> {
+        let mut sequence_ids = vec![];
+        while let Some(request) = queue.pop(now, AGING) {
+            sequence_ids.push(request.sequence_id);
+        }
+        sequence_ids
+    }
+
+    #[test]
+    fn it_serves_the_highest_priority_first() {
+        let now = Instant::now();
+        let mut queue = RequestQueue::default();
+        queue.push(request(1, Priority::Low), now);
+        queue.push(request(2, Priority::Normal), now);
+        queue.push(request(3, Priority::Critical), now);
+        queue.push(request(4, Priority::Normal), now);
+        queue.push(request(5, Priority::High), now);
+        assert_eq!(pop_all(&mut queue, now), vec![3, 5, 2, 4, 1]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn it_ages_waiting_requests() {
+        let start = Instant::now();
+        let mut queue = RequestQueue::default();
+        queue.push(request(1, Priority::Low), start);
+        // Waited long enough to count as `Critical`, and queued before the new ones.
+        let now = start + AGING * 3;
+        queue.push(request(2, Priority::Critical), now);
+        queue.push(request(3, Priority::High), now);
+        assert_eq!(pop_all(&mut queue, now), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn it_removes_cancelled_requests() {
+        let now = Instant::now();
+        let mut queue = RequestQueue::default();
+        queue.push(request(1, Priority::Normal), now);
+        queue.push(request(2, Priority::Normal), now);
+        assert!(queue.remove(1));
+        assert!(!queue.remove(1));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(pop_all(&mut queue, now), vec![2]);
+    }
+}
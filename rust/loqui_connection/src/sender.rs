@@ -1,51 +1,246 @@
 use crate::connection::Event;
-use crate::LoquiError;
+use crate::handler::ConnectionHealth;
+use crate::metrics::RequestTiming;
+use crate::{GoAwayCode, LoquiError};
 use failure::Error;
 use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use loqui_protocol::frames::Response;
+use futures::channel::oneshot;
+use loqui_protocol::frames::{LoquiFrame, Push, Response};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 /// A Sender for sending messages to a `Connection`.
 #[derive(Debug)]
 pub struct Sender<T: Send + 'static> {
     tx: UnboundedSender<Event<T>>,
+    /// The number of events sent that the connection hasn't taken off the queue yet.
+    depth: Arc<AtomicUsize>,
 }
 
 impl<T: Send + 'static> Sender<T> {
     pub(crate) fn new() -> (Self, UnboundedReceiver<Event<T>>) {
         let (tx, rx) = mpsc::unbounded();
-        (Self { tx }, rx)
+        let depth = Arc::new(AtomicUsize::new(0));
+        (Self { tx, depth }, rx)
     }
 
-    pub(crate) fn internal(&self, event: T) -> Result<(), Error> {
-        self.tx
-            .unbounded_send(Event::InternalEvent(event))
-            .map_err(|_e| LoquiError::ConnectionClosed.into())
+    /// The number of events waiting to be handled by the connection. Responses queue up here when
+    /// the socket is slow to write.
+    pub(crate) fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Called by the connection whenever it takes an event off the queue.
+    pub(crate) fn dequeued(&self) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn send(&self, event: Event<T>) -> Result<(), LoquiError> {
+        // Count before sending so the connection can't dequeue the event first.
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        self.tx.unbounded_send(event).map_err(|_e| {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+            LoquiError::ConnectionClosed
+        })
+    }
+
+    pub(crate) fn internal(&self, event: T) -> Result<(), LoquiError> {
+        self.send(Event::InternalEvent(event))
+    }
+
+    pub(crate) fn internal_flushed(
+        &self,
+        event: T,
+        flush_waiter: oneshot::Sender<()>,
+    ) -> Result<(), LoquiError> {
+        self.send(Event::InternalEventFlushed(event, flush_waiter))
     }
 
     pub(crate) fn response_complete(
         &self,
         result: Result<Response, (Error, u32)>,
-    ) -> Result<(), Error> {
-        self.tx
-            .unbounded_send(Event::ResponseComplete(result))
-            .map_err(|_e| LoquiError::ConnectionClosed.into())
+        timing: RequestTiming,
+    ) -> Result<(), LoquiError> {
+        self.send(Event::ResponseComplete(result, timing))
+    }
+
+    pub(crate) fn close(&self, reason: Option<String>) -> Result<(), LoquiError> {
+        self.send(Event::Close(reason))
+    }
+
+    /// Sends a `GoAway` with the code, then closes once the in flight requests drained.
+    pub(crate) fn initiate_graceful_shutdown(&self, code: GoAwayCode) -> Result<(), LoquiError> {
+        self.send(Event::GracefulShutdown(code))
+    }
+
+    pub(crate) fn half_close(&self) -> Result<(), LoquiError> {
+        self.send(Event::HalfClose)
+    }
+
+    pub(crate) fn drain_timeout(&self) -> Result<(), LoquiError> {
+        self.send(Event::DrainTimeout)
+    }
+
+    pub(crate) fn request_cancelled(&self) -> Result<(), LoquiError> {
+        self.send(Event::RequestCancelled)
+    }
+
+    pub(crate) fn stream_item(&self, response: Response) -> Result<(), LoquiError> {
+        self.send(Event::StreamItem(response))
+    }
+
+    pub(crate) fn send_delayed(
+        &self,
+        frame: LoquiFrame,
+        encoding: Option<&'static str>,
+        flush_waiter: Option<oneshot::Sender<()>>,
+    ) -> Result<(), LoquiError> {
+        self.send(Event::SendDelayed(frame, encoding, flush_waiter))
+    }
+
+    pub(crate) fn renegotiate_encoding(&self, encoding: &'static str) -> Result<(), LoquiError> {
+        self.send(Event::Renegotiate(encoding))
+    }
+
+    pub(crate) fn health_check(
+        &self,
+        waiter: oneshot::Sender<ConnectionHealth>,
+    ) -> Result<(), LoquiError> {
+        self.send(Event::HealthCheck(waiter))
+    }
+
+    pub(crate) fn subscription_push(&self, push: Push) -> Result<(), LoquiError> {
+        self.send(Event::SubscriptionPush(push))
+    }
+
+    pub(crate) fn subscription_ended(&self, subscription_id: u32) -> Result<(), LoquiError> {
+        self.send(Event::SubscriptionEnded(subscription_id))
+    }
+
+    pub(crate) fn flushed(&self, sequence_id: u32) -> Result<(), LoquiError> {
+        self.send(Event::Flushed(sequence_id))
+    }
+
+    pub(crate) fn pause_reading(&self) -> Result<(), LoquiError> {
+        self.send(Event::PauseReading)
+    }
+
+    pub(crate) fn resume_reading(&self) -> Result<(), LoquiError> {
+        self.send(Event::ResumeReading)
+    }
+
+    pub(crate) fn cancel_all_inflight(&self) -> Result<(), LoquiError> {
+        self.send(Event::CancelAllInflight)
     }
 
-    pub(crate) fn close(&self) -> Result<(), Error> {
-        self.tx
-            .unbounded_send(Event::Close)
-            .map_err(|_e| LoquiError::ConnectionClosed.into())
+    pub(crate) fn pong_due(&self) -> Result<(), LoquiError> {
+        self.send(Event::PongDue)
     }
 
     pub(crate) fn is_closed(&self) -> bool {
         self.tx.is_closed()
     }
+
+    /// Takes the events left on the queue once the connection closed, returning the frames they
+    /// would have sent. Only events carrying a finished frame count, e.g. a computed response or a
+    /// delayed frame, not internal events the handler never turned into one.
+    pub(crate) fn drain_unflushed(&self, rx: &mut UnboundedReceiver<Event<T>>) -> Vec<LoquiFrame> {
+        let mut unflushed = vec![];
+        while let Ok(Some(event)) = rx.try_next() {
+            self.dequeued();
+            match event {
+                Event::ResponseComplete(Ok(response), _) | Event::StreamItem(response) => {
+                    unflushed.push(response.into())
+                }
+                Event::SendDelayed(frame, _encoding, _flush_waiter) => unflushed.push(frame),
+                Event::SubscriptionPush(push) => unflushed.push(push.into()),
+                _ => {}
+            }
+        }
+        unflushed
+    }
 }
 
 impl<T: Send> Clone for Sender<T> {
     fn clone(&self) -> Sender<T> {
         Self {
             tx: self.tx.clone(),
+            depth: self.depth.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loqui_protocol::frames::Ping;
+    use std::time::Duration;
+
+    fn response(sequence_id: u32) -> Response {
+        Response {
+            flags: 0,
+            sequence_id,
+            trace_id: None,
+            payload: vec![],
+        }
+    }
+
+    #[test]
+    fn it_drains_the_unflushed_frames() {
+        let (sender, mut rx) = Sender::<()>::new();
+        let timing = RequestTiming {
+            queued: Duration::from_millis(1),
+            handled: Duration::from_millis(2),
+        };
+        sender.response_complete(Ok(response(1)), timing).unwrap();
+        sender.internal(()).unwrap();
+        sender.stream_item(response(2)).unwrap();
+        sender
+            .response_complete(Err((LoquiError::RequestTimeout.into(), 3)), timing)
+            .unwrap();
+        let ping = Ping {
+            flags: 0,
+            sequence_id: 4,
+            token: None,
+            sent_at: None,
+        };
+        sender
+            .send_delayed(ping.clone().into(), None, None)
+            .unwrap();
+        let push = Push {
+            flags: 0,
+            sequence_id: None,
+            subscription_id: Some(5),
+            payload: vec![],
+        };
+        sender.subscription_push(push.clone()).unwrap();
+        sender.subscription_ended(5).unwrap();
+
+        let unflushed = sender.drain_unflushed(&mut rx);
+        assert_eq!(
+            unflushed,
+            vec![
+                response(1).into(),
+                response(2).into(),
+                LoquiFrame::Ping(ping),
+                LoquiFrame::Push(push)
+            ]
+        );
+        assert_eq!(sender.depth(), 0);
+    }
+
+    #[test]
+    fn it_fails_with_connection_closed_once_the_connection_dropped() {
+        let (sender, rx) = Sender::<()>::new();
+        drop(rx);
+        assert!(matches!(
+            sender.close(None),
+            Err(LoquiError::ConnectionClosed)
+        ));
+        assert!(matches!(
+            sender.internal(()),
+            Err(LoquiError::ConnectionClosed)
+        ));
+        assert_eq!(sender.depth(), 0);
+    }
+}
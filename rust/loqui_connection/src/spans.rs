@@ -0,0 +1,264 @@
+//! `tracing` spans around event and frame handling, so logs of a handler are attributed to the
+//! frame it handles. Only with the `tracing` feature. Without it the spans are no-ops.
+
+#[cfg(feature = "tracing")]
+pub(crate) use self::enabled::*;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use self::disabled::*;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use crate::connection::Event;
+    use crate::connection_tag::ConnectionTag;
+    use crate::handler::DelegatedFrame;
+    use loqui_protocol::frames::LoquiFrame;
+    use std::future::Future;
+    use tracing::field::Empty;
+    use tracing::{debug_span, Instrument};
+
+    pub(crate) use tracing::Span;
+
+    pub(crate) fn connection_span(connection: &ConnectionTag) -> Span {
+        debug_span!(
+            "connection",
+            connection_id = connection.connection_id,
+            labels = %connection
+        )
+    }
+
+    pub(crate) fn event_span<T: Send + 'static>(event: &Event<T>) -> Span {
+        let event = match event {
+            Event::SocketReceive(_) => "socket_receive",
+            Event::Ping => "ping",
+            Event::InternalEvent(_) => "internal_event",
+            Event::InternalEventFlushed(..) => "internal_event_flushed",
+            Event::ResponseComplete(..) => "response_complete",
+            Event::Close(_) => "close",
+            Event::GracefulShutdown(_) => "graceful_shutdown",
+            Event::HalfClose => "half_close",
+            Event::DrainTimeout => "drain_timeout",
+            Event::RequestCancelled => "request_cancelled",
+            Event::StreamItem(_) => "stream_item",
+            Event::SendDelayed(..) => "send_delayed",
+            Event::Flushed(_) => "flushed",
+            Event::Renegotiate(_) => "renegotiate",
+            Event::HealthCheck(_) => "health_check",
+            Event::SubscriptionPush(_) => "subscription_push",
+            Event::SubscriptionEnded(_) => "subscription_ended",
+            Event::PauseReading => "pause_reading",
+            Event::ResumeReading => "resume_reading",
+            Event::PongDue => "pong_due",
+            Event::CancelAllInflight => "cancel_all_inflight",
+        };
+        debug_span!("handle_event", event)
+    }
+
+    pub(crate) fn frame_span(frame: &LoquiFrame) -> Span {
+        let (frame, sequence_id) = match frame {
+            LoquiFrame::Hello(_) => ("hello", None),
+            LoquiFrame::HelloAck(_) => ("hello_ack", None),
+            LoquiFrame::Ping(ping) => ("ping", Some(ping.sequence_id)),
+            LoquiFrame::Pong(pong) => ("pong", Some(pong.sequence_id)),
+            LoquiFrame::Request(request) => ("request", Some(request.sequence_id)),
+            LoquiFrame::Response(response) => ("response", Some(response.sequence_id)),
+            LoquiFrame::Push(push) => ("push", push.sequence_id),
+            LoquiFrame::GoAway(_) => ("go_away", None),
+            LoquiFrame::Error(error) => ("error", Some(error.sequence_id)),
+            LoquiFrame::Cancel(cancel) => ("cancel", Some(cancel.sequence_id)),
+            LoquiFrame::PushAck(push_ack) => ("push_ack", Some(push_ack.sequence_id)),
+            LoquiFrame::WindowUpdate(window_update) => {
+                ("window_update", Some(window_update.sequence_id))
+            }
+            LoquiFrame::RequestBatch(batch) => ("request_batch", Some(batch.sequence_id)),
+            LoquiFrame::ResponseBatch(batch) => ("response_batch", Some(batch.sequence_id)),
+            LoquiFrame::Renegotiate(renegotiate) => ("renegotiate", Some(renegotiate.sequence_id)),
+            LoquiFrame::HealthCheck(health_check) => {
+                ("health_check", Some(health_check.sequence_id))
+            }
+            LoquiFrame::HealthStatus(health_status) => {
+                ("health_status", Some(health_status.sequence_id))
+            }
+            LoquiFrame::Subscribe(subscribe) => ("subscribe", Some(subscribe.sequence_id)),
+            LoquiFrame::Unsubscribe(unsubscribe) => ("unsubscribe", Some(unsubscribe.sequence_id)),
+            LoquiFrame::Unknown(_) => ("unknown", None),
+        };
+        with_sequence_id(
+            debug_span!("handle_frame", frame, sequence_id = Empty),
+            sequence_id,
+        )
+    }
+
+    pub(crate) fn delegate_span(frame: &DelegatedFrame) -> Span {
+        let (frame, sequence_id) = match frame {
+            DelegatedFrame::Push(push) => ("push", push.sequence_id),
+            DelegatedFrame::Request(request) => ("request", Some(request.sequence_id)),
+            DelegatedFrame::Response(response) => ("response", Some(response.sequence_id)),
+            DelegatedFrame::Error(error) => ("error", Some(error.sequence_id)),
+        };
+        with_sequence_id(
+            debug_span!("delegate_frame", frame, sequence_id = Empty),
+            sequence_id,
+        )
+    }
+
+    /// Enters the span whenever the future is polled, so logs of a spawned response are
+    /// attributed to the frame it answers.
+    pub(crate) fn instrument<F: Future>(future: F, span: Span) -> impl Future<Output = F::Output> {
+        future.instrument(span)
+    }
+
+    fn with_sequence_id(span: Span, sequence_id: Option<u32>) -> Span {
+        if let Some(sequence_id) = sequence_id {
+            span.record("sequence_id", sequence_id);
+        }
+        span
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use crate::connection::Event;
+    use crate::connection_tag::ConnectionTag;
+    use crate::handler::DelegatedFrame;
+    use loqui_protocol::frames::LoquiFrame;
+    use std::future::Future;
+
+    #[derive(Clone)]
+    pub(crate) struct Span;
+
+    pub(crate) struct Entered;
+
+    impl Span {
+        pub fn enter(&self) -> Entered {
+            Entered
+        }
+    }
+
+    pub(crate) fn connection_span(_connection: &ConnectionTag) -> Span {
+        Span
+    }
+
+    pub(crate) fn event_span<T: Send + 'static>(_event: &Event<T>) -> Span {
+        Span
+    }
+
+    pub(crate) fn frame_span(_frame: &LoquiFrame) -> Span {
+        Span
+    }
+
+    pub(crate) fn delegate_span(_frame: &DelegatedFrame) -> Span {
+        Span
+    }
+
+    pub(crate) fn instrument<F: Future>(future: F, _span: Span) -> F {
+        future
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use crate::connection_tag::ConnectionTag;
+    use crate::handler::DelegatedFrame;
+    use loqui_protocol::frames::{Priority, Push, Request};
+    use std::fmt::{Debug, Write};
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{subscriber, Event as TracingEvent, Metadata, Subscriber};
+
+    /// Records the name and fields of every span. A span's id is its position, counting from 1.
+    #[derive(Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<(&'static str, String)>>>,
+    }
+
+    struct Fields<'a>(&'a mut String);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            write!(self.0, "{}={:?} ", field.name(), value).unwrap();
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut fields = String::new();
+            span.record(&mut Fields(&mut fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record) {
+            let mut spans = self.spans.lock().unwrap();
+            let (_name, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut Fields(fields));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &TracingEvent) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn it_records_the_frame_and_sequence_id() {
+        let recorder = Recorder::default();
+        let spans = recorder.spans.clone();
+        subscriber::with_default(recorder, || {
+            let request = Request {
+                flags: 0,
+                sequence_id: 4,
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                payload: vec![],
+            };
+            let _span = frame_span(&request.into());
+            let push = Push {
+                flags: 0,
+                sequence_id: None,
+                subscription_id: None,
+                payload: vec![],
+            };
+            let _span = delegate_span(&DelegatedFrame::Push(push));
+        });
+        assert_eq!(
+            *spans.lock().unwrap(),
+            vec![
+                (
+                    "handle_frame",
+                    "frame=\"request\" sequence_id=4 ".to_string()
+                ),
+                ("delegate_frame", "frame=\"push\" ".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_records_the_connection_id_and_labels() {
+        let recorder = Recorder::default();
+        let spans = recorder.spans.clone();
+        subscriber::with_default(recorder, || {
+            let connection = ConnectionTag {
+                connection_id: 9,
+                labels: vec![("shard".to_string(), "3".to_string())],
+            };
+            let _span = connection_span(&connection);
+        });
+        assert_eq!(
+            *spans.lock().unwrap(),
+            vec![("connection", "connection_id=9 labels=shard=3 ".to_string())]
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+
+/// A future run to completion by a `Spawn`.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs the futures a connection hands off, i.e. the responses, streams and subscriptions of the
+/// handler, see `TransportOptions::spawner`. E.g. to run them on another executor or in a bounded
+/// task pool. Timers of the connection itself always run on tokio.
+pub trait Spawn: Debug + Send + Sync + 'static {
+    /// Runs the task in the background. The connection doesn't wait for it.
+    fn spawn(&self, task: Task);
+}
+
+/// `Spawn` that runs tasks with `tokio::spawn` on the current runtime.
+#[derive(Debug, Default)]
+pub struct TokioSpawn;
+
+impl Spawn for TokioSpawn {
+    fn spawn(&self, task: Task) {
+        tokio::spawn(task);
+    }
+}
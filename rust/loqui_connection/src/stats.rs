@@ -0,0 +1,194 @@
+use crate::clock::Clock;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Every opcode is below this.
+const OPCODES: usize = 32;
+
+/// A snapshot of the counters of a connection, see `Connection::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// The frames received since the connection was ready, by opcode. Opcodes never received are
+    /// left out.
+    pub frames_received: BTreeMap<u8, u64>,
+    /// The frames sent since the connection was ready, by opcode.
+    pub frames_sent: BTreeMap<u8, u64>,
+    /// The bytes read from the socket, including the handshake.
+    pub bytes_read: u64,
+    /// The bytes written to the socket, including the handshake.
+    pub bytes_written: u64,
+    /// The requests of the other side being computed.
+    pub in_flight_requests: usize,
+    /// The round-trip time of the latest ping. `None` until the first `Pong` arrived.
+    pub last_rtt: Option<Duration>,
+    /// How long the connection has been ready. Zero until the handshake completed.
+    pub uptime: Duration,
+    /// The encoding in use. `None` until the handshake completed.
+    pub encoding: Option<&'static str>,
+    /// The compression in use, if any.
+    pub compression: Option<&'static str>,
+}
+
+/// What the handshake settled on, set once the connection is ready.
+#[derive(Debug)]
+struct Settled {
+    clock: Arc<dyn Clock>,
+    ready_at: Instant,
+    encoding: &'static str,
+    compression: Option<&'static str>,
+    bytes_read: Arc<AtomicU64>,
+    bytes_written: Arc<AtomicU64>,
+}
+
+/// The counters behind `ConnectionStats`, updated by the connection as it handles events and
+/// read by `Connection::stats` without going through the connection. Only the settings the
+/// handshake settled on are behind a lock, which is written once and on renegotiation.
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+    frames_received: [AtomicU64; OPCODES],
+    frames_sent: [AtomicU64; OPCODES],
+    in_flight_requests: AtomicUsize,
+    /// `0` until the first `Pong` arrived.
+    last_rtt_micros: AtomicU64,
+    settled: RwLock<Option<Settled>>,
+}
+
+impl StatsCounters {
+    pub fn ready(
+        &self,
+        clock: Arc<dyn Clock>,
+        encoding: &'static str,
+        compression: Option<&'static str>,
+        bytes_read: Arc<AtomicU64>,
+        bytes_written: Arc<AtomicU64>,
+    ) {
+        let ready_at = clock.now();
+        *self.settled.write().expect("Failed to write stats.") = Some(Settled {
+            clock,
+            ready_at,
+            encoding,
+            compression,
+            bytes_read,
+            bytes_written,
+        });
+    }
+
+    pub fn frame_received(&self, opcode: u8) {
+        if let Some(count) = self.frames_received.get(opcode as usize) {
+            count.fetch_add(1, Relaxed);
+        }
+    }
+
+    pub fn frame_sent(&self, opcode: u8) {
+        if let Some(count) = self.frames_sent.get(opcode as usize) {
+            count.fetch_add(1, Relaxed);
+        }
+    }
+
+    pub fn in_flight_requests(&self, count: usize) {
+        self.in_flight_requests.store(count, Relaxed);
+    }
+
+    pub fn rtt(&self, rtt: Duration) {
+        let micros = rtt.as_micros().clamp(1, u128::from(u64::MAX)) as u64;
+        self.last_rtt_micros.store(micros, Relaxed);
+    }
+
+    pub fn encoding(&self, encoding: &'static str) {
+        if let Some(settled) = self
+            .settled
+            .write()
+            .expect("Failed to write stats.")
+            .as_mut()
+        {
+            settled.encoding = encoding;
+        }
+    }
+
+    pub fn snapshot(&self) -> ConnectionStats {
+        let (uptime, encoding, compression, bytes_read, bytes_written) =
+            match self.settled.read().expect("Failed to read stats.").as_ref() {
+                Some(settled) => (
+                    settled.clock.now() - settled.ready_at,
+                    Some(settled.encoding),
+                    settled.compression,
+                    settled.bytes_read.load(Relaxed),
+                    settled.bytes_written.load(Relaxed),
+                ),
+                None => (Duration::from_secs(0), None, None, 0, 0),
+            };
+        let last_rtt = match self.last_rtt_micros.load(Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        };
+        ConnectionStats {
+            frames_received: by_opcode(&self.frames_received),
+            frames_sent: by_opcode(&self.frames_sent),
+            bytes_read,
+            bytes_written,
+            in_flight_requests: self.in_flight_requests.load(Relaxed),
+            last_rtt,
+            uptime,
+            encoding,
+            compression,
+        }
+    }
+}
+
+fn by_opcode(counts: &[AtomicU64; OPCODES]) -> BTreeMap<u8, u64> {
+    counts
+        .iter()
+        .enumerate()
+        .map(|(opcode, count)| (opcode as u8, count.load(Relaxed)))
+        .filter(|(_opcode, count)| *count > 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn it_is_empty_until_ready() {
+        let stats = StatsCounters::default();
+        assert_eq!(stats.snapshot(), ConnectionStats::default());
+    }
+
+    #[test]
+    fn it_snapshots_the_counters() {
+        let clock = Arc::new(ManualClock::new());
+        let stats = StatsCounters::default();
+        let bytes_read = Arc::new(AtomicU64::new(10));
+        let bytes_written = Arc::new(AtomicU64::new(20));
+        stats.ready(
+            clock.clone(),
+            "json",
+            Some("gzip"),
+            bytes_read.clone(),
+            bytes_written,
+        );
+        stats.frame_received(5);
+        stats.frame_received(5);
+        stats.frame_sent(6);
+        stats.in_flight_requests(1);
+        stats.rtt(Duration::from_millis(3));
+        bytes_read.fetch_add(5, Relaxed);
+        clock.advance(Duration::from_secs(2));
+        stats.encoding("msgpack");
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.frames_received, vec![(5, 2)].into_iter().collect());
+        assert_eq!(snapshot.frames_sent, vec![(6, 1)].into_iter().collect());
+        assert_eq!(snapshot.bytes_read, 15);
+        assert_eq!(snapshot.bytes_written, 20);
+        assert_eq!(snapshot.in_flight_requests, 1);
+        assert_eq!(snapshot.last_rtt, Some(Duration::from_millis(3)));
+        assert_eq!(snapshot.uptime, Duration::from_secs(2));
+        assert_eq!(snapshot.encoding, Some("msgpack"));
+        assert_eq!(snapshot.compression, Some("gzip"));
+    }
+}
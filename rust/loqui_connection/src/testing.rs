@@ -0,0 +1,311 @@
+//! Drives an `EventHandler` with scripted `Event`s, without a socket, so tests can assert on the
+//! exact frames that come out. Enabled with the `test-support` feature.
+
+use crate::error::LoquiError;
+use crate::event_handler::EventHandler;
+use crate::framed_io::ReaderWriter;
+use crate::handler::{
+    ConnectionState, DelegatedFrame, FrameOutcome, Handler, HandshakeFuture, IntoErrorPayload,
+};
+use crate::id_sequence::IdSequence;
+use crate::sender::Sender;
+use crate::transport_options::TransportOptions;
+use bytesize::ByteSize;
+use failure::Error;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::StreamExt;
+use loqui_protocol::frames::LoquiFrame;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::net::TcpStream;
+
+pub use crate::connection::Event;
+
+/// Decides what a `ScriptedHandler` does with each delegated frame.
+pub type Script = Box<dyn FnMut(DelegatedFrame, &'static str) -> FrameOutcome + Send + Sync>;
+
+/// A `Handler` whose answers to delegated frames are scripted. Frames are ignored until a script
+/// is set.
+pub struct ScriptedHandler {
+    transport_options: TransportOptions,
+    script: Script,
+}
+
+impl ScriptedHandler {
+    pub fn new(transport_options: TransportOptions) -> Self {
+        Self {
+            transport_options,
+            script: Box::new(|_frame, _encoding| FrameOutcome::Ignore),
+        }
+    }
+
+    /// Answers every delegated frame with the outcome the script returns.
+    pub fn script<F>(mut self, script: F) -> Self
+    where
+        F: FnMut(DelegatedFrame, &'static str) -> FrameOutcome + Send + Sync + 'static,
+    {
+        self.script = Box::new(script);
+        self
+    }
+}
+
+impl Default for ScriptedHandler {
+    fn default() -> Self {
+        Self::new(TransportOptions::default())
+    }
+}
+
+impl IntoErrorPayload for ScriptedHandler {}
+
+impl Handler for ScriptedHandler {
+    type InternalEvent = ();
+    const SEND_GO_AWAY: bool = false;
+
+    fn max_payload_size(&self) -> ByteSize {
+        ByteSize::mb(1)
+    }
+
+    fn transport_options(&self) -> &TransportOptions {
+        &self.transport_options
+    }
+
+    fn upgrade(
+        &self,
+        _tcp_stream: TcpStream,
+    ) -> Pin<Box<dyn Future<Output = Result<TcpStream, Error>> + Send>> {
+        unreachable!("The harness starts out ready. There is no socket to upgrade.")
+    }
+
+    fn handshake(&mut self, _reader_writer: ReaderWriter) -> HandshakeFuture {
+        unreachable!("The harness starts out ready. There is no socket to handshake over.")
+    }
+
+    fn handle_frame(&mut self, frame: DelegatedFrame, encoding: &'static str) -> FrameOutcome {
+        (self.script)(frame, encoding)
+    }
+
+    fn handle_internal_event(
+        &mut self,
+        _event: (),
+        _id_sequence: &mut IdSequence,
+    ) -> Option<LoquiFrame> {
+        None
+    }
+
+    fn on_ping_received(&mut self) {}
+}
+
+/// Feeds `Event`s to an `EventHandler` that is ready, as after a handshake, and records every
+/// frame it would have written to the socket.
+pub struct Harness<H: Handler> {
+    event_handler: EventHandler<H>,
+    self_sender: Sender<H::InternalEvent>,
+    /// The events the event handler sent itself, e.g. responses of completed futures.
+    self_rx: UnboundedReceiver<Event<H::InternalEvent>>,
+    sent: Vec<LoquiFrame>,
+}
+
+impl<H: Handler> Harness<H> {
+    pub fn new(handler: H, encoding: &'static str) -> Self {
+        let (self_sender, self_rx) = Sender::new();
+        let metrics = handler.transport_options().metrics.clone();
+        let mut event_handler =
+            EventHandler::new(self_sender.clone(), handler, encoding, None, metrics);
+        event_handler.set_state(ConnectionState::Ready);
+        Self {
+            event_handler,
+            self_sender,
+            self_rx,
+            sent: vec![],
+        }
+    }
+
+    /// Handles a single event like the connection loop does. Returns the error the connection
+    /// would have closed with.
+    pub fn handle(&mut self, event: Event<H::InternalEvent>) -> Result<(), Error> {
+        if let Some(frame) = self.event_handler.handle_event(event)? {
+            self.sent.push(frame);
+        }
+        match self
+            .event_handler
+            .drain_complete()
+            .or_else(|| self.event_handler.half_close_complete())
+        {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Handles a frame as if it was received from the socket.
+    pub fn receive<F: Into<LoquiFrame>>(&mut self, frame: F) -> Result<(), Error> {
+        self.handle(Event::SocketReceive(frame.into()))
+    }
+
+    /// Handles the events the event handler already sent itself.
+    pub fn handle_queued(&mut self) -> Result<(), Error> {
+        while let Ok(Some(event)) = self.self_rx.try_next() {
+            self.self_sender.dequeued();
+            self.handle(event)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next event the event handler sends itself, e.g. once a spawned future
+    /// completed, and handles it. Must run inside a runtime.
+    pub async fn handle_next_queued(&mut self) -> Result<(), Error> {
+        let event = self
+            .self_rx
+            .next()
+            .await
+            .expect("The harness holds a sender.");
+        self.self_sender.dequeued();
+        self.handle(event)
+    }
+
+    /// Closes the event handler like the connection loop does once the connection was dropped,
+    /// handing the handler the frames of the events still queued.
+    pub fn close(&mut self) {
+        let unflushed = self.self_sender.drain_unflushed(&mut self.self_rx);
+        self.event_handler
+            .close(&LoquiError::ConnectionClosed.into(), unflushed);
+    }
+
+    /// The frames sent so far, in order.
+    pub fn sent(&self) -> &[LoquiFrame] {
+        &self.sent
+    }
+
+    /// Takes the frames sent so far, so the next assertion only sees new ones.
+    pub fn take_sent(&mut self) -> Vec<LoquiFrame> {
+        std::mem::take(&mut self.sent)
+    }
+
+    pub fn handler(&self) -> &H {
+        self.event_handler.handler()
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        self.event_handler.handler_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{LoquiError, LoquiErrorCode};
+    use crate::transport_options::ProtocolViolationPolicy;
+    use loqui_protocol::frames::{
+        Error as ErrorFrame, Hello, Ping, Pong, Priority, Request, Response,
+    };
+    use tokio::runtime::Runtime;
+
+    fn hello() -> Hello {
+        Hello {
+            flags: 0,
+            version: 1,
+            encodings: vec!["identity".to_string()],
+            compressions: vec![],
+            ping_interval_ms: None,
+            dictionary_ids: vec![],
+            auth_token: None,
+        }
+    }
+
+    #[test]
+    fn it_times_out_an_unanswered_ping() {
+        let mut harness = Harness::new(ScriptedHandler::default(), "identity");
+        harness.handle(Event::Ping).unwrap();
+        harness
+            .receive(Pong {
+                flags: 0,
+                sequence_id: 1,
+                token: None,
+                timestamps: None,
+            })
+            .unwrap();
+        harness.handle(Event::Ping).unwrap();
+
+        let error = harness.handle(Event::Ping).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::PingTimeout)
+        ));
+        let ping = |sequence_id| {
+            LoquiFrame::from(Ping {
+                flags: 0,
+                sequence_id,
+                token: None,
+                sent_at: None,
+            })
+        };
+        assert_eq!(harness.sent(), &[ping(1), ping(2)]);
+    }
+
+    #[test]
+    fn it_closes_on_a_hello_after_the_handshake() {
+        let mut harness = Harness::new(ScriptedHandler::default(), "identity");
+        let error = harness.receive(hello()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::InvalidOpcode { actual: 1, .. })
+        ));
+        assert!(harness.sent().is_empty());
+    }
+
+    #[test]
+    fn it_reports_a_hello_after_the_handshake_when_lenient() {
+        let handler = ScriptedHandler::new(
+            TransportOptions::builder()
+                .protocol_violation_policy(ProtocolViolationPolicy::Lenient)
+                .build()
+                .unwrap(),
+        );
+        let mut harness = Harness::new(handler, "identity");
+        harness.receive(hello()).unwrap();
+        match harness.take_sent().as_slice() {
+            [LoquiFrame::Error(ErrorFrame {
+                sequence_id: 0,
+                code,
+                ..
+            })] => assert_eq!(*code, LoquiErrorCode::ProtocolViolation as u16),
+            other => panic!("violation not reported. {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_sends_scripted_responses() {
+        let handler = ScriptedHandler::default().script(|frame, _encoding| match frame {
+            DelegatedFrame::Request(request) => FrameOutcome::Respond(Box::pin(async move {
+                Ok(Response {
+                    flags: 0,
+                    sequence_id: request.sequence_id,
+                    trace_id: None,
+                    payload: request.payload,
+                })
+            })),
+            _ => FrameOutcome::Ignore,
+        });
+        let mut harness = Harness::new(handler, "identity");
+        Runtime::new().unwrap().block_on(async {
+            harness
+                .receive(Request {
+                    flags: 0,
+                    sequence_id: 5,
+                    trace_id: None,
+                    idempotency_key: None,
+                    priority: Priority::Normal,
+                    timeout_ms: 0,
+                    payload: b"hello".to_vec(),
+                })
+                .unwrap();
+            harness.handle_next_queued().await.unwrap();
+        });
+        let response = Response {
+            flags: 0,
+            sequence_id: 5,
+            trace_id: None,
+            payload: b"hello".to_vec(),
+        };
+        assert_eq!(harness.sent(), &[response.into()]);
+    }
+}
@@ -0,0 +1,951 @@
+use crate::buffer_pool::BufferPool;
+use crate::clock::{Clock, SystemClock};
+use crate::compressor::{Compressor, DictionaryRegistry};
+use crate::id_sequence::{IdStrategyFactory, IncrementingIdsFactory};
+use crate::metrics::{Metrics, NoopMetrics};
+use crate::rate_limiter::RateLimit;
+use crate::spawner::{Spawn, TokioSpawn};
+#[cfg(feature = "wire-tap")]
+use crate::wire_tap::WireTap;
+use crate::LoquiError;
+use failure::Error;
+use loqui_protocol::frames::{AuthToken, Frame, Ping, Pong};
+use loqui_protocol::Flags;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// The flags of a `Request` the connection sets itself, so a request can't: `Flags::Compressed`
+/// when it compressed the payload, and `Flags::Streaming` on the requests of
+/// `Client::request_stream`, which are the only ones expecting a stream of responses.
+pub const CONNECTION_REQUEST_FLAGS: u8 = Flags::Compressed as u8 | Flags::Streaming as u8;
+
+/// Connection level settings shared by the client and the server.
+///
+/// Prefer constructing them with `TransportOptions::builder()`, which rejects inconsistent
+/// settings up front.
+#[derive(Debug, Clone)]
+pub struct TransportOptions {
+    /// How long the upgrade and handshake may take once the socket is connected, on top of the
+    /// deadline the connection was spawned with. A peer that doesn't complete it in time, e.g.
+    /// because it went silent halfway through its `Hello`, is dropped with
+    /// `LoquiError::HandshakeTimeout`. `None` only applies the spawn deadline.
+    pub handshake_timeout: Option<Duration>,
+    /// The maximum duration a delegated request may take to compute its response. When it is
+    /// exceeded, the request is cancelled and an `Error` frame is sent back. `None` means there is
+    /// no limit.
+    pub handler_timeout: Option<Duration>,
+    /// The longest timeout a `Request` may ask for with its `timeout_ms`. Longer ones are clamped
+    /// to it. `None` lets requests ask for any timeout.
+    pub max_request_timeout: Option<Duration>,
+    /// After being told to go away, how long in flight requests have to complete before the
+    /// connection is closed anyway.
+    pub drain_timeout: Duration,
+    /// How long a `Ping` may wait for its `Pong`. Several pings can be in flight at once, each
+    /// timing out on its own. `None` requires every ping to be answered before the next one.
+    pub ping_timeout: Option<Duration>,
+    /// When set, pings are only sent once no frame has been received for this long, since any
+    /// inbound frame already proves the other side is alive. Idleness is checked on every
+    /// negotiated ping interval. `None` pings on every interval.
+    pub idle_ping_interval: Option<Duration>,
+    /// At most one `Pong` is sent per window. Pings received within the window of the last pong
+    /// are answered once at its end, with a pong for the latest of them, so a peer flooding pings
+    /// can't make us send a pong for each. Peers pinging less often than this are answered right
+    /// away. A peer keeping several pings in flight must count a pong as answering the pings it
+    /// sent earlier too, as this crate does. `None`, the default, answers every ping.
+    pub pong_coalescing_window: Option<Duration>,
+    /// The ping interval the client proposes in its `Hello`. The server settles on the shorter of
+    /// it and its own, and both sides ping on that. `None` leaves it to the server.
+    pub proposed_ping_interval: Option<Duration>,
+    /// The token the client sends in its `Hello` for the server to check in its `authorize` hook.
+    /// It only shows as its length when the options are debug formatted.
+    pub auth_token: Option<AuthToken>,
+    /// When this many responses and events are waiting to be handled, e.g. because the socket is
+    /// slow to write, new requests are rejected with `LoquiErrorCode::ServiceUnavailable`.
+    /// `None` never rejects.
+    pub outbound_high_water_mark: Option<usize>,
+    /// Once rejecting, requests are accepted again when the queue drains to this depth.
+    pub outbound_low_water_mark: usize,
+    /// The most delegated futures that may be computing at once. Further requests are rejected
+    /// with `LoquiErrorCode::ServiceUnavailable` instead of being spawned. `None` never rejects.
+    pub max_concurrent_requests: Option<usize>,
+    /// At `max_concurrent_requests`, up to this many further requests wait for a slot instead of
+    /// being rejected. The waiting request of the highest `Priority` is served first, the one that
+    /// waited longest among equals. `None` rejects them right away.
+    pub request_queue_depth: Option<usize>,
+    /// How long a queued request waits before it counts as one priority higher, so a steady flow
+    /// of urgent requests can't starve the others.
+    pub priority_aging: Duration,
+    /// How long the response to a request with an idempotency key is kept, so a retry of the
+    /// request is answered with it instead of running the handler again. Failures aren't kept,
+    /// and neither are streamed responses. A retry arriving while the first attempt is still
+    /// being computed runs the handler again, and so does a retry on another connection since
+    /// each connection keeps its own. `None` keeps nothing.
+    pub dedup_ttl: Option<Duration>,
+    /// The most responses kept for `dedup_ttl`. The oldest is dropped to make room.
+    pub dedup_capacity: usize,
+    /// The most request payload bytes that may be computing at once. A request that would go over
+    /// it is rejected with `LoquiErrorCode::ServiceUnavailable`, unless nothing else is in flight.
+    /// Applies on top of `max_concurrent_requests`. `None` never rejects.
+    pub max_in_flight_bytes: Option<usize>,
+    /// The most `sequence_id`s that may be open at once in each direction, bounding the state kept
+    /// for them. This side allocates no more ids for its own requests, pushes awaiting acks,
+    /// streams and subscriptions while that many wait for the other side; new ones fail locally
+    /// with `LoquiErrorCode::ServiceUnavailable` instead. Requests of the other side past that
+    /// many computing or queued are rejected with it before anything is kept for them, also when
+    /// `max_concurrent_requests` isn't set. `None` never rejects.
+    pub max_open_sequence_ids: Option<usize>,
+    /// Supported compressions, in order of preference. The client advertises them in its `Hello`
+    /// and the server picks the first one it also supports. Empty disables compression.
+    pub compressors: Vec<Arc<dyn Compressor>>,
+    /// Pre-shared dictionaries the negotiated compression is primed with, which helps small
+    /// payloads that share a lot of structure. The client advertises their ids in its `Hello` and
+    /// the server picks the first one it also has, if the compression supports dictionaries.
+    /// Both sides must register the same bytes under an id.
+    pub dictionaries: DictionaryRegistry,
+    /// Payloads smaller than this are sent uncompressed even when a compression was negotiated,
+    /// since compressing them tends to make them bigger. Each frame's flags say whether it is
+    /// compressed.
+    pub compression_min_bytes: usize,
+    /// Caps the payload of any frame below the configured max payload size. A frame whose length
+    /// prefix exceeds it is rejected before its payload is read, closing the connection with
+    /// `LoquiErrorCode::PayloadTooLarge`. `None` only applies the configured max payload size.
+    pub max_payload_bytes: Option<usize>,
+    /// What to do when the other side violates the protocol in a way the connection can survive,
+    /// e.g. by sending another `Hello` after the handshake.
+    pub protocol_violation_policy: ProtocolViolationPolicy,
+    /// What to do with a frame only this side should send, i.e. a `Response` received by a
+    /// server or a `Request` received by a client. They're dropped by default.
+    pub unexpected_frame_policy: UnexpectedFramePolicy,
+    /// Observes the frames sent and received by the connection.
+    pub metrics: Arc<dyn Metrics>,
+    /// Tags every connection made with these options, along with an id of its own, see
+    /// `ConnectionTag`. Empty by default.
+    pub labels: Vec<(String, String)>,
+    /// Tells the time for ping timeouts and idleness. Tests can swap in a `ManualClock`.
+    pub clock: Arc<dyn Clock>,
+    /// Makes the strategy each connection allocates `sequence_id`s with. Counts up by default.
+    pub id_strategy: Arc<dyn IdStrategyFactory>,
+    /// Runs the futures computing responses, streams and subscriptions. Spawns them on tokio by
+    /// default.
+    pub spawner: Arc<dyn Spawn>,
+    /// Timestamps pings, and answers timestamped pings, so each `Pong` tells the one-way delay and
+    /// how far the other side's clock is off, see `Handler::observe_clock_skew`. Off by default.
+    /// Both sides must turn it on, otherwise plain pings are sent.
+    pub ping_timestamps: bool,
+    /// Ends the payload of every `Request`, `Response` and `Push` with a CRC32 of it, for
+    /// transports without integrity checks of their own. A frame that doesn't match its checksum
+    /// closes the connection with `LoquiError::ChecksumMismatch`. Off by default. Both sides must
+    /// turn it on, otherwise frames are sent without checksums.
+    pub checksums: bool,
+    /// Negotiates every encoding both sides support instead of just one, and starts the payload of
+    /// every `Request`, `Response` and `Push` with a 1 byte id of the encoding it is encoded with,
+    /// e.g. to send debuggable control requests and bulk data on the same connection. Responses
+    /// use the encoding of their request. Off by default. Both sides must turn it on, otherwise
+    /// every frame uses the negotiated encoding.
+    pub encoding_ids: bool,
+    /// Calls `Handler::on_flush` once each request, response, error or push with a sequence id
+    /// has been flushed to the socket. Off by default since it costs an event per frame.
+    pub notify_flush: bool,
+    /// Flow controls streamed responses. Streaming requests ask for it and give back a credit
+    /// for each response consumed. A flow controlled stream starts with this many credits and
+    /// pauses, without holding up other streams, until the other side grants more. `None`
+    /// streams without limits.
+    pub stream_window: Option<u32>,
+    /// When the outbound queue stays at least this deep for `slow_consumer_duration`, e.g.
+    /// because the other side reads slower than we send, `Handler::on_slow_consumer` is called on
+    /// every ping tick until it drains. Nothing is dropped. `None` never reports.
+    pub slow_consumer_depth: Option<usize>,
+    /// How long the outbound queue must stay at `slow_consumer_depth` before it is reported.
+    pub slow_consumer_duration: Duration,
+    /// Closes the connection with a `GoAway(Normal)` once no frame other than pings and pongs
+    /// was sent or received for this long, to release connections nothing uses. Checked on every
+    /// ping interval. Requests still being computed keep it open, so a client waiting on a slow
+    /// server should set it above its request timeout. `None` keeps idle connections open.
+    pub idle_timeout: Option<Duration>,
+    /// Closes the connection with `LoquiError::WriteStalled` once a frame wasn't flushed for this
+    /// long, e.g. because the socket stopped draining while reads, and so pings, still work. No
+    /// `GoAway` is sent since it couldn't be written either. `None` waits on writes forever.
+    pub write_stall_timeout: Option<Duration>,
+    /// Lets either side switch the connection to another encoding with a `Renegotiate` frame,
+    /// without reconnecting. Off by default. Both sides must turn it on, otherwise the switch is
+    /// refused.
+    pub renegotiation: bool,
+    /// Lets the server send requests to the client and await their responses, for RPC both ways.
+    /// Off by default. Both sides must turn it on, otherwise the requests and their responses are
+    /// unexpected frames, see `unexpected_frame_policy`.
+    pub peer_requests: bool,
+    /// Limits how often frames of each opcode may be received, checked before they're handled.
+    /// Requests over their limit are rejected with `LoquiErrorCode::RateLimited`, other frames are
+    /// dropped. `Ping` and `Pong` can't be limited, so liveness is never throttled.
+    pub rate_limits: HashMap<u8, RateLimit>,
+    /// Rejects acked pushes over their rate limit with `LoquiErrorCode::RateLimited` instead of
+    /// dropping them. Pushes that aren't acked are always dropped since they can't be answered.
+    pub reject_rate_limited_pushes: bool,
+    /// Sets `TCP_NODELAY` on the socket, so each frame is sent as soon as it is written instead of
+    /// waiting for Nagle's algorithm to coalesce it with the next. On by default for request
+    /// latency. Turning it off trades latency for fewer packets under a flood of small frames,
+    /// but can hold a lone request back until the previous one is acked. `RequestBatch`es
+    /// already coalesce requests without that delay.
+    pub tcp_nodelay: bool,
+    /// Writes the frames of the events that are ready at once to the socket together, instead of
+    /// one write per frame. Frames keep their order, and the buffer is written as soon as no
+    /// event is ready or it holds `max_coalesce_bytes`. Flush notifications wait for the write.
+    /// Off by default.
+    pub write_coalescing: bool,
+    /// The most bytes buffered for a single write when `write_coalescing` is on. The buffer is
+    /// written once it holds at least this much, so a frame may take it over.
+    pub max_coalesce_bytes: usize,
+    /// The size of the socket's send buffer, `SO_SNDBUF`. The OS may round it. `None` keeps the
+    /// OS default.
+    pub send_buffer_size: Option<usize>,
+    /// The size of the socket's receive buffer, `SO_RCVBUF`. The OS may round it. `None` keeps
+    /// the OS default.
+    pub recv_buffer_size: Option<usize>,
+    /// Reuses the buffers of written payloads instead of freeing them, so payloads encoded with
+    /// `BufferPool::encode`, e.g. by a request handler, don't have to be allocated under load.
+    /// Shared by every connection with these options. `None` by default.
+    pub buffer_pool: Option<BufferPool>,
+    /// Holds requests and pushes made before the handshake completed until it does, instead of
+    /// failing them with `LoquiError::NotReady`. They still fail if the handshake does. Off by
+    /// default.
+    pub queue_until_ready: bool,
+    /// OR'd into the flags of every `Request` and `RequestBatch` the client sends, e.g.
+    /// `Flags::NO_COMPRESS`, along with the request's own, see `Client::request_with_flags`. The
+    /// flags a frame derives from its fields, e.g. `Flags::Traced`, are still set by it. Can't
+    /// set `CONNECTION_REQUEST_FLAGS`. `0` by default.
+    pub default_request_flags: u8,
+    /// Sees the raw bytes of the connection, for wire-level debugging. Only with the `wire-tap`
+    /// feature. `None` by default.
+    #[cfg(feature = "wire-tap")]
+    pub wire_tap: Option<Arc<dyn WireTap>>,
+}
+
+/// How a connection reacts to a non-fatal protocol violation by the other side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolViolationPolicy {
+    /// Close the connection with a `GoAway`.
+    #[default]
+    Strict,
+    /// Send back an `Error` frame with `LoquiErrorCode::ProtocolViolation` and sequence id `0`,
+    /// which is never used by a request, and keep the connection open.
+    Lenient,
+}
+
+/// How a connection reacts to receiving a frame only it should send, which means the other side is
+/// confused about its role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnexpectedFramePolicy {
+    /// Log the frame and drop it.
+    #[default]
+    Drop,
+    /// Send back an `Error` frame with `LoquiErrorCode::ProtocolViolation`. A `Request` is
+    /// answered with its sequence id, anything else with `0`, which is never used by a request.
+    Error,
+    /// Close the connection with a `GoAway`.
+    GoAway,
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: None,
+            handler_timeout: None,
+            max_request_timeout: None,
+            drain_timeout: Duration::from_secs(5),
+            ping_timeout: None,
+            idle_ping_interval: None,
+            pong_coalescing_window: None,
+            proposed_ping_interval: None,
+            auth_token: None,
+            outbound_high_water_mark: None,
+            outbound_low_water_mark: 0,
+            max_concurrent_requests: None,
+            request_queue_depth: None,
+            priority_aging: Duration::from_secs(1),
+            dedup_ttl: None,
+            dedup_capacity: 1024,
+            max_in_flight_bytes: None,
+            max_open_sequence_ids: None,
+            compressors: vec![],
+            dictionaries: DictionaryRegistry::default(),
+            compression_min_bytes: 1024,
+            max_payload_bytes: None,
+            protocol_violation_policy: ProtocolViolationPolicy::default(),
+            unexpected_frame_policy: UnexpectedFramePolicy::default(),
+            metrics: Arc::new(NoopMetrics),
+            labels: vec![],
+            clock: Arc::new(SystemClock),
+            id_strategy: Arc::new(IncrementingIdsFactory),
+            spawner: Arc::new(TokioSpawn),
+            ping_timestamps: false,
+            checksums: false,
+            encoding_ids: false,
+            notify_flush: false,
+            stream_window: None,
+            slow_consumer_depth: None,
+            slow_consumer_duration: Duration::from_secs(10),
+            idle_timeout: None,
+            write_stall_timeout: None,
+            renegotiation: false,
+            peer_requests: false,
+            rate_limits: HashMap::new(),
+            reject_rate_limited_pushes: false,
+            tcp_nodelay: true,
+            write_coalescing: false,
+            max_coalesce_bytes: 8 * 1024,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            buffer_pool: None,
+            queue_until_ready: false,
+            default_request_flags: 0,
+            #[cfg(feature = "wire-tap")]
+            wire_tap: None,
+        }
+    }
+}
+
+impl TransportOptions {
+    pub fn builder() -> TransportOptionsBuilder {
+        TransportOptionsBuilder::default()
+    }
+
+    /// Applies the socket options to a socket before the connection is negotiated on it.
+    pub(crate) fn configure_socket(&self, tcp_stream: &TcpStream) -> Result<(), Error> {
+        tcp_stream.set_nodelay(self.tcp_nodelay)?;
+        if let Some(send_buffer_size) = self.send_buffer_size {
+            tcp_stream.set_send_buffer_size(send_buffer_size)?;
+        }
+        if let Some(recv_buffer_size) = self.recv_buffer_size {
+            tcp_stream.set_recv_buffer_size(recv_buffer_size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds `TransportOptions`, validating them in `build`. Anything that isn't set keeps its
+/// default.
+#[derive(Debug, Clone, Default)]
+pub struct TransportOptionsBuilder {
+    options: TransportOptions,
+}
+
+impl TransportOptionsBuilder {
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.options.handshake_timeout = Some(handshake_timeout);
+        self
+    }
+
+    pub fn handler_timeout(mut self, handler_timeout: Duration) -> Self {
+        self.options.handler_timeout = Some(handler_timeout);
+        self
+    }
+
+    pub fn max_request_timeout(mut self, max_request_timeout: Duration) -> Self {
+        self.options.max_request_timeout = Some(max_request_timeout);
+        self
+    }
+
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.options.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn ping_timeout(mut self, ping_timeout: Duration) -> Self {
+        self.options.ping_timeout = Some(ping_timeout);
+        self
+    }
+
+    pub fn idle_ping_interval(mut self, idle_ping_interval: Duration) -> Self {
+        self.options.idle_ping_interval = Some(idle_ping_interval);
+        self
+    }
+
+    pub fn pong_coalescing_window(mut self, pong_coalescing_window: Duration) -> Self {
+        self.options.pong_coalescing_window = Some(pong_coalescing_window);
+        self
+    }
+
+    /// Answers every `Ping` with its own `Pong`, however often they arrive.
+    pub fn without_pong_coalescing(mut self) -> Self {
+        self.options.pong_coalescing_window = None;
+        self
+    }
+
+    pub fn proposed_ping_interval(mut self, proposed_ping_interval: Duration) -> Self {
+        self.options.proposed_ping_interval = Some(proposed_ping_interval);
+        self
+    }
+
+    pub fn auth_token(mut self, auth_token: Vec<u8>) -> Self {
+        self.options.auth_token = Some(AuthToken(auth_token));
+        self
+    }
+
+    /// Sets the high and low water marks of the outbound queue together.
+    pub fn outbound_water_marks(mut self, high: usize, low: usize) -> Self {
+        self.options.outbound_high_water_mark = Some(high);
+        self.options.outbound_low_water_mark = low;
+        self
+    }
+
+    pub fn max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.options.max_concurrent_requests = Some(max_concurrent_requests);
+        self
+    }
+
+    /// Queues requests over `max_concurrent_requests`, aging them by one priority every
+    /// `priority_aging`.
+    pub fn request_queue(mut self, depth: usize, priority_aging: Duration) -> Self {
+        self.options.request_queue_depth = Some(depth);
+        self.options.priority_aging = priority_aging;
+        self
+    }
+
+    /// Keeps up to `capacity` responses to idempotent requests for `ttl` to answer retries with.
+    pub fn dedup_window(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.options.dedup_ttl = Some(ttl);
+        self.options.dedup_capacity = capacity;
+        self
+    }
+
+    pub fn max_in_flight_bytes(mut self, max_in_flight_bytes: usize) -> Self {
+        self.options.max_in_flight_bytes = Some(max_in_flight_bytes);
+        self
+    }
+
+    pub fn max_open_sequence_ids(mut self, max_open_sequence_ids: usize) -> Self {
+        self.options.max_open_sequence_ids = Some(max_open_sequence_ids);
+        self
+    }
+
+    /// Adds a compression, after those already added in order of preference.
+    pub fn compressor(mut self, compressor: Arc<dyn Compressor>) -> Self {
+        self.options.compressors.push(compressor);
+        self
+    }
+
+    /// Adds zstd at the compression level, see `ZstdCompressor::new`.
+    #[cfg(feature = "zstd")]
+    pub fn zstd(self, level: i32) -> Self {
+        self.compressor(Arc::new(crate::compressors::ZstdCompressor::new(level)))
+    }
+
+    /// Registers a pre-shared dictionary, after those already registered in order of preference.
+    pub fn dictionary(mut self, id: u32, dictionary: Vec<u8>) -> Self {
+        self.options.dictionaries.register(id, dictionary);
+        self
+    }
+
+    pub fn compression_min_bytes(mut self, compression_min_bytes: usize) -> Self {
+        self.options.compression_min_bytes = compression_min_bytes;
+        self
+    }
+
+    pub fn max_payload_bytes(mut self, max_payload_bytes: usize) -> Self {
+        self.options.max_payload_bytes = Some(max_payload_bytes);
+        self
+    }
+
+    pub fn protocol_violation_policy(
+        mut self,
+        protocol_violation_policy: ProtocolViolationPolicy,
+    ) -> Self {
+        self.options.protocol_violation_policy = protocol_violation_policy;
+        self
+    }
+
+    pub fn unexpected_frame_policy(
+        mut self,
+        unexpected_frame_policy: UnexpectedFramePolicy,
+    ) -> Self {
+        self.options.unexpected_frame_policy = unexpected_frame_policy;
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.options.metrics = metrics;
+        self
+    }
+
+    /// Adds a label, after those already added.
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.options.labels.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.options.clock = clock;
+        self
+    }
+
+    pub fn id_strategy(mut self, id_strategy: Arc<dyn IdStrategyFactory>) -> Self {
+        self.options.id_strategy = id_strategy;
+        self
+    }
+
+    pub fn spawner(mut self, spawner: Arc<dyn Spawn>) -> Self {
+        self.options.spawner = spawner;
+        self
+    }
+
+    pub fn ping_timestamps(mut self, ping_timestamps: bool) -> Self {
+        self.options.ping_timestamps = ping_timestamps;
+        self
+    }
+
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.options.checksums = checksums;
+        self
+    }
+
+    pub fn encoding_ids(mut self, encoding_ids: bool) -> Self {
+        self.options.encoding_ids = encoding_ids;
+        self
+    }
+
+    pub fn notify_flush(mut self, notify_flush: bool) -> Self {
+        self.options.notify_flush = notify_flush;
+        self
+    }
+
+    pub fn stream_window(mut self, stream_window: u32) -> Self {
+        self.options.stream_window = Some(stream_window);
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.options.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    pub fn write_stall_timeout(mut self, write_stall_timeout: Duration) -> Self {
+        self.options.write_stall_timeout = Some(write_stall_timeout);
+        self
+    }
+
+    pub fn renegotiation(mut self, renegotiation: bool) -> Self {
+        self.options.renegotiation = renegotiation;
+        self
+    }
+
+    pub fn peer_requests(mut self, peer_requests: bool) -> Self {
+        self.options.peer_requests = peer_requests;
+        self
+    }
+
+    /// Limits frames of the opcode, e.g. `Push::OPCODE`, replacing its previous limit.
+    pub fn rate_limit(mut self, opcode: u8, rate_limit: RateLimit) -> Self {
+        self.options.rate_limits.insert(opcode, rate_limit);
+        self
+    }
+
+    pub fn reject_rate_limited_pushes(mut self, reject_rate_limited_pushes: bool) -> Self {
+        self.options.reject_rate_limited_pushes = reject_rate_limited_pushes;
+        self
+    }
+
+    pub fn tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.options.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    /// Turns `write_coalescing` on, writing at most about `max_coalesce_bytes` at once.
+    pub fn write_coalescing(mut self, max_coalesce_bytes: usize) -> Self {
+        self.options.write_coalescing = true;
+        self.options.max_coalesce_bytes = max_coalesce_bytes;
+        self
+    }
+
+    /// Sets the sizes of the socket's send and receive buffers together.
+    pub fn socket_buffer_sizes(mut self, send: usize, recv: usize) -> Self {
+        self.options.send_buffer_size = Some(send);
+        self.options.recv_buffer_size = Some(recv);
+        self
+    }
+
+    /// Pools the buffers of written payloads, keeping at most `max_bytes` of them around.
+    pub fn buffer_pool(mut self, max_bytes: usize) -> Self {
+        self.options.buffer_pool = Some(BufferPool::new(max_bytes));
+        self
+    }
+
+    pub fn queue_until_ready(mut self, queue_until_ready: bool) -> Self {
+        self.options.queue_until_ready = queue_until_ready;
+        self
+    }
+
+    pub fn default_request_flags(mut self, default_request_flags: u8) -> Self {
+        self.options.default_request_flags = default_request_flags;
+        self
+    }
+
+    #[cfg(feature = "wire-tap")]
+    pub fn wire_tap(mut self, wire_tap: Arc<dyn WireTap>) -> Self {
+        self.options.wire_tap = Some(wire_tap);
+        self
+    }
+
+    pub fn slow_consumer(mut self, depth: usize, duration: Duration) -> Self {
+        self.options.slow_consumer_depth = Some(depth);
+        self.options.slow_consumer_duration = duration;
+        self
+    }
+
+    /// Validates the settings. Fails with `LoquiError::InvalidTransportOptions` if they are
+    /// inconsistent.
+    pub fn build(self) -> Result<TransportOptions, Error> {
+        let options = self.options;
+        let zero_durations = [
+            ("handshake_timeout", options.handshake_timeout),
+            ("handler_timeout", options.handler_timeout),
+            ("max_request_timeout", options.max_request_timeout),
+            ("drain_timeout", Some(options.drain_timeout)),
+            ("ping_timeout", options.ping_timeout),
+            ("idle_ping_interval", options.idle_ping_interval),
+            ("pong_coalescing_window", options.pong_coalescing_window),
+            ("proposed_ping_interval", options.proposed_ping_interval),
+            ("idle_timeout", options.idle_timeout),
+            ("write_stall_timeout", options.write_stall_timeout),
+            ("priority_aging", Some(options.priority_aging)),
+            ("dedup_ttl", options.dedup_ttl),
+            (
+                "slow_consumer_duration",
+                Some(options.slow_consumer_duration),
+            ),
+        ];
+        for (name, duration) in zero_durations.iter() {
+            if *duration == Some(Duration::from_secs(0)) {
+                return Err(invalid(format!("{} must be greater than zero", name)));
+            }
+        }
+        if let Some(high_water_mark) = options.outbound_high_water_mark {
+            if high_water_mark == 0 {
+                return Err(invalid(
+                    "outbound_high_water_mark must be greater than zero",
+                ));
+            }
+            if options.outbound_low_water_mark >= high_water_mark {
+                return Err(invalid(
+                    "outbound_low_water_mark must be below outbound_high_water_mark",
+                ));
+            }
+        }
+        if options.max_concurrent_requests == Some(0) {
+            return Err(invalid("max_concurrent_requests must be greater than zero"));
+        }
+        if let Some(request_queue_depth) = options.request_queue_depth {
+            if request_queue_depth == 0 {
+                return Err(invalid("request_queue_depth must be greater than zero"));
+            }
+            if options.max_concurrent_requests.is_none() {
+                return Err(invalid(
+                    "request_queue_depth requires max_concurrent_requests",
+                ));
+            }
+        }
+        if options.dedup_ttl.is_some() && options.dedup_capacity == 0 {
+            return Err(invalid("dedup_capacity must be greater than zero"));
+        }
+        if options.max_in_flight_bytes == Some(0) {
+            return Err(invalid("max_in_flight_bytes must be greater than zero"));
+        }
+        if options.max_open_sequence_ids == Some(0) {
+            return Err(invalid("max_open_sequence_ids must be greater than zero"));
+        }
+        if options.write_coalescing && options.max_coalesce_bytes == 0 {
+            return Err(invalid("max_coalesce_bytes must be greater than zero"));
+        }
+        if options.slow_consumer_depth == Some(0) {
+            return Err(invalid("slow_consumer_depth must be greater than zero"));
+        }
+        if options.send_buffer_size == Some(0) || options.recv_buffer_size == Some(0) {
+            return Err(invalid("socket buffer sizes must be greater than zero"));
+        }
+        if let Some(buffer_pool) = &options.buffer_pool {
+            if buffer_pool.max_bytes() == 0 {
+                return Err(invalid("buffer_pool must be greater than zero"));
+            }
+        }
+        if options.default_request_flags & CONNECTION_REQUEST_FLAGS != 0 {
+            return Err(invalid(
+                "default_request_flags can't set the compressed or streaming flags",
+            ));
+        }
+        if options.stream_window == Some(0) {
+            return Err(invalid("stream_window must be greater than zero"));
+        }
+        if options.rate_limits.contains_key(&Ping::OPCODE)
+            || options.rate_limits.contains_key(&Pong::OPCODE)
+        {
+            return Err(invalid("rate_limits must not limit Ping or Pong"));
+        }
+        if options
+            .rate_limits
+            .values()
+            .any(|rate_limit| rate_limit.per_second == 0 || rate_limit.burst == 0)
+        {
+            return Err(invalid("rate_limits must be greater than zero"));
+        }
+        if let Some(max_payload_bytes) = options.max_payload_bytes {
+            if max_payload_bytes == 0 {
+                return Err(invalid("max_payload_bytes must be greater than zero"));
+            }
+            if options.compression_min_bytes > max_payload_bytes {
+                return Err(invalid(
+                    "compression_min_bytes must not exceed max_payload_bytes",
+                ));
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn invalid<S: Into<String>>(reason: S) -> Error {
+    LoquiError::InvalidTransportOptions {
+        reason: reason.into(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+
+    fn reason(result: Result<TransportOptions, Error>) -> String {
+        match result.unwrap_err().downcast::<LoquiError>().unwrap() {
+            LoquiError::InvalidTransportOptions { reason } => reason,
+            error => panic!("unexpected error. error={:?}", error),
+        }
+    }
+
+    #[test]
+    fn it_builds_valid_options() {
+        let options = TransportOptions::builder()
+            .ping_timeout(Duration::from_secs(3))
+            .outbound_water_marks(10, 5)
+            .compression_min_bytes(64)
+            .build()
+            .unwrap();
+        assert_eq!(options.ping_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(options.outbound_high_water_mark, Some(10));
+        assert_eq!(options.outbound_low_water_mark, 5);
+        assert_eq!(options.compression_min_bytes, 64);
+    }
+
+    #[test]
+    fn it_turns_pong_coalescing_on_and_off() {
+        assert_eq!(TransportOptions::default().pong_coalescing_window, None);
+        let options = TransportOptions::builder()
+            .pong_coalescing_window(Duration::from_millis(10))
+            .without_pong_coalescing()
+            .build()
+            .unwrap();
+        assert_eq!(options.pong_coalescing_window, None);
+        let result = TransportOptions::builder()
+            .pong_coalescing_window(Duration::from_secs(0))
+            .build();
+        assert_eq!(
+            reason(result),
+            "pong_coalescing_window must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_zero_ping_interval() {
+        let result = TransportOptions::builder()
+            .idle_ping_interval(Duration::from_secs(0))
+            .build();
+        assert_eq!(
+            reason(result),
+            "idle_ping_interval must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_zero_handshake_timeout() {
+        let result = TransportOptions::builder()
+            .handshake_timeout(Duration::from_secs(0))
+            .build();
+        assert_eq!(
+            reason(result),
+            "handshake_timeout must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_rejects_inverted_water_marks() {
+        let result = TransportOptions::builder()
+            .outbound_water_marks(5, 10)
+            .build();
+        assert_eq!(
+            reason(result),
+            "outbound_low_water_mark must be below outbound_high_water_mark"
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_zero_slow_consumer_depth() {
+        let result = TransportOptions::builder()
+            .slow_consumer(0, Duration::from_secs(1))
+            .build();
+        assert_eq!(
+            reason(result),
+            "slow_consumer_depth must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_rejects_an_empty_buffer_pool() {
+        let result = TransportOptions::builder().buffer_pool(0).build();
+        assert_eq!(reason(result), "buffer_pool must be greater than zero");
+    }
+
+    #[test]
+    fn it_rejects_default_request_flags_set_by_the_connection() {
+        for flags in [Flags::Compressed as u8, Flags::Streaming as u8] {
+            let result = TransportOptions::builder()
+                .default_request_flags(flags)
+                .build();
+            assert_eq!(
+                reason(result),
+                "default_request_flags can't set the compressed or streaming flags"
+            );
+        }
+        assert!(TransportOptions::builder()
+            .default_request_flags(Flags::NO_COMPRESS)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn it_rejects_zero_socket_buffer_sizes() {
+        let result = TransportOptions::builder()
+            .socket_buffer_sizes(0, 4096)
+            .build();
+        assert_eq!(
+            reason(result),
+            "socket buffer sizes must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_coalesces_writes() {
+        assert!(!TransportOptions::default().write_coalescing);
+        let options = TransportOptions::builder()
+            .write_coalescing(4096)
+            .build()
+            .unwrap();
+        assert!(options.write_coalescing);
+        assert_eq!(options.max_coalesce_bytes, 4096);
+        let result = TransportOptions::builder().write_coalescing(0).build();
+        assert_eq!(
+            reason(result),
+            "max_coalesce_bytes must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_configures_the_socket() {
+        let options = TransportOptions::builder()
+            .socket_buffer_sizes(64 * 1024, 64 * 1024)
+            .build()
+            .unwrap();
+        Runtime::new().unwrap().block_on(async move {
+            let mut listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+            let address = listener.local_addr().unwrap();
+            let (tcp_stream, _accepted) =
+                join(TcpStream::connect(address), listener.accept()).await;
+            let tcp_stream = tcp_stream.unwrap();
+            options.configure_socket(&tcp_stream).unwrap();
+            assert!(tcp_stream.nodelay().unwrap());
+            // The OS may round the sizes, e.g. Linux doubles them.
+            assert!(tcp_stream.send_buffer_size().unwrap() >= 64 * 1024);
+            assert!(tcp_stream.recv_buffer_size().unwrap() >= 64 * 1024);
+
+            let options = TransportOptions::builder()
+                .tcp_nodelay(false)
+                .build()
+                .unwrap();
+            options.configure_socket(&tcp_stream).unwrap();
+            assert!(!tcp_stream.nodelay().unwrap());
+        });
+    }
+
+    #[test]
+    fn it_rejects_an_empty_dedup_window() {
+        let result = TransportOptions::builder()
+            .dedup_window(Duration::from_secs(10), 0)
+            .build();
+        assert_eq!(reason(result), "dedup_capacity must be greater than zero");
+    }
+
+    #[test]
+    fn it_rejects_zero_max_in_flight_bytes() {
+        let result = TransportOptions::builder().max_in_flight_bytes(0).build();
+        assert_eq!(
+            reason(result),
+            "max_in_flight_bytes must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_rejects_zero_max_open_sequence_ids() {
+        let result = TransportOptions::builder().max_open_sequence_ids(0).build();
+        assert_eq!(
+            reason(result),
+            "max_open_sequence_ids must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_request_queue_without_a_concurrency_limit() {
+        let result = TransportOptions::builder()
+            .request_queue(8, Duration::from_secs(1))
+            .build();
+        assert_eq!(
+            reason(result),
+            "request_queue_depth requires max_concurrent_requests"
+        );
+    }
+
+    #[test]
+    fn it_rejects_rate_limits_on_keepalives() {
+        let rate_limit = RateLimit {
+            per_second: 10,
+            burst: 10,
+        };
+        let result = TransportOptions::builder()
+            .rate_limit(Pong::OPCODE, rate_limit)
+            .build();
+        assert_eq!(reason(result), "rate_limits must not limit Ping or Pong");
+    }
+
+    #[test]
+    fn it_rejects_a_zero_max_payload() {
+        let result = TransportOptions::builder().max_payload_bytes(0).build();
+        assert_eq!(
+            reason(result),
+            "max_payload_bytes must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_compression_min_above_the_max_payload() {
+        let result = TransportOptions::builder()
+            .max_payload_bytes(512)
+            .compression_min_bytes(1024)
+            .build();
+        assert_eq!(
+            reason(result),
+            "compression_min_bytes must not exceed max_payload_bytes"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! A tap on the raw bytes of a connection, e.g. to hexdump a handshake the other side doesn't
+//! understand. Only with the `wire-tap` feature. Without it the tap is a no-op.
+
+#[cfg(feature = "wire-tap")]
+pub use self::enabled::{WireDirection, WireTap};
+
+#[cfg(feature = "wire-tap")]
+pub(crate) use self::enabled::Tap;
+
+#[cfg(not(feature = "wire-tap"))]
+pub(crate) use self::disabled::Tap;
+
+#[cfg(feature = "wire-tap")]
+mod enabled {
+    use crate::transport_options::TransportOptions;
+    use std::fmt::Debug;
+    use std::sync::Arc;
+
+    /// Whether bytes were read from or written to the socket.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum WireDirection {
+        Read,
+        Written,
+    }
+
+    /// Sees the bytes of a connection exactly as they cross the socket, including the handshake:
+    /// read ones before they are decoded and written ones once they are encoded. See
+    /// `TransportOptions::wire_tap`.
+    pub trait WireTap: Debug + Send + Sync + 'static {
+        /// Called from the connection's socket with every chunk read or written, in order per
+        /// direction. Frames may span chunks and a chunk may hold several frames. Blocking here
+        /// blocks the connection.
+        fn observe(&self, direction: WireDirection, bytes: &[u8]);
+    }
+
+    /// The tap of a socket, if `TransportOptions::wire_tap` is set.
+    #[derive(Debug)]
+    pub(crate) struct Tap(Option<Arc<dyn WireTap>>);
+
+    impl Tap {
+        pub fn new(transport_options: &TransportOptions) -> Self {
+            Self(transport_options.wire_tap.clone())
+        }
+
+        pub fn untapped() -> Self {
+            Self(None)
+        }
+
+        pub fn read(&self, bytes: &[u8]) {
+            self.observe(WireDirection::Read, bytes)
+        }
+
+        pub fn written(&self, bytes: &[u8]) {
+            self.observe(WireDirection::Written, bytes)
+        }
+
+        fn observe(&self, direction: WireDirection, bytes: &[u8]) {
+            // A read of nothing is the end of the socket.
+            match &self.0 {
+                Some(wire_tap) if !bytes.is_empty() => wire_tap.observe(direction, bytes),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "wire-tap"))]
+mod disabled {
+    use crate::transport_options::TransportOptions;
+
+    #[derive(Debug)]
+    pub(crate) struct Tap;
+
+    impl Tap {
+        pub fn new(_transport_options: &TransportOptions) -> Self {
+            Tap
+        }
+
+        pub fn untapped() -> Self {
+            Tap
+        }
+
+        pub fn read(&self, _bytes: &[u8]) {}
+
+        pub fn written(&self, _bytes: &[u8]) {}
+    }
+}
@@ -0,0 +1,110 @@
+use crate::LoquiError;
+use failure::Error;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+/// Notices frames that weren't flushed within `TransportOptions::write_stall_timeout`, e.g.
+/// because the socket stopped draining while reads still work and pings still get answered.
+#[derive(Debug)]
+pub(crate) struct WriteWatchdog {
+    stall_timeout: Option<Duration>,
+    /// When the oldest frame fed to the writer that wasn't flushed yet was fed.
+    oldest_unflushed_at: Option<Instant>,
+}
+
+impl WriteWatchdog {
+    pub fn new(stall_timeout: Option<Duration>) -> Self {
+        Self {
+            stall_timeout,
+            oldest_unflushed_at: None,
+        }
+    }
+
+    /// A frame was fed to the writer.
+    pub fn fed(&mut self, now: Instant) {
+        self.oldest_unflushed_at.get_or_insert(now);
+    }
+
+    /// Every frame fed to the writer was flushed.
+    pub fn flushed(&mut self) {
+        self.oldest_unflushed_at = None;
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.oldest_unflushed_at? + self.stall_timeout?)
+    }
+
+    /// Fails with `LoquiError::WriteStalled` if the oldest unflushed frame is overdue, checked on
+    /// the ping tick.
+    pub fn check(&self, now: Instant) -> Result<(), LoquiError> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => Err(LoquiError::WriteStalled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for a write or flush of the writer, failing with `LoquiError::WriteStalled` if the
+    /// oldest unflushed frame becomes overdue first. The writer is dropped with the write then.
+    pub async fn guard<T, E, F>(&self, write: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: Into<Error>,
+    {
+        let result = match self.deadline() {
+            Some(deadline) => timeout_at(deadline, write)
+                .await
+                .map_err(|_elapsed| Error::from(LoquiError::WriteStalled))?,
+            None => write.await,
+        };
+        result.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{pending, ready};
+    use tokio::runtime::Runtime;
+
+    fn is_write_stalled(error: &Error) -> bool {
+        matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::WriteStalled)
+        )
+    }
+
+    #[test]
+    fn it_fails_writes_that_stall() {
+        Runtime::new().unwrap().block_on(async {
+            let mut watchdog = WriteWatchdog::new(Some(Duration::from_millis(20)));
+            watchdog.fed(Instant::now());
+            let written = watchdog.guard(ready(Ok::<_, LoquiError>(1))).await;
+            assert_eq!(written.unwrap(), 1);
+
+            let stalled = watchdog.guard(pending::<Result<(), LoquiError>>()).await;
+            assert!(is_write_stalled(&stalled.unwrap_err()));
+        });
+    }
+
+    #[test]
+    fn it_times_the_oldest_unflushed_frame() {
+        let started_at = Instant::now();
+        let mut watchdog = WriteWatchdog::new(Some(Duration::from_secs(1)));
+        assert!(watchdog.check(started_at + Duration::from_secs(5)).is_ok());
+
+        watchdog.fed(started_at);
+        watchdog.fed(started_at + Duration::from_millis(900));
+        assert!(watchdog
+            .check(started_at + Duration::from_millis(999))
+            .is_ok());
+        assert!(matches!(
+            watchdog.check(started_at + Duration::from_secs(1)),
+            Err(LoquiError::WriteStalled)
+        ));
+
+        watchdog.flushed();
+        assert!(watchdog.check(started_at + Duration::from_secs(5)).is_ok());
+        assert!(WriteWatchdog::new(None).check(started_at).is_ok());
+    }
+}
@@ -5,8 +5,9 @@ use tokio_util::codec::{Decoder, Encoder};
 
 use crate::error::ProtocolError;
 use crate::frames::{
-    Error as ErrorFrame, Frame, GoAway, Hello, HelloAck, LoquiFrame, Ping, Pong, Push, Request,
-    Response,
+    Cancel, Error as ErrorFrame, Frame, GoAway, HealthCheck, HealthStatus, Hello, HelloAck,
+    LoquiFrame, Ping, Pong, Push, PushAck, Renegotiate, Request, RequestBatch, Response,
+    ResponseBatch, Subscribe, UnknownFrame, Unsubscribe, WindowUpdate, FIRST_UNKNOWN_OPCODE,
 };
 
 /// Codec for loqui.
@@ -29,6 +30,15 @@ impl Encoder for Codec {
     type Error = ::failure::Error;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_reusing(item, dst);
+        Ok(())
+    }
+}
+
+impl Codec {
+    /// Encodes a frame like `Encoder::encode`, handing back the buffer of its payload once it was
+    /// copied into `dst`, so it can be reused.
+    pub fn encode_reusing(&mut self, item: LoquiFrame, dst: &mut BytesMut) -> Option<Vec<u8>> {
         match item {
             LoquiFrame::Hello(frame) => encode(frame, dst),
             LoquiFrame::HelloAck(frame) => encode(frame, dst),
@@ -39,8 +49,24 @@ impl Encoder for Codec {
             LoquiFrame::Push(frame) => encode(frame, dst),
             LoquiFrame::GoAway(frame) => encode(frame, dst),
             LoquiFrame::Error(frame) => encode(frame, dst),
-        };
-        Ok(())
+            LoquiFrame::Cancel(frame) => encode(frame, dst),
+            LoquiFrame::PushAck(frame) => encode(frame, dst),
+            LoquiFrame::WindowUpdate(frame) => encode(frame, dst),
+            LoquiFrame::RequestBatch(frame) => encode(frame, dst),
+            LoquiFrame::ResponseBatch(frame) => encode(frame, dst),
+            LoquiFrame::Renegotiate(frame) => encode(frame, dst),
+            LoquiFrame::HealthCheck(frame) => encode(frame, dst),
+            LoquiFrame::HealthStatus(frame) => encode(frame, dst),
+            LoquiFrame::Subscribe(frame) => encode(frame, dst),
+            LoquiFrame::Unsubscribe(frame) => encode(frame, dst),
+            LoquiFrame::Unknown(frame) => {
+                dst.reserve(UnknownFrame::HEADER_SIZE_IN_BYTES);
+                frame.put_header(dst);
+                dst.put_u32(frame.payload.len() as u32);
+                dst.extend_from_slice(&frame.payload);
+                Some(frame.payload)
+            }
+        }
     }
 }
 
@@ -63,19 +89,31 @@ impl Decoder for Codec {
             Push::OPCODE => decode::<Push>(self, buf),
             GoAway::OPCODE => decode::<GoAway>(self, buf),
             ErrorFrame::OPCODE => decode::<ErrorFrame>(self, buf),
+            Cancel::OPCODE => decode::<Cancel>(self, buf),
+            PushAck::OPCODE => decode::<PushAck>(self, buf),
+            WindowUpdate::OPCODE => decode::<WindowUpdate>(self, buf),
+            RequestBatch::OPCODE => decode::<RequestBatch>(self, buf),
+            ResponseBatch::OPCODE => decode::<ResponseBatch>(self, buf),
+            Renegotiate::OPCODE => decode::<Renegotiate>(self, buf),
+            HealthCheck::OPCODE => decode::<HealthCheck>(self, buf),
+            HealthStatus::OPCODE => decode::<HealthStatus>(self, buf),
+            Subscribe::OPCODE => decode::<Subscribe>(self, buf),
+            Unsubscribe::OPCODE => decode::<Unsubscribe>(self, buf),
+            opcode if opcode >= FIRST_UNKNOWN_OPCODE => decode_unknown(self, buf),
             _ => Err(ProtocolError::InvalidOpcode { opcode }.into()),
         }
     }
 }
 
-/// Encodes a frame into the byte buffer. Delegates most of the work to the `Frame` trait.
-fn encode<F: Frame>(frame: F, dst: &mut BytesMut) {
+/// Encodes a frame into the byte buffer, returning the payload it copied. Delegates most of the
+/// work to the `Frame` trait.
+fn encode<F: Frame>(frame: F, dst: &mut BytesMut) -> Option<Vec<u8>> {
     dst.reserve(F::HEADER_SIZE_IN_BYTES);
     frame.put_header(dst);
-    if let Some(payload) = frame.payload() {
-        dst.put_u32(payload.len() as u32);
-        dst.extend_from_slice(&payload[..]);
-    }
+    let payload = frame.payload()?;
+    dst.put_u32(payload.len() as u32);
+    dst.extend_from_slice(&payload[..]);
+    Some(payload)
 }
 
 /// Decodes a frame from the byte buffer.
@@ -111,9 +149,32 @@ fn decode<F: Frame + Into<LoquiFrame>>(
     }
 }
 
+/// Decodes a frame with an opcode from `FIRST_UNKNOWN_OPCODE` on, so it can be skipped.
+fn decode_unknown(codec: &mut Codec, buf: &mut BytesMut) -> Result<Option<LoquiFrame>, Error> {
+    if buf.len() < UnknownFrame::HEADER_SIZE_IN_BYTES {
+        return Ok(None);
+    }
+    let payload_size = UnknownFrame::read_payload_size(buf);
+    if payload_size > codec.max_payload_size_in_bytes {
+        return Err(ProtocolError::PayloadTooLarge {
+            actual: payload_size,
+            max: codec.max_payload_size_in_bytes,
+        }
+        .into());
+    }
+    let required_len = UnknownFrame::HEADER_SIZE_IN_BYTES + payload_size as usize;
+    if buf.len() < required_len {
+        return Ok(None);
+    }
+    let frame_buf = buf.split_to(required_len);
+    Ok(Some(UnknownFrame::from_buf(&frame_buf).into()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::frames::{AuthToken, BatchEntry, PongTimestamps, Priority};
+    use crate::Flags;
     use bytes::{BufMut, BytesMut};
 
     fn test_frame_round_trip<F: Into<LoquiFrame>>(frame_bytes: &[u8], expected_frame: F) {
@@ -147,10 +208,67 @@ mod tests {
                 version: 1,
                 encodings: vec!["msgpack".into(), "json".into()],
                 compressions: vec!["gzip".into(), "lzma".into()],
+                ping_interval_ms: None,
+                dictionary_ids: vec![],
+                auth_token: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_hello_with_ping_interval() {
+        test_frame_round_trip(
+            &b"    msgpack||500"[..],
+            Hello {
+                flags: 0,
+                version: 1,
+                encodings: vec!["msgpack".into()],
+                compressions: vec![],
+                ping_interval_ms: Some(500),
+                dictionary_ids: vec![],
+                auth_token: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_hello_with_dictionaries() {
+        test_frame_round_trip(
+            &b"\x01\x00\x01\x00\x00\x00\x14msgpack|deflate||7,9"[..],
+            Hello {
+                flags: 0,
+                version: 1,
+                encodings: vec!["msgpack".into()],
+                compressions: vec!["deflate".into()],
+                ping_interval_ms: None,
+                dictionary_ids: vec![7, 9],
+                auth_token: None,
             },
         );
     }
 
+    #[test]
+    fn test_hello_with_auth_token() {
+        test_frame_round_trip(
+            &b"\x01\x00\x01\x00\x00\x00\x10msgpack|\x00s3|cr\x00t"[..],
+            Hello {
+                flags: 0,
+                version: 1,
+                encodings: vec!["msgpack".into()],
+                compressions: vec![],
+                ping_interval_ms: None,
+                dictionary_ids: vec![],
+                auth_token: Some(AuthToken(b"s3|cr\x00t".to_vec())),
+            },
+        );
+    }
+
+    #[test]
+    fn test_auth_token_is_redacted() {
+        let auth_token = AuthToken(b"secret".to_vec());
+        assert_eq!(format!("{:?}", auth_token), "AuthToken(<6 bytes redacted>)");
+    }
+
     #[test]
     fn test_helloack() {
         test_frame_round_trip(
@@ -160,6 +278,49 @@ mod tests {
                 ping_interval_ms: 32000,
                 encoding: "msgpack".into(),
                 compression: Some("gzip".into()),
+                dictionary_id: None,
+                encodings: vec![],
+            },
+        );
+    }
+
+    #[test]
+    fn test_helloack_with_dictionary() {
+        test_frame_round_trip(
+            &b"\x02\x00\x00\x00}\x00\x00\x00\x00\x11msgpack|deflate|7"[..],
+            HelloAck {
+                flags: 0,
+                ping_interval_ms: 32000,
+                encoding: "msgpack".into(),
+                compression: Some("deflate".into()),
+                dictionary_id: Some(7),
+                encodings: vec![],
+            },
+        );
+    }
+
+    #[test]
+    fn test_helloack_with_encodings() {
+        test_frame_round_trip(
+            &b"\x02\x20\x00\x00}\x00\x00\x00\x00\x13msgpack|||json,cbor"[..],
+            HelloAck {
+                flags: Flags::ENCODING_IDS,
+                ping_interval_ms: 32000,
+                encoding: "msgpack".into(),
+                compression: None,
+                dictionary_id: None,
+                encodings: vec!["json".into(), "cbor".into()],
+            },
+        );
+        test_frame_round_trip(
+            &b"\x02\x20\x00\x00}\x00\x00\x00\x00\x16msgpack|deflate|7|json"[..],
+            HelloAck {
+                flags: Flags::ENCODING_IDS,
+                ping_interval_ms: 32000,
+                encoding: "msgpack".into(),
+                compression: Some("deflate".into()),
+                dictionary_id: Some(7),
+                encodings: vec!["json".into()],
             },
         );
     }
@@ -167,10 +328,25 @@ mod tests {
     #[test]
     fn test_ping() {
         test_frame_round_trip(
-            &b"\x03\x0f\x00\x00\x00\x01"[..],
+            &b"\x03\x0b\x00\x00\x00\x01"[..],
             Ping {
-                flags: 15,
+                flags: 11,
                 sequence_id: 1,
+                token: None,
+                sent_at: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_ping_with_token() {
+        test_frame_round_trip(
+            &b"\x03\x04\x00\x00\x00\x01\x00\x00\x00\x2a"[..],
+            Ping {
+                flags: 4,
+                sequence_id: 1,
+                token: Some(42),
+                sent_at: None,
             },
         );
     }
@@ -178,10 +354,54 @@ mod tests {
     #[test]
     fn test_pong() {
         test_frame_round_trip(
-            &b"\x04\x0f\x00\x00\x00\x01"[..],
+            &b"\x04\x0b\x00\x00\x00\x01"[..],
             Pong {
-                flags: 15,
+                flags: 11,
                 sequence_id: 1,
+                token: None,
+                timestamps: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_pong_with_token() {
+        test_frame_round_trip(
+            &b"\x04\x04\x00\x00\x00\x01\x00\x00\x00\x2a"[..],
+            Pong {
+                flags: 4,
+                sequence_id: 1,
+                token: Some(42),
+                timestamps: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_timestamped_ping() {
+        test_frame_round_trip(
+            &b"\x03\x14\x00\x00\x00\x01\x00\x00\x00\x2a\x00\x00\x00\x00\x00\x00\x00\x07"[..],
+            Ping {
+                flags: 20,
+                sequence_id: 1,
+                token: Some(42),
+                sent_at: Some(7),
+            },
+        );
+    }
+
+    #[test]
+    fn test_timestamped_pong() {
+        test_frame_round_trip(
+            &b"\x04\x10\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x07\x00\x00\x00\x00\x00\x00\x00\x09"[..],
+            Pong {
+                flags: 16,
+                sequence_id: 1,
+                token: None,
+                timestamps: Some(PongTimestamps {
+                    ping_sent_at: 7,
+                    received_at: 9,
+                }),
             },
         );
     }
@@ -189,20 +409,73 @@ mod tests {
     #[test]
     fn test_request() {
         test_frame_round_trip(
-            &b"\x05\x1f\x00\x00\x00\x01\x00\x00\x00\x15hello this is my data"[..],
+            &b"\x05\x0b\x00\x00\x00\x01\x00\x00\x00\x15hello this is my data"[..],
             Request {
-                flags: 31,
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                flags: 11,
                 sequence_id: 1,
                 payload: b"hello this is my data".to_vec(),
             },
         );
     }
 
+    #[test]
+    fn test_prioritized_request() {
+        test_frame_round_trip(
+            &b"\x05\x24\x00\x00\x00\x01\x00\x00\x00\x14key-key-key-key-\x03hey"[..],
+            Request {
+                flags: 36,
+                sequence_id: 1,
+                trace_id: None,
+                idempotency_key: Some(*b"key-key-key-key-"),
+                priority: Priority::Critical,
+                timeout_ms: 0,
+                payload: b"hey".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_timed_request() {
+        test_frame_round_trip(
+            &b"\x05\x14\x00\x00\x00\x01\x00\x00\x00\x08\x03\x00\x00\x01\xf4hey"[..],
+            Request {
+                flags: 20,
+                sequence_id: 1,
+                trace_id: None,
+                idempotency_key: None,
+                priority: Priority::Critical,
+                timeout_ms: 500,
+                payload: b"hey".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_missing_timeout() {
+        let mut codec = Codec::new(ByteSize::b(500));
+        let buf = &mut BytesMut::with_capacity(1024);
+        buf.put(&b"\x05\x10\x00\x00\x00\x01\x00\x00\x00\x02\x00\x01"[..]);
+        assert!(codec.decode(buf).is_err());
+    }
+
+    #[test]
+    fn test_unknown_priority() {
+        let mut codec = Codec::new(ByteSize::b(500));
+        let buf = &mut BytesMut::with_capacity(1024);
+        buf.put(&b"\x05\x04\x00\x00\x00\x01\x00\x00\x00\x04\x09hey"[..]);
+        assert!(codec.decode(buf).is_err());
+    }
+
     #[test]
     fn test_response() {
         test_frame_round_trip(
             &b"\x06\x1f\x00\x00\x0b\xb8\x00\x00\x00\x15hello this is my data"[..],
             Response {
+                trace_id: None,
                 flags: 31,
                 sequence_id: 3000,
                 payload: b"hello this is my data".to_vec(),
@@ -213,14 +486,193 @@ mod tests {
     #[test]
     fn test_push() {
         test_frame_round_trip(
-            &b"\x07[\x00\x00\x00\x15hello this is my push"[..],
+            &b"\x07\x1b\x00\x00\x00\x15hello this is my push"[..],
             Push {
-                flags: 91,
+                flags: 27,
+                sequence_id: None,
+                subscription_id: None,
                 payload: b"hello this is my push".to_vec(),
             },
         );
     }
 
+    #[test]
+    fn test_acked_push() {
+        test_frame_round_trip(
+            &b"\x07\x20\x00\x00\x00\x07\x00\x00\x0b\xb8hey"[..],
+            Push {
+                flags: 32,
+                sequence_id: Some(3000),
+                subscription_id: None,
+                payload: b"hey".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_subscription_push() {
+        test_frame_round_trip(
+            &b"\x07\x40\x00\x00\x00\x07\x00\x00\x00\x05hey"[..],
+            Push {
+                flags: 64,
+                sequence_id: None,
+                subscription_id: Some(5),
+                payload: b"hey".to_vec(),
+            },
+        );
+        // The sequence id comes first.
+        test_frame_round_trip(
+            &b"\x07\x60\x00\x00\x00\x0b\x00\x00\x0b\xb8\x00\x00\x00\x05hey"[..],
+            Push {
+                flags: 96,
+                sequence_id: Some(3000),
+                subscription_id: Some(5),
+                payload: b"hey".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_subscribe() {
+        test_frame_round_trip(
+            &b"\x12\x00\x00\x00\x00\x05\x00\x00\x00\x06prices"[..],
+            Subscribe {
+                flags: 0,
+                sequence_id: 5,
+                payload: b"prices".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        test_frame_round_trip(
+            &b"\x13\x00\x00\x00\x00\x05"[..],
+            Unsubscribe {
+                flags: 0,
+                sequence_id: 5,
+            },
+        );
+    }
+
+    #[test]
+    fn test_window_update() {
+        test_frame_round_trip(
+            &b"\x0c\x00\x00\x00\x0b\xb8\x00\x00\x00\x02"[..],
+            WindowUpdate {
+                flags: 0,
+                sequence_id: 3000,
+                increment: 2,
+            },
+        );
+    }
+
+    #[test]
+    fn test_request_batch() {
+        test_frame_round_trip(
+            &b"\x0d\x00\x00\x00\x00\x07\x00\x00\x00\x16\x00\x00\x00\x01\x00\x00\x00\x00\x00\x02hi\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00"[..],
+            RequestBatch {
+                flags: 0,
+                sequence_id: 7,
+                entries: vec![
+                    BatchEntry {
+                        sequence_id: 1,
+                        code: 0,
+                        payload: b"hi".to_vec(),
+                    },
+                    BatchEntry {
+                        sequence_id: 2,
+                        code: 0,
+                        payload: vec![],
+                    },
+                ],
+            },
+        );
+    }
+
+    #[test]
+    fn test_response_batch() {
+        test_frame_round_trip(
+            &b"\x0e\x00\x00\x00\x00\x07\x00\x00\x00\x1a\x00\x00\x00\x01\x00\x00\x00\x00\x00\x02ok\x00\x00\x00\x02\x00\x09\x00\x00\x00\x04busy"[..],
+            ResponseBatch {
+                flags: 0,
+                sequence_id: 7,
+                entries: vec![
+                    BatchEntry {
+                        sequence_id: 1,
+                        code: 0,
+                        payload: b"ok".to_vec(),
+                    },
+                    BatchEntry {
+                        sequence_id: 2,
+                        code: 9,
+                        payload: b"busy".to_vec(),
+                    },
+                ],
+            },
+        );
+    }
+
+    #[test]
+    fn test_renegotiate() {
+        test_frame_round_trip(
+            &b"\x0f\x00\x00\x00\x00\x03\x00\x00\x00\x07msgpack"[..],
+            Renegotiate {
+                flags: 0,
+                sequence_id: 3,
+                encoding: "msgpack".to_string(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_health_check() {
+        test_frame_round_trip(
+            &b"\x10\x00\x00\x00\x00\x04"[..],
+            HealthCheck {
+                flags: 0,
+                sequence_id: 4,
+            },
+        );
+    }
+
+    #[test]
+    fn test_health_status() {
+        test_frame_round_trip(
+            &b"\x11\x00\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x02\x00\x00\x00\x04json"[..],
+            HealthStatus {
+                flags: 0,
+                sequence_id: 4,
+                uptime_ms: 256,
+                in_flight_requests: 2,
+                encoding: "json".to_string(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_batch_entry_too_short() {
+        let mut codec = Codec::new(ByteSize::b(500));
+        let buf = &mut BytesMut::with_capacity(1024);
+        // The entry claims 5 bytes of payload but only has 2.
+        buf.put(
+            &b"\x0d\x00\x00\x00\x00\x07\x00\x00\x00\x0c\x00\x00\x00\x01\x00\x00\x00\x00\x00\x05hi"
+                [..],
+        );
+        assert!(codec.decode(buf).is_err());
+    }
+
+    #[test]
+    fn test_push_ack() {
+        test_frame_round_trip(
+            &b"\x0b\x00\x00\x00\x0b\xb8"[..],
+            PushAck {
+                flags: 0,
+                sequence_id: 3000,
+            },
+        );
+    }
+
     #[test]
     fn test_goaway() {
         test_frame_round_trip(
@@ -233,6 +685,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_goaway_reason() {
+        let go_away = GoAway {
+            flags: 0,
+            code: 13,
+            payload: b"deploying".to_vec(),
+        };
+        assert_eq!(go_away.reason(), Some("deploying"));
+        let without_reason = GoAway {
+            flags: 0,
+            code: 13,
+            payload: vec![],
+        };
+        test_frame_round_trip(
+            &b"\x08\x00\x00\x0d\x00\x00\x00\x00"[..],
+            without_reason.clone(),
+        );
+        assert_eq!(without_reason.reason(), None);
+    }
+
     #[test]
     fn test_error() {
         test_frame_round_trip(
@@ -245,4 +717,106 @@ mod tests {
             },
         );
     }
+
+    #[test]
+    fn test_cancel() {
+        test_frame_round_trip(
+            &b"\n\x00\x00\x00\x0b\xb8"[..],
+            Cancel {
+                flags: 0,
+                sequence_id: 3000,
+            },
+        );
+    }
+
+    #[test]
+    fn test_traced_request() {
+        test_frame_round_trip(
+            &b"\x05\x80\x00\x00\x00\x01\x00\x00\x00\x130123456789abcdefhey"[..],
+            Request {
+                flags: 128,
+                sequence_id: 1,
+                trace_id: Some(*b"0123456789abcdef"),
+                idempotency_key: None,
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                payload: b"hey".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_idempotent_traced_request() {
+        test_frame_round_trip(
+            &b"\x05\xa0\x00\x00\x00\x01\x00\x00\x00\x230123456789abcdefkey-key-key-key-hey"[..],
+            Request {
+                flags: 160,
+                sequence_id: 1,
+                trace_id: Some(*b"0123456789abcdef"),
+                idempotency_key: Some(*b"key-key-key-key-"),
+                priority: Priority::Normal,
+                timeout_ms: 0,
+                payload: b"hey".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_idempotent_request_too_short() {
+        let mut codec = Codec::new(ByteSize::b(500));
+        let buf = &mut BytesMut::with_capacity(1024);
+        buf.put(&b"\x05\x20\x00\x00\x00\x01\x00\x00\x00\x03key"[..]);
+        assert!(codec.decode(buf).is_err());
+    }
+
+    #[test]
+    fn test_traced_response_too_short() {
+        let mut codec = Codec::new(ByteSize::b(500));
+        let buf = &mut BytesMut::with_capacity(1024);
+        buf.put(&b"\x06\x80\x00\x00\x00\x01\x00\x00\x00\x030ab"[..]);
+        assert!(codec.decode(buf).is_err());
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        test_frame_round_trip(
+            b"\x2a\x80\x00\x00\x00\x03new",
+            UnknownFrame {
+                opcode: 42,
+                flags: Flags::MANDATORY,
+                payload: b"new".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_invalid_opcode() {
+        let mut codec = Codec::new(ByteSize::kb(64));
+        let buf = &mut BytesMut::with_capacity(16);
+        buf.put(&b"\x00\x00\x00\x00\x00\x00"[..]);
+        let error = codec.decode(buf).unwrap_err();
+        assert_eq!(
+            error.downcast::<ProtocolError>().unwrap(),
+            ProtocolError::InvalidOpcode { opcode: 0 }
+        );
+    }
+
+    #[test]
+    fn test_payload_too_large() {
+        let mut codec = Codec::new(ByteSize::kb(64));
+        let buf = &mut BytesMut::with_capacity(16);
+        // A request whose length prefix claims 1GB without any of the payload following.
+        buf.put(&b"\x05\x00\x00\x00\x00\x01\x40\x00\x00\x00"[..]);
+        let error = codec.decode(buf).unwrap_err();
+        match error.downcast::<ProtocolError>().unwrap() {
+            ProtocolError::PayloadTooLarge { actual, max } => {
+                assert_eq!(actual, 1 << 30);
+                assert_eq!(max, 64_000);
+            }
+            error => panic!("unexpected error. error={:?}", error),
+        }
+        // Rejected from the header alone, nothing was consumed or reserved for the payload.
+        assert_eq!(buf.len(), 10);
+        assert!(buf.capacity() < 1024);
+    }
 }
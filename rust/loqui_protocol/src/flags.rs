@@ -1,13 +1,137 @@
 pub enum Flags {
     None = 0,
     Compressed = 1,
+    /// A `Ping` tells the receiver the sender won't send any more requests or pushes.
+    HalfClosed = 2,
+    /// A `Ping` or `Pong` carries a 4 byte token after its sequence id.
+    PingToken = 4,
+    /// A streaming `Request` asks for its responses to be flow controlled with `WindowUpdate`s.
+    FlowControlled = 8,
+    /// A `Response` ends a streamed response. Its payload is empty.
+    StreamEnd = 16,
+    /// The payload of a `Push` starts with a 4 byte sequence id the receiver acknowledges with a
+    /// `PushAck`.
+    Acked = 32,
+    /// A `Request` asks for a streamed response, and each `Response` of one is part of it.
+    Streaming = 64,
+    /// The payload of a `Request` or `Response` starts with a 16 byte trace id.
+    Traced = 128,
+}
+
+impl Flags {
+    /// The payload of a `Request` starts with a 16 byte idempotency key, after the trace id if
+    /// there is one. Every bit is taken, so it shares its bit with `Flags::Acked`, which only
+    /// applies to `Push`es.
+    pub const IDEMPOTENT: u8 = Flags::Acked as u8;
+    /// On a `Hello` the client can send `RequestBatch`es, and on a `HelloAck` the server accepts
+    /// them. Shares its bit with `Flags::Streaming`, which only applies to data frames.
+    pub const BATCHES: u8 = Flags::Streaming as u8;
+    /// Set on a `Request`, `Response` or `Push` to send its payload uncompressed even though a
+    /// compression was negotiated, e.g. because it is already compressed. It is cleared before the
+    /// frame is sent, so it shares its bit with `Flags::HalfClosed`, which only applies to `Ping`s.
+    pub const NO_COMPRESS: u8 = Flags::HalfClosed as u8;
+    /// On a `Hello` the client can send timestamped pings, and on a `HelloAck` the server accepts
+    /// them. On a `Ping` or `Pong` timestamps follow the token. Shares its bit with
+    /// `Flags::StreamEnd`, which only applies to `Response`s.
+    pub const TIMESTAMPS: u8 = Flags::StreamEnd as u8;
+    /// On a `Hello` the client offers to end the payload of every `Request`, `Response` and `Push`
+    /// with a CRC32 trailer, and on a `HelloAck` the server agrees. Shares its bit with
+    /// `Flags::FlowControlled`, which only applies to `Request`s.
+    pub const CHECKSUMS: u8 = Flags::FlowControlled as u8;
+    /// The payload of a `Request` has a 1 byte priority after the idempotency key, if there is
+    /// one. Shares its bit with `Flags::PingToken`, which only applies to `Ping`s and `Pong`s.
+    pub const PRIORITIZED: u8 = Flags::PingToken as u8;
+    /// The payload of a `Push` has the 4 byte id of the subscription it belongs to after the
+    /// sequence id, if there is one. Shares its bit with `Flags::Streaming`, which only applies to
+    /// `Request`s and `Response`s.
+    pub const SUBSCRIPTION: u8 = Flags::Streaming as u8;
+    /// The payload of a `Request` has a 4 byte timeout in milliseconds after the priority, if
+    /// there is one. Shares its bit with `Flags::StreamEnd`, which only applies to `Response`s.
+    pub const TIMEOUT: u8 = Flags::StreamEnd as u8;
+    /// On a `Hello` the client offers to start the payload of every `Request`, `Response` and
+    /// `Push` with a 1 byte encoding id, and on a `HelloAck` the server agrees. Shares its bit
+    /// with `Flags::Acked`, which only applies to `Push`es.
+    pub const ENCODING_IDS: u8 = Flags::Acked as u8;
+    /// On a frame with an opcode the receiver doesn't know, the receiver must go away rather than
+    /// ignore it. Shares its bit with `Flags::Traced`, which only applies to data frames.
+    pub const MANDATORY: u8 = Flags::Traced as u8;
 }
 
 pub fn is_compressed(flags: u8) -> bool {
     (flags & Flags::Compressed as u8) != 0
 }
 
-/// Creates the u8 flags for a frame. We only have one flag right now, Flags::Compressed.
+pub fn is_half_closed(flags: u8) -> bool {
+    (flags & Flags::HalfClosed as u8) != 0
+}
+
+pub fn has_ping_token(flags: u8) -> bool {
+    (flags & Flags::PingToken as u8) != 0
+}
+
+pub fn is_flow_controlled(flags: u8) -> bool {
+    (flags & Flags::FlowControlled as u8) != 0
+}
+
+pub fn is_traced(flags: u8) -> bool {
+    (flags & Flags::Traced as u8) != 0
+}
+
+pub fn is_mandatory(flags: u8) -> bool {
+    (flags & Flags::MANDATORY) != 0
+}
+
+pub fn is_acked(flags: u8) -> bool {
+    (flags & Flags::Acked as u8) != 0
+}
+
+pub fn is_idempotent(flags: u8) -> bool {
+    (flags & Flags::IDEMPOTENT) != 0
+}
+
+pub fn is_no_compress(flags: u8) -> bool {
+    (flags & Flags::NO_COMPRESS) != 0
+}
+
+pub fn has_batches(flags: u8) -> bool {
+    (flags & Flags::BATCHES) != 0
+}
+
+pub fn is_prioritized(flags: u8) -> bool {
+    (flags & Flags::PRIORITIZED) != 0
+}
+
+pub fn has_subscription(flags: u8) -> bool {
+    (flags & Flags::SUBSCRIPTION) != 0
+}
+
+pub fn has_checksums(flags: u8) -> bool {
+    (flags & Flags::CHECKSUMS) != 0
+}
+
+pub fn has_timestamps(flags: u8) -> bool {
+    (flags & Flags::TIMESTAMPS) != 0
+}
+
+pub fn has_timeout(flags: u8) -> bool {
+    (flags & Flags::TIMEOUT) != 0
+}
+
+pub fn has_encoding_ids(flags: u8) -> bool {
+    (flags & Flags::ENCODING_IDS) != 0
+}
+
+pub fn is_streaming(flags: u8) -> bool {
+    (flags & Flags::Streaming as u8) != 0
+}
+
+pub fn is_stream_end(flags: u8) -> bool {
+    (flags & Flags::StreamEnd as u8) != 0
+}
+
+/// Creates the u8 flags for a frame. `Flags::Traced`, `Flags::Acked`, `Flags::IDEMPOTENT` and
+/// `Flags::PingToken` are set by the frame itself based on whether it has a trace id, a sequence
+/// id, an idempotency key or a token.
 pub fn make_flags(compressed: bool) -> u8 {
     let flag = if compressed {
         Flags::Compressed
@@ -36,6 +160,12 @@ mod tests {
         assert!(is_compressed(3))
     }
 
+    #[test]
+    fn it_is_no_compress() {
+        assert!(is_no_compress(Flags::NO_COMPRESS));
+        assert!(!is_no_compress(Flags::Compressed as u8));
+    }
+
     #[test]
     fn it_makes_flags_compressed() {
         let flags = make_flags(true);
@@ -1,10 +1,62 @@
 use crate::error::ProtocolError;
+use crate::flags::{
+    has_ping_token, has_subscription, has_timeout, has_timestamps, is_acked, is_idempotent,
+    is_prioritized, is_traced, Flags,
+};
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
+use std::fmt;
 use std::str::from_utf8;
 
 type DecodeResult<T> = Result<Option<T>, ProtocolError>;
 
+/// A 16 byte id, e.g. a UUID, that follows a request through a distributed trace.
+pub type TraceId = [u8; 16];
+
+/// A 16 byte key the client sends every attempt of a request with, so the server can tell a retry
+/// from a new request.
+pub type IdempotencyKey = [u8; 16];
+
+/// A bearer token the client authenticates with in its `Hello`. Debug formatting only shows its
+/// length, so logging a `Hello` doesn't leak it.
+#[derive(PartialEq, Eq, Clone)]
+pub struct AuthToken(pub Vec<u8>);
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuthToken(<{} bytes redacted>)", self.0.len())
+    }
+}
+
+/// Separates the settings of a `Hello` from its auth token, which may be any bytes.
+const AUTH_TOKEN_SEPARATOR: u8 = 0;
+
+/// How urgently a request should be served when the other side limits how many requests it
+/// computes at once. Higher priorities are served first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Bulk traffic that can wait.
+    Low = 0,
+    #[default]
+    Normal = 1,
+    High = 2,
+    /// E.g. control plane requests.
+    Critical = 3,
+}
+
+impl Priority {
+    /// The priority sent as this byte, or `None` if it isn't one.
+    pub fn from_u8(priority: u8) -> Option<Self> {
+        match priority {
+            0 => Some(Priority::Low),
+            1 => Some(Priority::Normal),
+            2 => Some(Priority::High),
+            3 => Some(Priority::Critical),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum LoquiFrame {
     Hello(Hello),
@@ -16,6 +68,52 @@ pub enum LoquiFrame {
     Push(Push),
     GoAway(GoAway),
     Error(Error),
+    Cancel(Cancel),
+    PushAck(PushAck),
+    WindowUpdate(WindowUpdate),
+    RequestBatch(RequestBatch),
+    ResponseBatch(ResponseBatch),
+    Renegotiate(Renegotiate),
+    HealthCheck(HealthCheck),
+    HealthStatus(HealthStatus),
+    Subscribe(Subscribe),
+    Unsubscribe(Unsubscribe),
+    Unknown(UnknownFrame),
+}
+
+/// The first opcode this version doesn't know. Frames from it on are laid out like an
+/// `UnknownFrame`, so a peer that doesn't know them can skip them.
+pub const FIRST_UNKNOWN_OPCODE: u8 = 20;
+
+/// A frame with an opcode this version doesn't know, sent by a newer peer. Its header is the
+/// opcode, flags and a uint32 payload size. The receiver ignores it unless
+/// `Flags::MANDATORY` is set.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnknownFrame {
+    pub opcode: u8,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+impl UnknownFrame {
+    pub const HEADER_SIZE_IN_BYTES: usize = 6;
+
+    pub fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(self.opcode);
+        dst.put_u8(self.flags);
+    }
+
+    pub fn read_payload_size(buf: &mut BytesMut) -> u32 {
+        BigEndian::read_u32(&buf[2..6])
+    }
+
+    pub fn from_buf(buf: &BytesMut) -> Self {
+        Self {
+            opcode: buf[0],
+            flags: buf[1],
+            payload: buf[6..].to_vec(),
+        }
+    }
 }
 
 pub trait Frame: Sized + 'static {
@@ -54,6 +152,15 @@ pub struct Hello {
     pub version: u8,
     pub encodings: Vec<String>,
     pub compressions: Vec<String>,
+    /// The ping interval the client would like. Sent as a third setting when set.
+    pub ping_interval_ms: Option<u32>,
+    /// The ids of the pre-shared compression dictionaries the client has, in order of preference.
+    /// Sent as a fourth setting when not empty, after an empty third one if there is no ping
+    /// interval.
+    pub dictionary_ids: Vec<u32>,
+    /// Sent after the settings and a NUL byte when set, e.g. for the server to check in
+    /// `authorize`.
+    pub auth_token: Option<AuthToken>,
 }
 
 impl Frame for Hello {
@@ -67,12 +174,28 @@ impl Frame for Hello {
     }
 
     fn payload(self) -> Option<Vec<u8>> {
-        let payload = format!(
+        let mut payload = format!(
             "{}|{}",
             self.encodings.join(","),
             self.compressions.join(","),
         );
-        Some(payload.as_bytes().to_vec())
+        if self.ping_interval_ms.is_some() || !self.dictionary_ids.is_empty() {
+            payload.push('|');
+        }
+        if let Some(ping_interval_ms) = self.ping_interval_ms {
+            payload.push_str(&ping_interval_ms.to_string());
+        }
+        if !self.dictionary_ids.is_empty() {
+            let dictionary_ids: Vec<String> =
+                self.dictionary_ids.iter().map(u32::to_string).collect();
+            payload.push_str(&format!("|{}", dictionary_ids.join(",")));
+        }
+        let mut payload = payload.into_bytes();
+        if let Some(AuthToken(auth_token)) = self.auth_token {
+            payload.push(AUTH_TOKEN_SEPARATOR);
+            payload.extend(auth_token);
+        }
+        Some(payload)
     }
 
     fn read_payload_size(buf: &mut BytesMut) -> u32 {
@@ -82,14 +205,24 @@ impl Frame for Hello {
     fn from_buf(buf: &BytesMut) -> Result<Option<Self>, ProtocolError> {
         let flags = buf[1];
         let version = buf[2];
-        let payload = from_utf8(&buf[7..]).map_err(|_| ProtocolError::InvalidPayload {
+        let (payload, auth_token) = match buf[7..]
+            .iter()
+            .position(|byte| *byte == AUTH_TOKEN_SEPARATOR)
+        {
+            Some(separator) => (
+                &buf[7..7 + separator],
+                Some(AuthToken(buf[7 + separator + 1..].to_vec())),
+            ),
+            None => (&buf[7..], None),
+        };
+        let payload = from_utf8(payload).map_err(|_| ProtocolError::InvalidPayload {
             reason: "Failed to decode as string".into(),
         })?;
 
         let settings: Vec<&str> = payload.split('|').collect();
-        if settings.len() != 2 {
+        if settings.len() < 2 || settings.len() > 4 {
             return Err(ProtocolError::InvalidPayload {
-                reason: "Expected exactly two settings.".into(),
+                reason: "Expected two to four settings.".into(),
             });
         }
 
@@ -103,11 +236,39 @@ impl Frame for Hello {
             .map(String::from)
             .collect::<Vec<String>>();
 
+        let ping_interval_ms = match settings.get(2) {
+            Some(ping_interval_ms) if !ping_interval_ms.is_empty() => Some(
+                ping_interval_ms
+                    .parse()
+                    .map_err(|_| ProtocolError::InvalidPayload {
+                        reason: "Failed to decode ping interval".into(),
+                    })?,
+            ),
+            _ => None,
+        };
+
+        let dictionary_ids = match settings.get(3) {
+            Some(dictionary_ids) => dictionary_ids
+                .split_terminator(',')
+                .map(|dictionary_id| {
+                    dictionary_id
+                        .parse()
+                        .map_err(|_| ProtocolError::InvalidPayload {
+                            reason: "Failed to decode dictionary id".into(),
+                        })
+                })
+                .collect::<Result<Vec<u32>, ProtocolError>>()?,
+            None => vec![],
+        };
+
         Ok(Some(Self {
             flags,
             version,
             encodings,
             compressions,
+            ping_interval_ms,
+            dictionary_ids,
+            auth_token,
         }))
     }
 }
@@ -118,6 +279,13 @@ pub struct HelloAck {
     pub ping_interval_ms: u32,
     pub encoding: String,
     pub compression: Option<String>,
+    /// The pre-shared dictionary the compression uses, one the client offered. Sent as a third
+    /// setting when set.
+    pub dictionary_id: Option<u32>,
+    /// The other encodings of the client the server supports too, which data frames may select
+    /// with their encoding id, see `Flags::ENCODING_IDS`. Sent as a fourth setting when not empty,
+    /// after an empty third one if there is no dictionary.
+    pub encodings: Vec<String>,
 }
 
 impl Frame for HelloAck {
@@ -131,15 +299,17 @@ impl Frame for HelloAck {
     }
 
     fn payload(self) -> Option<Vec<u8>> {
-        Some(
-            format!(
-                "{}|{}",
-                self.encoding,
-                self.compression.unwrap_or_else(|| "".to_string())
-            )
-            .as_bytes()
-            .to_vec(),
-        )
+        let mut payload = format!("{}|{}", self.encoding, self.compression.unwrap_or_default());
+        if self.dictionary_id.is_some() || !self.encodings.is_empty() {
+            payload.push('|');
+        }
+        if let Some(dictionary_id) = self.dictionary_id {
+            payload.push_str(&dictionary_id.to_string());
+        }
+        if !self.encodings.is_empty() {
+            payload.push_str(&format!("|{}", self.encodings.join(",")));
+        }
+        Some(payload.as_bytes().to_vec())
     }
 
     fn read_payload_size(buf: &mut BytesMut) -> u32 {
@@ -155,24 +325,40 @@ impl Frame for HelloAck {
         })?;
 
         let settings: Vec<&str> = payload.split('|').collect();
-        if settings.len() != 2 {
+        if settings.len() < 2 || settings.len() > 4 {
             return Err(ProtocolError::InvalidPayload {
-                reason: "Expected exactly two settings.".into(),
+                reason: "Expected two to four settings.".into(),
             });
         }
         let encoding = settings[0].to_string();
         let compression = settings[1];
-        let compression = if compression == "" {
+        let compression = if compression.is_empty() {
             None
         } else {
             Some(compression.to_string())
         };
+        let dictionary_id = match settings.get(2) {
+            Some(dictionary_id) if !dictionary_id.is_empty() => Some(
+                dictionary_id
+                    .parse()
+                    .map_err(|_| ProtocolError::InvalidPayload {
+                        reason: "Failed to decode dictionary id".into(),
+                    })?,
+            ),
+            _ => None,
+        };
+        let encodings = match settings.get(3) {
+            Some(encodings) => encodings.split_terminator(',').map(String::from).collect(),
+            None => vec![],
+        };
 
         Ok(Some(Self {
             flags,
             ping_interval_ms,
             encoding,
             compression,
+            dictionary_id,
+            encodings,
         }))
     }
 }
@@ -181,6 +367,12 @@ impl Frame for HelloAck {
 pub struct Ping {
     pub flags: u8,
     pub sequence_id: u32,
+    /// An opaque token, e.g. a node epoch, the pong echoes. Sent after the sequence id when
+    /// `Flags::PingToken` is set.
+    pub token: Option<u32>,
+    /// When the ping was sent, in microseconds since the Unix epoch on the sender's clock. Sent
+    /// after the token when `Flags::TIMESTAMPS` is set.
+    pub sent_at: Option<u64>,
 }
 
 impl Frame for Ping {
@@ -189,29 +381,58 @@ impl Frame for Ping {
 
     fn put_header(&self, dst: &mut BytesMut) {
         dst.put_u8(Self::OPCODE);
-        dst.put_u8(self.flags);
+        let flags = ping_token_flags(self.flags, &self.token);
+        dst.put_u8(timestamps_flags(flags, self.sent_at.is_some()));
         dst.put_u32(self.sequence_id);
+        if let Some(token) = self.token {
+            dst.put_u32(token);
+        }
+        if let Some(sent_at) = self.sent_at {
+            dst.put_u64(sent_at);
+        }
     }
 
     fn payload(self) -> Option<Vec<u8>> {
         None
     }
 
-    fn read_payload_size(_buf: &mut BytesMut) -> u32 {
-        0
+    fn read_payload_size(buf: &mut BytesMut) -> u32 {
+        ping_token_size(buf[1]) + timestamps_size(buf[1], 1)
     }
 
     fn from_buf(buf: &BytesMut) -> Result<Option<Self>, ProtocolError> {
         let flags = buf[1];
         let sequence_id = BigEndian::read_u32(&buf[2..6]);
-        Ok(Some(Self { flags, sequence_id }))
+        let token = read_ping_token(flags, buf);
+        let sent_at = read_timestamps(flags, buf, 1).map(|timestamps| timestamps[0]);
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            token,
+            sent_at,
+        }))
     }
 }
 
+/// The timestamps a `Pong` answers a timestamped `Ping` with, in microseconds since the Unix
+/// epoch.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PongTimestamps {
+    /// Echoed from the ping, on the pinging side's clock.
+    pub ping_sent_at: u64,
+    /// When the ping was received, on the ponging side's clock.
+    pub received_at: u64,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Pong {
     pub flags: u8,
     pub sequence_id: u32,
+    /// An opaque token, e.g. a node epoch, echoed from the ping. Sent after the sequence id when
+    /// `Flags::PingToken` is set.
+    pub token: Option<u32>,
+    /// Answers a timestamped ping. Sent after the token when `Flags::TIMESTAMPS` is set.
+    pub timestamps: Option<PongTimestamps>,
 }
 
 impl Frame for Pong {
@@ -220,22 +441,40 @@ impl Frame for Pong {
 
     fn put_header(&self, dst: &mut BytesMut) {
         dst.put_u8(Self::OPCODE);
-        dst.put_u8(self.flags);
+        let flags = ping_token_flags(self.flags, &self.token);
+        dst.put_u8(timestamps_flags(flags, self.timestamps.is_some()));
         dst.put_u32(self.sequence_id);
+        if let Some(token) = self.token {
+            dst.put_u32(token);
+        }
+        if let Some(timestamps) = self.timestamps {
+            dst.put_u64(timestamps.ping_sent_at);
+            dst.put_u64(timestamps.received_at);
+        }
     }
 
     fn payload(self) -> Option<Vec<u8>> {
         None
     }
 
-    fn read_payload_size(_buf: &mut BytesMut) -> u32 {
-        0
+    fn read_payload_size(buf: &mut BytesMut) -> u32 {
+        ping_token_size(buf[1]) + timestamps_size(buf[1], 2)
     }
 
     fn from_buf(buf: &BytesMut) -> Result<Option<Self>, ProtocolError> {
         let flags = buf[1];
         let sequence_id = BigEndian::read_u32(&buf[2..6]);
-        Ok(Some(Self { flags, sequence_id }))
+        let token = read_ping_token(flags, buf);
+        let timestamps = read_timestamps(flags, buf, 2).map(|timestamps| PongTimestamps {
+            ping_sent_at: timestamps[0],
+            received_at: timestamps[1],
+        });
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            token,
+            timestamps,
+        }))
     }
 }
 
@@ -243,6 +482,19 @@ impl Frame for Pong {
 pub struct Request {
     pub flags: u8,
     pub sequence_id: u32,
+    /// Correlates the request and its response across services. Sent as the first bytes of the
+    /// payload when `Flags::Traced` is set.
+    pub trace_id: Option<TraceId>,
+    /// Set on every attempt of a request the client may retry. Sent after the trace id when
+    /// `Flags::IDEMPOTENT` is set.
+    pub idempotency_key: Option<IdempotencyKey>,
+    /// Sent after the idempotency key when `Flags::PRIORITIZED` is set, which it is unless the
+    /// priority is `Priority::Normal`.
+    pub priority: Priority,
+    /// How long the receiver may take to respond, overriding its `handler_timeout`. `0` leaves it
+    /// to the receiver. Sent after the priority when `Flags::TIMEOUT` is set, which it is unless
+    /// the timeout is `0`.
+    pub timeout_ms: u32,
     pub payload: Vec<u8>,
 }
 
@@ -252,12 +504,18 @@ impl Frame for Request {
 
     fn put_header(&self, dst: &mut BytesMut) {
         dst.put_u8(Self::OPCODE);
-        dst.put_u8(self.flags);
+        let flags = idempotent_flags(self.flags, &self.idempotency_key);
+        let flags = prioritized_flags(flags, self.priority);
+        let flags = timeout_flags(flags, self.timeout_ms);
+        dst.put_u8(traced_flags(flags, &self.trace_id));
         dst.put_u32(self.sequence_id);
     }
 
     fn payload(self) -> Option<Vec<u8>> {
-        Some(self.payload)
+        let payload = timeout_payload(self.timeout_ms, self.payload);
+        let payload = prioritized_payload(self.priority, payload);
+        let payload = traced_payload(self.idempotency_key, payload);
+        Some(traced_payload(self.trace_id, payload))
     }
 
     fn read_payload_size(buf: &mut BytesMut) -> u32 {
@@ -267,11 +525,18 @@ impl Frame for Request {
     fn from_buf(buf: &BytesMut) -> DecodeResult<Self> {
         let flags = buf[1];
         let sequence_id = BigEndian::read_u32(&buf[2..6]);
-        let payload = buf[10..].to_vec();
+        let (trace_id, payload) = split_trace_id(flags, &buf[10..])?;
+        let (idempotency_key, payload) = split_idempotency_key(flags, payload)?;
+        let (priority, payload) = split_priority(flags, payload)?;
+        let (timeout_ms, payload) = split_timeout(flags, payload)?;
         Ok(Some(Self {
             flags,
             sequence_id,
-            payload,
+            trace_id,
+            idempotency_key,
+            priority,
+            timeout_ms,
+            payload: payload.to_vec(),
         }))
     }
 }
@@ -280,6 +545,9 @@ impl Frame for Request {
 pub struct Response {
     pub flags: u8,
     pub sequence_id: u32,
+    /// Correlates the request and its response across services. Sent as the first bytes of the
+    /// payload when `Flags::Traced` is set.
+    pub trace_id: Option<TraceId>,
     pub payload: Vec<u8>,
 }
 
@@ -289,12 +557,12 @@ impl Frame for Response {
 
     fn put_header(&self, dst: &mut BytesMut) {
         dst.put_u8(Self::OPCODE);
-        dst.put_u8(self.flags);
+        dst.put_u8(traced_flags(self.flags, &self.trace_id));
         dst.put_u32(self.sequence_id);
     }
 
     fn payload(self) -> Option<Vec<u8>> {
-        Some(self.payload)
+        Some(traced_payload(self.trace_id, self.payload))
     }
 
     fn read_payload_size(buf: &mut BytesMut) -> u32 {
@@ -304,11 +572,12 @@ impl Frame for Response {
     fn from_buf(buf: &BytesMut) -> Result<Option<Self>, ProtocolError> {
         let flags = buf[1];
         let sequence_id = BigEndian::read_u32(&buf[2..6]);
-        let payload = buf[10..].to_vec();
+        let (trace_id, payload) = split_trace_id(flags, &buf[10..])?;
         Ok(Some(Self {
             flags,
             sequence_id,
-            payload,
+            trace_id,
+            payload: payload.to_vec(),
         }))
     }
 }
@@ -316,6 +585,12 @@ impl Frame for Response {
 #[derive(Debug, PartialEq, Clone)]
 pub struct Push {
     pub flags: u8,
+    /// Asks the receiver to acknowledge the push with a `PushAck` carrying this id. Sent as the
+    /// first bytes of the payload when `Flags::Acked` is set.
+    pub sequence_id: Option<u32>,
+    /// The subscription the push is published to, see `Subscribe`. Sent after the sequence id
+    /// when `Flags::SUBSCRIPTION` is set.
+    pub subscription_id: Option<u32>,
     pub payload: Vec<u8>,
 }
 
@@ -325,11 +600,27 @@ impl Frame for Push {
 
     fn put_header(&self, dst: &mut BytesMut) {
         dst.put_u8(Self::OPCODE);
-        dst.put_u8(self.flags);
+        let flags = match self.sequence_id {
+            Some(_) => self.flags | Flags::Acked as u8,
+            None => self.flags & !(Flags::Acked as u8),
+        };
+        let flags = match self.subscription_id {
+            Some(_) => flags | Flags::SUBSCRIPTION,
+            None => flags & !Flags::SUBSCRIPTION,
+        };
+        dst.put_u8(flags);
     }
 
     fn payload(self) -> Option<Vec<u8>> {
-        Some(self.payload)
+        if self.sequence_id.is_none() && self.subscription_id.is_none() {
+            return Some(self.payload);
+        }
+        let mut tagged = Vec::with_capacity(8 + self.payload.len());
+        for id in self.sequence_id.iter().chain(self.subscription_id.iter()) {
+            tagged.put_u32(*id);
+        }
+        tagged.extend(self.payload);
+        Some(tagged)
     }
 
     fn read_payload_size(buf: &mut BytesMut) -> u32 {
@@ -338,8 +629,16 @@ impl Frame for Push {
 
     fn from_buf(buf: &BytesMut) -> Result<Option<Self>, ProtocolError> {
         let flags = buf[1];
-        let payload = buf[6..].to_vec();
-        Ok(Some(Self { flags, payload }))
+        let payload = &buf[6..];
+        let (sequence_id, payload) = split_push_id(is_acked(flags), payload, "a sequence id")?;
+        let (subscription_id, payload) =
+            split_push_id(has_subscription(flags), payload, "a subscription id")?;
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            subscription_id,
+            payload: payload.to_vec(),
+        }))
     }
 }
 
@@ -350,6 +649,17 @@ pub struct GoAway {
     pub payload: Vec<u8>,
 }
 
+impl GoAway {
+    /// The human-readable reason the other side went away with, carried in the payload. `None`
+    /// when it didn't send one or it isn't UTF-8.
+    pub fn reason(&self) -> Option<&str> {
+        if self.payload.is_empty() {
+            return None;
+        }
+        std::str::from_utf8(&self.payload).ok()
+    }
+}
+
 impl Frame for GoAway {
     const OPCODE: u8 = 8;
     const HEADER_SIZE_IN_BYTES: usize = 8;
@@ -423,6 +733,652 @@ impl Frame for Error {
     }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cancel {
+    pub flags: u8,
+    pub sequence_id: u32,
+}
+
+impl Frame for Cancel {
+    const OPCODE: u8 = 10;
+    const HEADER_SIZE_IN_BYTES: usize = 6;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn read_payload_size(_buf: &mut BytesMut) -> u32 {
+        0
+    }
+
+    fn from_buf(buf: &BytesMut) -> Result<Option<Self>, ProtocolError> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        Ok(Some(Self { flags, sequence_id }))
+    }
+}
+
+/// Lets the other side send `increment` more responses of the flow controlled stream of a request.
+#[derive(Debug, PartialEq, Clone)]
+pub struct WindowUpdate {
+    pub flags: u8,
+    pub sequence_id: u32,
+    pub increment: u32,
+}
+
+impl Frame for WindowUpdate {
+    const OPCODE: u8 = 12;
+    const HEADER_SIZE_IN_BYTES: usize = 10;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+        dst.put_u32(self.increment);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn read_payload_size(_buf: &mut BytesMut) -> u32 {
+        0
+    }
+
+    fn from_buf(buf: &BytesMut) -> Result<Option<Self>, ProtocolError> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        let increment = BigEndian::read_u32(&buf[6..10]);
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            increment,
+        }))
+    }
+}
+
+/// One logical request or response of a batch, with its own sequence id.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BatchEntry {
+    pub sequence_id: u32,
+    /// `0` for requests and responses. The `LoquiErrorCode` of a request that failed.
+    pub code: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Size of the sequence id, code and payload size preceding the payload of each entry.
+const BATCH_ENTRY_HEADER_SIZE_IN_BYTES: usize = 10;
+
+/// Puts the entries of a batch one after the other.
+fn batch_payload(entries: Vec<BatchEntry>) -> Vec<u8> {
+    let size = entries
+        .iter()
+        .map(|entry| BATCH_ENTRY_HEADER_SIZE_IN_BYTES + entry.payload.len())
+        .sum();
+    let mut payload = Vec::with_capacity(size);
+    for entry in entries {
+        let mut header = [0; BATCH_ENTRY_HEADER_SIZE_IN_BYTES];
+        BigEndian::write_u32(&mut header[0..4], entry.sequence_id);
+        BigEndian::write_u16(&mut header[4..6], entry.code);
+        BigEndian::write_u32(&mut header[6..10], entry.payload.len() as u32);
+        payload.extend_from_slice(&header);
+        payload.extend(entry.payload);
+    }
+    payload
+}
+
+/// Splits the payload of a batch into its entries.
+fn split_batch(mut payload: &[u8]) -> Result<Vec<BatchEntry>, ProtocolError> {
+    let mut entries = vec![];
+    while !payload.is_empty() {
+        if payload.len() < BATCH_ENTRY_HEADER_SIZE_IN_BYTES {
+            return Err(ProtocolError::InvalidPayload {
+                reason: "Batch entry is shorter than its header.".to_string(),
+            });
+        }
+        let sequence_id = BigEndian::read_u32(&payload[0..4]);
+        let code = BigEndian::read_u16(&payload[4..6]);
+        let size = BigEndian::read_u32(&payload[6..10]) as usize;
+        payload = &payload[BATCH_ENTRY_HEADER_SIZE_IN_BYTES..];
+        if payload.len() < size {
+            return Err(ProtocolError::InvalidPayload {
+                reason: "Batch entry is shorter than its payload size.".to_string(),
+            });
+        }
+        let (entry_payload, rest) = payload.split_at(size);
+        entries.push(BatchEntry {
+            sequence_id,
+            code,
+            payload: entry_payload.to_vec(),
+        });
+        payload = rest;
+    }
+    Ok(entries)
+}
+
+/// Several requests sent in a single frame, once both sides negotiated `Flags::BATCHES`. Each
+/// request is handled on its own, and the responses are sent back together in a
+/// `ResponseBatch` with the same sequence id.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RequestBatch {
+    pub flags: u8,
+    pub sequence_id: u32,
+    pub entries: Vec<BatchEntry>,
+}
+
+impl Frame for RequestBatch {
+    const OPCODE: u8 = 13;
+    const HEADER_SIZE_IN_BYTES: usize = 10;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        Some(batch_payload(self.entries))
+    }
+
+    fn read_payload_size(buf: &mut BytesMut) -> u32 {
+        BigEndian::read_u32(&buf[6..10])
+    }
+
+    fn from_buf(buf: &BytesMut) -> DecodeResult<Self> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        let entries = split_batch(&buf[10..])?;
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            entries,
+        }))
+    }
+}
+
+/// The responses and errors of the requests of a `RequestBatch`, sent once all of them
+/// completed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResponseBatch {
+    pub flags: u8,
+    /// The sequence id of the `RequestBatch`.
+    pub sequence_id: u32,
+    pub entries: Vec<BatchEntry>,
+}
+
+impl Frame for ResponseBatch {
+    const OPCODE: u8 = 14;
+    const HEADER_SIZE_IN_BYTES: usize = 10;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        Some(batch_payload(self.entries))
+    }
+
+    fn read_payload_size(buf: &mut BytesMut) -> u32 {
+        BigEndian::read_u32(&buf[6..10])
+    }
+
+    fn from_buf(buf: &BytesMut) -> DecodeResult<Self> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        let entries = split_batch(&buf[10..])?;
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            entries,
+        }))
+    }
+}
+
+/// Asks the other side to switch the connection to another encoding, or answers such a request
+/// with the same sequence id and the encoding in use from then on. The answer carries the old
+/// encoding if the switch was refused.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Renegotiate {
+    pub flags: u8,
+    pub sequence_id: u32,
+    pub encoding: String,
+}
+
+impl Frame for Renegotiate {
+    const OPCODE: u8 = 15;
+    const HEADER_SIZE_IN_BYTES: usize = 10;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        Some(self.encoding.into_bytes())
+    }
+
+    fn read_payload_size(buf: &mut BytesMut) -> u32 {
+        BigEndian::read_u32(&buf[6..10])
+    }
+
+    fn from_buf(buf: &BytesMut) -> DecodeResult<Self> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        let encoding = from_utf8(&buf[10..]).map_err(|_| ProtocolError::InvalidPayload {
+            reason: "Failed to decode as string".into(),
+        })?;
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            encoding: encoding.to_string(),
+        }))
+    }
+}
+
+/// Asks the other side how the connection is doing. It answers with a `HealthStatus` with the
+/// same sequence id, without involving the application.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HealthCheck {
+    pub flags: u8,
+    pub sequence_id: u32,
+}
+
+impl Frame for HealthCheck {
+    const OPCODE: u8 = 16;
+    const HEADER_SIZE_IN_BYTES: usize = 6;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn read_payload_size(_buf: &mut BytesMut) -> u32 {
+        0
+    }
+
+    fn from_buf(buf: &BytesMut) -> DecodeResult<Self> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        Ok(Some(Self { flags, sequence_id }))
+    }
+}
+
+/// Answers a `HealthCheck` with the stats of the connection as seen by the side answering.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HealthStatus {
+    pub flags: u8,
+    /// The sequence id of the `HealthCheck`.
+    pub sequence_id: u32,
+    /// How long the connection has been ready, in milliseconds.
+    pub uptime_ms: u64,
+    /// The requests that are being computed.
+    pub in_flight_requests: u32,
+    /// The encoding in use.
+    pub encoding: String,
+}
+
+impl Frame for HealthStatus {
+    const OPCODE: u8 = 17;
+    const HEADER_SIZE_IN_BYTES: usize = 22;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+        dst.put_u64(self.uptime_ms);
+        dst.put_u32(self.in_flight_requests);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        Some(self.encoding.into_bytes())
+    }
+
+    fn read_payload_size(buf: &mut BytesMut) -> u32 {
+        BigEndian::read_u32(&buf[18..22])
+    }
+
+    fn from_buf(buf: &BytesMut) -> DecodeResult<Self> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        let uptime_ms = BigEndian::read_u64(&buf[6..14]);
+        let in_flight_requests = BigEndian::read_u32(&buf[14..18]);
+        let encoding = from_utf8(&buf[22..]).map_err(|_| ProtocolError::InvalidPayload {
+            reason: "Failed to decode as string".into(),
+        })?;
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            uptime_ms,
+            in_flight_requests,
+            encoding: encoding.to_string(),
+        }))
+    }
+}
+
+/// Subscribes to pushes from the other side, e.g. to a topic named by the payload. The sequence id
+/// is the subscription's id, which each `Push` published to it carries. A rejected subscription
+/// is answered with an `Error` frame with the same sequence id.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Subscribe {
+    pub flags: u8,
+    pub sequence_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame for Subscribe {
+    const OPCODE: u8 = 18;
+    const HEADER_SIZE_IN_BYTES: usize = 10;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        Some(self.payload)
+    }
+
+    fn read_payload_size(buf: &mut BytesMut) -> u32 {
+        BigEndian::read_u32(&buf[6..10])
+    }
+
+    fn from_buf(buf: &BytesMut) -> DecodeResult<Self> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        Ok(Some(Self {
+            flags,
+            sequence_id,
+            payload: buf[10..].to_vec(),
+        }))
+    }
+}
+
+/// Ends a subscription. Sent by the subscriber to stop the pushes, or by the publisher once it
+/// has nothing more to publish.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Unsubscribe {
+    pub flags: u8,
+    /// The sequence id of the `Subscribe`.
+    pub sequence_id: u32,
+}
+
+impl Frame for Unsubscribe {
+    const OPCODE: u8 = 19;
+    const HEADER_SIZE_IN_BYTES: usize = 6;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn read_payload_size(_buf: &mut BytesMut) -> u32 {
+        0
+    }
+
+    fn from_buf(buf: &BytesMut) -> DecodeResult<Self> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        Ok(Some(Self { flags, sequence_id }))
+    }
+}
+
+/// Acknowledges a `Push` sent with a sequence id.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PushAck {
+    pub flags: u8,
+    pub sequence_id: u32,
+}
+
+impl Frame for PushAck {
+    const OPCODE: u8 = 11;
+    const HEADER_SIZE_IN_BYTES: usize = 6;
+
+    fn put_header(&self, dst: &mut BytesMut) {
+        dst.put_u8(Self::OPCODE);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.sequence_id);
+    }
+
+    fn payload(self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn read_payload_size(_buf: &mut BytesMut) -> u32 {
+        0
+    }
+
+    fn from_buf(buf: &BytesMut) -> Result<Option<Self>, ProtocolError> {
+        let flags = buf[1];
+        let sequence_id = BigEndian::read_u32(&buf[2..6]);
+        Ok(Some(Self { flags, sequence_id }))
+    }
+}
+
+/// Splits off one of the 4 byte ids a `Push` payload may start with, if its flag is set.
+fn split_push_id<'a>(
+    flagged: bool,
+    payload: &'a [u8],
+    name: &str,
+) -> Result<(Option<u32>, &'a [u8]), ProtocolError> {
+    if !flagged {
+        return Ok((None, payload));
+    }
+    if payload.len() < 4 {
+        return Err(ProtocolError::InvalidPayload {
+            reason: format!("Push payload is shorter than {}.", name),
+        });
+    }
+    Ok((Some(BigEndian::read_u32(&payload[..4])), &payload[4..]))
+}
+
+/// Sets `Flags::PingToken` if and only if there is a token.
+fn ping_token_flags(flags: u8, token: &Option<u32>) -> u8 {
+    match token {
+        Some(_) => flags | Flags::PingToken as u8,
+        None => flags & !(Flags::PingToken as u8),
+    }
+}
+
+/// The token isn't length prefixed. It is read as the payload of a `Ping` or `Pong` so the codec
+/// waits for it.
+fn ping_token_size(flags: u8) -> u32 {
+    if has_ping_token(flags) {
+        4
+    } else {
+        0
+    }
+}
+
+fn read_ping_token(flags: u8, buf: &BytesMut) -> Option<u32> {
+    if has_ping_token(flags) {
+        Some(BigEndian::read_u32(&buf[6..10]))
+    } else {
+        None
+    }
+}
+
+/// Sets `Flags::TIMESTAMPS` if and only if a `Ping` or `Pong` has timestamps.
+fn timestamps_flags(flags: u8, timestamped: bool) -> u8 {
+    if timestamped {
+        flags | Flags::TIMESTAMPS
+    } else {
+        flags & !Flags::TIMESTAMPS
+    }
+}
+
+/// Like the token, the `count` 8 byte timestamps are read as the payload.
+fn timestamps_size(flags: u8, count: u32) -> u32 {
+    if has_timestamps(flags) {
+        8 * count
+    } else {
+        0
+    }
+}
+
+/// Reads the `count` timestamps following the sequence id and the token, if there is one.
+fn read_timestamps(flags: u8, buf: &BytesMut, count: usize) -> Option<Vec<u64>> {
+    if !has_timestamps(flags) {
+        return None;
+    }
+    let start = 6 + ping_token_size(flags) as usize;
+    let timestamps = (0..count)
+        .map(|index| BigEndian::read_u64(&buf[start + 8 * index..start + 8 * (index + 1)]))
+        .collect();
+    Some(timestamps)
+}
+
+fn traced_flags(flags: u8, trace_id: &Option<TraceId>) -> u8 {
+    match trace_id {
+        Some(_) => flags | Flags::Traced as u8,
+        None => flags & !(Flags::Traced as u8),
+    }
+}
+
+/// Sets `Flags::IDEMPOTENT` if and only if there is an idempotency key.
+fn idempotent_flags(flags: u8, idempotency_key: &Option<IdempotencyKey>) -> u8 {
+    match idempotency_key {
+        Some(_) => flags | Flags::IDEMPOTENT,
+        None => flags & !Flags::IDEMPOTENT,
+    }
+}
+
+/// Prefixes the payload with the trace id, or any other 16 byte id like an idempotency key.
+fn traced_payload(trace_id: Option<TraceId>, payload: Vec<u8>) -> Vec<u8> {
+    match trace_id {
+        Some(trace_id) => {
+            let mut traced = Vec::with_capacity(trace_id.len() + payload.len());
+            traced.extend_from_slice(&trace_id);
+            traced.extend(payload);
+            traced
+        }
+        None => payload,
+    }
+}
+
+/// Splits the trace id off the front of the payload if the flags say there is one. Borrows the
+/// rest, which is copied once every optional header is split off.
+fn split_trace_id(flags: u8, payload: &[u8]) -> Result<(Option<TraceId>, &[u8]), ProtocolError> {
+    if !is_traced(flags) {
+        return Ok((None, payload));
+    }
+    let mut trace_id = TraceId::default();
+    if payload.len() < trace_id.len() {
+        return Err(ProtocolError::InvalidPayload {
+            reason: "Traced payload is shorter than a trace id.".to_string(),
+        });
+    }
+    let (id, payload) = payload.split_at(trace_id.len());
+    trace_id.copy_from_slice(id);
+    Ok((Some(trace_id), payload))
+}
+
+/// Splits the idempotency key off the front of the payload if the flags say there is one.
+fn split_idempotency_key(
+    flags: u8,
+    payload: &[u8],
+) -> Result<(Option<IdempotencyKey>, &[u8]), ProtocolError> {
+    if !is_idempotent(flags) {
+        return Ok((None, payload));
+    }
+    let mut idempotency_key = IdempotencyKey::default();
+    if payload.len() < idempotency_key.len() {
+        return Err(ProtocolError::InvalidPayload {
+            reason: "Idempotent payload is shorter than an idempotency key.".to_string(),
+        });
+    }
+    let (key, payload) = payload.split_at(idempotency_key.len());
+    idempotency_key.copy_from_slice(key);
+    Ok((Some(idempotency_key), payload))
+}
+
+fn prioritized_flags(flags: u8, priority: Priority) -> u8 {
+    if priority == Priority::Normal {
+        flags & !Flags::PRIORITIZED
+    } else {
+        flags | Flags::PRIORITIZED
+    }
+}
+
+/// Prefixes the payload with the priority, unless it is `Priority::Normal`.
+fn prioritized_payload(priority: Priority, payload: Vec<u8>) -> Vec<u8> {
+    if priority == Priority::Normal {
+        return payload;
+    }
+    let mut prioritized = Vec::with_capacity(1 + payload.len());
+    prioritized.push(priority as u8);
+    prioritized.extend(payload);
+    prioritized
+}
+
+fn split_priority(flags: u8, payload: &[u8]) -> Result<(Priority, &[u8]), ProtocolError> {
+    if !is_prioritized(flags) {
+        return Ok((Priority::Normal, payload));
+    }
+    match payload.split_first() {
+        Some((priority, payload)) => match Priority::from_u8(*priority) {
+            Some(priority) => Ok((priority, payload)),
+            None => Err(ProtocolError::InvalidPayload {
+                reason: format!("Unknown priority. priority={}", priority),
+            }),
+        },
+        None => Err(ProtocolError::InvalidPayload {
+            reason: "Prioritized payload is missing its priority.".to_string(),
+        }),
+    }
+}
+
+fn timeout_flags(flags: u8, timeout_ms: u32) -> u8 {
+    if timeout_ms == 0 {
+        flags & !Flags::TIMEOUT
+    } else {
+        flags | Flags::TIMEOUT
+    }
+}
+
+/// Prefixes the payload with the timeout, unless it is `0`.
+fn timeout_payload(timeout_ms: u32, payload: Vec<u8>) -> Vec<u8> {
+    if timeout_ms == 0 {
+        return payload;
+    }
+    let mut timed = Vec::with_capacity(4 + payload.len());
+    timed.extend_from_slice(&timeout_ms.to_be_bytes());
+    timed.extend(payload);
+    timed
+}
+
+fn split_timeout(flags: u8, payload: &[u8]) -> Result<(u32, &[u8]), ProtocolError> {
+    if !has_timeout(flags) {
+        return Ok((0, payload));
+    }
+    if payload.len() < 4 {
+        return Err(ProtocolError::InvalidPayload {
+            reason: "Timed payload is missing its timeout.".to_string(),
+        });
+    }
+    let (timeout_ms, payload) = payload.split_at(4);
+    Ok((BigEndian::read_u32(timeout_ms), payload))
+}
+
 impl From<Hello> for LoquiFrame {
     fn from(hello: Hello) -> LoquiFrame {
         LoquiFrame::Hello(hello)
@@ -477,6 +1433,72 @@ impl From<Error> for LoquiFrame {
     }
 }
 
+impl From<Cancel> for LoquiFrame {
+    fn from(cancel: Cancel) -> LoquiFrame {
+        LoquiFrame::Cancel(cancel)
+    }
+}
+
+impl From<WindowUpdate> for LoquiFrame {
+    fn from(window_update: WindowUpdate) -> LoquiFrame {
+        LoquiFrame::WindowUpdate(window_update)
+    }
+}
+
+impl From<RequestBatch> for LoquiFrame {
+    fn from(request_batch: RequestBatch) -> LoquiFrame {
+        LoquiFrame::RequestBatch(request_batch)
+    }
+}
+
+impl From<ResponseBatch> for LoquiFrame {
+    fn from(response_batch: ResponseBatch) -> LoquiFrame {
+        LoquiFrame::ResponseBatch(response_batch)
+    }
+}
+
+impl From<Renegotiate> for LoquiFrame {
+    fn from(renegotiate: Renegotiate) -> LoquiFrame {
+        LoquiFrame::Renegotiate(renegotiate)
+    }
+}
+
+impl From<HealthCheck> for LoquiFrame {
+    fn from(health_check: HealthCheck) -> LoquiFrame {
+        LoquiFrame::HealthCheck(health_check)
+    }
+}
+
+impl From<HealthStatus> for LoquiFrame {
+    fn from(health_status: HealthStatus) -> LoquiFrame {
+        LoquiFrame::HealthStatus(health_status)
+    }
+}
+
+impl From<Subscribe> for LoquiFrame {
+    fn from(subscribe: Subscribe) -> LoquiFrame {
+        LoquiFrame::Subscribe(subscribe)
+    }
+}
+
+impl From<Unsubscribe> for LoquiFrame {
+    fn from(unsubscribe: Unsubscribe) -> LoquiFrame {
+        LoquiFrame::Unsubscribe(unsubscribe)
+    }
+}
+
+impl From<UnknownFrame> for LoquiFrame {
+    fn from(unknown: UnknownFrame) -> LoquiFrame {
+        LoquiFrame::Unknown(unknown)
+    }
+}
+
+impl From<PushAck> for LoquiFrame {
+    fn from(push_ack: PushAck) -> LoquiFrame {
+        LoquiFrame::PushAck(push_ack)
+    }
+}
+
 impl LoquiFrame {
     pub fn opcode(&self) -> u8 {
         match self {
@@ -489,6 +1511,17 @@ impl LoquiFrame {
             LoquiFrame::Push(_) => Push::OPCODE,
             LoquiFrame::GoAway(_) => GoAway::OPCODE,
             LoquiFrame::Error(_) => Error::OPCODE,
+            LoquiFrame::Cancel(_) => Cancel::OPCODE,
+            LoquiFrame::PushAck(_) => PushAck::OPCODE,
+            LoquiFrame::WindowUpdate(_) => WindowUpdate::OPCODE,
+            LoquiFrame::RequestBatch(_) => RequestBatch::OPCODE,
+            LoquiFrame::ResponseBatch(_) => ResponseBatch::OPCODE,
+            LoquiFrame::Renegotiate(_) => Renegotiate::OPCODE,
+            LoquiFrame::HealthCheck(_) => HealthCheck::OPCODE,
+            LoquiFrame::HealthStatus(_) => HealthStatus::OPCODE,
+            LoquiFrame::Subscribe(_) => Subscribe::OPCODE,
+            LoquiFrame::Unsubscribe(_) => Unsubscribe::OPCODE,
+            LoquiFrame::Unknown(unknown) => unknown.opcode,
         }
     }
 }
@@ -1,9 +1,17 @@
+// `failure_derive` generates impls inside anonymous consts.
+#![allow(non_local_definitions)]
+
 pub mod codec;
 pub mod error;
 mod flags;
 pub mod frames;
 pub mod upgrade;
 
-pub use self::flags::{is_compressed, make_flags, Flags};
+pub use self::flags::{
+    has_batches, has_checksums, has_encoding_ids, has_ping_token, has_subscription, has_timeout,
+    has_timestamps, is_acked, is_compressed, is_flow_controlled, is_half_closed, is_idempotent,
+    is_mandatory, is_no_compress, is_prioritized, is_stream_end, is_streaming, is_traced,
+    make_flags, Flags,
+};
 
 pub const VERSION: u8 = 1;
@@ -92,7 +92,6 @@ mod tests {
     use bytes::{BufMut, BytesMut};
 
     fn test_round_trip(frame_bytes: &[u8], expected_frame: UpgradeFrame) {
-        let expected_frame = expected_frame.into();
         let mut codec = Codec::new(ByteSize::b(500));
         let buf = &mut BytesMut::with_capacity(1024);
 
@@ -0,0 +1,83 @@
+use crate::connection_handler::{ConnectionHandler, InternalEvent};
+use crate::RequestHandler;
+use failure::Error;
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use loqui_connection::{Connection, LoquiError};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// A handle for sending requests to a connected client and awaiting its responses, see
+/// `RequestHandler::on_client_connected`. Clones share the connection.
+#[derive(Clone)]
+pub struct ClientConnection {
+    connection: Arc<dyn SendRequest>,
+    peer: SocketAddr,
+}
+
+/// The part of a `Connection` the handle needs, so it doesn't carry the request handler's type.
+trait SendRequest: Send + Sync {
+    fn ready(&self) -> BoxFuture<'_, Result<(), LoquiError>>;
+    fn send(&self, event: InternalEvent) -> Result<(), LoquiError>;
+    fn is_closed(&self) -> bool;
+}
+
+impl<R: RequestHandler> SendRequest for Connection<ConnectionHandler<R>> {
+    fn ready(&self) -> BoxFuture<'_, Result<(), LoquiError>> {
+        Connection::ready(self).boxed()
+    }
+
+    fn send(&self, event: InternalEvent) -> Result<(), LoquiError> {
+        Connection::send(self, event)
+    }
+
+    fn is_closed(&self) -> bool {
+        Connection::is_closed(self)
+    }
+}
+
+impl ClientConnection {
+    pub(crate) fn new<R: RequestHandler>(
+        connection: Connection<ConnectionHandler<R>>,
+        peer: SocketAddr,
+    ) -> Self {
+        Self {
+            connection: Arc::new(connection),
+            peer,
+        }
+    }
+
+    /// The client's address.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.connection.is_closed()
+    }
+
+    /// Send a request to the client once the handshake completed. The payload is encoded with the
+    /// negotiated encoding. Fails with `LoquiError::RequestTimeout` if no response arrived within
+    /// the timeout, counted from the call, and with the client's error if it failed the request.
+    pub async fn request(
+        &self,
+        payload: Vec<u8>,
+        timeout_after: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let request = async {
+            self.connection.ready().await?;
+            let (waiter, response) = oneshot::channel();
+            self.connection
+                .send(InternalEvent::Request { payload, waiter })?;
+            response
+                .await
+                .map_err(|_canceled| Error::from(LoquiError::ConnectionClosed))?
+        };
+        timeout(timeout_after, request)
+            .await
+            .unwrap_or_else(|_elapsed| Err(LoquiError::RequestTimeout.into()))
+    }
+}
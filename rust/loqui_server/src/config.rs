@@ -1,5 +1,6 @@
 use super::request_handler::RequestHandler;
 use bytesize::ByteSize;
+use loqui_connection::TransportOptions;
 use std::time::Duration;
 
 /// Configuration for the server.
@@ -14,4 +15,6 @@ pub struct Config<R: RequestHandler> {
     pub handshake_timeout: Duration,
     /// Supported encodings.
     pub supported_encodings: &'static [&'static str],
+    /// Connection level settings.
+    pub transport_options: TransportOptions,
 }
@@ -1,15 +1,26 @@
 use crate::{Config, RequestHandler};
 use bytesize::ByteSize;
 use failure::Error;
+use futures::channel::oneshot;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
-use loqui_connection::handler::{DelegatedFrame, Handler, Ready};
-use loqui_connection::{find_encoding, ReaderWriter};
-use loqui_connection::{IdSequence, LoquiError};
-use loqui_protocol::frames::{Frame, Hello, HelloAck, LoquiFrame, Push, Request, Response};
+use loqui_connection::compressor::find_compressor;
+use loqui_connection::handler::{
+    ClockSkew, DelegatedFrame, FrameOutcome, Handler, HandshakeFuture, HandshakeTiming,
+    IntoErrorPayload, Negotiated, Ready, Role, SubscribeOutcome,
+};
+use loqui_connection::{find_encoding, ConnectionTag, ReaderWriter};
+use loqui_connection::{IdSequence, LoquiError, LoquiErrorCode, TransportOptions};
+use loqui_protocol::frames::{
+    Error as ErrorFrame, Frame, Hello, HelloAck, LoquiFrame, Priority, Push, Request, Response,
+};
 use loqui_protocol::upgrade::{Codec, UpgradeFrame};
-use loqui_protocol::VERSION;
+use loqui_protocol::{
+    has_batches, has_checksums, has_encoding_ids, has_timestamps, is_streaming, Flags, VERSION,
+};
+use std::collections::HashMap;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
@@ -17,26 +28,59 @@ use tokio::net::TcpStream;
 use tokio::task::spawn;
 use tokio_util::codec::Framed;
 
+/// What a `ClientConnection` asks the connection to do.
+pub enum InternalEvent {
+    /// A request to the client, see `TransportOptions::peer_requests`. The waiter is resolved with
+    /// the client's response.
+    Request {
+        payload: Vec<u8>,
+        waiter: oneshot::Sender<Result<Vec<u8>, Error>>,
+    },
+}
+
 pub struct ConnectionHandler<R: RequestHandler> {
     config: Arc<Config<R>>,
+    /// The client's address, see `RequestHandler::authorize`.
+    peer: SocketAddr,
+    /// The waiters of the requests sent to the client that weren't answered yet, keyed by
+    /// `sequence_id`.
+    pending: HashMap<u32, oneshot::Sender<Result<Vec<u8>, Error>>>,
 }
 
 impl<R: RequestHandler> ConnectionHandler<R> {
-    pub fn new(config: Arc<Config<R>>) -> Self {
-        Self { config }
+    pub fn new(config: Arc<Config<R>>, peer: SocketAddr) -> Self {
+        Self {
+            config,
+            peer,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<R: RequestHandler> IntoErrorPayload for ConnectionHandler<R> {
+    fn error_payload(
+        &self,
+        error: &Error,
+        encoding: &'static str,
+    ) -> Option<(LoquiErrorCode, Vec<u8>)> {
+        self.config.request_handler.error_payload(error, encoding)
     }
 }
 
 impl<R: RequestHandler> Handler for ConnectionHandler<R> {
-    // Server doesn't have any internal events.
-    type InternalEvent = ();
+    type InternalEvent = InternalEvent;
 
     const SEND_GO_AWAY: bool = true;
+    const ROLE: Option<Role> = Some(Role::Server);
 
     fn max_payload_size(&self) -> ByteSize {
         self.config.max_payload_size
     }
 
+    fn transport_options(&self) -> &TransportOptions {
+        &self.config.transport_options
+    }
+
     fn upgrade(
         &self,
         tcp_stream: TcpStream,
@@ -48,9 +92,7 @@ impl<R: RequestHandler> Handler for ConnectionHandler<R> {
         Box::pin(async move {
             match reader.next().await {
                 Some(Ok(UpgradeFrame::Request)) => {
-                    if let Err(e) = writer.send(UpgradeFrame::Response).await {
-                        return Err(e);
-                    }
+                    writer.send(UpgradeFrame::Response).await?;
                     Ok(writer.reunite(reader)?.into_inner())
                 }
                 Some(Ok(frame)) => Err(LoquiError::InvalidUpgradeFrame { frame }.into()),
@@ -60,21 +102,19 @@ impl<R: RequestHandler> Handler for ConnectionHandler<R> {
         })
     }
 
-    fn handshake(
-        &mut self,
-        mut reader_writer: ReaderWriter,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<(Ready, ReaderWriter), (Error, Option<ReaderWriter>)>>
-                + Send,
-        >,
-    > {
-        let ping_interval = self.config.ping_interval;
-        let supported_encodings = self.config.supported_encodings;
+    fn handshake(&mut self, mut reader_writer: ReaderWriter) -> HandshakeFuture {
+        let config = self.config.clone();
+        let supported_compressions = self.supported_compressions();
+        let peer = self.peer;
         Box::pin(async move {
             match reader_writer.reader.next().await {
                 Some(Ok(frame)) => {
-                    match Self::handle_handshake_frame(frame, ping_interval, supported_encodings) {
+                    match Self::handle_handshake_frame(
+                        frame,
+                        peer,
+                        &config,
+                        &supported_compressions,
+                    ) {
                         Ok((ready, hello_ack)) => {
                             reader_writer = match reader_writer.write(hello_ack).await {
                                 Ok(reader_writer) => reader_writer,
@@ -91,46 +131,207 @@ impl<R: RequestHandler> Handler for ConnectionHandler<R> {
         })
     }
 
-    fn handle_frame(
-        &mut self,
-        frame: DelegatedFrame,
-        encoding: &'static str,
-    ) -> Option<Pin<Box<dyn Future<Output = Result<Response, (Error, u32)>> + Send>>> {
+    fn on_connection_start(&mut self, connection: &ConnectionTag) {
+        self.config.request_handler.on_connection_start(connection);
+    }
+
+    fn on_handshake_complete(&mut self, negotiated: &Negotiated, timing: &HandshakeTiming) {
+        debug!(
+            "Handshake complete. negotiated={:?} timing={:?}",
+            negotiated, timing
+        );
+        self.config
+            .request_handler
+            .on_handshake_complete(negotiated, timing);
+    }
+
+    fn handle_frame(&mut self, frame: DelegatedFrame, encoding: &'static str) -> FrameOutcome {
         match frame {
             DelegatedFrame::Push(push) => {
                 spawn(handle_push(self.config.clone(), push, encoding));
-                None
+                FrameOutcome::Ignore
             }
             DelegatedFrame::Request(request) => {
-                let response_future = handle_request(self.config.clone(), request, encoding);
-                Some(Box::pin(response_future))
+                let request_handler = &self.config.request_handler;
+                if let Err(error) = request_handler.validate_request(&request.payload, encoding) {
+                    debug!(
+                        "Bad request. sequence_id={} error={:?}",
+                        request.sequence_id, error
+                    );
+                    return FrameOutcome::Reject {
+                        code: LoquiErrorCode::BadRequest,
+                        message: format!(
+                            "Failed to decode request. encoding={} error={}",
+                            encoding, error
+                        ),
+                    };
+                }
+                if let Some(inspector) = request_handler.request_inspector() {
+                    if let Err((code, message)) =
+                        inspector.inspect_payload(&request.payload, encoding)
+                    {
+                        debug!(
+                            "Request rejected by the inspector. sequence_id={} code={:?}",
+                            request.sequence_id, code
+                        );
+                        return FrameOutcome::Reject { code, message };
+                    }
+                }
+                if is_streaming(request.flags) {
+                    let stream = request_handler
+                        .handle_request_stream(request.payload, encoding)
+                        .map(Ok);
+                    FrameOutcome::Stream(Box::pin(stream))
+                } else {
+                    let response_future = handle_request(self.config.clone(), request, encoding);
+                    FrameOutcome::Respond(Box::pin(response_future))
+                }
+            }
+            DelegatedFrame::Error(error) => {
+                self.handle_error(error);
+                FrameOutcome::Ignore
+            }
+            DelegatedFrame::Response(response) => {
+                self.handle_response(response);
+                FrameOutcome::Ignore
             }
-            DelegatedFrame::Error(_) => None,
-            DelegatedFrame::Response(_) => None,
         }
     }
 
     fn handle_internal_event(
         &mut self,
-        _event: (),
-        _id_sequence: &mut IdSequence,
+        event: InternalEvent,
+        id_sequence: &mut IdSequence,
     ) -> Option<LoquiFrame> {
-        None
+        match event {
+            InternalEvent::Request { payload, waiter } => {
+                // Requests whose caller gave up waiting are forgotten, their responses dropped.
+                self.pending
+                    .retain(|_sequence_id, waiter| !waiter.is_canceled());
+                let max_open_sequence_ids = self.config.transport_options.max_open_sequence_ids;
+                if let Some(max_open_sequence_ids) = max_open_sequence_ids {
+                    if self.pending.len() >= max_open_sequence_ids {
+                        let error = LoquiError::service_unavailable("Too many open sequence ids.");
+                        // It's okay to ignore this result. The caller stopped waiting.
+                        let _result = waiter.send(Err(error.into()));
+                        return None;
+                    }
+                }
+                let sequence_id = id_sequence.next();
+                self.pending.insert(sequence_id, waiter);
+                let request = Request {
+                    flags: 0,
+                    sequence_id,
+                    trace_id: None,
+                    idempotency_key: None,
+                    priority: Priority::Normal,
+                    timeout_ms: 0,
+                    payload,
+                };
+                Some(request.into())
+            }
+        }
     }
 
     fn on_ping_received(&mut self) {}
+
+    fn handle_subscribe(
+        &mut self,
+        subscription_id: u32,
+        payload: Vec<u8>,
+        encoding: &'static str,
+    ) -> SubscribeOutcome {
+        match self
+            .config
+            .request_handler
+            .handle_subscribe(payload, encoding)
+        {
+            Some(stream) => SubscribeOutcome::Accept(Box::pin(stream.map(Ok))),
+            None => {
+                debug!("Subscription rejected. subscription_id={}", subscription_id);
+                SubscribeOutcome::Reject {
+                    code: LoquiErrorCode::BadRequest,
+                    message: "Subscription rejected.".to_string(),
+                }
+            }
+        }
+    }
+
+    fn on_slow_consumer(&mut self, depth: usize, duration: Duration) {
+        self.config
+            .request_handler
+            .on_slow_consumer(depth, duration);
+    }
+
+    fn observe_clock_skew(&mut self, clock_skew: ClockSkew) {
+        self.config.request_handler.on_clock_skew(clock_skew);
+    }
+
+    fn accept_encoding(&mut self, encoding: &str) -> Option<&'static str> {
+        find_encoding(encoding, self.config.supported_encodings)
+    }
 }
+
 impl<R: RequestHandler> ConnectionHandler<R> {
+    /// Resolves the waiter of a request sent to the client with its response.
+    fn handle_response(&mut self, response: Response) {
+        match self.pending.remove(&response.sequence_id) {
+            Some(waiter) => {
+                // It's okay to ignore this result. The caller stopped waiting.
+                let _result = waiter.send(Ok(response.payload));
+            }
+            None => debug!(
+                "Response to no pending request. sequence_id={}",
+                response.sequence_id
+            ),
+        }
+    }
+
+    /// Fails the waiter of a request sent to the client with the client's error.
+    fn handle_error(&mut self, error: ErrorFrame) {
+        let ErrorFrame {
+            sequence_id,
+            code,
+            payload,
+            ..
+        } = error;
+        if let Some(waiter) = self.pending.remove(&sequence_id) {
+            let error = LoquiError::ErrorResponse {
+                code,
+                reason: String::from_utf8_lossy(&payload).into_owned(),
+                sequence_id,
+                payload,
+            };
+            // It's okay to ignore this result. The caller stopped waiting.
+            let _result = waiter.send(Err(error.into()));
+        }
+    }
+
+    /// The names of the configured compressors.
+    fn supported_compressions(&self) -> Vec<&'static str> {
+        self.config
+            .transport_options
+            .compressors
+            .iter()
+            .map(|compressor| compressor.name())
+            .collect()
+    }
+
     fn handle_handshake_frame(
         frame: LoquiFrame,
-        ping_interval: Duration,
-        supported_encodings: &'static [&'static str],
+        peer: SocketAddr,
+        config: &Config<R>,
+        supported_compressions: &[&'static str],
     ) -> Result<(Ready, HelloAck), Error> {
         match frame {
             LoquiFrame::Hello(hello) => {
-                Self::handle_handshake_hello(hello, ping_interval, supported_encodings)
+                // Refused before anything is negotiated, so nothing is ever delegated.
+                if let Err(code) = config.request_handler.authorize(peer, &hello) {
+                    return Err(LoquiError::Unauthorized { code }.into());
+                }
+                Self::handle_handshake_hello(hello, config, supported_compressions)
             }
-            LoquiFrame::GoAway(go_away) => Err(LoquiError::ToldToGoAway { go_away }.into()),
+            LoquiFrame::GoAway(go_away) => Err(LoquiError::told_to_go_away(go_away).into()),
             frame => Err(LoquiError::InvalidOpcode {
                 actual: frame.opcode(),
                 expected: Some(Hello::OPCODE),
@@ -141,15 +342,18 @@ impl<R: RequestHandler> ConnectionHandler<R> {
 
     fn handle_handshake_hello(
         hello: Hello,
-        ping_interval: Duration,
-        supported_encodings: &'static [&'static str],
+        config: &Config<R>,
+        supported_compressions: &[&'static str],
     ) -> Result<(Ready, HelloAck), Error> {
         let Hello {
             flags,
             version,
             encodings,
-            // compression not supported
-            compressions: _compressions,
+            compressions,
+            ping_interval_ms,
+            dictionary_ids,
+            // Checked by `RequestHandler::authorize` already.
+            auth_token: _auth_token,
         } = hello;
         if version != VERSION {
             return Err(LoquiError::UnsupportedVersion {
@@ -158,30 +362,97 @@ impl<R: RequestHandler> ConnectionHandler<R> {
             }
             .into());
         }
-        let encoding = Self::negotiate_encoding(&encodings, supported_encodings)?;
+        let ping_interval = Self::negotiate_ping_interval(ping_interval_ms, config.ping_interval);
+        let encoding = config
+            .request_handler
+            .select_encoding(&encodings, config.supported_encodings)?;
+        let compression = config
+            .request_handler
+            .select_compression(&compressions, supported_compressions)?;
+        let dictionary_id = compression.and_then(|compression| {
+            Self::negotiate_dictionary(compression, &dictionary_ids, &config.transport_options)
+        });
+        let ping_timestamps = has_timestamps(flags) && config.transport_options.ping_timestamps;
+        let mut ack_flags = flags & Flags::BATCHES;
+        if ping_timestamps {
+            ack_flags |= Flags::TIMESTAMPS;
+        }
+        let checksums = has_checksums(flags) && config.transport_options.checksums;
+        if checksums {
+            ack_flags |= Flags::CHECKSUMS;
+        }
+        // The client's other encodings we support too, in the client's order.
+        let encodings = if has_encoding_ids(flags) && config.transport_options.encoding_ids {
+            ack_flags |= Flags::ENCODING_IDS;
+            let mut others: Vec<&'static str> = vec![];
+            for other in &encodings {
+                match find_encoding(other, config.supported_encodings) {
+                    Some(other) if other != encoding && !others.contains(&other) => {
+                        others.push(other)
+                    }
+                    _ => {}
+                }
+            }
+            Some(others)
+        } else {
+            None
+        };
         let hello_ack = HelloAck {
-            flags,
+            // Batches are always accepted.
+            flags: ack_flags,
             ping_interval_ms: ping_interval.as_millis() as u32,
             encoding: encoding.to_string(),
-            compression: None,
+            compression: compression.map(String::from),
+            dictionary_id,
+            encodings: encodings
+                .iter()
+                .flatten()
+                .copied()
+                .map(String::from)
+                .collect(),
         };
         let ready = Ready {
             ping_interval,
             encoding,
+            compression,
+            dictionary_id,
+            peer_version: version,
+            batches: has_batches(flags),
+            ping_timestamps,
+            checksums,
+            encodings,
         };
         Ok((ready, hello_ack))
     }
 
-    fn negotiate_encoding(
-        client_encodings: &[String],
-        supported_encodings: &'static [&'static str],
-    ) -> Result<&'static str, Error> {
-        for client_encoding in client_encodings {
-            if let Some(encoding) = find_encoding(client_encoding, supported_encodings) {
-                return Ok(encoding);
+    /// Picks the first of the client's dictionaries that we also have, as long as the negotiated
+    /// compression supports dictionaries.
+    fn negotiate_dictionary(
+        compression: &str,
+        client_dictionary_ids: &[u32],
+        transport_options: &TransportOptions,
+    ) -> Option<u32> {
+        let compressor = find_compressor(compression, &transport_options.compressors)?;
+        let dictionaries = &transport_options.dictionaries;
+        let dictionary_id = dictionaries.negotiate(client_dictionary_ids)?;
+        compressor
+            .with_dictionary(dictionaries.get(dictionary_id)?)
+            .map(|_compressor| dictionary_id)
+    }
+
+    /// Settles on the shorter of the client's proposed ping interval and our own, so neither side
+    /// pings less often than the other expects.
+    fn negotiate_ping_interval(
+        client_ping_interval_ms: Option<u32>,
+        ping_interval: Duration,
+    ) -> Duration {
+        match client_ping_interval_ms {
+            // A zero interval would ping in a busy loop.
+            Some(client_ping_interval_ms) if client_ping_interval_ms > 0 => {
+                ping_interval.min(Duration::from_millis(u64::from(client_ping_interval_ms)))
             }
+            _ => ping_interval,
         }
-        Err(LoquiError::NoCommonEncoding.into())
     }
 }
 
@@ -190,10 +461,7 @@ async fn handle_push<R: RequestHandler>(
     push: Push,
     encoding: &'static str,
 ) {
-    let Push {
-        payload,
-        flags: _flags,
-    } = push;
+    let Push { payload, .. } = push;
     config.request_handler.handle_push(payload, encoding).await
 }
 
@@ -206,14 +474,388 @@ async fn handle_request<R: RequestHandler>(
         payload: request_payload,
         flags: _flags,
         sequence_id,
+        trace_id,
+        idempotency_key,
+        priority: _priority,
+        timeout_ms: _timeout_ms,
     } = request;
-    let response_payload = config
-        .request_handler
-        .handle_request(request_payload, encoding)
-        .await;
+    let response_payload = match idempotency_key {
+        Some(idempotency_key) => {
+            config
+                .request_handler
+                .handle_idempotent_request(request_payload, encoding, idempotency_key)
+                .await
+        }
+        None => {
+            config
+                .request_handler
+                .handle_request(request_payload, encoding)
+                .await
+        }
+    };
+    // Echo the trace id so the client can correlate the response.
     Ok(Response {
+        trace_id,
         flags: 0,
         sequence_id,
         payload: response_payload,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loqui_connection::compressors::{DeflateCompressor, SnappyCompressor};
+    use loqui_connection::GoAwayCode;
+
+    struct EchoHandler {}
+
+    impl RequestHandler for EchoHandler {
+        fn handle_request(
+            &self,
+            payload: Vec<u8>,
+            _encoding: &'static str,
+        ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+            Box::pin(async move { payload })
+        }
+
+        fn handle_push(
+            &self,
+            _payload: Vec<u8>,
+            _encoding: &'static str,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+            Box::pin(async {})
+        }
+    }
+
+    fn config() -> Config<EchoHandler> {
+        Config {
+            request_handler: EchoHandler {},
+            max_payload_size: ByteSize::kb(64),
+            ping_interval: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(5),
+            supported_encodings: &["json"],
+            transport_options: TransportOptions::default(),
+        }
+    }
+
+    fn hello(version: u8) -> Hello {
+        Hello {
+            flags: 0,
+            version,
+            encodings: vec!["json".to_string()],
+            compressions: vec![],
+            ping_interval_ms: None,
+            dictionary_ids: vec![],
+            auth_token: None,
+        }
+    }
+
+    #[test]
+    fn it_resolves_requests_sent_to_the_client() {
+        let mut handler =
+            ConnectionHandler::new(Arc::new(config()), "127.0.0.1:0".parse().unwrap());
+        let mut id_sequence = IdSequence::default();
+        let mut send = |handler: &mut ConnectionHandler<EchoHandler>| {
+            let (waiter, response) = oneshot::channel();
+            let event = InternalEvent::Request {
+                payload: b"hi".to_vec(),
+                waiter,
+            };
+            match handler.handle_internal_event(event, &mut id_sequence) {
+                Some(LoquiFrame::Request(request)) => (request.sequence_id, response),
+                other => panic!("request not returned. {:?}", other),
+            }
+        };
+
+        let (sequence_id, mut response) = send(&mut handler);
+        let answer = Response {
+            flags: 0,
+            sequence_id,
+            trace_id: None,
+            payload: b"hello".to_vec(),
+        };
+        assert!(matches!(
+            handler.handle_frame(DelegatedFrame::Response(answer), "json"),
+            FrameOutcome::Ignore
+        ));
+        assert_eq!(response.try_recv().unwrap().unwrap().unwrap(), b"hello");
+
+        let (sequence_id, mut response) = send(&mut handler);
+        let error = ErrorFrame {
+            flags: 0,
+            sequence_id,
+            code: LoquiErrorCode::BadRequest as u16,
+            payload: b"nope".to_vec(),
+        };
+        handler.handle_frame(DelegatedFrame::Error(error), "json");
+        let error = response.try_recv().unwrap().unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::ErrorResponse { .. })
+        ));
+
+        // Forgotten once nothing waits for it.
+        let (_sequence_id, response) = send(&mut handler);
+        drop(response);
+        let _pending = send(&mut handler);
+        assert_eq!(handler.pending.len(), 1);
+    }
+
+    #[test]
+    fn it_limits_open_sequence_ids_of_requests_sent_to_the_client() {
+        let mut config = config();
+        config.transport_options = TransportOptions::builder()
+            .max_open_sequence_ids(1)
+            .build()
+            .unwrap();
+        let mut handler = ConnectionHandler::new(Arc::new(config), "127.0.0.1:0".parse().unwrap());
+        let mut id_sequence = IdSequence::default();
+        let mut send = |handler: &mut ConnectionHandler<EchoHandler>| {
+            let (waiter, response) = oneshot::channel();
+            let event = InternalEvent::Request {
+                payload: b"hi".to_vec(),
+                waiter,
+            };
+            (
+                handler.handle_internal_event(event, &mut id_sequence),
+                response,
+            )
+        };
+
+        let (frame, _response) = send(&mut handler);
+        assert!(matches!(frame, Some(LoquiFrame::Request(_))));
+        let (frame, mut response) = send(&mut handler);
+        assert!(frame.is_none());
+        let error = response.try_recv().unwrap().unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::ErrorResponse { code, .. })
+                if *code == LoquiErrorCode::ServiceUnavailable as u16
+        ));
+        assert_eq!(handler.pending.len(), 1);
+    }
+
+    #[test]
+    fn it_reports_the_peer_version_once_the_handshake_completed() {
+        let (ready, _hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello(VERSION), &config(), &[]).unwrap();
+        assert_eq!(ready.negotiated().peer_version, VERSION);
+    }
+
+    #[test]
+    fn it_accepts_batches_only_when_offered() {
+        let (ready, hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello(VERSION), &config(), &[]).unwrap();
+        assert!(!ready.batches);
+        assert_eq!(hello_ack.flags, 0);
+
+        let hello = Hello {
+            flags: Flags::BATCHES | Flags::Compressed as u8,
+            ..hello(VERSION)
+        };
+        let (ready, hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello, &config(), &[]).unwrap();
+        assert!(ready.batches);
+        assert_eq!(hello_ack.flags, Flags::BATCHES);
+    }
+
+    #[test]
+    fn it_negotiates_encoding_ids_only_when_both_sides_do() {
+        let hello = Hello {
+            flags: Flags::ENCODING_IDS,
+            encodings: vec![
+                "json".to_string(),
+                "msgpack".to_string(),
+                "cbor".to_string(),
+            ],
+            ..hello(VERSION)
+        };
+        let (ready, hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello.clone(), &config(), &[]).unwrap();
+        assert_eq!(ready.encodings, None);
+        assert_eq!(hello_ack.flags, 0);
+
+        let config = Config {
+            supported_encodings: &["msgpack", "json"],
+            transport_options: TransportOptions::builder()
+                .encoding_ids(true)
+                .build()
+                .unwrap(),
+            ..config()
+        };
+        let (ready, hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello, &config, &[]).unwrap();
+        assert_eq!(ready.encoding, "json");
+        assert_eq!(ready.encodings, Some(vec!["msgpack"]));
+        assert_eq!(hello_ack.flags, Flags::ENCODING_IDS);
+        assert_eq!(hello_ack.encodings, vec!["msgpack".to_string()]);
+    }
+
+    #[test]
+    fn it_timestamps_pings_only_when_both_sides_do() {
+        let hello = Hello {
+            flags: Flags::TIMESTAMPS,
+            ..hello(VERSION)
+        };
+        let (ready, hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello.clone(), &config(), &[]).unwrap();
+        assert!(!ready.ping_timestamps);
+        assert_eq!(hello_ack.flags, 0);
+
+        let config = Config {
+            transport_options: TransportOptions::builder()
+                .ping_timestamps(true)
+                .build()
+                .unwrap(),
+            ..config()
+        };
+        let (ready, hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello, &config, &[]).unwrap();
+        assert!(ready.ping_timestamps);
+        assert_eq!(hello_ack.flags, Flags::TIMESTAMPS);
+    }
+
+    #[test]
+    fn it_checksums_only_when_both_sides_do() {
+        let hello = Hello {
+            flags: Flags::CHECKSUMS,
+            ..hello(VERSION)
+        };
+        let (ready, hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello.clone(), &config(), &[]).unwrap();
+        assert!(!ready.checksums);
+        assert_eq!(hello_ack.flags, 0);
+
+        let config = Config {
+            transport_options: TransportOptions::builder().checksums(true).build().unwrap(),
+            ..config()
+        };
+        let (ready, hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello, &config, &[]).unwrap();
+        assert!(ready.checksums);
+        assert_eq!(hello_ack.flags, Flags::CHECKSUMS);
+    }
+
+    #[test]
+    fn it_primes_the_compression_only_with_a_shared_dictionary() {
+        let config = Config {
+            transport_options: TransportOptions::builder()
+                .compressor(Arc::new(DeflateCompressor::default()))
+                .compressor(Arc::new(SnappyCompressor::default()))
+                .dictionary(7, b"loqui".to_vec())
+                .build()
+                .unwrap(),
+            ..config()
+        };
+        let hello = |compression: &str, dictionary_ids: Vec<u32>| Hello {
+            compressions: vec![compression.to_string()],
+            dictionary_ids,
+            ..hello(VERSION)
+        };
+        let supported = &["deflate", "snappy"];
+
+        let (ready, hello_ack) = ConnectionHandler::handle_handshake_hello(
+            hello("deflate", vec![3, 7]),
+            &config,
+            supported,
+        )
+        .unwrap();
+        assert_eq!(ready.dictionary_id, Some(7));
+        assert_eq!(hello_ack.dictionary_id, Some(7));
+
+        let (ready, hello_ack) = ConnectionHandler::handle_handshake_hello(
+            hello("deflate", vec![3]),
+            &config,
+            supported,
+        )
+        .unwrap();
+        assert_eq!(ready.dictionary_id, None);
+        assert_eq!(hello_ack.dictionary_id, None);
+
+        // Snappy has no dictionaries.
+        let (ready, _hello_ack) =
+            ConnectionHandler::handle_handshake_hello(hello("snappy", vec![7]), &config, supported)
+                .unwrap();
+        assert_eq!(ready.compression, Some("snappy"));
+        assert_eq!(ready.dictionary_id, None);
+    }
+
+    /// Only lets clients through whose `Hello` offers msgpack.
+    struct MsgpackOnlyHandler;
+
+    impl RequestHandler for MsgpackOnlyHandler {
+        fn handle_request(
+            &self,
+            payload: Vec<u8>,
+            _encoding: &'static str,
+        ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+            Box::pin(async move { payload })
+        }
+
+        fn handle_push(
+            &self,
+            _payload: Vec<u8>,
+            _encoding: &'static str,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+            Box::pin(async {})
+        }
+
+        fn authorize(&self, _peer: SocketAddr, hello: &Hello) -> Result<(), GoAwayCode> {
+            if hello.encodings.iter().any(|encoding| encoding == "msgpack") {
+                Ok(())
+            } else {
+                Err(GoAwayCode::Unknown(4003))
+            }
+        }
+    }
+
+    #[test]
+    fn it_refuses_unauthorized_peers_before_negotiating() {
+        let config = Config {
+            request_handler: MsgpackOnlyHandler,
+            max_payload_size: ByteSize::kb(64),
+            ping_interval: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(5),
+            supported_encodings: &["json"],
+            transport_options: TransportOptions::default(),
+        };
+        let peer = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let error =
+            ConnectionHandler::handle_handshake_frame(hello(VERSION).into(), peer, &config, &[])
+                .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::Unauthorized { code }) => assert_eq!(*code, GoAwayCode::Unknown(4003)),
+            other => panic!("expected unauthorized. {:?}", other),
+        }
+
+        let hello = Hello {
+            encodings: vec!["msgpack".to_string(), "json".to_string()],
+            ..hello(VERSION)
+        };
+        let (ready, _hello_ack) =
+            ConnectionHandler::handle_handshake_frame(hello.into(), peer, &config, &[]).unwrap();
+        assert_eq!(ready.encoding, "json");
+    }
+
+    #[test]
+    fn it_refuses_unsupported_versions() {
+        let error = ConnectionHandler::handle_handshake_hello(hello(VERSION + 1), &config(), &[])
+            .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::UnsupportedVersion { expected, actual }) => {
+                assert_eq!(*expected, VERSION);
+                assert_eq!(*actual, VERSION + 1);
+            }
+            other => panic!("expected unsupported version. {:?}", other),
+        }
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Unsupported Version. expected={} actual={}",
+                VERSION,
+                VERSION + 1
+            )
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use loqui_connection::{Encoder, Factory, LoquiErrorCode};
+
+/// The type a `RequestInspector` sees payloads decoded into.
+pub type Decoded<I> = <<<I as RequestInspector>::Factory as Factory>::Encoder as Encoder>::Decoded;
+
+/// Sees the payload of every request, decoded with the negotiated encoding, before its handler
+/// runs, e.g. for auditing or schema validation. See `RequestHandler::request_inspector`.
+pub trait RequestInspector: Send + Sync + 'static {
+    type Factory: Factory;
+
+    /// Returning a code rejects the request with an `Error` frame carrying it, without handling
+    /// it.
+    fn inspect_request(&self, decoded: &Decoded<Self>) -> Result<(), LoquiErrorCode>;
+}
+
+/// A `RequestInspector` that decodes the payload itself, so any inspector can be handed out by
+/// `RequestHandler::request_inspector`.
+pub trait InspectPayload: Send + Sync {
+    /// Returns the code and message to reject the request with. Payloads that don't decode are
+    /// rejected with `LoquiErrorCode::BadRequest`.
+    fn inspect_payload(
+        &self,
+        payload: &[u8],
+        encoding: &'static str,
+    ) -> Result<(), (LoquiErrorCode, String)>;
+}
+
+impl<I: RequestInspector> InspectPayload for I {
+    fn inspect_payload(
+        &self,
+        payload: &[u8],
+        encoding: &'static str,
+    ) -> Result<(), (LoquiErrorCode, String)> {
+        let encoder = I::Factory::make(encoding).ok_or_else(|| {
+            (
+                LoquiErrorCode::BadRequest,
+                format!(
+                    "Inspector doesn't support the encoding. encoding={}",
+                    encoding
+                ),
+            )
+        })?;
+        let mut reader = payload;
+        let decoded = encoder
+            .decode_from(&mut reader, payload.len())
+            .map_err(|error| {
+                (
+                    LoquiErrorCode::BadRequest,
+                    format!(
+                        "Failed to decode request. encoding={} error={}",
+                        encoding, error
+                    ),
+                )
+            })?;
+        self.inspect_request(&decoded)
+            .map_err(|code| (code, format!("Request rejected. code={:?}", code)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loqui_connection::encoders::{BincodeEncoder, BincodeFactory};
+
+    type Bincode = BincodeFactory<u32, u32>;
+
+    /// Only lets even numbers through.
+    struct EvenInspector;
+
+    impl RequestInspector for EvenInspector {
+        type Factory = Bincode;
+
+        fn inspect_request(&self, decoded: &u32) -> Result<(), LoquiErrorCode> {
+            if decoded.is_multiple_of(2) {
+                Ok(())
+            } else {
+                Err(LoquiErrorCode::Unauthorized)
+            }
+        }
+    }
+
+    fn encode(value: u32) -> Vec<u8> {
+        let encoder: BincodeEncoder<u32, u32> = Bincode::make("bincode").unwrap();
+        encoder.encode(value).unwrap()
+    }
+
+    #[test]
+    fn it_inspects_the_decoded_payload() {
+        assert!(EvenInspector.inspect_payload(&encode(4), "bincode").is_ok());
+        let (code, _message) = EvenInspector
+            .inspect_payload(&encode(5), "bincode")
+            .unwrap_err();
+        assert_eq!(code, LoquiErrorCode::Unauthorized);
+    }
+
+    #[test]
+    fn it_rejects_payloads_that_dont_decode() {
+        let (code, _message) = EvenInspector.inspect_payload(&[1], "bincode").unwrap_err();
+        assert_eq!(code, LoquiErrorCode::BadRequest);
+        let (code, _message) = EvenInspector
+            .inspect_payload(&encode(4), "json")
+            .unwrap_err();
+        assert_eq!(code, LoquiErrorCode::BadRequest);
+    }
+}
@@ -1,11 +1,22 @@
 #[macro_use]
 extern crate log;
 
+mod client_connection;
 mod config;
 mod connection_handler;
+mod inspector;
 mod request_handler;
 mod server;
 
+pub use self::client_connection::ClientConnection;
 pub use self::config::Config;
+pub use self::inspector::{Decoded, InspectPayload, RequestInspector};
 pub use self::request_handler::RequestHandler;
 pub use self::server::Server;
+pub use loqui_connection::handler::{ClockSkew, ConnectionHealth, HandshakeTiming, Negotiated};
+pub use loqui_connection::{
+    ConnectionStats, ConnectionTag, GoAwayCode, IdStrategy, IdStrategyFactory,
+    ProtocolViolationPolicy, RateLimit, Spawn, Task, TokioSpawn, TransportOptions,
+    TransportOptionsBuilder, UnexpectedFramePolicy,
+};
+pub use loqui_protocol::frames::{AuthToken, Hello, IdempotencyKey, Priority};
@@ -1,5 +1,15 @@
+use crate::inspector::InspectPayload;
+use crate::ClientConnection;
+use failure::Error;
+use futures::stream::{once, Stream};
+use loqui_connection::compressor::negotiate_compression;
+use loqui_connection::handler::{ClockSkew, HandshakeTiming, Negotiated};
+use loqui_connection::{negotiate_encoding, ConnectionTag, GoAwayCode, LoquiErrorCode};
+use loqui_protocol::frames::{Hello, IdempotencyKey};
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::time::Duration;
 
 /// Trait implemented by servers for handling individual `Request`s and `Push`es.
 pub trait RequestHandler: Send + Sync + 'static {
@@ -9,10 +19,111 @@ pub trait RequestHandler: Send + Sync + 'static {
         payload: Vec<u8>,
         encoding: &'static str,
     ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>>;
+    /// Handle a single request the client may send more than once, e.g. to retry it after
+    /// `LoquiErrorCode::ServiceUnavailable`. Every attempt carries the same idempotency key, which
+    /// can be used to dedupe them. By default the key is ignored and `handle_request` handles it.
+    fn handle_idempotent_request(
+        &self,
+        payload: Vec<u8>,
+        encoding: &'static str,
+        _idempotency_key: IdempotencyKey,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        self.handle_request(payload, encoding)
+    }
+    /// Handle a single request asking for a streamed response. Each payload of the returned stream
+    /// is sent to the client as soon as it is ready. By default the stream is the single response
+    /// of `handle_request`.
+    fn handle_request_stream(
+        &self,
+        payload: Vec<u8>,
+        encoding: &'static str,
+    ) -> Pin<Box<dyn Stream<Item = Vec<u8>> + Send>> {
+        Box::pin(once(self.handle_request(payload, encoding)))
+    }
+    /// Handle a subscription to pushes. Each payload of the returned stream is pushed to the
+    /// client, tagged with the subscription, until the client unsubscribes or the stream ends.
+    /// Returning `None` rejects the subscription. Subscriptions are rejected by default.
+    fn handle_subscribe(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Option<Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>> {
+        None
+    }
+    /// Checks that the payload of a request decodes with the negotiated encoding, before the
+    /// request is handled. An error rejects the request with `LoquiErrorCode::BadRequest`, so
+    /// decode failures aren't mistaken for failures of the handler. Accepts everything by default.
+    fn validate_request(&self, _payload: &[u8], _encoding: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Sees the decoded payload of every request after `validate_request`, before it is handled,
+    /// see `RequestInspector`. A rejection is sent back as an `Error` frame with its code. No
+    /// inspector by default.
+    fn request_inspector(&self) -> Option<&dyn InspectPayload> {
+        None
+    }
     /// Handle a single push.
     fn handle_push(
         &self,
         payload: Vec<u8>,
         encoding: &'static str,
     ) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+    /// Maps the error of a failed request, e.g. `LoquiError::RequestTimeout`, to the code and
+    /// payload of the `Error` frame sent to the client. Returning `None` sends the error's message
+    /// as a string.
+    fn error_payload(
+        &self,
+        _error: &Error,
+        _encoding: &'static str,
+    ) -> Option<(LoquiErrorCode, Vec<u8>)> {
+        None
+    }
+    /// Called with the client's address and `Hello` before anything is negotiated, e.g. for
+    /// access control. Refusing goes away with the code and closes the connection without
+    /// handling any of its frames. Accepts everyone by default.
+    fn authorize(&self, _peer: SocketAddr, _hello: &Hello) -> Result<(), GoAwayCode> {
+        Ok(())
+    }
+    /// Called once per accepted connection with a handle for sending requests to the client, when
+    /// `TransportOptions::peer_requests` is on. The handle can be kept for as long as the
+    /// connection lives.
+    fn on_client_connected(&self, _client: ClientConnection) {}
+    /// Called once per connection when it started, before the handshake, with the id and labels
+    /// its logs and metrics are tagged with.
+    fn on_connection_start(&self, _connection: &ConnectionTag) {}
+    /// Called once per connection when the handshake with a client completed, along with how
+    /// long it took.
+    fn on_handshake_complete(&self, _negotiated: &Negotiated, _timing: &HandshakeTiming) {}
+    /// Called on each ping tick while responses have queued up for a client that reads slower
+    /// than they are sent, see `TransportOptions::slow_consumer_depth`. The connection stays open.
+    fn on_slow_consumer(&self, _depth: usize, _duration: Duration) {}
+    /// Called with the clock skew of the client estimated from each timestamped `Pong`, once
+    /// both sides turned on `TransportOptions::ping_timestamps`.
+    fn on_clock_skew(&self, _clock_skew: ClockSkew) {}
+    /// Picks the encoding for a connection from those offered by the client. Encodings may
+    /// carry a schema version, e.g. `json@2`. By default the highest version of the client's most
+    /// preferred common encoding is chosen. An error closes the connection with a `GoAway`, e.g. to
+    /// refuse an encoding in production.
+    fn select_encoding(
+        &self,
+        client_encodings: &[String],
+        supported_encodings: &'static [&'static str],
+    ) -> Result<&'static str, Error> {
+        negotiate_encoding(client_encodings, supported_encodings)
+    }
+    /// Picks the compression for a connection from those offered by the client, or `None` to send
+    /// frames uncompressed. By default the client's most preferred supported compression is
+    /// chosen, and frames are sent uncompressed if there is none. An error closes the connection
+    /// with a `GoAway`, e.g. `LoquiError::NoCommonCompression` from `require_compression` to
+    /// require a compression.
+    fn select_compression(
+        &self,
+        client_compressions: &[String],
+        supported_compressions: &[&'static str],
+    ) -> Result<Option<&'static str>, Error> {
+        Ok(negotiate_compression(
+            client_compressions,
+            supported_compressions,
+        ))
+    }
 }
@@ -1,5 +1,5 @@
 use crate::connection_handler::ConnectionHandler;
-use crate::{Config, RequestHandler};
+use crate::{ClientConnection, Config, RequestHandler};
 use failure::Error;
 use loqui_connection::Connection;
 use std::net::SocketAddr;
@@ -18,21 +18,31 @@ impl<R: RequestHandler> Server<R> {
         }
     }
 
-    fn handle_connection(&self, tcp_stream: TcpStream) {
-        info!("Accepted connection. {:?}", tcp_stream.peer_addr());
-        let connection_handler = ConnectionHandler::new(self.config.clone());
+    fn handle_connection(&self, tcp_stream: TcpStream, peer: SocketAddr) {
+        info!("Accepted connection. {:?}", peer);
+        let connection_handler = ConnectionHandler::new(self.config.clone(), peer);
         let handshake_deadline = Instant::now() + self.config.handshake_timeout;
-        let _connection =
+        let connection =
             Connection::spawn(tcp_stream, connection_handler, handshake_deadline, None);
+        if self.config.transport_options.peer_requests {
+            let client = ClientConnection::new(connection, peer);
+            self.config.request_handler.on_client_connected(client);
+        }
     }
 
     pub async fn listen_and_serve(&self, address: SocketAddr) -> Result<(), Error> {
-        let mut listener = TcpListener::bind(&address).await?;
+        let listener = TcpListener::bind(&address).await?;
         info!("Starting {:?} ...", address);
+        self.serve(listener).await
+    }
+
+    /// Serves the connections of a listener that is already bound, e.g. to port `0` so the OS
+    /// picks a free port. Connections are accepted as soon as it is bound.
+    pub async fn serve(&self, mut listener: TcpListener) -> Result<(), Error> {
         loop {
             match listener.accept().await {
-                Ok((tcp_stream, _address)) => {
-                    self.handle_connection(tcp_stream);
+                Ok((tcp_stream, peer)) => {
+                    self.handle_connection(tcp_stream, peer);
                 }
                 other => {
                     println!("listener.accept() failed. {:?}", other);
@@ -0,0 +1,137 @@
+mod common;
+
+use common::{client_config, server_config, start_server};
+use failure::Error;
+use loqui_client::{Client, Config as ClientConfig};
+use loqui_server::{
+    AuthToken, GoAwayCode, HandshakeTiming, Hello, Negotiated, RequestHandler, TransportOptions,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+
+/// Refuses every client, recording who asked and whether anything got past the handshake.
+#[derive(Default)]
+struct RefusingHandler {
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+    handled: Arc<AtomicUsize>,
+}
+
+impl RequestHandler for RefusingHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        self.handled.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.handled.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async {})
+    }
+
+    fn on_handshake_complete(&self, _negotiated: &Negotiated, _timing: &HandshakeTiming) {
+        self.handled.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn authorize(&self, peer: SocketAddr, _hello: &Hello) -> Result<(), GoAwayCode> {
+        self.peers.lock().unwrap().push(peer);
+        Err(GoAwayCode::Unknown(4003))
+    }
+}
+
+#[test]
+fn it_refuses_unauthorized_clients_during_the_handshake() {
+    let request_handler = RefusingHandler::default();
+    let peers = request_handler.peers.clone();
+    let handled = request_handler.handled.clone();
+
+    let ready = Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(request_handler)).await;
+        let client = Client::start_connect(address, client_config())
+            .await
+            .unwrap();
+        client.await_ready().await
+    });
+
+    assert!(ready.is_err());
+    let peers = peers.lock().unwrap();
+    assert_eq!(peers.len(), 1);
+    assert!(peers[0].ip().is_loopback());
+    assert_eq!(handled.load(Ordering::SeqCst), 0);
+}
+
+/// Only lets clients in that send the token.
+struct TokenHandler;
+
+impl RequestHandler for TokenHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    fn authorize(&self, _peer: SocketAddr, hello: &Hello) -> Result<(), GoAwayCode> {
+        match &hello.auth_token {
+            Some(AuthToken(auth_token)) if auth_token == b"open sesame" => Ok(()),
+            _ => Err(GoAwayCode::Unauthorized),
+        }
+    }
+}
+
+/// Connects to a server that checks tokens and sends a request with each of the tokens.
+fn request_with_tokens(auth_tokens: Vec<Option<&'static [u8]>>) -> Vec<Result<Vec<u8>, Error>> {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(TokenHandler)).await;
+
+        let mut results = vec![];
+        for auth_token in auth_tokens {
+            let mut transport_options = TransportOptions::builder();
+            if let Some(auth_token) = auth_token {
+                transport_options = transport_options.auth_token(auth_token.to_vec());
+            }
+            let client = Client::start_connect(
+                address,
+                ClientConfig {
+                    transport_options: transport_options.build().unwrap(),
+                    ..client_config()
+                },
+            )
+            .await
+            .unwrap();
+            let result = match client.await_ready().await {
+                Ok(()) => client.request(b"hello".to_vec()).await,
+                Err(e) => Err(e),
+            };
+            results.push(result);
+        }
+        results
+    })
+}
+
+#[test]
+fn it_completes_the_handshake_only_with_a_valid_token() {
+    let results = request_with_tokens(vec![Some(b"open sesame"), Some(b"guess"), None]);
+    assert_eq!(results[0].as_ref().unwrap(), b"hello");
+    assert!(results[1].is_err());
+    assert!(results[2].is_err());
+}
@@ -0,0 +1,24 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server, EchoHandler};
+use tokio::runtime::Runtime;
+
+#[test]
+fn it_responds_to_each_request_of_a_batch() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(EchoHandler)).await;
+        let client = connect(address, client_config()).await;
+
+        let payloads = vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()];
+        let responses = client.request_batch(payloads.clone()).await.unwrap();
+        let responses: Vec<Vec<u8>> = responses
+            .into_iter()
+            .map(|response| response.unwrap())
+            .collect();
+        assert_eq!(responses, payloads);
+
+        assert!(client.request_batch(vec![]).await.unwrap().is_empty());
+        // Plain requests still work alongside batches.
+        assert_eq!(client.request(b"four".to_vec()).await.unwrap(), b"four");
+    });
+}
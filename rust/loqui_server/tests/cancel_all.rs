@@ -0,0 +1,57 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use futures::future::join;
+use loqui_connection::LoquiError;
+use loqui_server::RequestHandler;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+
+/// Echoes the request back after as many milliseconds as its first byte says.
+struct DelayedEchoHandler {}
+
+impl RequestHandler for DelayedEchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move {
+            delay_for(Duration::from_millis(u64::from(payload[0]))).await;
+            payload
+        })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_fails_pending_requests_when_cancelling_all() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(DelayedEchoHandler {})).await;
+        let client = connect(address, client_config()).await;
+
+        let (pending, cancelled) = join(client.request(vec![200]), async {
+            client.cancel_all_inflight()
+        })
+        .await;
+        cancelled.unwrap();
+        match pending.unwrap_err().downcast_ref::<LoquiError>() {
+            Some(LoquiError::ConnectionClosing) => {}
+            other => panic!("expected the request to be cancelled. {:?}", other),
+        }
+
+        // Cancelling again does nothing, and the connection stays open.
+        client.cancel_all_inflight().unwrap();
+        assert_eq!(client.request(vec![10]).await.unwrap(), vec![10]);
+    });
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use bytesize::ByteSize;
+use common::{client_config, connect, server_config, start_server};
+use loqui_client::Config as ClientConfig;
+use loqui_connection::compressors::DeflateCompressor;
+use loqui_connection::Compressor;
+use loqui_server::{
+    Config as ServerConfig, HandshakeTiming, Negotiated, RequestHandler, TransportOptions,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+
+/// Echoes requests and records whether checksums were negotiated.
+struct EchoHandler {
+    checksums: Arc<Mutex<Vec<bool>>>,
+}
+
+impl RequestHandler for EchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    fn on_handshake_complete(&self, negotiated: &Negotiated, _timing: &HandshakeTiming) {
+        self.checksums.lock().unwrap().push(negotiated.checksums);
+    }
+}
+
+fn checksummed_options() -> TransportOptions {
+    let deflate: Arc<dyn Compressor> = Arc::new(DeflateCompressor::default());
+    TransportOptions::builder()
+        .compressor(deflate)
+        .checksums(true)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn it_round_trips_checksummed_payloads() {
+    let checksums = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = EchoHandler {
+        checksums: checksums.clone(),
+    };
+    let payload = b"the quick brown fox jumps over the lazy dog. ".repeat(2048);
+    let expected = payload.clone();
+
+    let (empty, small, response) = Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            max_payload_size: ByteSize::kb(256),
+            transport_options: checksummed_options(),
+            ..server_config(request_handler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                max_payload_size: ByteSize::kb(256),
+                transport_options: checksummed_options(),
+                ..client_config()
+            },
+        )
+        .await;
+        let empty = client.request(vec![]).await.unwrap();
+        // Sent uncompressed, while the large payload is checksummed once compressed.
+        let small = client.request(b"0123456789".to_vec()).await.unwrap();
+        (empty, small, client.request(payload).await.unwrap())
+    });
+
+    assert_eq!(empty, Vec::<u8>::new());
+    assert_eq!(small, b"0123456789".to_vec());
+    assert_eq!(response, expected);
+    assert_eq!(*checksums.lock().unwrap(), vec![true]);
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use common::{client_config, server_config, start_server, EchoHandler};
+use loqui_client::{Client, Config as ClientConfig};
+use loqui_server::{Config as ServerConfig, TransportOptions};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+
+/// Connects a client to a server pinging every 50ms, and returns it after a few pings.
+async fn connect(server_timestamps: bool, client_timestamps: bool) -> Client {
+    let address = start_server(ServerConfig {
+        ping_interval: Duration::from_millis(50),
+        supported_encodings: &["json"],
+        transport_options: TransportOptions::builder()
+            .ping_timestamps(server_timestamps)
+            .build()
+            .unwrap(),
+        ..server_config(EchoHandler)
+    })
+    .await;
+    let client = common::connect(
+        address,
+        ClientConfig {
+            supported_encodings: &["json"],
+            transport_options: TransportOptions::builder()
+                .ping_timestamps(client_timestamps)
+                .build()
+                .unwrap(),
+            ..client_config()
+        },
+    )
+    .await;
+    delay_for(Duration::from_millis(200)).await;
+    client
+}
+
+#[test]
+fn it_estimates_the_clock_skew_when_both_sides_timestamp_pings() {
+    Runtime::new().unwrap().block_on(async {
+        let client = connect(true, true).await;
+        assert!(client.rtt().is_some());
+        let clock_skew = client.clock_skew().unwrap();
+        // Both sides read the same clock.
+        assert!(clock_skew.one_way_delay < Duration::from_millis(50));
+        assert!(clock_skew.offset_micros.abs() < 50_000);
+    });
+}
+
+#[test]
+fn it_sends_plain_pings_unless_both_sides_timestamp_them() {
+    Runtime::new().unwrap().block_on(async {
+        let client = connect(false, true).await;
+        assert!(client.rtt().is_some());
+        assert_eq!(client.clock_skew(), None);
+    });
+}
@@ -0,0 +1,93 @@
+//! Fixtures shared by the integration tests: an echo server on a free port and default configs.
+
+// Every test uses only some of these.
+#![allow(dead_code)]
+
+use bytesize::ByteSize;
+use loqui_client::{Client, Config as ClientConfig};
+use loqui_server::{Config as ServerConfig, RequestHandler, Server, TransportOptions};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio::task::spawn;
+
+/// Responds to every request with its payload and drops pushes.
+pub struct EchoHandler;
+
+impl RequestHandler for EchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+/// A server config for the handler, speaking "identity".
+pub fn server_config<R: RequestHandler>(request_handler: R) -> ServerConfig<R> {
+    ServerConfig {
+        request_handler,
+        max_payload_size: ByteSize::kb(64),
+        ping_interval: Duration::from_secs(5),
+        handshake_timeout: Duration::from_secs(5),
+        supported_encodings: &["identity"],
+        transport_options: TransportOptions::default(),
+    }
+}
+
+/// A client config speaking "identity", without retries.
+pub fn client_config() -> ClientConfig {
+    ClientConfig {
+        max_payload_size: ByteSize::kb(64),
+        request_timeout: Duration::from_secs(5),
+        handshake_timeout: Duration::from_secs(5),
+        supported_encodings: &["identity"],
+        transport_options: TransportOptions::default(),
+        retry_policy: None,
+    }
+}
+
+/// Starts a server on a port the OS picks. It accepts connections once this returns its
+/// address. Must run inside a runtime.
+pub async fn start_server<R: RequestHandler>(config: ServerConfig<R>) -> SocketAddr {
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    spawn(async move { Server::new(config).serve(listener).await });
+    address
+}
+
+/// Like `start_server`, for tests that talk to the server over blocking sockets: the server runs
+/// on a runtime of its own thread.
+pub fn start_server_on_thread<R: RequestHandler>(config: ServerConfig<R>) -> SocketAddr {
+    let (address_sender, address_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        Runtime::new().unwrap().block_on(async move {
+            address_sender.send(start_server(config).await).unwrap();
+            futures::future::pending::<()>().await
+        })
+    });
+    address_receiver.recv().unwrap()
+}
+
+/// Connects a client and waits for its handshake to complete.
+pub async fn connect(address: SocketAddr, config: ClientConfig) -> Client {
+    let client = Client::start_connect(address, config).await.unwrap();
+    client.await_ready().await.unwrap();
+    client
+}
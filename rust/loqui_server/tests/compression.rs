@@ -0,0 +1,233 @@
+mod common;
+
+use bytesize::ByteSize;
+use common::{client_config, server_config, start_server};
+use failure::Error;
+use loqui_client::{Client, Config as ClientConfig};
+use loqui_connection::compressor::{negotiate_compression, require_compression};
+use loqui_connection::compressors::{DeflateCompressor, SnappyCompressor};
+use loqui_connection::Compressor;
+use loqui_server::{
+    Config as ServerConfig, HandshakeTiming, Negotiated, RequestHandler, TransportOptions,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+
+/// Echoes requests and records the negotiated compression and dictionary.
+#[derive(Default)]
+struct EchoHandler {
+    compressions: Arc<Mutex<Vec<Option<&'static str>>>>,
+    dictionary_ids: Arc<Mutex<Vec<Option<u32>>>>,
+    /// Refuses clients that don't offer a supported compression.
+    require_compression: bool,
+}
+
+impl RequestHandler for EchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    fn on_handshake_complete(&self, negotiated: &Negotiated, _timing: &HandshakeTiming) {
+        self.compressions
+            .lock()
+            .unwrap()
+            .push(negotiated.compression);
+        self.dictionary_ids
+            .lock()
+            .unwrap()
+            .push(negotiated.dictionary_id);
+    }
+
+    fn select_compression(
+        &self,
+        client_compressions: &[String],
+        supported_compressions: &[&'static str],
+    ) -> Result<Option<&'static str>, Error> {
+        if self.require_compression {
+            require_compression(client_compressions, supported_compressions).map(Some)
+        } else {
+            Ok(negotiate_compression(
+                client_compressions,
+                supported_compressions,
+            ))
+        }
+    }
+}
+
+fn deflate_options() -> TransportOptions {
+    let deflate: Arc<dyn Compressor> = Arc::new(DeflateCompressor::default());
+    TransportOptions::builder()
+        .compressor(deflate)
+        .build()
+        .unwrap()
+}
+
+/// Starts a server taking payloads of up to 256kb and connects a client to it, without waiting for
+/// the handshake.
+async fn start_connect(
+    request_handler: EchoHandler,
+    server_options: TransportOptions,
+    client_options: TransportOptions,
+) -> Client {
+    let address = start_server(ServerConfig {
+        max_payload_size: ByteSize::kb(256),
+        transport_options: server_options,
+        ..server_config(request_handler)
+    })
+    .await;
+    Client::start_connect(
+        address,
+        ClientConfig {
+            max_payload_size: ByteSize::kb(256),
+            transport_options: client_options,
+            ..client_config()
+        },
+    )
+    .await
+    .unwrap()
+}
+
+#[test]
+fn it_round_trips_deflated_payloads() {
+    let compressions = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = EchoHandler {
+        compressions: compressions.clone(),
+        require_compression: false,
+        ..EchoHandler::default()
+    };
+    let payload = b"the quick brown fox jumps over the lazy dog. ".repeat(2048);
+    let expected = payload.clone();
+
+    let (small, response) = Runtime::new().unwrap().block_on(async move {
+        let client = start_connect(request_handler, deflate_options(), deflate_options()).await;
+        client.await_ready().await.unwrap();
+        // Below `compression_min_bytes`, so it is sent uncompressed on the same connection.
+        let small = client.request(b"0123456789".to_vec()).await.unwrap();
+        (small, client.request(payload).await.unwrap())
+    });
+
+    assert_eq!(small, b"0123456789".to_vec());
+    assert_eq!(response, expected);
+    assert_eq!(*compressions.lock().unwrap(), vec![Some("deflate")]);
+}
+
+#[test]
+fn it_round_trips_snappy_payloads_when_the_client_prefers_it() {
+    let compressions = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = EchoHandler {
+        compressions: compressions.clone(),
+        require_compression: false,
+        ..EchoHandler::default()
+    };
+    let payload = br#"{"id":"80351110224678912","username":"loqui","bot":false}"#.repeat(512);
+    let expected = payload.clone();
+    let snappy: Arc<dyn Compressor> = Arc::new(SnappyCompressor::default());
+    let deflate: Arc<dyn Compressor> = Arc::new(DeflateCompressor::default());
+    let server_options = TransportOptions::builder()
+        .compressor(deflate.clone())
+        .compressor(snappy.clone())
+        .build()
+        .unwrap();
+    let client_options = TransportOptions::builder()
+        .compressor(snappy)
+        .compressor(deflate)
+        .build()
+        .unwrap();
+
+    let response = Runtime::new().unwrap().block_on(async move {
+        let client = start_connect(request_handler, server_options, client_options).await;
+        client.await_ready().await.unwrap();
+        client.request(payload).await.unwrap()
+    });
+
+    assert_eq!(response, expected);
+    assert_eq!(*compressions.lock().unwrap(), vec![Some("snappy")]);
+}
+
+#[test]
+fn it_refuses_clients_without_a_required_compression() {
+    let compressions = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = EchoHandler {
+        compressions: compressions.clone(),
+        require_compression: true,
+        ..EchoHandler::default()
+    };
+
+    let ready = Runtime::new().unwrap().block_on(async move {
+        let client = start_connect(
+            request_handler,
+            deflate_options(),
+            TransportOptions::default(),
+        )
+        .await;
+        client.await_ready().await
+    });
+
+    assert!(ready.is_err());
+    assert!(compressions.lock().unwrap().is_empty());
+}
+
+#[test]
+fn it_primes_deflate_with_a_shared_dictionary() {
+    let request_handler = EchoHandler::default();
+    let dictionary_ids = request_handler.dictionary_ids.clone();
+    let dictionary = b"{\"user_id\": , \"username\": \"\", \"avatar\": null}".to_vec();
+    let options = || {
+        TransportOptions::builder()
+            .compressor(Arc::new(DeflateCompressor::default()))
+            .compression_min_bytes(0)
+            .dictionary(7, dictionary.clone())
+            .build()
+            .unwrap()
+    };
+    let server_options = options();
+    let client_options = options();
+    let payload = b"{\"user_id\": 42, \"username\": \"jake\", \"avatar\": null}".to_vec();
+    let expected = payload.clone();
+
+    let response = Runtime::new().unwrap().block_on(async move {
+        let client = start_connect(request_handler, server_options, client_options).await;
+        client.await_ready().await.unwrap();
+        client.request(payload).await.unwrap()
+    });
+
+    assert_eq!(response, expected);
+    assert_eq!(*dictionary_ids.lock().unwrap(), vec![Some(7)]);
+}
+
+#[test]
+fn it_round_trips_zstd_payloads_at_different_levels() {
+    let request_handler = EchoHandler::default();
+    let compressions = request_handler.compressions.clone();
+    let payload = b"the quick brown fox jumps over the lazy dog. ".repeat(2048);
+    let expected = payload.clone();
+    // Each side compresses at its own level, the other decompresses either.
+    let server_options = TransportOptions::builder().zstd(1).build().unwrap();
+    let client_options = TransportOptions::builder().zstd(19).build().unwrap();
+
+    let (small, response) = Runtime::new().unwrap().block_on(async move {
+        let client = start_connect(request_handler, server_options, client_options).await;
+        client.await_ready().await.unwrap();
+        // Below `compression_min_bytes`, so it is sent uncompressed on the same connection.
+        let small = client.request(b"0123456789".to_vec()).await.unwrap();
+        (small, client.request(payload).await.unwrap())
+    });
+
+    assert_eq!(small, b"0123456789".to_vec());
+    assert_eq!(response, expected);
+    assert_eq!(*compressions.lock().unwrap(), vec![Some("zstd")]);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use loqui_connection::Metrics;
+use loqui_server::{Config as ServerConfig, ConnectionTag, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+
+/// Records the tag of every connection that started.
+struct TaggingHandler {
+    started: Arc<Mutex<Vec<ConnectionTag>>>,
+}
+
+impl RequestHandler for TaggingHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    fn on_connection_start(&self, connection: &ConnectionTag) {
+        self.started.lock().unwrap().push(connection.clone());
+    }
+}
+
+/// Records the tag of every connection it reported for.
+#[derive(Debug, Default)]
+struct TaggingMetrics {
+    tagged: Arc<Mutex<Vec<ConnectionTag>>>,
+}
+
+impl Metrics for TaggingMetrics {
+    fn for_connection(&self, connection: &ConnectionTag) -> Option<Arc<dyn Metrics>> {
+        self.tagged.lock().unwrap().push(connection.clone());
+        None
+    }
+}
+
+#[test]
+fn it_tags_each_connection_with_an_id_and_labels() {
+    let started = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = TaggingHandler {
+        started: started.clone(),
+    };
+    let metrics = TaggingMetrics::default();
+    let tagged = metrics.tagged.clone();
+    let server_options = TransportOptions::builder()
+        .label("role", "server")
+        .label("shard", "3")
+        .metrics(Arc::new(metrics))
+        .build()
+        .unwrap();
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            transport_options: server_options,
+            ..server_config(request_handler)
+        })
+        .await;
+
+        for _ in 0..2 {
+            let client = connect(address, client_config()).await;
+            client.request(vec![]).await.unwrap();
+        }
+    });
+
+    let started = started.lock().unwrap().clone();
+    assert_eq!(started.len(), 2);
+    assert_ne!(started[0].connection_id, started[1].connection_id);
+    for connection in &started {
+        assert_eq!(connection.label("shard"), Some("3"));
+        assert_eq!(connection.to_string(), "role=server shard=3");
+    }
+    assert_eq!(*tagged.lock().unwrap(), started);
+}
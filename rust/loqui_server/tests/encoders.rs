@@ -0,0 +1,121 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use failure::Error;
+use loqui_client::{Client, Config as ClientConfig, ErrorDetails};
+use loqui_connection::encoders::CborFactory;
+use loqui_connection::{Encoder, Factory, LoquiErrorCode};
+use loqui_server::{Config as ServerConfig, RequestHandler};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::runtime::Runtime;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Reading {
+    name: String,
+    values: Vec<f64>,
+    labels: BTreeMap<String, BTreeMap<String, f64>>,
+}
+
+type Cbor = CborFactory<Reading, Reading>;
+
+/// Decodes with the negotiated encoding and echoes the decoded value back.
+struct EchoHandler {}
+
+impl RequestHandler for EchoHandler {
+    fn validate_request(&self, payload: &[u8], encoding: &'static str) -> Result<(), Error> {
+        let encoder = Cbor::make(encoding).expect("Unsupported encoding.");
+        encoder.decode(payload.to_vec()).map(|_reading| ())
+    }
+
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        let encoder = Cbor::make(encoding).expect("Unsupported encoding.");
+        let reading = encoder.decode(payload).expect("Failed to decode.");
+        Box::pin(async move { encoder.encode(reading).expect("Failed to encode.") })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+/// Connects a client speaking cbor to a fresh server.
+async fn connect_cbor() -> Client {
+    let address = start_server(ServerConfig {
+        supported_encodings: Cbor::ENCODINGS,
+        ..server_config(EchoHandler {})
+    })
+    .await;
+    connect(
+        address,
+        ClientConfig {
+            supported_encodings: Cbor::ENCODINGS,
+            ..client_config()
+        },
+    )
+    .await
+}
+
+#[test]
+fn it_round_trips_cbor() {
+    let mut labels = BTreeMap::new();
+    let mut region = BTreeMap::new();
+    region.insert("us-east".to_string(), 0.5);
+    labels.insert("region".to_string(), region);
+    let reading = Reading {
+        name: "cpu".to_string(),
+        values: vec![1.5, -2.25, 1e-9],
+        labels,
+    };
+
+    let result = Runtime::new().unwrap().block_on(async move {
+        let client = connect_cbor().await;
+
+        let encoding = client.encoding().unwrap();
+        assert_eq!(encoding, "cbor");
+        let encoder = Cbor::make(encoding).unwrap();
+        let response = client
+            .request(encoder.encode(reading.clone()).unwrap())
+            .await
+            .unwrap();
+        (reading, encoder.decode(response).unwrap())
+    });
+    assert_eq!(result.0, result.1);
+}
+
+#[test]
+fn it_rejects_requests_that_fail_to_decode() {
+    let result = Runtime::new().unwrap().block_on(async move {
+        let client = connect_cbor().await;
+        client.request(b"not cbor".to_vec()).await
+    });
+    let error = result.unwrap_err().to_string();
+    assert!(error.starts_with("Failed to decode request. encoding=cbor"));
+}
+
+#[test]
+fn it_decodes_error_responses_with_the_encoding() {
+    Runtime::new().unwrap().block_on(async move {
+        let client = connect_cbor().await;
+        let error = client.request(b"not cbor".to_vec()).await.unwrap_err();
+        let error = client.decode_error::<Cbor>(error).unwrap();
+        assert_eq!(error.code, Some(LoquiErrorCode::BadRequest));
+        // The server sends the reason of a bad request as a string, which isn't cbor.
+        match error.details {
+            ErrorDetails::Message(message) => {
+                assert!(message.starts_with("Failed to decode request. encoding=cbor"))
+            }
+            details => panic!("Unexpected details. {:?}", details),
+        }
+    });
+}
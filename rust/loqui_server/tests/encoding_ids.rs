@@ -0,0 +1,118 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use loqui_client::Config as ClientConfig;
+use loqui_connection::LoquiError;
+use loqui_server::{Config as ServerConfig, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::runtime::Runtime;
+
+/// Responds with the encoding each request was handled with, after its payload.
+struct EncodingHandler;
+
+impl RequestHandler for EncodingHandler {
+    fn handle_request(
+        &self,
+        mut payload: Vec<u8>,
+        encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        payload.extend_from_slice(encoding.as_bytes());
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+fn transport_options(encoding_ids: bool) -> TransportOptions {
+    TransportOptions::builder()
+        .encoding_ids(encoding_ids)
+        .build()
+        .unwrap()
+}
+
+fn is_invalid_encoding(result: Result<Vec<u8>, failure::Error>) -> bool {
+    matches!(
+        result.unwrap_err().downcast_ref::<LoquiError>(),
+        Some(LoquiError::InvalidEncoding)
+    )
+}
+
+#[test]
+fn it_handles_each_request_with_the_encoding_it_selected() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            supported_encodings: &["json", "msgpack"],
+            transport_options: transport_options(true),
+            ..server_config(EncodingHandler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                supported_encodings: &["json", "msgpack", "cbor"],
+                transport_options: transport_options(true),
+                ..client_config()
+            },
+        )
+        .await;
+        assert_eq!(client.encoding().unwrap(), "json");
+
+        assert_eq!(
+            client
+                .request_with_encoding(b"bulk:".to_vec(), "msgpack")
+                .await
+                .unwrap(),
+            b"bulk:msgpack"
+        );
+        assert_eq!(
+            client.request(b"control:".to_vec()).await.unwrap(),
+            b"control:json"
+        );
+        // Requests with an empty payload still select their encoding.
+        assert_eq!(
+            client
+                .request_with_encoding(vec![], "msgpack")
+                .await
+                .unwrap(),
+            b"msgpack"
+        );
+        // The server doesn't support it.
+        assert!(is_invalid_encoding(
+            client.request_with_encoding(vec![], "cbor").await
+        ));
+        assert_eq!(client.request(vec![]).await.unwrap(), b"json");
+    });
+}
+
+#[test]
+fn it_uses_the_negotiated_encoding_unless_both_sides_opted_in() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            supported_encodings: &["json", "msgpack"],
+            transport_options: transport_options(false),
+            ..server_config(EncodingHandler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                supported_encodings: &["json", "msgpack"],
+                transport_options: transport_options(true),
+                ..client_config()
+            },
+        )
+        .await;
+
+        assert!(is_invalid_encoding(
+            client.request_with_encoding(vec![], "msgpack").await
+        ));
+        assert_eq!(client.request(vec![]).await.unwrap(), b"json");
+    });
+}
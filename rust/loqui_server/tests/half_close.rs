@@ -0,0 +1,59 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use futures::future::join;
+use loqui_connection::LoquiError;
+use loqui_server::RequestHandler;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+
+/// Echoes the request back after a while.
+struct SlowEchoHandler {}
+
+impl RequestHandler for SlowEchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move {
+            delay_for(Duration::from_millis(200)).await;
+            payload
+        })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_responds_to_in_flight_requests_after_half_close() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(SlowEchoHandler {})).await;
+        let client = connect(address, client_config()).await;
+
+        let half_close = async {
+            delay_for(Duration::from_millis(50)).await;
+            client.half_close().unwrap();
+            client.request(b"late".to_vec()).await
+        };
+        let (response, late) = join(client.request(b"hello".to_vec()), half_close).await;
+        assert_eq!(response.unwrap(), b"hello".to_vec());
+        match late.unwrap_err().downcast_ref::<LoquiError>() {
+            Some(LoquiError::HalfClosed) => {}
+            other => panic!("expected half closed. {:?}", other),
+        }
+
+        // The server goes away once it responded.
+        delay_for(Duration::from_millis(100)).await;
+        assert!(client.is_closed());
+    });
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::{server_config, start_server_on_thread, EchoHandler};
+use loqui_server::Config as ServerConfig;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const UPGRADE_REQUEST: &[u8] =
+    b"GET /_rpc HTTP/1.1\r\nHost: 127.0.0.1 \r\nUpgrade: loqui\r\nConnection: upgrade\r\n\r\n";
+
+#[test]
+fn it_goes_away_with_both_lists_without_a_common_encoding() {
+    let address = start_server_on_thread(ServerConfig {
+        supported_encodings: &["json", "cbor"],
+        ..server_config(EchoHandler)
+    });
+
+    let mut socket = TcpStream::connect(address).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .unwrap();
+    socket.write_all(UPGRADE_REQUEST).unwrap();
+    let mut upgrade_response = [0; 73];
+    socket.read_exact(&mut upgrade_response).unwrap();
+
+    // A `Hello` offering only msgpack and no compression.
+    let settings = b"msgpack|";
+    let mut hello = vec![1, 0, 1];
+    hello.extend_from_slice(&(settings.len() as u32).to_be_bytes());
+    hello.extend_from_slice(settings);
+    socket.write_all(&hello).unwrap();
+
+    let mut go_away = vec![];
+    socket.read_to_end(&mut go_away).unwrap();
+    // The opcode, flags and the no common encoding code.
+    assert_eq!(&go_away[..4], &[8, 0, 0, 3]);
+    assert_eq!(
+        String::from_utf8_lossy(&go_away[8..]),
+        "No common encoding. offered=[\"msgpack\"] supported=[\"json\", \"cbor\"]"
+    );
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use common::{server_config, start_server_on_thread, EchoHandler};
+use loqui_server::{Config as ServerConfig, TransportOptions};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const UPGRADE_REQUEST: &[u8] =
+    b"GET /_rpc HTTP/1.1\r\nHost: 127.0.0.1 \r\nUpgrade: loqui\r\nConnection: upgrade\r\n\r\n";
+
+#[test]
+fn it_drops_peers_that_stall_in_the_handshake() {
+    let address = start_server_on_thread(ServerConfig {
+        supported_encodings: &["json"],
+        transport_options: TransportOptions::builder()
+            .handshake_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap(),
+        ..server_config(EchoHandler)
+    });
+
+    let mut socket = TcpStream::connect(address).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .unwrap();
+    socket.write_all(UPGRADE_REQUEST).unwrap();
+    let mut upgrade_response = [0; 73];
+    socket.read_exact(&mut upgrade_response).unwrap();
+
+    // The opcode, flags and version of a `Hello`, then nothing.
+    let started_at = Instant::now();
+    socket.write_all(&[1, 0, 1]).unwrap();
+    let mut buffer = [0; 64];
+    match socket.read(&mut buffer) {
+        Ok(0) => {}
+        Ok(read) => panic!("Unexpected bytes. read={}", read),
+        Err(error) => assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset),
+    }
+    let elapsed = started_at.elapsed();
+    assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+}
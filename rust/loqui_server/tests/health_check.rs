@@ -0,0 +1,68 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use loqui_client::Config as ClientConfig;
+use loqui_server::{Config as ServerConfig, RequestHandler};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::task::spawn;
+use tokio::time::delay_for;
+
+/// Takes a while to answer, so requests stay in flight.
+struct SlowHandler {}
+
+impl RequestHandler for SlowHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move {
+            delay_for(Duration::from_millis(300)).await;
+            payload
+        })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_answers_health_checks_without_the_handler() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            supported_encodings: &["msgpack", "json"],
+            ..server_config(SlowHandler {})
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                supported_encodings: &["json"],
+                ..client_config()
+            },
+        )
+        .await;
+        let client = Arc::new(client);
+
+        let request_client = client.clone();
+        let request = spawn(async move { request_client.request(b"slow".to_vec()).await });
+        delay_for(Duration::from_millis(100)).await;
+        let health = client.health_check().await.unwrap();
+        assert_eq!(health.in_flight_requests, 1);
+        assert_eq!(health.encoding, "json");
+        assert!(health.uptime >= Duration::from_millis(100));
+
+        assert_eq!(request.await.unwrap().unwrap(), b"slow".to_vec());
+        let health = client.health_check().await.unwrap();
+        assert_eq!(health.in_flight_requests, 0);
+    });
+}
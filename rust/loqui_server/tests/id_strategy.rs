@@ -0,0 +1,74 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server, EchoHandler};
+use loqui_client::Config as ClientConfig;
+use loqui_server::{Config as ServerConfig, IdStrategy, IdStrategyFactory, TransportOptions};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+
+/// Wraps around within a small range, striding so an id isn't reused until the whole range was.
+/// Records the ids it hands out.
+#[derive(Debug, Default)]
+struct StridedIdsFactory {
+    allocated: Arc<Mutex<Vec<u32>>>,
+}
+
+struct StridedIds {
+    next: u32,
+    allocated: Arc<Mutex<Vec<u32>>>,
+}
+
+impl IdStrategy for StridedIds {
+    fn next(&mut self) -> u32 {
+        let next = self.next;
+        self.next = (self.next + 3) % 10;
+        self.allocated.lock().unwrap().push(next);
+        next
+    }
+}
+
+impl IdStrategyFactory for StridedIdsFactory {
+    fn make(&self, _epoch: u32) -> Box<dyn IdStrategy> {
+        Box::new(StridedIds {
+            next: 4,
+            allocated: self.allocated.clone(),
+        })
+    }
+}
+
+#[test]
+fn it_allocates_ids_with_the_configured_strategy() {
+    let id_strategy = StridedIdsFactory::default();
+    let allocated = id_strategy.allocated.clone();
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            supported_encodings: &["json"],
+            ..server_config(EchoHandler)
+        })
+        .await;
+
+        let transport_options = TransportOptions::builder()
+            .id_strategy(Arc::new(id_strategy))
+            .build()
+            .unwrap();
+        let client = connect(
+            address,
+            ClientConfig {
+                supported_encodings: &["json"],
+                transport_options,
+                ..client_config()
+            },
+        )
+        .await;
+        for payload in &[b"one", b"two", b"six"] {
+            let response = client.request(payload.to_vec()).await.unwrap();
+            assert_eq!(response, payload.to_vec());
+        }
+    });
+
+    // Pings take ids too. The sequence wrapped to 0, which is skipped.
+    let allocated = allocated.lock().unwrap();
+    assert!(allocated.len() >= 4);
+    assert_eq!(allocated[..4], [4, 7, 0, 3]);
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use loqui_client::Config as ClientConfig;
+use loqui_connection::encoders::CborFactory;
+use loqui_connection::{Encoder, Factory, LoquiError, LoquiErrorCode};
+use loqui_server::{Config as ServerConfig, InspectPayload, RequestHandler, RequestInspector};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Command {
+    user: String,
+}
+
+type Cbor = CborFactory<Command, Command>;
+
+/// Rejects commands of anonymous users.
+struct AuditInspector;
+
+impl RequestInspector for AuditInspector {
+    type Factory = Cbor;
+
+    fn inspect_request(&self, command: &Command) -> Result<(), LoquiErrorCode> {
+        if command.user.is_empty() {
+            Err(LoquiErrorCode::Unauthorized)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Echoes the request back, counting the requests it handled.
+struct EchoHandler {
+    handled: Arc<AtomicUsize>,
+}
+
+impl RequestHandler for EchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        self.handled.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    fn request_inspector(&self) -> Option<&dyn InspectPayload> {
+        Some(&AuditInspector)
+    }
+}
+
+#[test]
+fn it_rejects_requests_the_inspector_refuses() {
+    let handled = Arc::new(AtomicUsize::new(0));
+    let server_handled = handled.clone();
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            supported_encodings: Cbor::ENCODINGS,
+            ..server_config(EchoHandler {
+                handled: server_handled,
+            })
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                supported_encodings: Cbor::ENCODINGS,
+                ..client_config()
+            },
+        )
+        .await;
+        let encoder = Cbor::make(client.encoding().unwrap()).unwrap();
+
+        let command = Command {
+            user: "ada".to_string(),
+        };
+        let response = client
+            .request(encoder.encode(command.clone()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(encoder.decode(response).unwrap(), command);
+
+        let anonymous = Command {
+            user: String::new(),
+        };
+        let error = client
+            .request(encoder.encode(anonymous).unwrap())
+            .await
+            .unwrap_err();
+        match error.downcast_ref::<LoquiError>() {
+            Some(LoquiError::ErrorResponse { code, .. }) => {
+                assert_eq!(*code, LoquiErrorCode::Unauthorized as u16)
+            }
+            other => panic!("expected an error response. {:?}", other),
+        }
+    });
+    assert_eq!(handled.load(Ordering::SeqCst), 1);
+}
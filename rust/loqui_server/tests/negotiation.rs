@@ -0,0 +1,140 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use loqui_client::Config as ClientConfig;
+use loqui_server::{
+    Config as ServerConfig, HandshakeTiming, Negotiated, RequestHandler, TransportOptions,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// Records the settings and timing of every completed handshake.
+struct RecordingHandler {
+    negotiated: Arc<Mutex<Vec<Negotiated>>>,
+    timings: Arc<Mutex<Vec<HandshakeTiming>>>,
+}
+
+impl RequestHandler for RecordingHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    fn on_handshake_complete(&self, negotiated: &Negotiated, timing: &HandshakeTiming) {
+        self.negotiated.lock().unwrap().push(negotiated.clone());
+        self.timings.lock().unwrap().push(timing.clone());
+    }
+}
+
+/// Connects a client to a fresh server and returns the encoding the client settled on along with
+/// what the server negotiated and how long its handshake took.
+fn negotiate(
+    server_encodings: &'static [&'static str],
+    client_encodings: &'static [&'static str],
+    client_transport_options: TransportOptions,
+) -> (&'static str, Vec<Negotiated>, Vec<HandshakeTiming>) {
+    let negotiated = Arc::new(Mutex::new(Vec::new()));
+    let timings = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = RecordingHandler {
+        negotiated: negotiated.clone(),
+        timings: timings.clone(),
+    };
+
+    let client_encoding = Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            supported_encodings: server_encodings,
+            ..server_config(request_handler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                supported_encodings: client_encodings,
+                transport_options: client_transport_options,
+                ..client_config()
+            },
+        )
+        .await;
+        // The server handles requests only after its handshake callback ran.
+        client.request(vec![]).await.unwrap();
+        client.encoding().unwrap()
+    });
+    let negotiated = negotiated.lock().unwrap().clone();
+    let timings = timings.lock().unwrap().clone();
+    (client_encoding, negotiated, timings)
+}
+
+#[test]
+fn it_settles_on_the_common_encoding() {
+    let (client_encoding, negotiated, _timings) =
+        negotiate(&["json"], &["msgpack", "json"], TransportOptions::default());
+
+    assert_eq!(client_encoding, "json");
+    assert_eq!(
+        negotiated,
+        vec![Negotiated {
+            encoding: "json",
+            compression: None,
+            dictionary_id: None,
+            ping_interval: Duration::from_secs(5),
+            peer_version: 1,
+            batches: true,
+            ping_timestamps: false,
+            checksums: false,
+            encodings: None,
+        }]
+    );
+}
+
+#[test]
+fn it_settles_on_the_highest_common_schema_version() {
+    let (client_encoding, negotiated, _timings) = negotiate(
+        &["json@1", "json@2", "json@3"],
+        &["json@1", "json@2"],
+        TransportOptions::default(),
+    );
+
+    assert_eq!(client_encoding, "json@2");
+    assert_eq!(negotiated[0].encoding, "json@2");
+}
+
+#[test]
+fn it_settles_on_the_shorter_ping_interval() {
+    let propose = |ping_interval| {
+        TransportOptions::builder()
+            .proposed_ping_interval(ping_interval)
+            .build()
+            .unwrap()
+    };
+    let (_client_encoding, shorter, _timings) =
+        negotiate(&["json"], &["json"], propose(Duration::from_secs(2)));
+    let (_client_encoding, longer, _timings) =
+        negotiate(&["json"], &["json"], propose(Duration::from_secs(30)));
+
+    assert_eq!(shorter[0].ping_interval, Duration::from_secs(2));
+    assert_eq!(longer[0].ping_interval, Duration::from_secs(5));
+}
+
+#[test]
+fn it_reports_how_long_the_handshake_took() {
+    let (_client_encoding, _negotiated, timings) =
+        negotiate(&["json"], &["json"], TransportOptions::default());
+
+    assert_eq!(timings.len(), 1);
+    assert!(timings[0].duration < Duration::from_secs(5));
+    assert!(!timings[0].resumed);
+}
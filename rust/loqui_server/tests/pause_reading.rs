@@ -0,0 +1,44 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server, EchoHandler};
+use futures::future::{select, Either};
+use loqui_client::Config as ClientConfig;
+use loqui_server::{Config as ServerConfig, TransportOptions};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+
+#[test]
+fn it_holds_responses_back_while_reading_is_paused() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            ping_interval: Duration::from_millis(50),
+            ..server_config(EchoHandler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                transport_options: TransportOptions::builder()
+                    .ping_timeout(Duration::from_millis(100))
+                    .build()
+                    .unwrap(),
+                ..client_config()
+            },
+        )
+        .await;
+
+        client.pause_reading().unwrap();
+        let request = client.request(b"hello".to_vec());
+        futures::pin_mut!(request);
+        // Longer than the ping timeout, the connection survives the pongs it couldn't read.
+        let request = match select(request, delay_for(Duration::from_millis(300))).await {
+            Either::Left((response, _delay)) => panic!("read while paused. {:?}", response),
+            Either::Right(((), request)) => request,
+        };
+        assert!(!client.is_closed());
+
+        client.resume_reading().unwrap();
+        assert_eq!(request.await.unwrap(), b"hello".to_vec());
+    });
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use common::{server_config, start_server};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::StreamExt;
+use loqui_client::{Client, Config as ClientConfig, ServerRequestHandler};
+use loqui_connection::LoquiError;
+use loqui_server::{ClientConnection, Config as ServerConfig, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// Echoes requests and hands every connected client to the test.
+struct EchoHandler {
+    clients: UnboundedSender<ClientConnection>,
+}
+
+impl RequestHandler for EchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    fn on_client_connected(&self, client: ClientConnection) {
+        self.clients.unbounded_send(client).unwrap();
+    }
+}
+
+/// Answers the server's requests with their payload reversed.
+struct ReverseHandler;
+
+impl ServerRequestHandler for ReverseHandler {
+    fn handle_request(
+        &self,
+        mut payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        payload.reverse();
+        Box::pin(async move { payload })
+    }
+}
+
+fn transport_options() -> TransportOptions {
+    TransportOptions::builder()
+        .peer_requests(true)
+        .build()
+        .unwrap()
+}
+
+fn client_config() -> ClientConfig {
+    ClientConfig {
+        supported_encodings: &["json"],
+        transport_options: transport_options(),
+        ..common::client_config()
+    }
+}
+
+#[test]
+fn it_sends_requests_both_ways() {
+    let (clients, mut connected) = unbounded();
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            supported_encodings: &["json"],
+            transport_options: transport_options(),
+            ..server_config(EchoHandler { clients })
+        })
+        .await;
+
+        let client = Client::start_connect_with_handler(address, client_config(), ReverseHandler)
+            .await
+            .unwrap();
+        let server_side = connected.next().await.unwrap();
+        let timeout = Duration::from_secs(5);
+        // Waits for the handshake before it's sent.
+        assert_eq!(
+            server_side.request(b"abc".to_vec(), timeout).await.unwrap(),
+            b"cba"
+        );
+        assert_eq!(client.request(b"abc".to_vec()).await.unwrap(), b"abc");
+        assert_eq!(
+            server_side.request(vec![], timeout).await.unwrap(),
+            Vec::<u8>::new()
+        );
+
+        // A client without a handler rejects the server's requests.
+        let client = Client::start_connect(address, client_config())
+            .await
+            .unwrap();
+        let server_side = connected.next().await.unwrap();
+        let error = server_side
+            .request(b"abc".to_vec(), timeout)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::ErrorResponse { .. })
+        ));
+        assert_eq!(client.request(b"abc".to_vec()).await.unwrap(), b"abc");
+        assert!(!server_side.is_closed());
+    });
+}
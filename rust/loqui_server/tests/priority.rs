@@ -0,0 +1,102 @@
+mod common;
+
+use bytesize::ByteSize;
+use common::{client_config, connect, server_config, start_server};
+use futures::future::{join, join3};
+use loqui_client::{Config as ClientConfig, Priority};
+use loqui_server::{Config as ServerConfig, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+
+/// Records the order requests are served in, each taking a while to compute.
+struct SlowHandler {
+    served: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl RequestHandler for SlowHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        self.served.lock().unwrap().push(payload.clone());
+        Box::pin(async move {
+            delay_for(Duration::from_millis(100)).await;
+            payload
+        })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_serves_urgent_requests_first() {
+    let served = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = SlowHandler {
+        served: served.clone(),
+    };
+
+    let responses = Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            max_payload_size: ByteSize::kb(5),
+            transport_options: TransportOptions::builder()
+                .max_concurrent_requests(1)
+                .request_queue(8, Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            ..server_config(request_handler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                max_payload_size: ByteSize::kb(5),
+                ..client_config()
+            },
+        )
+        .await;
+        // Takes the only slot, so the others are queued behind it.
+        let first = client.request(b"first".to_vec());
+        let queued = async {
+            delay_for(Duration::from_millis(20)).await;
+            join3(
+                client.request_with_priority(b"low".to_vec(), Priority::Low),
+                client.request(b"normal".to_vec()),
+                client.request_with_priority(b"critical".to_vec(), Priority::Critical),
+            )
+            .await
+        };
+        let (first, (low, normal, critical)) = join(first, queued).await;
+        vec![first, low, normal, critical]
+    });
+
+    let responses: Vec<Vec<u8>> = responses.into_iter().map(Result::unwrap).collect();
+    assert_eq!(
+        responses,
+        vec![
+            b"first".to_vec(),
+            b"low".to_vec(),
+            b"normal".to_vec(),
+            b"critical".to_vec()
+        ]
+    );
+    assert_eq!(
+        *served.lock().unwrap(),
+        vec![
+            b"first".to_vec(),
+            b"critical".to_vec(),
+            b"normal".to_vec(),
+            b"low".to_vec()
+        ]
+    );
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use loqui_server::RequestHandler;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+
+/// Records the payload of every push.
+struct RecordingHandler {
+    pushes: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl RequestHandler for RecordingHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_push(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.pushes.lock().unwrap().push(payload);
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_acks_pushes() {
+    let pushes = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = RecordingHandler {
+        pushes: pushes.clone(),
+    };
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(request_handler)).await;
+        let client = connect(address, client_config()).await;
+        client.push(b"unacked".to_vec()).await.unwrap();
+        client.push_acked(b"acked".to_vec()).await.unwrap();
+        // Pushes are acked once delegated, the server handles them in their own tasks.
+        delay_for(Duration::from_millis(100)).await;
+    });
+
+    let mut pushes = pushes.lock().unwrap().clone();
+    pushes.sort();
+    assert_eq!(pushes, vec![b"acked".to_vec(), b"unacked".to_vec()]);
+}
+
+#[test]
+fn it_resolves_pushes_once_flushed() {
+    let pushes = Arc::new(Mutex::new(Vec::new()));
+    let request_handler = RecordingHandler {
+        pushes: pushes.clone(),
+    };
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(request_handler)).await;
+        let client = connect(address, client_config()).await;
+        client.push_flushed(b"flushed".to_vec()).await.unwrap();
+        // Flushed doesn't mean handled, the server handles pushes in their own tasks.
+        delay_for(Duration::from_millis(100)).await;
+    });
+
+    assert_eq!(*pushes.lock().unwrap(), vec![b"flushed".to_vec()]);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{server_config, start_server, EchoHandler};
+use loqui_client::{Client, Config as ClientConfig};
+use loqui_connection::LoquiError;
+use loqui_server::{Config as ServerConfig, TransportOptions};
+use tokio::runtime::Runtime;
+
+fn client_config(
+    supported_encodings: &'static [&'static str],
+    queue_until_ready: bool,
+) -> ClientConfig {
+    ClientConfig {
+        supported_encodings,
+        transport_options: TransportOptions::builder()
+            .queue_until_ready(queue_until_ready)
+            .build()
+            .unwrap(),
+        ..common::client_config()
+    }
+}
+
+fn json_server_config() -> ServerConfig<EchoHandler> {
+    ServerConfig {
+        supported_encodings: &["json"],
+        ..server_config(EchoHandler)
+    }
+}
+
+#[test]
+fn it_queues_or_rejects_requests_made_before_ready() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(json_server_config()).await;
+
+        let client = Client::start_connect(address, client_config(&["json"], true))
+            .await
+            .unwrap();
+        assert!(!client.is_ready());
+        assert_eq!(client.request(b"early".to_vec()).await.unwrap(), b"early");
+
+        let client = Client::start_connect(address, client_config(&["json"], false))
+            .await
+            .unwrap();
+        let result = client.request(b"early".to_vec()).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<LoquiError>(),
+            Some(LoquiError::NotReady)
+        ));
+        client.await_ready().await.unwrap();
+        assert_eq!(client.request(b"later".to_vec()).await.unwrap(), b"later");
+    });
+}
+
+#[test]
+fn it_fails_ready_when_the_handshake_fails() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(json_server_config()).await;
+
+        let client = Client::start_connect(address, client_config(&["msgpack"], true))
+            .await
+            .unwrap();
+        let error = client.await_ready().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LoquiError>(),
+            Some(LoquiError::HandshakeFailed { .. })
+        ));
+        // Queued requests fail with the handshake.
+        let error = client.request(vec![]).await.unwrap_err();
+        assert!(error.downcast_ref::<LoquiError>().is_some());
+    });
+}
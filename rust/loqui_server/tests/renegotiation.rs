@@ -0,0 +1,87 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use loqui_client::Config as ClientConfig;
+use loqui_connection::LoquiError;
+use loqui_server::{Config as ServerConfig, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::runtime::Runtime;
+
+/// Responds with the encoding each request was handled with.
+struct EncodingHandler;
+
+impl RequestHandler for EncodingHandler {
+    fn handle_request(
+        &self,
+        _payload: Vec<u8>,
+        encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { encoding.as_bytes().to_vec() })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+fn transport_options(renegotiation: bool) -> TransportOptions {
+    TransportOptions::builder()
+        .renegotiation(renegotiation)
+        .build()
+        .unwrap()
+}
+
+/// Connects a client that can renegotiate to a fresh server, then asks to switch to msgpack.
+/// Returns the result along with the encoding the next request was handled with.
+fn renegotiate(server_renegotiation: bool) -> (Result<&'static str, String>, Vec<u8>) {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            supported_encodings: &["json", "msgpack"],
+            transport_options: transport_options(server_renegotiation),
+            ..server_config(EncodingHandler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                supported_encodings: &["json", "msgpack"],
+                transport_options: transport_options(true),
+                ..client_config()
+            },
+        )
+        .await;
+        assert_eq!(client.request(vec![]).await.unwrap(), b"json");
+
+        let result = client
+            .renegotiate_encoding("msgpack")
+            .await
+            .map_err(|error| match error.downcast_ref::<LoquiError>() {
+                Some(LoquiError::RenegotiationRefused { requested }) => requested.to_string(),
+                other => panic!("expected refused renegotiation. {:?}", other),
+            });
+        assert_eq!(
+            client.encoding().unwrap(),
+            *result.as_ref().unwrap_or(&"json")
+        );
+        (result, client.request(vec![]).await.unwrap())
+    })
+}
+
+#[test]
+fn it_switches_encoding_without_reconnecting() {
+    let (result, encoding) = renegotiate(true);
+    assert_eq!(result, Ok("msgpack"));
+    assert_eq!(encoding, b"msgpack");
+}
+
+#[test]
+fn it_keeps_the_encoding_unless_both_sides_opted_in() {
+    let (result, encoding) = renegotiate(false);
+    assert_eq!(result, Err("msgpack".to_string()));
+    assert_eq!(encoding, b"json");
+}
@@ -0,0 +1,32 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server, EchoHandler};
+use loqui_client::Flags;
+use loqui_connection::LoquiError;
+use tokio::runtime::Runtime;
+
+#[test]
+fn it_rejects_request_flags_the_connection_sets() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(EchoHandler)).await;
+        let client = connect(address, client_config()).await;
+
+        for flags in &[Flags::Compressed as u8, Flags::Streaming as u8] {
+            let error = client
+                .request_with_flags(b"hello".to_vec(), *flags)
+                .await
+                .unwrap_err();
+            match error.downcast_ref::<LoquiError>() {
+                Some(LoquiError::InvalidRequestFlags { flags: rejected }) => {
+                    assert_eq!(rejected, flags)
+                }
+                other => panic!("expected invalid request flags. {:?}", other),
+            }
+        }
+        let response = client
+            .request_with_flags(b"hello".to_vec(), Flags::NO_COMPRESS)
+            .await
+            .unwrap();
+        assert_eq!(response, b"hello");
+    });
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use futures::future::join3;
+use loqui_connection::{LoquiError, LoquiErrorCode};
+use loqui_server::{Config as ServerConfig, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+
+/// Echoes the request back after as many milliseconds as its first byte says.
+struct DelayedEchoHandler {}
+
+impl RequestHandler for DelayedEchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move {
+            delay_for(Duration::from_millis(u64::from(payload[0]))).await;
+            payload
+        })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_times_out_each_request_with_its_own_timeout() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            transport_options: TransportOptions::builder()
+                .max_request_timeout(Duration::from_millis(50))
+                .build()
+                .unwrap(),
+            ..server_config(DelayedEchoHandler {})
+        })
+        .await;
+        let client = connect(address, client_config()).await;
+
+        let (fast, clamped, default) = join3(
+            client.request_with_timeout(vec![10], Duration::from_secs(2)),
+            // Asks for longer than the server allows.
+            client.request_with_timeout(vec![200], Duration::from_secs(2)),
+            // Without a timeout of its own the server has no limit.
+            client.request(vec![100]),
+        )
+        .await;
+        assert_eq!(fast.unwrap(), vec![10]);
+        match clamped.unwrap_err().downcast_ref::<LoquiError>() {
+            Some(LoquiError::ErrorResponse { code, .. }) => {
+                assert_eq!(*code, LoquiErrorCode::RequestTimeout as u16)
+            }
+            other => panic!("expected a timeout from the server. {:?}", other),
+        }
+        assert_eq!(default.unwrap(), vec![100]);
+
+        // The client gives up within the timeout of the request.
+        match client
+            .request_with_timeout(vec![200], Duration::from_millis(20))
+            .await
+            .unwrap_err()
+            .downcast_ref::<LoquiError>()
+        {
+            Some(LoquiError::RequestTimeout) | Some(LoquiError::ErrorResponse { .. }) => {}
+            other => panic!("expected a timeout. {:?}", other),
+        }
+    });
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use futures::future::join;
+use loqui_client::{Config as ClientConfig, RetryPolicy};
+use loqui_connection::{LoquiError, LoquiErrorCode};
+use loqui_server::{Config as ServerConfig, IdempotencyKey, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+
+/// Echoes the request back after a while, recording the idempotency key of every idempotent
+/// request it handled.
+struct SlowEchoHandler {
+    keys: Arc<Mutex<Vec<IdempotencyKey>>>,
+}
+
+impl RequestHandler for SlowEchoHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move {
+            delay_for(Duration::from_millis(200)).await;
+            payload
+        })
+    }
+
+    fn handle_idempotent_request(
+        &self,
+        payload: Vec<u8>,
+        encoding: &'static str,
+        idempotency_key: IdempotencyKey,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        self.keys.lock().unwrap().push(idempotency_key);
+        self.handle_request(payload, encoding)
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_retries_idempotent_requests_while_the_server_is_unavailable() {
+    let keys = Arc::new(Mutex::new(vec![]));
+    let server_keys = keys.clone();
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            // Requests sent while one is being handled are rejected as unavailable.
+            transport_options: TransportOptions::builder()
+                .max_concurrent_requests(1)
+                .build()
+                .unwrap(),
+            ..server_config(SlowEchoHandler { keys: server_keys })
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                retry_policy: Some(RetryPolicy {
+                    max_attempts: 5,
+                    backoff: Duration::from_millis(100),
+                    max_backoff: Duration::from_millis(100),
+                }),
+                ..client_config()
+            },
+        )
+        .await;
+
+        let busy = async {
+            delay_for(Duration::from_millis(50)).await;
+            join(
+                client.request(b"plain".to_vec()),
+                client.request_idempotent(b"retried".to_vec()),
+            )
+            .await
+        };
+        let (first, (plain, retried)) = join(client.request(b"first".to_vec()), busy).await;
+        assert_eq!(first.unwrap(), b"first".to_vec());
+        assert_eq!(retried.unwrap(), b"retried".to_vec());
+        // Plain requests are never retried.
+        match plain.unwrap_err().downcast_ref::<LoquiError>() {
+            Some(LoquiError::ErrorResponse { code, .. }) => {
+                assert_eq!(*code, LoquiErrorCode::ServiceUnavailable as u16)
+            }
+            other => panic!("expected service unavailable. {:?}", other),
+        }
+        // Only the attempt that wasn't rejected reached the handler.
+        assert_eq!(keys.lock().unwrap().len(), 1);
+    });
+}
@@ -0,0 +1,27 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server, EchoHandler};
+use loqui_protocol::frames::{Frame, Request, Response};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+#[test]
+fn it_counts_the_frames_and_bytes_of_the_connection() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(EchoHandler)).await;
+        let client = connect(address, client_config()).await;
+
+        for _ in 0..3 {
+            client.request(b"hello".to_vec()).await.unwrap();
+        }
+        let stats = client.stats();
+        assert_eq!(stats.frames_sent.get(&Request::OPCODE), Some(&3));
+        assert_eq!(stats.frames_received.get(&Response::OPCODE), Some(&3));
+        assert!(stats.bytes_written > 0);
+        assert!(stats.bytes_read > 0);
+        assert_eq!(stats.in_flight_requests, 0);
+        assert_eq!(stats.encoding, Some("identity"));
+        assert_eq!(stats.compression, None);
+        assert!(stats.uptime > Duration::from_secs(0));
+    });
+}
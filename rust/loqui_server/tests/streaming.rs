@@ -0,0 +1,99 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use futures::stream::{iter, Stream, StreamExt};
+use loqui_client::Config as ClientConfig;
+use loqui_server::{Config as ServerConfig, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::timeout;
+
+/// Streams back each byte of the request as its own response.
+struct SplitHandler {}
+
+impl RequestHandler for SplitHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async move { payload })
+    }
+
+    fn handle_request_stream(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Stream<Item = Vec<u8>> + Send>> {
+        let payloads: Vec<Vec<u8>> = payload.into_iter().map(|byte| vec![byte]).collect();
+        Box::pin(iter(payloads))
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_streams_responses() {
+    let (streamed, unary) = Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(SplitHandler {})).await;
+        let client = connect(address, client_config()).await;
+        let streamed: Vec<Vec<u8>> = client
+            .request_stream(b"abc".to_vec())
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        // Unary requests on the same connection are unaffected.
+        let unary = client.request(b"abc".to_vec()).await.unwrap();
+        (streamed, unary)
+    });
+
+    assert_eq!(streamed, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    assert_eq!(unary, b"abc".to_vec());
+}
+
+#[test]
+fn it_flow_controls_streamed_responses() {
+    let transport_options = || {
+        TransportOptions::builder()
+            .stream_window(1)
+            .build()
+            .unwrap()
+    };
+
+    let streamed = Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            transport_options: transport_options(),
+            ..server_config(SplitHandler {})
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                transport_options: transport_options(),
+                ..client_config()
+            },
+        )
+        .await;
+        let stream = client.request_stream(b"abcde".to_vec()).await.unwrap();
+        // Stalls unless the client grants a credit for each response it takes.
+        timeout(
+            Duration::from_secs(5),
+            stream.map(Result::unwrap).collect::<Vec<Vec<u8>>>(),
+        )
+        .await
+        .unwrap()
+    });
+
+    let expected: Vec<Vec<u8>> = b"abcde".iter().map(|byte| vec![*byte]).collect();
+    assert_eq!(streamed, expected);
+}
@@ -0,0 +1,134 @@
+mod common;
+
+use bytesize::ByteSize;
+use common::{client_config, connect, server_config, start_server};
+use futures::stream::{self, Stream, StreamExt};
+use loqui_client::Config as ClientConfig;
+use loqui_server::{Config as ServerConfig, RequestHandler};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::{delay_for, interval};
+
+/// Publishes three pushes to `countdown` subscriptions, a push every few milliseconds to `ticks`
+/// subscriptions, and rejects the rest.
+struct PublishingHandler {
+    /// Set once the publishing of `ticks` stopped.
+    ticks_stopped: Arc<AtomicBool>,
+}
+
+/// Sets the flag when dropped.
+struct SetOnDrop(Arc<AtomicBool>);
+
+impl Drop for SetOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, SeqCst);
+    }
+}
+
+impl RequestHandler for PublishingHandler {
+    fn handle_request(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async { payload })
+    }
+
+    fn handle_subscribe(
+        &self,
+        payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Option<Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>> {
+        match &payload[..] {
+            b"countdown" => Some(Box::pin(stream::iter(vec![
+                b"3".to_vec(),
+                b"2".to_vec(),
+                b"1".to_vec(),
+            ]))),
+            b"ticks" => {
+                let guard = SetOnDrop(self.ticks_stopped.clone());
+                let ticks = interval(Duration::from_millis(10)).map(move |_instant| {
+                    let _guard = &guard;
+                    b"tick".to_vec()
+                });
+                Some(Box::pin(ticks))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_publishes_until_unsubscribed() {
+    let ticks_stopped = Arc::new(AtomicBool::new(false));
+    let request_handler = PublishingHandler {
+        ticks_stopped: ticks_stopped.clone(),
+    };
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            max_payload_size: ByteSize::kb(5),
+            ..server_config(request_handler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                max_payload_size: ByteSize::kb(5),
+                ..client_config()
+            },
+        )
+        .await;
+
+        // The subscription ends once the server stopped publishing.
+        let countdown: Vec<Vec<u8>> = client
+            .subscribe(b"countdown".to_vec())
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(countdown, vec![b"3".to_vec(), b"2".to_vec(), b"1".to_vec()]);
+
+        // Dropping the stream unsubscribes.
+        let ticks: Vec<Vec<u8>> = client
+            .subscribe(b"ticks".to_vec())
+            .await
+            .unwrap()
+            .take(2)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(ticks, vec![b"tick".to_vec(), b"tick".to_vec()]);
+        delay_for(Duration::from_millis(100)).await;
+        assert!(ticks_stopped.load(SeqCst));
+
+        // A rejected subscription yields the error, then ends.
+        let rejected: Vec<_> = client
+            .subscribe(b"unknown".to_vec())
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].is_err());
+
+        // Requests are still served alongside subscriptions.
+        assert_eq!(
+            client.request(b"hi".to_vec()).await.unwrap(),
+            b"hi".to_vec()
+        );
+    });
+}
@@ -0,0 +1,24 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server, EchoHandler};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+#[test]
+fn it_echoes_the_trace_id() {
+    let trace_id = *Uuid::new_v4().as_bytes();
+
+    let (traced, untraced) = Runtime::new().unwrap().block_on(async move {
+        let address = start_server(server_config(EchoHandler)).await;
+        let client = connect(address, client_config()).await;
+        let traced = client
+            .request_traced(b"traced".to_vec(), trace_id)
+            .await
+            .unwrap();
+        let untraced = client.request(b"untraced".to_vec()).await.unwrap();
+        (traced, untraced)
+    });
+
+    assert_eq!(traced, (b"traced".to_vec(), Some(trace_id)));
+    assert_eq!(untraced, b"untraced".to_vec());
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server, EchoHandler};
+use futures::future::join_all;
+use loqui_client::Config as ClientConfig;
+use loqui_server::{Config as ServerConfig, TransportOptions};
+use tokio::runtime::Runtime;
+
+#[test]
+fn it_answers_every_request_with_coalesced_writes() {
+    // Small enough that the buffer is written before every ready frame is in it.
+    let transport_options = || {
+        TransportOptions::builder()
+            .write_coalescing(64)
+            .build()
+            .unwrap()
+    };
+
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            transport_options: transport_options(),
+            ..server_config(EchoHandler)
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                transport_options: transport_options(),
+                ..client_config()
+            },
+        )
+        .await;
+
+        let responses = join_all((0..50u8).map(|n| client.request(vec![n; 10]))).await;
+        for (n, response) in (0..50u8).zip(responses) {
+            assert_eq!(response.unwrap(), vec![n; 10]);
+        }
+        // Flushes are only reported once the coalesced write happened.
+        client.push_flushed(b"hello".to_vec()).await.unwrap();
+    });
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::{client_config, connect, server_config, start_server};
+use futures::future::join_all;
+use loqui_client::Config as ClientConfig;
+use loqui_server::{Config as ServerConfig, RequestHandler, TransportOptions};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time::Instant;
+
+/// Responds with a payload big enough to fill the socket buffers in a few responses.
+struct BulkHandler {}
+
+impl RequestHandler for BulkHandler {
+    fn handle_request(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send>> {
+        Box::pin(async { vec![7; 60 * 1024] })
+    }
+
+    fn handle_push(
+        &self,
+        _payload: Vec<u8>,
+        _encoding: &'static str,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+}
+
+#[test]
+fn it_closes_the_connection_once_writes_stall() {
+    Runtime::new().unwrap().block_on(async move {
+        let address = start_server(ServerConfig {
+            ping_interval: Duration::from_millis(50),
+            transport_options: TransportOptions::builder()
+                .socket_buffer_sizes(4096, 4096)
+                .write_stall_timeout(Duration::from_millis(200))
+                .build()
+                .unwrap(),
+            ..server_config(BulkHandler {})
+        })
+        .await;
+        let client = connect(
+            address,
+            ClientConfig {
+                transport_options: TransportOptions::builder()
+                    .socket_buffer_sizes(4096, 4096)
+                    .build()
+                    .unwrap(),
+                ..client_config()
+            },
+        )
+        .await;
+
+        // The server can't write the responses while the client doesn't read them, so it closes
+        // the connection well before the requests time out.
+        client.pause_reading().unwrap();
+        let started_at = Instant::now();
+        let results = join_all((0..40).map(|_| client.request(vec![]))).await;
+        assert!(results.iter().all(|result| result.is_err()));
+        assert!(started_at.elapsed() < Duration::from_secs(2));
+        assert!(client.is_closed());
+    });
+}